@@ -0,0 +1,189 @@
+//! Parsers for boot protocol structures which are left in guest memory by the bootloader.
+
+use memflow::prelude::v1::umem;
+
+/// Conventional physical address of the real-mode linux kernel (segment `0x9000`).
+pub const LINUX_REAL_MODE_BASE: umem = 0x90000;
+
+/// `HdrS` magic of the linux boot protocol.
+const SETUP_HEADER_MAGIC: u32 = 0x53726448;
+
+/// Size of the real-mode kernel data required to parse the setup header.
+pub const LINUX_SETUP_HEADER_SIZE: usize = 0x26c;
+
+/// Size of the multiboot information structure.
+pub const MULTIBOOT_INFO_SIZE: usize = 0x58;
+
+/// Selected fields of the linux kernel `setup_header`.
+///
+/// See <https://www.kernel.org/doc/html/latest/arch/x86/boot.html> for details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinuxSetupHeader {
+    /// Boot protocol version, e.g. `0x020f` for 2.15.
+    pub version: u16,
+    pub loadflags: u8,
+    /// Load address of the protected-mode kernel.
+    pub code32_start: u32,
+    pub ramdisk_image: u32,
+    pub ramdisk_size: u32,
+    pub cmd_line_ptr: u32,
+    pub kernel_alignment: u32,
+    pub relocatable_kernel: bool,
+    /// Preferred load address of a relocatable kernel (protocol 2.10+).
+    pub pref_address: u64,
+    /// Amount of memory required by the kernel during initialization (protocol 2.10+).
+    pub init_size: u32,
+}
+
+impl LinuxSetupHeader {
+    /// Parses the setup header from the start of the real-mode kernel or `boot_params`.
+    ///
+    /// Returns `None` if the buffer is too small or does not contain a valid header.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < LINUX_SETUP_HEADER_SIZE
+            || read_u16(buf, 0x1fe) != 0xaa55
+            || read_u32(buf, 0x202) != SETUP_HEADER_MAGIC
+        {
+            return None;
+        }
+
+        let version = read_u16(buf, 0x206);
+        let (pref_address, init_size) = if version >= 0x020a {
+            (read_u64(buf, 0x258), read_u32(buf, 0x260))
+        } else {
+            (0, 0)
+        };
+
+        Some(Self {
+            version,
+            loadflags: buf[0x211],
+            code32_start: read_u32(buf, 0x214),
+            ramdisk_image: read_u32(buf, 0x218),
+            ramdisk_size: read_u32(buf, 0x21c),
+            cmd_line_ptr: read_u32(buf, 0x228),
+            kernel_alignment: read_u32(buf, 0x230),
+            relocatable_kernel: buf[0x234] != 0,
+            pref_address,
+            init_size,
+        })
+    }
+
+    /// Returns true if the protected-mode kernel is loaded at 0x100000.
+    pub fn loaded_high(&self) -> bool {
+        self.loadflags & 0x1 != 0
+    }
+}
+
+/// Selected fields of the multiboot (v1) information structure.
+///
+/// Fields which are not flagged as present by the bootloader are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultibootInfo {
+    pub flags: u32,
+    /// Amount of lower and upper memory in kb.
+    pub mem: Option<(u32, u32)>,
+    pub cmdline: Option<u32>,
+    /// Number and address of the loaded boot modules.
+    pub mods: Option<(u32, u32)>,
+    /// Length and address of the memory map.
+    pub mmap: Option<(u32, u32)>,
+    pub boot_loader_name: Option<u32>,
+}
+
+impl MultibootInfo {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < MULTIBOOT_INFO_SIZE {
+            return None;
+        }
+
+        let flags = read_u32(buf, 0);
+        let flag = |bit: u32| flags & (1 << bit) != 0;
+
+        Some(Self {
+            flags,
+            mem: flag(0).then(|| (read_u32(buf, 4), read_u32(buf, 8))),
+            cmdline: flag(2).then(|| read_u32(buf, 16)),
+            mods: flag(3).then(|| (read_u32(buf, 20), read_u32(buf, 24))),
+            mmap: flag(6).then(|| (read_u32(buf, 44), read_u32(buf, 48))),
+            boot_loader_name: flag(9).then(|| read_u32(buf, 64)),
+        })
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offset of the `setup_header` in the real-mode kernel / `boot_params`.
+    const SETUP_HEADER_OFFSET: usize = 0x1f1;
+
+    /// Setup header of a bzImage with boot protocol 2.15 (offsets 0x1f1..0x26c).
+    const SETUP_HEADER: &[u8] = &[
+        0x1e, 0x01, 0x00, 0x8c, 0x9b, 0x01, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x55, 0xaa,
+        0xeb, 0x66, 0x48, 0x64, 0x72, 0x53, 0x0f, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0xc0,
+        0x3f, 0xb0, 0x81, 0x00, 0x80, 0x00, 0x00, 0x10, 0x00, 0x00, 0xa0, 0x53, 0x7f, 0x00, 0xf0,
+        0xa2, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0xff,
+        0xff, 0xff, 0x7f, 0x00, 0x00, 0x20, 0x00, 0x01, 0x15, 0x7f, 0x00, 0xff, 0x07, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa5, 0x03, 0x00,
+        0x00, 0x00, 0x0c, 0x9a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7e, 0x05, 0x90, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x9a, 0x01,
+    ];
+
+    #[test]
+    fn test_parse_setup_header() {
+        let mut buf = vec![0u8; LINUX_SETUP_HEADER_SIZE];
+        buf[SETUP_HEADER_OFFSET..].copy_from_slice(SETUP_HEADER);
+
+        let header = LinuxSetupHeader::parse(&buf).unwrap();
+        assert_eq!(header.version, 0x020f);
+        assert!(header.loaded_high());
+        assert_eq!(header.code32_start, 0x100000);
+        assert_eq!(header.ramdisk_image, 0x7f53a000);
+        assert_eq!(header.ramdisk_size, 0x1a2f000);
+        assert_eq!(header.cmd_line_ptr, 0x20000);
+        assert_eq!(header.kernel_alignment, 0x200000);
+        assert!(header.relocatable_kernel);
+        assert_eq!(header.pref_address, 0x1000000);
+        assert_eq!(header.init_size, 0x57e0000);
+
+        buf[0x202] = 0;
+        assert_eq!(LinuxSetupHeader::parse(&buf), None);
+    }
+
+    #[test]
+    fn test_parse_multiboot_info() {
+        let mut buf = vec![0u8; MULTIBOOT_INFO_SIZE];
+        buf[0..4].copy_from_slice(&0x24du32.to_le_bytes());
+        buf[4..8].copy_from_slice(&639u32.to_le_bytes());
+        buf[8..12].copy_from_slice(&130048u32.to_le_bytes());
+        buf[16..20].copy_from_slice(&0x9000u32.to_le_bytes());
+        buf[20..24].copy_from_slice(&1u32.to_le_bytes());
+        buf[24..28].copy_from_slice(&0x9100u32.to_le_bytes());
+        buf[44..48].copy_from_slice(&0x90u32.to_le_bytes());
+        buf[48..52].copy_from_slice(&0x9200u32.to_le_bytes());
+        buf[64..68].copy_from_slice(&0x9300u32.to_le_bytes());
+
+        let info = MultibootInfo::parse(&buf).unwrap();
+        assert_eq!(info.mem, Some((639, 130048)));
+        assert_eq!(info.cmdline, Some(0x9000));
+        assert_eq!(info.mods, Some((1, 0x9100)));
+        assert_eq!(info.mmap, Some((0x90, 0x9200)));
+        assert_eq!(info.boot_loader_name, Some(0x9300));
+
+        buf[0] = 0;
+        assert_eq!(MultibootInfo::parse(&buf).unwrap().mem, None);
+    }
+}
@@ -0,0 +1,500 @@
+use memflow::os::root::Os;
+use memflow::prelude::v1::*;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::mem_map::{MappingOptions, MappingSource};
+use crate::procfs::PROCFS_ROOT;
+use crate::qmp::{QmpRetry, QmpTransport};
+use crate::swap::SWAP_WIDTHS;
+use crate::QemuProcfs;
+
+/// Specifies which qemu process the connector should attach to.
+enum QemuTarget {
+    Any,
+    GuestName(String),
+    GuestNameOrPid(String, Pid),
+    Pid(Pid),
+}
+
+/// Options which are forwarded to the connector once the qemu process has been found.
+#[derive(Default)]
+pub(crate) struct BuildOptions {
+    pub map_override: Option<CTup2<Address, umem>>,
+    pub read_alignment: Option<usize>,
+    pub swap_width: Option<usize>,
+    pub ideal_batch_size: Option<u32>,
+    pub prefault: bool,
+    pub mapping: MappingOptions,
+    pub qmp_prefer: Option<QmpTransport>,
+    pub qmp: Option<String>,
+    pub qmp_retry: QmpRetry,
+    pub zeroize_buffers: bool,
+    pub cache_pages: Option<usize>,
+    pub log_samples: bool,
+    pub scan_range: Option<(Address, Address)>,
+    pub kvm_only: bool,
+    pub read_timeout: Option<Duration>,
+    pub use_mmap: bool,
+    pub soft_dirty: bool,
+    pub readonly: bool,
+    pub auto_recover: bool,
+    pub qmp_events: bool,
+    pub dump_map: Option<PathBuf>,
+    pub procfs_root: Option<PathBuf>,
+    pub process_view_only: bool,
+}
+
+impl BuildOptions {
+    /// Returns the mount point of the procfs the qemu process is accessed through.
+    ///
+    /// Returns `None` if the qemu process is only accessed through the process of the os.
+    pub fn procfs_root(&self) -> Option<PathBuf> {
+        (!self.process_view_only).then(|| {
+            self.procfs_root
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(PROCFS_ROOT))
+        })
+    }
+}
+
+/// Builder for a qemu connector instance.
+///
+/// # Examples
+///
+/// ```no_run
+/// use memflow::prelude::v1::*;
+/// use memflow_qemu::QemuProcfs;
+///
+/// let os = memflow_native::create_os(
+///     &Default::default(),
+///     Option::<std::sync::Arc<_>>::None.into(),
+/// )
+/// .unwrap();
+/// let connector = QemuProcfs::builder(os)
+///     .guest_name("win10")
+///     .read_alignment(0x1000)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct QemuProcfsBuilder<O> {
+    os: O,
+    target: QemuTarget,
+    options: BuildOptions,
+}
+
+impl<O: Os> QemuProcfsBuilder<O> {
+    pub fn new(os: O) -> Self {
+        Self {
+            os,
+            target: QemuTarget::Any,
+            options: Default::default(),
+        }
+    }
+
+    /// Attaches to the qemu process with the given `-name`.
+    pub fn guest_name(mut self, name: &str) -> Self {
+        self.target = QemuTarget::GuestName(name.to_owned());
+        self
+    }
+
+    /// Attaches to the qemu process with the given `-name`, or with `pid` if no guest has that name.
+    pub(crate) fn guest_name_or_pid(mut self, name: &str, pid: Pid) -> Self {
+        self.target = QemuTarget::GuestNameOrPid(name.to_owned(), pid);
+        self
+    }
+
+    /// Attaches to the qemu process with the given pid.
+    pub fn pid(mut self, pid: Pid) -> Self {
+        self.target = QemuTarget::Pid(pid);
+        self
+    }
+
+    /// Accesses the qemu process through the procfs mounted at `root` instead of `/proc`.
+    ///
+    /// This is required when qemu runs in another pid namespace than the connector, e.g. with
+    /// the host procfs bind-mounted to `/host/proc` in a container. The os passed to the builder
+    /// has to enumerate the processes of the same namespace, see [`ProcfsBackend::with_root`].
+    ///
+    /// [`ProcfsBackend::with_root`]: crate::ProcfsBackend::with_root
+    pub fn procfs_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.options.procfs_root = Some(root.into());
+        self
+    }
+
+    /// Accesses the qemu process through the pid namespace of the host process `pid`.
+    ///
+    /// This is a shorthand for a [`procfs_root`](Self::procfs_root) of `/proc/<pid>/root/proc`,
+    /// e.g. for a qemu process running in a container whose init process is `pid`.
+    pub fn host_pid(self, pid: Pid) -> Self {
+        self.procfs_root(host_pid_procfs_root(pid))
+    }
+
+    /// Reads the guest memory only through the process of the os passed to the builder.
+    ///
+    /// By default the guest memory is read directly through `/proc/<pid>/mem` of the qemu
+    /// process, which requires the os to enumerate the processes of that procfs. Enable this
+    /// for any other os, e.g. a bridge to a remote host, so a local process with the same pid
+    /// is never read instead. `use_mmap`, `kvm_only`, prefaulting and dirty tracking are not
+    /// available then.
+    pub fn process_view_only(mut self, process_view_only: bool) -> Self {
+        self.options.process_view_only = process_view_only;
+        self
+    }
+
+    /// Prefers qemu processes which have `/dev/kvm` open when searching for the target.
+    ///
+    /// This skips unrelated processes on busy hosts. Tcg guests are still found
+    /// when no kvm accelerated guest matches.
+    pub fn kvm_only(mut self, kvm_only: bool) -> Self {
+        self.options.kvm_only = kvm_only;
+        self
+    }
+
+    /// Overrides the base and size of the guest memory mapping in the qemu process.
+    pub fn map_override(mut self, map_override: Option<CTup2<Address, umem>>) -> Self {
+        self.options.map_override = map_override;
+        self
+    }
+
+    /// Treats the given memory region names as guest ram when parsing the qmp memory tree.
+    ///
+    /// This is only required for patched qemu builds which name their ram regions differently.
+    pub fn ram_region_names(mut self, names: Vec<String>) -> Self {
+        self.options.mapping.ram_region_names = names;
+        self
+    }
+
+    /// Restricts the guest memory mappings to the memory backend with the given id.
+    ///
+    /// This isolates e.g. a single numa node (`-object memory-backend-ram,id=mem1,...`)
+    /// or an ivshmem region. The backend is looked up in the qmp memory tree,
+    /// building the connector fails if qmp is not available or the backend does not exist.
+    pub fn backend(mut self, id: &str) -> Self {
+        self.options.mapping.backend = Some(id.to_owned());
+        self
+    }
+
+    /// Forces the guest memory mappings to be obtained from the given source.
+    ///
+    /// Unlike the default behavior this never falls back to another source,
+    /// building the connector fails if the source is not available.
+    /// [`MappingSource::File`] requires a [`map_file`](Self::map_file).
+    pub fn mapping_source(mut self, source: MappingSource) -> Self {
+        self.options.mapping.source = Some(source);
+        self
+    }
+
+    /// Json file with the guest memory mappings used by [`MappingSource::File`].
+    ///
+    /// The file contains an array of `{range_start, range_end, remap_start}` entries,
+    /// `remap_start` is relative to the guest memory mapping in the qemu process.
+    /// Unless another [`mapping_source`](Self::mapping_source) is forced, the file replaces
+    /// both the qmp and the fallback mappings. The ranges must neither be empty nor overlap.
+    pub fn map_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.mapping.map_file = Some(path.into());
+        self
+    }
+
+    /// Writes the resolved guest memory mappings to `path` once the connector was created.
+    ///
+    /// See [`QemuProcfs::dump_memory_map`] for details.
+    pub fn dump_map(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.dump_map = Some(path.into());
+        self
+    }
+
+    /// Connects to the qmp monitor at the given address instead of the `-qmp` sockets
+    /// found in the qemu command line.
+    ///
+    /// Accepts `unix:/path/to/socket`, `tcp:host:port` or a bare path to a unix socket.
+    /// This is required when the socket path differs from the one qemu was started with,
+    /// e.g. when the connector runs in a container with the socket bind-mounted.
+    pub fn qmp(mut self, addr: &str) -> Self {
+        self.options.qmp = Some(addr.to_owned());
+        self
+    }
+
+    /// Fills gaps in the memory tree received from qmp with the fallback mappings of the machine.
+    ///
+    /// Some qemu versions omit ram blocks from the memory tree. With this option guest ram
+    /// which is neither mapped by qmp nor overlaps any qmp mapping is added from the
+    /// fallback mappings. Each added range is logged.
+    pub fn hybrid_mappings(mut self, hybrid: bool) -> Self {
+        self.options.mapping.hybrid = hybrid;
+        self
+    }
+
+    /// Prefers qmp sockets with the given transport when qemu exposes multiple monitors.
+    pub fn qmp_prefer(mut self, transport: QmpTransport) -> Self {
+        self.options.qmp_prefer = Some(transport);
+        self
+    }
+
+    /// Attempts to connect to the qmp monitor `attempts` times, e.g. when qemu was just started
+    /// and has not created the socket yet.
+    ///
+    /// The delay between the attempts starts at `backoff` and is doubled after every attempt,
+    /// up to one second. Only the first connection is retried. Defaults to 5 attempts
+    /// with a backoff of 50ms.
+    pub fn qmp_retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.options.qmp_retry = QmpRetry { attempts, backoff };
+        self
+    }
+
+    /// Reuses the qmp mappings resolved by another connector for the same qemu process
+    /// within the last few seconds instead of querying qmp again.
+    pub fn mtree_cache(mut self, mtree_cache: bool) -> Self {
+        self.options.mapping.mtree_cache = mtree_cache;
+        self
+    }
+
+    /// Logs the raw memory tree received from qmp at debug level.
+    ///
+    /// The memory tree is always queried in this case, even if [`Self::mtree_cache`] is enabled.
+    pub fn dump_mtree(mut self, dump_mtree: bool) -> Self {
+        self.options.mapping.dump_mtree = dump_mtree;
+        self
+    }
+
+    /// Aligns all physical reads to the given boundary.
+    ///
+    /// Each read is extended down and up to the next multiple of `alignment`
+    /// and trimmed afterwards. This is useful for backends which only
+    /// support (or are much faster with) aligned accesses.
+    ///
+    /// The alignment has to be a power of two.
+    pub fn read_alignment(mut self, alignment: usize) -> Self {
+        self.options.read_alignment = Some(alignment);
+        self
+    }
+
+    /// Fails physical reads which take longer than `timeout`.
+    ///
+    /// This cannot be combined with the page cache or a read alignment.
+    /// See [`QemuProcfs::set_read_timeout`] for details.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.options.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Maps the guest memory of the qemu process into the connector and reads it with plain copies.
+    ///
+    /// This only works for shared file backed guest memory, e.g. `memory-backend-memfd`
+    /// or `memory-backend-file,share=on`. Anonymous guest memory cannot be mapped by another
+    /// process and is still read through `/proc/<pid>/mem`. Reads outside of the mapping
+    /// fall back to procfs as well.
+    ///
+    /// Guest memory on persistent memory (`/dev/dax` or `pmem=on`) is always mapped, so writes
+    /// to it can be flushed to the persistent memory.
+    pub fn use_mmap(mut self, use_mmap: bool) -> Self {
+        self.options.use_mmap = use_mmap;
+        self
+    }
+
+    /// Tracks the written guest pages with the soft-dirty page bits of the host kernel.
+    ///
+    /// qmp only reports the rate at which the guest dirties its memory, enable this to collect
+    /// the pages themselves with [`QemuProcfs::poll_dirty_pages`].
+    pub fn soft_dirty(mut self, soft_dirty: bool) -> Self {
+        self.options.soft_dirty = soft_dirty;
+        self
+    }
+
+    /// Advises the host kernel to swap in the guest memory once the connector was created.
+    ///
+    /// See [`QemuProcfs::prefault`] for details.
+    pub fn prefault(mut self, prefault: bool) -> Self {
+        self.options.prefault = prefault;
+        self
+    }
+
+    /// Logs the first bytes of each memory mapping at debug level once the connector was created.
+    ///
+    /// See [`QemuProcfs::sample_mappings`] for details.
+    pub fn log_samples(mut self, log_samples: bool) -> Self {
+        self.options.log_samples = log_samples;
+        self
+    }
+
+    /// Restricts bulk operations to the guest physical range `[start, end)`.
+    ///
+    /// See [`QemuProcfs::set_scan_range`] for details.
+    pub fn scan_range(mut self, start: Address, end: Address) -> Self {
+        self.options.scan_range = Some((start, end));
+        self
+    }
+
+    /// Caches up to `pages` guest pages of 4kb.
+    ///
+    /// The cache is shared between all clones of the connector.
+    /// Writes through any clone invalidate the affected pages.
+    pub fn page_cache(mut self, pages: usize) -> Self {
+        self.options.cache_pages = Some(pages);
+        self
+    }
+
+    /// Rejects all writes to guest memory with [`ErrorKind::ReadOnly`].
+    ///
+    /// The connector then also reports itself as read-only in its metadata.
+    pub fn readonly(mut self, readonly: bool) -> Self {
+        self.options.readonly = readonly;
+        self
+    }
+
+    /// Byte-swaps every `width` sized element of physical reads, like a [`ByteSwapView`](crate::ByteSwapView).
+    ///
+    /// This returns host-native integers when reading a guest of the other byte order,
+    /// e.g. a big endian guest on a little endian host. Writes are not swapped.
+    /// [`QemuProcfs::read_struct`] already converts from the guest byte order and
+    /// should not be combined with this.
+    pub fn byte_swap(mut self, width: usize) -> Self {
+        self.options.swap_width = Some(width);
+        self
+    }
+
+    /// Overrides the `ideal_batch_size` reported in the physical memory metadata.
+    ///
+    /// The size has to be a power of two multiple of the 4kb page size. The best value depends
+    /// on whether guest memory is read through procfs or mapped with [`use_mmap`](Self::use_mmap).
+    pub fn ideal_batch_size(mut self, size: u32) -> Self {
+        self.options.ideal_batch_size = Some(size);
+        self
+    }
+
+    /// Reinitializes the connector once when reads of mapped guest memory fail.
+    ///
+    /// See [`QemuProcfs::set_auto_recover`] for details.
+    pub fn auto_recover(mut self, auto_recover: bool) -> Self {
+        self.options.auto_recover = auto_recover;
+        self
+    }
+
+    /// Rebuilds the memory map after the guest was reset or shut down.
+    ///
+    /// See [`QemuProcfs::watch_qmp_events`] for details. Requires the `qmp` feature.
+    pub fn qmp_events(mut self, qmp_events: bool) -> Self {
+        self.options.qmp_events = qmp_events;
+        self
+    }
+
+    /// Zeroes temporary buffers used by the connector after each read.
+    ///
+    /// This covers the read buffers, pages evicted from the page cache and the buffers of
+    /// [`find_pattern`](QemuProcfs::find_pattern), [`scan_ram`](QemuProcfs::scan_ram) and
+    /// [`read_records`](QemuProcfs::read_records). It prevents copies of sensitive guest memory
+    /// from lingering in the connector's memory. Requires the `zeroize` feature.
+    pub fn zeroize_buffers(mut self, zeroize: bool) -> Self {
+        self.options.zeroize_buffers = zeroize;
+        self
+    }
+
+    pub fn build(self) -> Result<QemuProcfs<O::IntoProcessType>> {
+        if self.options.zeroize_buffers && !cfg!(feature = "zeroize") {
+            return Err(Error(
+                ErrorOrigin::Connector,
+                ErrorKind::UnsupportedOptionalFeature,
+            )
+            .log_error("zeroizing buffers requires the `zeroize` feature"));
+        }
+
+        if let Some(width) = self.options.swap_width {
+            if !SWAP_WIDTHS.contains(&width) {
+                return Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                    .log_error("the byte swap width has to be 2, 4 or 8"));
+            }
+        }
+
+        if let Some(size) = self.options.ideal_batch_size {
+            if !size.is_power_of_two() || (size as umem) < mem::kb(4) {
+                return Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                    .log_error("the ideal batch size has to be a power of two multiple of 4kb"));
+            }
+        }
+
+        if self.options.qmp_events && !cfg!(all(target_os = "linux", feature = "qmp")) {
+            return Err(Error(
+                ErrorOrigin::Connector,
+                ErrorKind::UnsupportedOptionalFeature,
+            )
+            .log_error("watching qmp events requires the `qmp` feature"));
+        }
+
+        if self.options.dump_map.is_some() && !cfg!(feature = "serde") {
+            return Err(Error(
+                ErrorOrigin::Connector,
+                ErrorKind::UnsupportedOptionalFeature,
+            )
+            .log_error("dumping the memory map requires the `serde` feature"));
+        }
+
+        if self.options.mapping.backend.is_some() {
+            if self
+                .options
+                .mapping
+                .forced_source()
+                .is_some_and(|source| source != MappingSource::Qmp)
+            {
+                return Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                    .log_error("a memory backend can only be selected from the qmp mappings"));
+            }
+            if self.options.mapping.hybrid {
+                return Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                    .log_error("a memory backend cannot be combined with hybrid mappings"));
+            }
+        }
+
+        if let Some(alignment) = self.options.read_alignment {
+            if !alignment.is_power_of_two() {
+                return Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                    .log_error("read alignment has to be a power of two"));
+            }
+        }
+
+        let read_timeout = self.options.read_timeout;
+        let auto_recover = self.options.auto_recover;
+        let qmp_events = self.options.qmp_events;
+        let dump_map = self.options.dump_map.clone();
+        if read_timeout.is_some()
+            && (self.options.cache_pages.is_some() || self.options.read_alignment.is_some())
+        {
+            return Err(
+                Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument).log_error(
+                    "a read timeout cannot be combined with the page cache or a read alignment",
+                ),
+            );
+        }
+
+        let mut qemu = match self.target {
+            QemuTarget::Any => QemuProcfs::find_any(self.os, self.options),
+            QemuTarget::GuestName(name) => {
+                QemuProcfs::find_by_guest_name(self.os, &name, None, self.options)
+            }
+            QemuTarget::GuestNameOrPid(name, pid) => {
+                QemuProcfs::find_by_guest_name(self.os, &name, Some(pid), self.options)
+            }
+            QemuTarget::Pid(pid) => QemuProcfs::find_by_pid(self.os, pid, self.options),
+        }?;
+
+        if read_timeout.is_some() {
+            qemu.set_read_timeout(read_timeout);
+        }
+        qemu.set_auto_recover(auto_recover);
+        if qmp_events {
+            qemu.watch_qmp_events()?;
+        }
+        if let Some(path) = dump_map {
+            qemu.dump_memory_map(path)?;
+        }
+        Ok(qemu)
+    }
+}
+
+/// Returns the procfs mounted in the pid namespace of the process `pid`.
+pub(crate) fn host_pid_procfs_root(pid: Pid) -> PathBuf {
+    Path::new(PROCFS_ROOT)
+        .join(pid.to_string())
+        .join("root")
+        .join("proc")
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use memflow::prelude::v1::umem;
+
+use crate::scrub::scrub;
+
+/// A page cache which is shared between all clones of a connector.
+pub type SharedPageCache = Arc<Mutex<PageCache>>;
+
+/// A least recently used cache of guest physical pages.
+pub struct PageCache {
+    page_size: umem,
+    capacity: usize,
+    pages: HashMap<umem, CachedPage>,
+    zeroize: bool,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+struct CachedPage {
+    data: Box<[u8]>,
+    last_used: u64,
+    zeroize: bool,
+}
+
+impl Drop for CachedPage {
+    fn drop(&mut self) {
+        if self.zeroize {
+            scrub(&mut self.data);
+        }
+    }
+}
+
+impl PageCache {
+    /// Creates a new cache holding up to `capacity` pages of `page_size` bytes.
+    ///
+    /// The page size has to be a power of two.
+    pub fn new(capacity: usize, page_size: umem) -> Self {
+        debug_assert!(page_size.is_power_of_two());
+        Self {
+            page_size,
+            capacity,
+            pages: HashMap::with_capacity(capacity),
+            zeroize: false,
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Creates a cache shared between clones, with `zeroize` pages are zeroed once they are
+    /// evicted, invalidated or the cache is dropped.
+    pub fn shared(capacity: usize, page_size: umem, zeroize: bool) -> SharedPageCache {
+        Arc::new(Mutex::new(Self {
+            zeroize,
+            ..Self::new(capacity, page_size)
+        }))
+    }
+
+    pub fn page_size(&self) -> umem {
+        self.page_size
+    }
+
+    /// Returns the number of cache hits and misses.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Looks up the page starting at `page` and marks it as recently used.
+    pub fn get(&mut self, page: umem) -> Option<&[u8]> {
+        self.tick += 1;
+        match self.pages.get_mut(&page) {
+            Some(entry) => {
+                self.hits += 1;
+                entry.last_used = self.tick;
+                Some(&entry.data)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Inserts a page, evicting the least recently used one if the cache is full.
+    pub fn insert(&mut self, page: umem, data: Box<[u8]>) {
+        // wrapped right away so a page which is not cached is zeroed as well
+        let mut entry = CachedPage {
+            data,
+            last_used: 0,
+            zeroize: self.zeroize,
+        };
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.pages.len() >= self.capacity && !self.pages.contains_key(&page) {
+            if let Some(oldest) = self
+                .pages
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(&page, _)| page)
+            {
+                self.pages.remove(&oldest);
+            }
+        }
+
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.pages.insert(page, entry);
+    }
+
+    /// Drops all cached pages.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+
+    /// Drops all pages overlapping the given range.
+    pub fn invalidate(&mut self, start: umem, len: umem) {
+        if len == 0 {
+            return;
+        }
+
+        let first = start & !(self.page_size - 1);
+        let last = (start + len - 1) & !(self.page_size - 1);
+        let mut page = first;
+        while page <= last {
+            self.pages.remove(&page);
+            page += self.page_size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = PageCache::new(2, 0x1000);
+        cache.insert(0x0, vec![0; 0x1000].into_boxed_slice());
+        cache.insert(0x1000, vec![1; 0x1000].into_boxed_slice());
+
+        // touch the first page so the second one is evicted
+        assert!(cache.get(0x0).is_some());
+        cache.insert(0x2000, vec![2; 0x1000].into_boxed_slice());
+
+        assert!(cache.get(0x0).is_some());
+        assert!(cache.get(0x1000).is_none());
+        assert!(cache.get(0x2000).is_some());
+
+        cache.invalidate(0x2ff0, 0x20);
+        assert!(cache.get(0x2000).is_none());
+        assert_eq!(cache.stats(), (3, 2));
+    }
+}
@@ -0,0 +1,165 @@
+use memflow::prelude::v1::*;
+
+/// The specific reason the connector failed to attach to a qemu process.
+///
+/// All public functions keep returning a memflow [`Error`]. As memflow errors only consist of
+/// an origin and a kind and cannot carry a source, every variant is encoded as a unique
+/// origin / kind pair instead. These pairs are reserved for `QemuError`, no other error of the
+/// connector uses them. Use `QemuError::try_from(err)` to recover the variant:
+///
+/// ```
+/// use memflow::prelude::v1::*;
+/// use memflow_qemu::QemuError;
+///
+/// let err: Error = QemuError::ProcessNotFound.into();
+/// assert_eq!(QemuError::try_from(err), Ok(QemuError::ProcessNotFound));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QemuError {
+    /// No matching qemu process is running.
+    ProcessNotFound,
+    /// The memory mappings of the qemu process could not be enumerated,
+    /// usually because of insufficient access rights.
+    PermissionDenied,
+    /// No guest memory mappings could be determined.
+    NoMemoryMap,
+    /// No qmp monitor is available, e.g. because qemu was started without `-qmp`.
+    QmpNotConfigured,
+    /// Connecting to or communicating with the qmp monitor failed.
+    QmpConnect,
+    /// The qmp monitor rejected a command or the command failed.
+    QmpCommand,
+    /// The qmp monitor did not complete the protocol handshake.
+    QmpHandshake,
+    /// The qmp memory tree does not contain any guest ram.
+    QmpEmptyMtree,
+    /// The output of a qmp or monitor command could not be parsed.
+    QmpParse,
+    /// There are no memory mappings available for the emulated machine.
+    UnsupportedMachine,
+}
+
+impl QemuError {
+    const ALL: [QemuError; 10] = [
+        QemuError::ProcessNotFound,
+        QemuError::PermissionDenied,
+        QemuError::NoMemoryMap,
+        QemuError::QmpNotConfigured,
+        QemuError::QmpConnect,
+        QemuError::QmpCommand,
+        QemuError::QmpHandshake,
+        QemuError::QmpEmptyMtree,
+        QemuError::QmpParse,
+        QemuError::UnsupportedMachine,
+    ];
+
+    /// Returns the memflow error origin this variant is encoded with.
+    pub fn origin(self) -> ErrorOrigin {
+        match self {
+            QemuError::NoMemoryMap | QemuError::QmpEmptyMtree => ErrorOrigin::MemoryMap,
+            _ => ErrorOrigin::Connector,
+        }
+    }
+
+    /// Returns the memflow error kind this variant is encoded with.
+    pub fn kind(self) -> ErrorKind {
+        match self {
+            QemuError::ProcessNotFound => ErrorKind::TargetNotFound,
+            QemuError::PermissionDenied => ErrorKind::UnableToReadDir,
+            QemuError::NoMemoryMap => ErrorKind::NotFound,
+            QemuError::QmpNotConfigured => ErrorKind::ArgNotExists,
+            QemuError::QmpConnect => ErrorKind::Uninitialized,
+            QemuError::QmpCommand => ErrorKind::Unknown,
+            QemuError::QmpHandshake => ErrorKind::VersionMismatch,
+            QemuError::QmpEmptyMtree => ErrorKind::Configuration,
+            QemuError::QmpParse => ErrorKind::Encoding,
+            QemuError::UnsupportedMachine => ErrorKind::InvalidArchitecture,
+        }
+    }
+}
+
+impl std::fmt::Display for QemuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            QemuError::ProcessNotFound => "no qemu process could be found",
+            QemuError::PermissionDenied => "the qemu memory mappings could not be read",
+            QemuError::NoMemoryMap => "no guest memory mappings could be found",
+            QemuError::QmpNotConfigured => "no qmp monitor is configured",
+            QemuError::QmpConnect => "unable to communicate with the qmp monitor",
+            QemuError::QmpCommand => "the qmp command failed",
+            QemuError::QmpHandshake => "the qmp handshake failed",
+            QemuError::QmpEmptyMtree => "the qmp memory tree does not contain any guest ram",
+            QemuError::QmpParse => "unable to parse the qmp response",
+            QemuError::UnsupportedMachine => "the qemu machine type is not supported",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for QemuError {}
+
+impl From<QemuError> for Error {
+    fn from(err: QemuError) -> Self {
+        Error(err.origin(), err.kind())
+    }
+}
+
+impl TryFrom<Error> for QemuError {
+    type Error = Error;
+
+    /// Recovers the variant from a memflow error, errors from other sources are returned as is.
+    fn try_from(err: Error) -> std::result::Result<Self, Error> {
+        QemuError::ALL
+            .into_iter()
+            .find(|e| e.origin() == err.0 && e.kind() == err.1)
+            .ok_or(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for err in QemuError::ALL {
+            assert_eq!(QemuError::try_from(Error::from(err)), Ok(err));
+        }
+
+        let other = Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument);
+        assert_eq!(QemuError::try_from(other), Err(other));
+
+        // e.g. a failed guest agent connection is not a qmp error
+        let other = Error(ErrorOrigin::Connector, ErrorKind::Configuration);
+        assert_eq!(QemuError::try_from(other), Err(other));
+    }
+
+    #[test]
+    fn test_reserved_encodings() {
+        // errors of the connector must never decode as a `QemuError` by accident
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap() == "error.rs" {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path)
+                .unwrap()
+                .split_whitespace()
+                .collect::<String>();
+            for err in QemuError::ALL {
+                let encoding = format!(
+                    "ErrorOrigin::{:?},ErrorKind::{:?}",
+                    err.origin(),
+                    err.kind()
+                );
+                assert!(
+                    !source.contains(&encoding),
+                    "{} uses the encoding of QemuError::{:?}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
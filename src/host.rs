@@ -0,0 +1,210 @@
+use memflow::prelude::v1::*;
+
+use std::path::{Path, PathBuf};
+
+use crate::procfs::PROCFS_ROOT;
+
+/// Provides the os of the host the qemu process runs on.
+///
+/// The connector finds the qemu process and reads the guest memory through the process
+/// interface of this os. Implementing it allows to substitute the local procfs, e.g. with
+/// a bridge to a qemu process on a remote machine.
+pub trait HostBackend {
+    /// Short name of the backend used in log messages.
+    fn name(&self) -> &'static str;
+
+    /// Creates the os the qemu process is searched in.
+    fn create_os(&self) -> Result<OsInstanceArcBox<'static>>;
+
+    /// Returns the local procfs the processes of the os can be accessed through.
+    ///
+    /// The connector reads the guest memory directly through `<root>/<pid>/mem` then.
+    /// Backends without a local procfs are only read through the processes of their os.
+    fn procfs_root(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Reads the qemu process of the local machine through the linux procfs.
+///
+/// By default the processes of the connector's own pid namespace are read with `memflow-native`.
+/// With a [`root`](Self::with_root) all accesses go through the procfs mounted there instead,
+/// e.g. `/host/proc` in a container or `/proc/<pid>/root/proc` for the pid namespace of a
+/// container. Other host operating systems are not supported, creating the os fails with
+/// `UnsupportedOptionalFeature` there.
+#[derive(Debug, Clone)]
+pub struct ProcfsBackend {
+    root: PathBuf,
+    custom_root: bool,
+}
+
+impl Default for ProcfsBackend {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from(PROCFS_ROOT),
+            custom_root: false,
+        }
+    }
+}
+
+impl ProcfsBackend {
+    /// Reads the processes from the procfs mounted at `root`.
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            custom_root: true,
+        }
+    }
+
+    /// Returns the mount point of the procfs the processes are read from.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl HostBackend for ProcfsBackend {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn procfs_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    fn create_os(&self) -> Result<OsInstanceArcBox<'static>> {
+        if !cfg!(target_os = "linux") {
+            return Err(Error(
+                ErrorOrigin::Connector,
+                ErrorKind::UnsupportedOptionalFeature,
+            )
+            .log_error(
+                "the qemu connector requires a linux host, use a custom HostBackend on other hosts",
+            ));
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.custom_root {
+            return Ok(memflow::plugins::os::create_instance(
+                crate::procfs_os::ProcfsOs::new(&self.root)?,
+                Option::<std::sync::Arc<_>>::None.into(),
+                &Default::default(),
+            ));
+        }
+
+        memflow_native::create_os(
+            &Default::default(),
+            Option::<std::sync::Arc<_>>::None.into(),
+        )
+    }
+}
+
+/// Returns the backend for the host os the connector was compiled for.
+pub fn host_backend() -> Box<dyn HostBackend> {
+    Box::new(ProcfsBackend::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingBackend;
+
+    impl HostBackend for FailingBackend {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn create_os(&self) -> Result<OsInstanceArcBox<'static>> {
+            Err(Error(ErrorOrigin::Connector, ErrorKind::NotFound))
+        }
+    }
+
+    /// Serves the procfs at `root` like a remote host which has no local procfs.
+    #[cfg(target_os = "linux")]
+    struct RemoteBackend {
+        root: PathBuf,
+    }
+
+    #[cfg(target_os = "linux")]
+    impl HostBackend for RemoteBackend {
+        fn name(&self) -> &'static str {
+            "remote"
+        }
+
+        fn create_os(&self) -> Result<OsInstanceArcBox<'static>> {
+            Ok(memflow::plugins::os::create_instance(
+                crate::procfs_os::ProcfsOs::new(&self.root)?,
+                Option::<std::sync::Arc<_>>::None.into(),
+                &Default::default(),
+            ))
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_remote_backend_reads_process() {
+        use std::fs;
+        use std::os::unix::fs::FileExt;
+
+        // the remote qemu process has the same pid as the local test process
+        let pid = std::process::id();
+        let root = std::env::temp_dir().join(format!("memflow-qemu-remote-{}", pid));
+        let proc_dir = root.join(pid.to_string());
+        fs::create_dir_all(&proc_dir).unwrap();
+        fs::write(
+            proc_dir.join("cmdline"),
+            "qemu-system-x86_64\0-machine\0q35\0",
+        )
+        .unwrap();
+        fs::write(
+            proc_dir.join("maps"),
+            "00400000-00800000 rw-p 00000000 00:00 0\n",
+        )
+        .unwrap();
+        let ram = (0..0x400000u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let mem = fs::File::create(proc_dir.join("mem")).unwrap();
+        mem.write_all_at(&ram, 0x400000).unwrap();
+
+        let backend = RemoteBackend { root: root.clone() };
+        assert!(backend.procfs_root().is_none());
+        let mut connector =
+            crate::create_connector_with_backend(&Default::default(), &backend).unwrap();
+        assert!(connector.proc_mem.is_none());
+
+        let mut buf = [0u8; 16];
+        connector
+            .phys_view()
+            .read_raw_into(Address::from(0x1000), &mut buf)
+            .unwrap();
+        assert_eq!(buf, ram[0x1000..0x1010]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_host_backend() {
+        assert_eq!(host_backend().name(), "procfs");
+        assert_eq!(
+            ProcfsBackend::default().create_os().is_ok(),
+            cfg!(target_os = "linux")
+        );
+        assert_eq!(
+            ProcfsBackend::with_root("/proc").create_os().is_ok(),
+            cfg!(target_os = "linux")
+        );
+        assert_eq!(
+            ProcfsBackend::with_root("/host/proc").procfs_root(),
+            Some(Path::new("/host/proc"))
+        );
+        assert!(ProcfsBackend::with_root("/nonexistent/proc")
+            .create_os()
+            .is_err());
+
+        let err = crate::create_connector_with_backend(&Default::default(), &FailingBackend)
+            .err()
+            .unwrap();
+        assert_eq!(err.1, ErrorKind::NotFound);
+    }
+}
@@ -1,334 +1,4440 @@
-use log::{error, info};
+use log::{debug, error, info, warn};
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use memflow::cglue;
 use memflow::connector::cpu_state::*;
-use memflow::mem::memory_view::RemapView;
 use memflow::mem::phys_mem::*;
 use memflow::os::root::Os;
 use memflow::prelude::v1::*;
 
+mod error;
+pub use error::QemuError;
+
 mod qemu_args;
-use qemu_args::{is_qemu, qemu_arg_opt};
+use qemu_args::{
+    is_qemu, qemu_expand_readconfig, qemu_guest_label, qemu_machine_version, qemu_mem_dax,
+    qemu_mem_prealloc, qemu_objects, qemu_smp_cpus, qemu_target_endianess,
+};
 
 #[cfg(all(target_os = "linux", feature = "qmp"))]
 #[macro_use]
 extern crate scan_fmt;
 
 mod mem_map;
-use mem_map::qemu_mem_mappings;
+#[cfg(feature = "serde")]
+pub use mem_map::mapping_from_json;
+use mem_map::{
+    qemu_mem_mappings, qemu_target_arch, qmp_get_device_bar, qmp_get_firmware_regions,
+    MappingOptions,
+};
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+use mem_map::{qmp_gpa2hva, qmp_snapshot};
+pub use mem_map::{AddressMapping, MappingSource};
+
+mod qmp;
+pub use qmp::QmpTransport;
+use qmp::{QmpMonitor, QmpRetry};
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+mod registers;
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+pub use registers::GuestRegisters;
+
+mod qga;
+use qga::GuestAgent;
+pub use qga::GuestOsInfo;
+
+mod host;
+pub use host::{host_backend, HostBackend, ProcfsBackend};
+
+mod procfs;
+#[cfg(target_os = "linux")]
+mod procfs_os;
+use procfs::{MappedRam, ProcMem, SoftDirty};
+
+mod boot;
+pub use boot::{LinuxSetupHeader, MultibootInfo, LINUX_REAL_MODE_BASE};
+
+mod cache;
+use cache::{PageCache, SharedPageCache};
+
+mod timeout;
+use timeout::TimeoutReader;
+
+mod scrub;
+use scrub::{scrub, ScratchBuf};
+
+mod self_check;
+pub use self_check::{SelfCheckFinding, SelfCheckReport, SelfCheckSeverity};
+
+mod swap;
+use swap::swapped;
+pub use swap::ByteSwapView;
+
+mod remap;
+use remap::GuestView;
+
+mod builder;
+use builder::BuildOptions;
+pub use builder::QemuProcfsBuilder;
 
 cglue_impl_group!(QemuProcfs<P: MemoryView + Clone>, ConnectorInstance, {
     ConnectorCpuState
 });
 cglue_impl_group!(QemuProcfs<P: MemoryView + Clone>, IntoCpuState);
 
+/// Batch size reported in the physical memory metadata unless overridden.
+const DEFAULT_BATCH_SIZE: u32 = 4096;
+
+/// Duration over which qemu measures the dirty rate in [`QemuProcfs::enable_dirty_tracking`].
+const DIRTY_RATE_CALC_TIME: Duration = Duration::from_secs(1);
+
+/// Locates the guest memory mapping in the qemu process.
+type LocateFn<P> = fn(&mut P) -> Option<CTup2<Address, umem>>;
+
+/// Guest resets counted by the qmp event watcher and the number of them already handled.
+struct ResetWatch<T> {
+    resets: Arc<AtomicUsize>,
+    seen: usize,
+    refresh: fn(&mut T) -> Result<()>,
+}
+
+impl<T> Clone for ResetWatch<T> {
+    fn clone(&self) -> Self {
+        Self {
+            resets: self.resets.clone(),
+            seen: self.seen,
+            refresh: self.refresh,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct QemuProcfs<P: MemoryView> {
-    view: RemapView<P>,
+    view: GuestView<P>,
+    qemu_map: CTup2<Address, umem>,
+    mem_map: MemoryMap<(Address, umem)>,
+    mapping_source: MappingSource,
+    cmdline: String,
+    mapping: MappingOptions,
+    relocate: Option<LocateFn<P>>,
+    recover: Option<fn(&mut Self) -> Result<()>>,
+    reset_watch: Option<ResetWatch<Self>>,
+    timings: DetectionTimings,
+    pid: Option<Pid>,
+    qmp: Option<QmpMonitor>,
+    qmp_reachable: OnceLock<()>,
+    qga: Option<GuestAgent>,
+    cpu_count: OnceLock<usize>,
+    qemu_version: OnceLock<Option<(u32, u32)>>,
+    read_alignment: Option<usize>,
+    swap_width: Option<usize>,
+    ideal_batch_size: u32,
+    endianess: OnceLock<Endianess>,
+    read_buf: Vec<u8>,
+    cache: Option<SharedPageCache>,
+    scan_range: Option<(Address, Address)>,
+    readonly: bool,
+    timeout_reader: Option<TimeoutReader>,
+    proc_mem: Option<ProcMem>,
+    ram_mmap: Option<Arc<MappedRam>>,
+    past_file_end: Option<(Address, Address)>,
+    dirty_tracker: Option<SoftDirty>,
+    soft_dirty: bool,
+    procfs_root: Option<PathBuf>,
+    zeroize_buffers: bool,
 }
 
 impl<P: MemoryView + Process> QemuProcfs<P> {
+    /// Returns a builder to configure and construct a new connector instance.
+    pub fn builder<O: Os<IntoProcessType = P>>(os: O) -> QemuProcfsBuilder<O> {
+        QemuProcfsBuilder::new(os)
+    }
+
     pub fn new<O: Os<IntoProcessType = P>>(
-        mut os: O,
+        os: O,
         map_override: Option<CTup2<Address, umem>>,
     ) -> Result<Self> {
-        let mut proc = None;
+        Self::builder(os).map_override(map_override).build()
+    }
 
-        let callback = &mut |info: ProcessInfo| {
-            if proc.is_none() && is_qemu(&info) {
-                proc = Some(info);
-            }
+    pub fn with_guest_name<O: Os<IntoProcessType = P>>(
+        os: O,
+        name: &str,
+        map_override: Option<CTup2<Address, umem>>,
+    ) -> Result<Self> {
+        Self::builder(os)
+            .guest_name(name)
+            .map_override(map_override)
+            .build()
+    }
 
-            proc.is_none()
-        };
+    pub fn with_pid<O: Os<IntoProcessType = P>>(
+        os: O,
+        pid: Pid,
+        map_override: Option<CTup2<Address, umem>>,
+    ) -> Result<Self> {
+        Self::builder(os)
+            .pid(pid)
+            .map_override(map_override)
+            .build()
+    }
 
-        os.process_info_list_callback(callback.into())?;
+    pub(crate) fn find_any<O: Os<IntoProcessType = P>>(
+        mut os: O,
+        options: BuildOptions,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        let proc = find_qemu_process(
+            &mut os,
+            options.procfs_root().as_deref(),
+            options.kvm_only,
+            |_| true,
+        )?;
+        let process_scan = start.elapsed();
 
-        Self::with_process(
+        let mut qemu = Self::with_process(
             os,
             proc.ok_or_else(|| {
-                Error(ErrorOrigin::Connector, ErrorKind::TargetNotFound)
+                Error::from(QemuError::ProcessNotFound)
                     .log_error("No QEMU process could be found. Is QEMU running?")
             })?,
-            map_override,
-        )
+            options,
+        )?;
+        qemu.timings.process_scan = process_scan;
+        Ok(qemu)
     }
 
-    pub fn with_guest_name<O: Os<IntoProcessType = P>>(
+    pub(crate) fn find_by_guest_name<O: Os<IntoProcessType = P>>(
         mut os: O,
         name: &str,
-        map_override: Option<CTup2<Address, umem>>,
+        fallback_pid: Option<Pid>,
+        options: BuildOptions,
     ) -> Result<Self> {
-        let mut proc = None;
-
-        let callback = &mut |info: ProcessInfo| {
-            if proc.is_none()
-                && is_qemu(&info)
-                && qemu_arg_opt(info.command_line.split_whitespace(), "-name", "guest").as_deref()
-                    == Some(name)
-            {
-                proc = Some(info);
-            }
-
-            proc.is_none()
-        };
-
-        os.process_info_list_callback(callback.into())?;
+        let start = Instant::now();
+        let proc = find_guest_process(
+            &mut os,
+            options.procfs_root().as_deref(),
+            options.kvm_only,
+            name,
+            fallback_pid,
+        )?;
+        let process_scan = start.elapsed();
 
-        Self::with_process(
+        let mut qemu = Self::with_process(
             os,
             proc.ok_or_else(||
-                Error(ErrorOrigin::Connector, ErrorKind::TargetNotFound)
+                Error::from(QemuError::ProcessNotFound)
                     .log_error("A QEMU process for the specified guest name could not be found. Is the QEMU process running?")
             )?,
-            map_override,
-        )
+            options,
+        )?;
+        qemu.timings.process_scan = process_scan;
+        Ok(qemu)
     }
 
-    pub fn with_pid<O: Os<IntoProcessType = P>>(
+    pub(crate) fn find_by_pid<O: Os<IntoProcessType = P>>(
         mut os: O,
         pid: Pid,
-        map_override: Option<CTup2<Address, umem>>,
+        options: BuildOptions,
     ) -> Result<Self> {
+        let start = Instant::now();
         let proc = os.process_info_by_pid(pid)?;
+        let process_scan = start.elapsed();
 
-        Self::with_process(os, proc, map_override)
+        let mut qemu = Self::with_process(os, proc, options)?;
+        qemu.timings.process_scan = process_scan;
+        Ok(qemu)
     }
 
     fn with_process<O: Os<IntoProcessType = P>>(
         os: O,
         info: ProcessInfo,
-        map_override: Option<CTup2<Address, umem>>,
+        mut options: BuildOptions,
     ) -> Result<Self> {
         info!(
             "qemu process with name {} found with pid {:?}",
             info.name, info.pid
         );
 
-        let cmdline: String = info.command_line.to_string();
+        let start = Instant::now();
+        let procfs_root = options.procfs_root();
+        let cmdline = process_cmdline(procfs_root.as_deref(), &info);
+        let pid = info.pid;
+        options.mapping.pid = Some(pid);
 
         let mut prc = os.into_process_by_info(info)?;
+        let cmdline_read = start.elapsed();
 
-        let mut biggest_map = map_override;
+        let start = Instant::now();
+        let relocate = options
+            .map_override
+            .is_none()
+            .then_some(locate_guest_ram::<P> as LocateFn<P>);
+        let biggest_map = options.map_override.or_else(|| locate_guest_ram(&mut prc));
+        let locate_time = start.elapsed();
 
-        let callback = &mut |range: MemoryRange| {
-            if biggest_map
-                .map(|CTup2(_, oldsize)| oldsize < range.1)
-                .unwrap_or(true)
-            {
-                biggest_map = Some(CTup2(range.0, range.1));
+        let qemu_map = biggest_map.ok_or_else(|| Error::from(QemuError::PermissionDenied)
+            .log_error("Unable to find the QEMU guest memory map. This usually indicates insufficient permissions to acquire the QEMU memory maps. Are you running with appropiate access rights?")
+        )?;
+
+        info!("qemu memory map found {:?}", qemu_map);
+
+        let prefault = options.prefault;
+        let use_mmap = options.use_mmap;
+        let dax = qemu_mem_dax(&cmdline);
+
+        let mut qemu = Self::with_cmdline_and_mem(prc, &cmdline, qemu_map, options)?;
+        qemu.pid = Some(pid);
+        qemu.relocate = relocate;
+        qemu.timings.cmdline_read = cmdline_read;
+        qemu.timings.map_build += locate_time;
+        match &procfs_root {
+            Some(procfs_root) => {
+                // reads are issued through this handle directly, the remap view is only used as a fallback
+                qemu.proc_mem = ProcMem::open(procfs_root, pid).ok();
+
+                // a sparse backing file shorter than the guest memory faults on reads past its end
+                qemu.past_file_end =
+                    procfs::past_file_end(procfs_root, pid, qemu_map.0, qemu_map.1);
+                if let Some((start, end)) = qemu.past_file_end {
+                    info!(
+                        "guest memory backing file ends at {:x}, reading zeroes up to {:x}",
+                        start, end
+                    );
+                }
+
+                // persistent memory is always mapped so writes to it can be flushed
+                if (use_mmap || dax) && qemu.proc_mem.is_some() {
+                    match MappedRam::map(procfs_root, pid, qemu_map.0, qemu_map.1, dax) {
+                        Ok(ram) => qemu.ram_mmap = Some(Arc::new(ram)),
+                        Err(err) => {
+                            warn!("unable to map guest memory, using procfs reads: {}", err)
+                        }
+                    }
+                }
             }
+            None if use_mmap => {
+                warn!("the guest memory can only be mapped through a local procfs, using process reads")
+            }
+            None => {}
+        }
 
-            true
-        };
+        if prefault {
+            if let Err(err) = qemu.prefault() {
+                warn!("unable to prefault guest memory: {}", err);
+            }
+        }
+
+        Ok(qemu)
+    }
+}
+
+impl<P: MemoryView> QemuProcfs<P> {
+    fn with_cmdline_and_mem(
+        prc: P,
+        cmdline: &str,
+        qemu_map: CTup2<Address, umem>,
+        options: BuildOptions,
+    ) -> Result<Self> {
+        let qmp = match &options.qmp {
+            Some(addr) => QmpMonitor::from_addr(addr),
+            None => QmpMonitor::from_cmdline(cmdline, options.qmp_prefer),
+        }
+        .map(|qmp| qmp.with_retry(options.qmp_retry));
 
-        if map_override.is_none() {
-            prc.mapped_mem_range(
-                smem::mb(-1),
-                Address::NULL,
-                Address::INVALID,
-                callback.into(),
+        for backend in qemu_objects(cmdline)
+            .iter()
+            .filter(|object| object.is_dax_backend())
+        {
+            // dax mappings are regular shared mappings in the qemu process and can be read through procfs,
+            // writes through the qemu process are flushed with the mapping of the connector.
+            info!(
+                "guest memory backend {:?} is backed by persistent memory at {:?}",
+                backend.prop("id"),
+                backend.prop("mem-path")
             );
         }
 
-        let qemu_map = biggest_map.ok_or_else(|| Error(ErrorOrigin::Connector, ErrorKind::NotFound)
-            .log_error("Unable to find the QEMU guest memory map. This usually indicates insufficient permissions to acquire the QEMU memory maps. Are you running with appropiate access rights?")
-        )?;
+        if !qemu_mem_prealloc(cmdline) {
+            debug!("guest memory is not preallocated, pages which were not touched by the guest yet read as zero");
+        }
 
-        info!("qemu memory map found {:?}", qemu_map);
+        let start = Instant::now();
+        let (mem_map, mapping_source) =
+            qemu_mem_mappings(cmdline, &qemu_map, qmp.as_ref(), &options.mapping)?;
+        info!("qemu machine mem_map: {:?}", mem_map);
+        let qmp_connect = qmp
+            .as_ref()
+            .map(QmpMonitor::connect_time)
+            .unwrap_or_default();
+        let timings = DetectionTimings {
+            qmp_connect,
+            map_build: start.elapsed().saturating_sub(qmp_connect),
+            ..Default::default()
+        };
+
+        let log_samples = options.log_samples;
+
+        let mut qemu = Self {
+            view: GuestView::new(prc, mem_map.clone()),
+            qemu_map,
+            mem_map,
+            mapping_source,
+            cmdline: cmdline.to_owned(),
+            mapping: options.mapping.clone(),
+            relocate: None,
+            recover: None,
+            reset_watch: None,
+            timings,
+            pid: None,
+            qmp,
+            qmp_reachable: OnceLock::new(),
+            qga: GuestAgent::from_cmdline(cmdline),
+            cpu_count: OnceLock::new(),
+            qemu_version: OnceLock::new(),
+            read_alignment: options.read_alignment,
+            swap_width: options.swap_width,
+            ideal_batch_size: options.ideal_batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+            endianess: OnceLock::new(),
+            read_buf: vec![],
+            cache: options
+                .cache_pages
+                .map(|pages| PageCache::shared(pages, mem::kb(4), options.zeroize_buffers)),
+            scan_range: options.scan_range,
+            readonly: options.readonly,
+            timeout_reader: None,
+            proc_mem: None,
+            ram_mmap: None,
+            past_file_end: None,
+            dirty_tracker: None,
+            soft_dirty: options.soft_dirty,
+            procfs_root: options.procfs_root(),
+            zeroize_buffers: options.zeroize_buffers,
+        };
+
+        if log_samples {
+            for (addr, bytes) in qemu.sample_mappings(16) {
+                debug!("guest memory at {:x}: {:02x?}", addr, bytes);
+            }
+        }
 
-        Self::with_cmdline_and_mem(prc, &cmdline, qemu_map)
+        Ok(qemu)
     }
 
-    fn with_cmdline_and_mem(prc: P, cmdline: &str, qemu_map: CTup2<Address, umem>) -> Result<Self> {
-        let mem_map = qemu_mem_mappings(cmdline, &qemu_map)?;
-        info!("qemu machine mem_map: {:?}", mem_map);
+    /// Returns true if the guest can be paused and resumed through the `CpuState` interface.
+    ///
+    /// This is only the case when a qmp monitor was found for the qemu process and answers
+    /// `query-status`. An unreachable monitor is probed again on the next call.
+    pub fn cpu_state_supported(&self) -> bool {
+        if self.qmp_reachable.get().is_some() {
+            return true;
+        }
 
-        Ok(Self {
-            view: prc.into_remap_view(mem_map),
-        })
+        match self.qmp.as_ref().map(QmpMonitor::probe) {
+            Some(Ok(())) => {
+                let _ = self.qmp_reachable.set(());
+                true
+            }
+            Some(Err(err)) => {
+                warn!("the qmp monitor is not reachable: {}", err);
+                false
+            }
+            None => false,
+        }
     }
-}
 
-impl<P: MemoryView> PhysicalMemory for QemuProcfs<P> {
-    fn phys_read_raw_iter(
-        &mut self,
-        MemOps { inp, out, out_fail }: PhysicalReadMemOps,
-    ) -> Result<()> {
-        let inp = inp.map(|CTup3(addr, meta_addr, data)| CTup3(addr.into(), meta_addr, data));
-        MemOps::with_raw(inp, out, out_fail, |data| self.view.read_raw_iter(data))
+    /// Returns the translation of guest physical addresses into the qemu process.
+    ///
+    /// Each mapping maps a guest physical base to a host virtual address and size.
+    pub fn memory_map(&self) -> &MemoryMap<(Address, umem)> {
+        &self.mem_map
     }
 
-    fn phys_write_raw_iter(
-        &mut self,
-        MemOps { inp, out, out_fail }: PhysicalWriteMemOps,
-    ) -> Result<()> {
-        let inp = inp.map(|CTup3(addr, meta_addr, data)| CTup3(addr.into(), meta_addr, data));
-        MemOps::with_raw(inp, out, out_fail, |data| self.view.write_raw_iter(data))
+    /// Returns the guest physical ranges backed by guest ram as base and size.
+    ///
+    /// Holes in between, e.g. the pci hole on x86 or device mmio on other architectures,
+    /// are left out so the ranges can be scanned without faulting. Adjacent mappings are
+    /// merged into a single range.
+    pub fn ram_regions(&self) -> Vec<(Address, umem)> {
+        let mut regions: Vec<(Address, umem)> = vec![];
+        for mapping in self.mem_map.iter() {
+            let (_, size) = *mapping.output();
+            match regions.last_mut() {
+                Some((base, len)) if *base + *len == mapping.base() => *len += size,
+                _ => regions.push((mapping.base(), size)),
+            }
+        }
+        regions
     }
 
-    fn metadata(&self) -> PhysicalMemoryMetadata {
-        let md = self.view.metadata();
+    /// Translates a guest physical address into the qemu process.
+    ///
+    /// Returns the host virtual address `gpa` is mapped to and the number of bytes the
+    /// containing mapping continues from there, e.g. to map that part of the guest memory
+    /// into another process. Returns `None` if `gpa` is not backed by guest ram.
+    pub fn to_host_offset(&self, gpa: Address) -> Option<(Address, umem)> {
+        self.mem_map.iter().find_map(|mapping| {
+            let (host, size) = *mapping.output();
+            let offset = gpa.to_umem().checked_sub(mapping.base().to_umem())?;
+            (offset < size).then(|| (host + offset, size - offset))
+        })
+    }
 
-        PhysicalMemoryMetadata {
-            max_address: md.max_address,
-            real_size: md.real_size,
-            readonly: md.readonly,
-            ideal_batch_size: 4096,
-        }
+    /// Returns each mapped guest physical range as a slice into the mapped guest memory.
+    ///
+    /// This allows analyzing the entire guest memory without copying it, e.g. scanning it
+    /// with yara. It is only available when the guest memory was mapped with `use_mmap`,
+    /// otherwise an empty list is returned.
+    ///
+    /// # Safety
+    ///
+    /// The slices are a live view of the guest memory which the running guest and qemu keep
+    /// writing to. A shared slice promises that its contents do not change while it is
+    /// borrowed, so the caller has to ensure that the guest memory is not written during the
+    /// lifetime of the slices, e.g. by pausing the guest with [`with_paused`](Self::with_paused)
+    /// and not writing guest memory through the connector in the meantime.
+    pub unsafe fn as_slices(&self) -> Vec<(Address, &[u8])> {
+        let Some(ram) = &self.ram_mmap else {
+            return vec![];
+        };
+
+        self.mem_map
+            .iter()
+            .filter_map(|mapping| {
+                let (host, size) = *mapping.output();
+                unsafe { ram.slice(host.to_umem(), size) }.map(|slice| (mapping.base(), slice))
+            })
+            .collect()
     }
-}
 
-impl<P: MemoryView + 'static> ConnectorCpuState for QemuProcfs<P> {
-    type CpuStateType<'a> = Fwd<&'a mut QemuProcfs<P>>;
-    type IntoCpuStateType = QemuProcfs<P>;
+    /// Returns how long each step of attaching to the qemu process took.
+    pub fn detection_timings(&self) -> DetectionTimings {
+        self.timings
+    }
 
-    fn cpu_state(&mut self) -> Result<Self::CpuStateType<'_>> {
-        Ok(self.forward_mut())
+    /// Returns where the guest memory mappings of this connector were obtained from.
+    pub fn mapping_source(&self) -> MappingSource {
+        self.mapping_source
     }
 
-    fn into_cpu_state(self) -> Result<Self::IntoCpuStateType> {
-        Ok(self)
+    /// Serializes the guest memory mappings to json.
+    ///
+    /// Each mapping contains the guest physical range, the backing virtual address
+    /// in the qemu process and the source of the mapping. The output can be
+    /// attached to bug reports or restored with [`mapping_from_json`].
+    #[cfg(feature = "serde")]
+    pub fn mapping_to_json(&self) -> String {
+        mem_map::mapping_to_json(&self.mem_map, self.mapping_source)
     }
-}
 
-impl<P: MemoryView> CpuState for QemuProcfs<P> {
-    fn pause(&mut self) {}
+    /// Writes the guest memory mappings to `path` in the format read by `map_file`.
+    ///
+    /// This captures a known-good memory map while qmp is reachable, which can be
+    /// replayed later with [`QemuProcfsBuilder::map_file`] when qmp is not available.
+    /// Without the `serde` feature this fails with [`ErrorKind::UnsupportedOptionalFeature`].
+    pub fn dump_memory_map(&self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        let path = path.as_ref();
+        let json = mem_map::mapping_to_map_file(&self.mem_map, &self.qemu_map)?;
+        std::fs::write(path, json).map_err(|err| {
+            Error(ErrorOrigin::Connector, ErrorKind::UnableToWriteFile).log_error(format!(
+                "unable to write map file {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        info!("memory map written to {}", path.display());
+        Ok(())
+    }
 
-    fn resume(&mut self) {}
-}
+    /// Queries the guest operating system through the qemu guest agent.
+    ///
+    /// Returns `None` if no guest agent channel is configured for the qemu process.
+    /// This can be used to pick the appropriate memflow os plugin.
+    pub fn guest_os_info(&mut self) -> Result<Option<GuestOsInfo>> {
+        self.qga.as_ref().map(GuestAgent::guest_os_info).transpose()
+    }
 
-fn validator() -> ArgsValidator {
-    ArgsValidator::new()
-        .arg(ArgDescriptor::new("map_base").description("override of VM memory base"))
-        .arg(ArgDescriptor::new("map_size").description("override of VM memory size"))
-}
+    /// Returns the number of vCPUs of the guest.
+    ///
+    /// The vCPUs are counted with the `query-cpus-fast` qmp command, without a qmp monitor
+    /// the count is taken from the `-smp` argument. The result is cached after the first call.
+    pub fn cpu_count(&self) -> Result<usize> {
+        if let Some(count) = self.cpu_count.get() {
+            return Ok(*count);
+        }
 
-/// Creates a new Qemu Procfs instance.
-#[connector(
-    name = "qemu",
-    help_fn = "help",
-    target_list_fn = "target_list",
-    accept_input = true,
-    return_wrapped = true
-)]
-fn create_plugin(
-    args: &ConnectorArgs,
-    os: Option<OsInstanceArcBox<'static>>,
-    lib: LibArc,
-) -> Result<ConnectorInstanceArcBox<'static>> {
-    let os = os.map(Result::Ok).unwrap_or_else(|| {
-        memflow_native::create_os(
-            &Default::default(),
-            Option::<std::sync::Arc<_>>::None.into(),
-        )
-    })?;
+        let count = match self.qmp.as_ref().map(QmpMonitor::cpu_count) {
+            Some(Ok(count)) => count,
+            Some(Err(err)) => {
+                warn!("unable to query the vcpus, falling back to -smp: {}", err);
+                qemu_smp_cpus(&self.cmdline)
+            }
+            None => qemu_smp_cpus(&self.cmdline),
+        };
+        Ok(*self.cpu_count.get_or_init(|| count))
+    }
 
-    let qemu = create_connector_with_os(args, os)?;
-    Ok(memflow::plugins::connector::create_instance(
-        qemu, lib, args, false,
-    ))
-}
+    /// Returns the major and minor version of qemu.
+    ///
+    /// The version is queried with the `query-version` qmp command. Without a qmp monitor
+    /// it is taken from a versioned machine type like `pc-q35-10.0`, which is the
+    /// release whose guest layout is emulated rather than the version of the binary.
+    /// The result is cached after the first call.
+    pub fn qemu_version(&self) -> Option<(u32, u32)> {
+        *self
+            .qemu_version
+            .get_or_init(|| match self.qmp.as_ref().map(QmpMonitor::version) {
+                Some(Ok(version)) => Some(version),
+                Some(Err(err)) => {
+                    warn!(
+                        "unable to query the qemu version, falling back to the machine type: {}",
+                        err
+                    );
+                    qemu_machine_version(&self.cmdline)
+                }
+                None => qemu_machine_version(&self.cmdline),
+            })
+    }
 
-pub fn create_connector(
-    args: &ConnectorArgs,
-) -> Result<QemuProcfs<IntoProcessInstanceArcBox<'static>>> {
-    create_connector_with_os(
-        args,
-        memflow_native::create_os(
-            &Default::default(),
-            Option::<std::sync::Arc<_>>::None.into(),
-        )?,
-    )
-}
+    /// Returns the byte order of the guest.
+    ///
+    /// The byte order is derived from the target architecture reported by the `query-target`
+    /// qmp command, without a qmp monitor it is derived from the name of the qemu binary.
+    /// Bi-endian targets report their default byte order. The result is cached after the first call.
+    pub fn endianess(&self) -> Endianess {
+        *self.endianess.get_or_init(|| {
+            qemu_target_endianess(&qemu_target_arch(&self.cmdline, self.qmp.as_ref()))
+        })
+    }
 
-pub fn create_connector_with_os<O: Os>(
-    args: &ConnectorArgs,
-    os: O,
-) -> Result<QemuProcfs<O::IntoProcessType>> {
-    let validator = validator();
+    /// Reads the general purpose and control registers of the given vCPU.
+    ///
+    /// The registers are queried with the `info registers` monitor command
+    /// and therefore require a qmp monitor. Only x86 guests are supported.
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    pub fn read_registers(&mut self, cpu_index: usize) -> Result<GuestRegisters> {
+        let qmp = self.qmp.as_ref().ok_or_else(cpu_state_unsupported)?;
+        let output = qmp.human_monitor_command_on("info registers", Some(cpu_index as i64))?;
+        GuestRegisters::parse(&output).ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                .log_error(format!("unable to parse registers of cpu {}", cpu_index))
+        })
+    }
 
-    let name = args.target.as_deref();
+    /// Runs a human monitor (HMP) command like `info lapic` or `info tlb` and returns its raw output.
+    ///
+    /// The command is sent through the qmp monitor of the qemu process.
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    pub fn hmp_command(&mut self, cmd: &str) -> Result<String> {
+        let qmp = self.qmp.as_ref().ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::NotImplemented)
+                .log_warn("monitor commands require a qmp monitor")
+        })?;
+        qmp.human_monitor_command(cmd)
+    }
 
-    let args = &args.extra_args;
+    /// Saves the state of the guest in the snapshot `tag` with the `savevm` monitor command.
+    ///
+    /// This requires a qmp monitor and disk images which support snapshots (e.g. qcow2).
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    pub fn snapshot_save(&mut self, tag: &str) -> Result<()> {
+        qmp_snapshot(self.qmp.as_ref(), "savevm", tag)
+    }
 
-    let qemu = match validator.validate(args) {
-        Ok(_) => {
-            let map_override = args
-                .get("map_base")
-                .and_then(|base| umem::from_str_radix(base, 16).ok())
-                .zip(
-                    args.get("map_size")
-                        .and_then(|size| umem::from_str_radix(size, 16).ok()),
-                )
-                .map(|(start, size)| CTup2(Address::from(start), size));
+    /// Restores the guest from the snapshot `tag` with the `loadvm` monitor command.
+    ///
+    /// The snapshot may have been taken with a different memory configuration and qemu
+    /// can reallocate the guest memory while loading it. The memory map of this connector
+    /// is not updated, rebuild the connector after loading a snapshot.
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    pub fn snapshot_load(&mut self, tag: &str) -> Result<()> {
+        qmp_snapshot(self.qmp.as_ref(), "loadvm", tag)?;
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+        Ok(())
+    }
 
-            if let Some(name) = name.or_else(|| args.get("name")) {
-                if let Ok(pid) = Pid::from_str_radix(name, 10) {
-                    QemuProcfs::with_pid(os, pid, map_override)
-                } else {
-                    QemuProcfs::with_guest_name(os, name, map_override)
-                }
-            } else {
-                QemuProcfs::new(os, map_override)
+    /// Runs `f` while the guest is paused to get a consistent view of its memory.
+    ///
+    /// The guest is resumed afterwards, even if `f` panics. Without a qmp monitor, or if the
+    /// guest could not be paused through it, the closure is run without pausing the guest.
+    pub fn with_paused<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> Result<R> {
+        let _guard = match self.qmp.as_ref().map(QmpMonitor::pause_guard) {
+            Some(Ok(guard)) => Some(guard),
+            Some(Err(err)) => {
+                warn!(
+                    "unable to pause the guest, running without pausing it: {}",
+                    err
+                );
+                None
+            }
+            None => {
+                warn!("no qmp monitor available, running without pausing the guest");
+                None
             }
+        };
+        Ok(f(self))
+    }
+
+    /// Advises the host kernel that the entire guest memory will be read soon.
+    ///
+    /// When guest memory is partially swapped out on the host (e.g. with `-overcommit mem-lock=off`)
+    /// this pulls the pages back in ahead of time and speeds up subsequent large reads.
+    /// Reads are always correct without prefaulting, the kernel faults in pages on demand.
+    ///
+    /// This requires Linux 5.10 or newer and the `CAP_SYS_NICE` capability
+    /// in addition to the ptrace permissions required by the connector.
+    pub fn prefault(&mut self) -> Result<()> {
+        let pid = self
+            .pid
+            .filter(|_| self.procfs_root.is_some())
+            .ok_or_else(|| {
+                Error(ErrorOrigin::Connector, ErrorKind::NotSupported)
+                    .log_error("prefaulting requires a qemu process on the local procfs")
+            })?;
+        self.prefault_with(|ranges| procfs::madvise_willneed(pid, ranges))
+    }
+
+    /// Starts tracking writes to the guest memory.
+    ///
+    /// By default qemu measures the rate at which the guest dirties its memory with the qmp
+    /// command `calc-dirty-rate`, see [`poll_dirty_rate`](Self::poll_dirty_rate). This requires
+    /// qemu 5.2 or newer and returns `NotImplemented` if qemu does not support the command.
+    ///
+    /// qmp does not report the dirty pages themselves. With
+    /// [`soft_dirty`](QemuProcfsBuilder::soft_dirty) enabled the written pages are tracked with
+    /// the soft-dirty page bits of the host kernel instead, see
+    /// [`poll_dirty_pages`](Self::poll_dirty_pages). This works with any qemu version and for kvm
+    /// as well as tcg guests, but requires a kernel built with `CONFIG_MEM_SOFT_DIRTY` and write
+    /// access to `/proc/<pid>/clear_refs`. Guest ram backed by hugetlbfs is not tracked.
+    ///
+    /// Enabling the tracking again restarts it.
+    pub fn enable_dirty_tracking(&mut self) -> Result<()> {
+        if !self.soft_dirty {
+            let qmp = self.qmp.as_ref().ok_or_else(|| {
+                Error::from(QemuError::QmpNotConfigured)
+                    .log_error("dirty rate tracking requires a qmp monitor")
+            })?;
+            return qmp.calc_dirty_rate(DIRTY_RATE_CALC_TIME);
         }
-        Err(err) => {
-            error!(
-                "unable to validate provided arguments, valid arguments are:\n{}",
-                validator
+
+        let (pid, procfs_root) = self.pid.zip(self.procfs_root.as_ref()).ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::NotSupported)
+                .log_error("soft-dirty tracking requires a qemu process on the local procfs")
+        })?;
+        self.dirty_tracker = Some(SoftDirty::open(procfs_root, pid)?);
+        Ok(())
+    }
+
+    /// Returns the rate in MB/s at which the guest dirtied its memory, measured by qemu over
+    /// one second after [`enable_dirty_tracking`](Self::enable_dirty_tracking).
+    ///
+    /// Returns `None` while qemu is still measuring.
+    pub fn poll_dirty_rate(&self) -> Result<Option<u64>> {
+        let qmp = self.qmp.as_ref().ok_or_else(|| {
+            Error::from(QemuError::QmpNotConfigured)
+                .log_error("dirty rate tracking requires a qmp monitor")
+        })?;
+        qmp.dirty_rate()
+    }
+
+    /// Returns the guest physical addresses of all pages written since dirty tracking was
+    /// enabled or since the previous call, sorted by address.
+    ///
+    /// The pages are only known with [`soft_dirty`](QemuProcfsBuilder::soft_dirty) tracking,
+    /// otherwise `NotImplemented` is returned as qmp only reports the dirty rate.
+    ///
+    /// Pages written while the pages are collected might not be reported,
+    /// pause the guest first to get an exact result.
+    pub fn poll_dirty_pages(&mut self) -> Result<Vec<Address>> {
+        if !self.soft_dirty {
+            return Err(
+                Error(ErrorOrigin::Connector, ErrorKind::NotImplemented).log_error(
+                    "qmp only reports the dirty rate, enable soft_dirty to collect the dirty pages",
+                ),
             );
-            Err(err)
         }
-    }?;
 
-    Ok(qemu)
-}
+        let tracker = self.dirty_tracker.as_ref().ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::NotSupported)
+                .log_error("dirty page tracking has not been enabled")
+        })?;
+        let dirty =
+            Self::collect_dirty_pages(&self.mem_map, |host, size| tracker.dirty_pages(host, size))?;
+        tracker.clear()?;
+        Ok(dirty)
+    }
 
-/// Retrieve the help text for the Qemu Procfs Connector.
-pub fn help() -> String {
-    let validator = validator();
-    format!(
-        "\
-The `qemu` connector implements a memflow plugin interface
-for QEMU on top of the Process Filesystem on Linux.
+    fn collect_dirty_pages(
+        mem_map: &MemoryMap<(Address, umem)>,
+        mut dirty_pages: impl FnMut(umem, umem) -> Result<Vec<umem>>,
+    ) -> Result<Vec<Address>> {
+        let mut dirty = vec![];
+        for mapping in mem_map.iter() {
+            let (host, size) = *mapping.output();
+            dirty.extend(
+                dirty_pages(host.to_umem(), size)?
+                    .into_iter()
+                    .filter(|offset| *offset < size)
+                    .map(|offset| mapping.base() + offset),
+            );
+        }
+        dirty.sort_unstable();
+        dirty.dedup();
+        Ok(dirty)
+    }
 
-This connector requires access to the qemu process via the linux procfs.
-This means any process which loads this connector requires
-to have at least ptrace permissions set.
+    fn prefault_with(&self, advise: impl FnOnce(&[(Address, umem)]) -> Result<()>) -> Result<()> {
+        let ranges = self
+            .scan_mappings()
+            .into_iter()
+            .map(|(_, size, host)| (host, size))
+            .collect::<Vec<_>>();
+        info!("prefaulting {} guest memory ranges", ranges.len());
+        advise(&ranges)
+    }
 
-The `target` argument specifies the target qemu virtual machine.
-The qemu virtual machine name can be specified when starting qemu with the -name flag.
+    /// Reads the first `n` bytes of each mapped guest memory range.
+    ///
+    /// This is useful to quickly sanity-check the memory map, e.g. whether the IVT,
+    /// the BIOS and high memory contain plausible data. Ranges which cannot be read are skipped.
+    pub fn sample_mappings(&mut self, n: usize) -> Vec<(Address, Vec<u8>)> {
+        let ranges = self.scan_mappings();
+
+        let mut samples = Vec::with_capacity(ranges.len());
+        for (base, size, _) in ranges {
+            let mut buf = vec![0u8; n.min(size as usize)];
+            match self.phys_view().read_raw_into(base, &mut buf).data() {
+                Ok(_) => samples.push((base, buf)),
+                Err(err) => warn!("unable to sample guest memory at {:x}: {}", base, err),
+            }
+        }
+        samples
+    }
 
-Alternatively, if `target` is a number, qemu process by PID will be accessed.
+    /// Runs sanity checks on the memory map and returns the problems found with suggested fixes.
+    ///
+    /// The memory map is checked for empty maps, gaps and overlapping host ranges, the start of
+    /// each mapping is read and, if a qmp monitor is available, the memory map is compared with
+    /// the one reported by qmp. The report is meant to be attached to issues.
+    pub fn self_check(&mut self) -> SelfCheckReport {
+        let mut report = SelfCheckReport::new(&self.mem_map, self.mapping_source);
+        report.check_mem_map(&self.qemu_map);
 
-Available arguments are:
-{validator}"
-    )
+        let scan_range = self.scan_range.take();
+        let samples = self.sample_mappings(16);
+        self.scan_range = scan_range;
+        report.check_samples(&samples);
+
+        if self.qmp.is_some() {
+            let mapping = MappingOptions {
+                source: Some(MappingSource::Qmp),
+                mtree_cache: false,
+                ..self.mapping.clone()
+            };
+            report.check_qmp_mem_map(
+                qemu_mem_mappings(&self.cmdline, &self.qemu_map, self.qmp.as_ref(), &mapping)
+                    .map(|(mem_map, _)| mem_map),
+            );
+        }
+
+        report
+    }
+
+    /// Restricts bulk operations like [`find_pattern`](Self::find_pattern), [`prefault`](Self::prefault)
+    /// and [`sample_mappings`](Self::sample_mappings) to the guest physical range `[start, end)`.
+    ///
+    /// Passing `None` removes the restriction. Regular reads and writes are not affected.
+    pub fn set_scan_range(&mut self, range: Option<(Address, Address)>) {
+        self.scan_range = range;
+    }
+
+    /// Returns the mapping containing the guest physical address `gpa`
+    /// and the host virtual address it is translated to.
+    ///
+    /// This is useful to cross-check reads with a debugger attached to the qemu process.
+    /// Returns `None` if the address is not mapped.
+    pub fn describe_address(&self, gpa: Address) -> Option<AddressMapping> {
+        self.mem_map.iter().find_map(|mapping| {
+            let (host_va, size) = *mapping.output();
+            let gpa_start = mapping.base();
+            (gpa >= gpa_start && gpa < gpa_start + size).then(|| AddressMapping {
+                gpa_start,
+                size,
+                host_va,
+                host_address: host_va + (gpa - gpa_start),
+            })
+        })
+    }
+
+    /// Compares the translation of `gpa` with the host virtual address qemu reports for it.
+    ///
+    /// qemu translates the address with the `gpa2hva` monitor command, a mismatch with the
+    /// memory map of this connector is logged as a warning. This helps to diagnose reads
+    /// returning wrong data and to file accurate bug reports for wrong mappings.
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    pub fn verify_translation(&mut self, gpa: Address) -> Result<()> {
+        let qmp = self.qmp.as_ref().ok_or_else(|| {
+            Error::from(QemuError::QmpNotConfigured)
+                .log_error("verifying translations requires a qmp monitor")
+        })?;
+        let expected = Some(Address::from(qmp_gpa2hva(qmp, gpa.to_umem())?));
+        let actual = self
+            .describe_address(gpa)
+            .map(|mapping| mapping.host_address);
+
+        if actual == expected {
+            debug!(
+                "guest physical address {:x} is translated to {:?}",
+                gpa, actual
+            );
+        } else {
+            warn!(
+                "guest physical address {:x} is translated to {:?} but qemu maps it to {:?}",
+                gpa, actual, expected
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the guest physical base, size and host address of all mappings
+    /// intersected with the scan range.
+    fn scan_mappings(&self) -> Vec<(Address, umem, Address)> {
+        let (start, end) = self.scan_range.unwrap_or((Address::NULL, Address::INVALID));
+
+        self.mem_map
+            .iter()
+            .filter_map(|mapping| {
+                let (host, size) = *mapping.output();
+                let base = mapping.base();
+                let lo = base.max(start);
+                let hi = (base + size).min(end);
+                (lo < hi).then(|| (lo, (hi - lo) as umem, host + (lo - base)))
+            })
+            .collect()
+    }
+
+    /// Searches all mapped guest memory within the scan range for `pattern`.
+    ///
+    /// Returns the guest physical addresses of all matches in ascending order.
+    /// Chunks which cannot be read are skipped.
+    pub fn find_pattern(&mut self, pattern: &[u8]) -> Vec<Address> {
+        const CHUNK_SIZE: umem = 0x100000;
+
+        let mut matches = vec![];
+        if pattern.is_empty() {
+            return matches;
+        }
+
+        let overlap = pattern.len() as umem - 1;
+        let mut buf = ScratchBuf::new(self.zeroize_buffers);
+        for (base, size, _) in self.scan_mappings() {
+            let mut offset = 0;
+            while offset < size {
+                // chunks overlap so matches crossing a chunk boundary are found
+                let len = (CHUNK_SIZE + overlap).min(size - offset);
+                let addr = base + offset;
+                buf.resize(len as usize, 0);
+                match self.phys_view().read_raw_into(addr, &mut buf).data() {
+                    Ok(_) => matches.extend(
+                        buf.windows(pattern.len())
+                            .enumerate()
+                            .filter(|(_, window)| *window == pattern)
+                            .map(|(i, _)| addr + i),
+                    ),
+                    Err(err) => warn!("unable to scan guest memory at {:x}: {}", addr, err),
+                }
+                offset += CHUNK_SIZE;
+            }
+        }
+        matches
+    }
+
+    /// Reads a `#[repr(C)]` structure from the given guest physical address.
+    ///
+    /// All fields are converted from the guest byte order in case it differs from the host.
+    /// Use [`read_guest_struct!`] to additionally verify the size of the structure at compile time.
+    pub fn read_struct<T: Pod + ByteSwap>(&mut self, addr: Address) -> Result<T> {
+        let mut obj = self.phys_view().read::<T>(addr).data()?;
+        if self.endianess() != host_endianess() {
+            obj.byte_swap();
+        }
+        Ok(obj)
+    }
+
+    /// Reads `count` records of type `T` which are `stride` bytes apart, starting at `base`.
+    ///
+    /// The records are read in batches and passed to `f` together with their index.
+    /// Like [`read_struct`](Self::read_struct) each record is converted from the guest byte order.
+    pub fn read_records<T: Pod + ByteSwap, F: FnMut(usize, T)>(
+        &mut self,
+        base: Address,
+        count: usize,
+        stride: usize,
+        mut f: F,
+    ) -> Result<()> {
+        const BATCH_SIZE: usize = 0x10000;
+
+        let size = std::mem::size_of::<T>();
+        if size == 0 {
+            // zero-sized records do not occupy any guest memory, there is nothing to read
+            (0..count).for_each(|index| f(index, T::zeroed()));
+            return Ok(());
+        }
+        if stride < size {
+            return Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                .log_error("the record stride is smaller than the record"));
+        }
+
+        let swap = self.endianess() != host_endianess();
+        let batch = (BATCH_SIZE / stride).max(1);
+        let mut buf = ScratchBuf::new(self.zeroize_buffers);
+
+        let mut index = 0;
+        while index < count {
+            let records = batch.min(count - index);
+            // the padding after the last record is not read
+            buf.resize((records - 1) * stride + size, 0);
+            self.phys_view()
+                .read_raw_into(base + index * stride, &mut buf)
+                .data()?;
+
+            for (i, record) in buf.chunks(stride).enumerate() {
+                let mut obj = T::zeroed();
+                obj.as_bytes_mut().copy_from_slice(&record[..size]);
+                if swap {
+                    obj.byte_swap();
+                }
+                f(index + i, obj);
+            }
+
+            index += records;
+        }
+
+        Ok(())
+    }
+
+    /// Reads `data` through the shared page cache, fetching missing pages from the qemu process.
+    fn read_cached(
+        &mut self,
+        cache: &Mutex<PageCache>,
+        addr: Address,
+        data: &mut [u8],
+    ) -> Result<()> {
+        let page_size = cache.lock().unwrap().page_size();
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let cur = addr.to_umem() + pos as umem;
+            let page = cur & !(page_size - 1);
+            let offset = (cur - page) as usize;
+            let len = (page_size as usize - offset).min(data.len() - pos);
+
+            let hit = cache
+                .lock()
+                .unwrap()
+                .get(page)
+                .map(|buf| data[pos..pos + len].copy_from_slice(&buf[offset..offset + len]))
+                .is_some();
+
+            if !hit {
+                // the lock is not held while reading so other clones are not blocked
+                let mut buf = vec![0u8; page_size as usize].into_boxed_slice();
+                if let Err(err) = self.view.read_raw_into(page.into(), &mut buf).data() {
+                    if self.zeroize_buffers {
+                        scrub(&mut buf);
+                    }
+                    return Err(err);
+                }
+                data[pos..pos + len].copy_from_slice(&buf[offset..offset + len]);
+                cache.lock().unwrap().insert(page, buf);
+            }
+
+            pos += len;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes writes to guest memory backed by persistent memory, see [`MappedRam::flush`].
+    fn flush_dax_writes(&self, ram: &MappedRam, writes: &[(Address, umem)]) {
+        for &(addr, len) in writes {
+            for mapping in self.mem_map.iter() {
+                let (host, size) = *mapping.output();
+                let base = mapping.base();
+                let start = addr.max(base);
+                let end = (addr + len).min(base + size);
+                if start >= end {
+                    continue;
+                }
+                if let Err(err) =
+                    ram.flush((host + (start - base)).to_umem(), (end - start) as umem)
+                {
+                    warn!(
+                        "unable to flush the write to persistent memory at {:x}: {}",
+                        start, err
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the number of page cache hits and misses across all clones of this connector.
+    pub fn cache_stats(&self) -> Option<(u64, u64)> {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.lock().unwrap().stats())
+    }
+
+    /// Clears the intermediate read buffer if `zeroize_buffers` was requested.
+    fn scrub_read_buf(&mut self) {
+        if self.zeroize_buffers {
+            scrub(&mut self.read_buf);
+        }
+    }
+
+    /// Translates a guest virtual address by walking the x86_64 4-level page tables at `cr3`.
+    ///
+    /// Large (2mb) and huge (1gb) pages are supported.
+    /// Returns `None` if the address is not mapped by the page tables.
+    pub fn translate_gva(&mut self, cr3: Address, gva: Address) -> Result<Option<Address>> {
+        const PRESENT: umem = 1 << 0;
+        const PAGE_SIZE: umem = 1 << 7;
+        const ADDR_MASK: umem = 0x000f_ffff_ffff_f000;
+
+        let gva = gva.to_umem();
+        let mut table = cr3.to_umem() & ADDR_MASK;
+
+        // pml4, pdpt, pd and pt are indexed by 9 bits each
+        for (level, shift) in [39, 30, 21, 12].into_iter().enumerate() {
+            let index = (gva >> shift) & 0x1ff;
+            let entry = u64::from_le(
+                self.phys_view()
+                    .read::<u64>(Address::from(table + index * 8))
+                    .data()?,
+            ) as umem;
+
+            if entry & PRESENT == 0 {
+                return Ok(None);
+            }
+
+            // the pdpt and pd entries may map 1gb and 2mb pages directly
+            if (level == 1 || level == 2) && entry & PAGE_SIZE != 0 {
+                let offset_mask = (1 << shift) - 1;
+                return Ok(Some(Address::from(
+                    (entry & ADDR_MASK & !offset_mask) | (gva & offset_mask),
+                )));
+            }
+
+            table = entry & ADDR_MASK;
+        }
+
+        Ok(Some(Address::from(table | (gva & 0xfff))))
+    }
+
+    /// Reads the linux kernel `setup_header` from the real-mode kernel at `base`.
+    ///
+    /// Bootloaders conventionally place the real-mode kernel at [`LINUX_REAL_MODE_BASE`],
+    /// the header is also part of the `boot_params` (zero page) passed to the kernel.
+    pub fn read_linux_setup_header(&mut self, base: Address) -> Result<LinuxSetupHeader> {
+        let mut buf = vec![0u8; boot::LINUX_SETUP_HEADER_SIZE];
+        self.phys_view().read_raw_into(base, &mut buf).data()?;
+        LinuxSetupHeader::parse(&buf).ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::NotFound)
+                .log_info("no linux setup header found at the given address")
+        })
+    }
+
+    /// Reads the multiboot information structure at `addr`.
+    ///
+    /// The address is passed to the kernel by the bootloader in `ebx`.
+    pub fn read_multiboot_info(&mut self, addr: Address) -> Result<MultibootInfo> {
+        let mut buf = vec![0u8; boot::MULTIBOOT_INFO_SIZE];
+        self.phys_view().read_raw_into(addr, &mut buf).data()?;
+        MultibootInfo::parse(&buf).ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                .log_info("no multiboot information found at the given address")
+        })
+    }
+
+    /// Returns the guest physical address and size of a device BAR.
+    ///
+    /// The device is identified by its PCI address (e.g. `0000:0c:00.0`).
+    /// This requires a qmp monitor to be available.
+    ///
+    /// Device BARs are not backed by the guest RAM mapping
+    /// and are therefore not accessible through this connector.
+    pub fn device_bar_mapping(&mut self, bdf: &str, bar: u32) -> Result<(Address, umem)> {
+        qmp_get_device_bar(self.qmp.as_ref(), bdf, bar)
+    }
+
+    /// Reads the firmware of the guest, e.g. for UEFI or BIOS analysis.
+    ///
+    /// Returns the name and contents of the `system.flash0/1`, `pc.bios`, `isa-bios`
+    /// and `pc.rom` regions found in the qmp memory tree. Flash and rom are separate
+    /// allocations in the qemu process, they are located with `gpa2hva` and read directly
+    /// from the host. Regions which cannot be located are read from guest physical memory.
+    /// This requires a qmp monitor to be available.
+    pub fn dump_firmware(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let regions = qmp_get_firmware_regions(self.qmp.as_ref())?;
+
+        let mut firmware = vec![];
+        for region in regions {
+            let mut buf = vec![0u8; (region.range_end - region.range_start) as usize];
+            let host_read = match (&self.proc_mem, region.host) {
+                (Some(proc_mem), Some(host)) => proc_mem.read_ranges(&mut [(host, &mut buf)])[0],
+                _ => false,
+            };
+            if !host_read {
+                self.phys_view()
+                    .read_raw_into(region.range_start.into(), &mut buf)
+                    .data()?;
+            }
+            firmware.push((region.name, buf));
+        }
+        Ok(firmware)
+    }
 }
 
-/// Retrieve a list of all currently available Qemu targets.
-pub fn target_list() -> Result<Vec<TargetInfo>> {
-    let mut os = memflow_native::create_os(
-        &Default::default(),
-        Option::<std::sync::Arc<_>>::None.into(),
-    )?;
+impl<P: MemoryView + Clone + 'static> QemuProcfs<P> {
+    /// Bounds the duration of physical reads, reads taking longer fail with
+    /// [`ErrorKind::UnableToReadMemory`].
+    ///
+    /// Reads blocked on the host (e.g. guest memory backed by a hung nfs mount) cannot be
+    /// interrupted. With a timeout every read is therefore issued on a separate thread which
+    /// is abandoned when the timeout expires. This adds the overhead of spawning a thread
+    /// to every read and bypasses the page cache and read alignment.
+    pub fn set_read_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.timeout_reader = timeout
+            .map(|timeout| TimeoutReader::new(self.view.clone(), timeout, self.zeroize_buffers));
+    }
 
-    let mut out = vec![];
+    /// Locates the guest memory in the qemu process again and rebuilds the memory map.
+    ///
+    /// The guest memory can be reallocated by qemu, e.g. while the guest reboots, which
+    /// leaves the connector reading from a stale host address. This is only supported when
+    /// the guest memory mapping was detected, not when it was passed as an override.
+    pub fn reinitialize(&mut self) -> Result<()> {
+        let relocate = self.relocate.ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::NotSupported)
+                .log_error("reinitializing requires the guest memory mapping to be detected")
+        })?;
+        let qemu_map = relocate(self.view.inner_mut()).ok_or_else(|| {
+            Error::from(QemuError::PermissionDenied)
+                .log_error("unable to locate the guest memory in the qemu process")
+        })?;
+        self.rebuild_mem_map(qemu_map)
+    }
 
-    let callback = &mut |info: ProcessInfo| {
-        if is_qemu(&info) {
-            if let Some(n) = qemu_arg_opt(info.command_line.split_whitespace(), "-name", "guest") {
-                out.push(TargetInfo {
-                    name: ReprCString::from(n),
-                });
+    /// Rebuilds the memory map after a guest reset, relocating the guest memory if possible.
+    fn refresh_mem_map(&mut self) -> Result<()> {
+        let qemu_map = self
+            .relocate
+            .and_then(|relocate| relocate(self.view.inner_mut()))
+            .unwrap_or(self.qemu_map);
+        self.rebuild_mem_map(qemu_map)
+    }
+
+    fn rebuild_mem_map(&mut self, qemu_map: CTup2<Address, umem>) -> Result<()> {
+        // the cached mappings may be the stale ones
+        let mapping = MappingOptions {
+            mtree_cache: false,
+            ..self.mapping.clone()
+        };
+        let (mem_map, mapping_source) =
+            qemu_mem_mappings(&self.cmdline, &qemu_map, self.qmp.as_ref(), &mapping)?;
+        info!("qemu machine mem_map reinitialized: {:?}", mem_map);
+
+        self.view.set_mem_map(mem_map.clone());
+        self.qemu_map = qemu_map;
+        self.mem_map = mem_map;
+        self.mapping_source = mapping_source;
+
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+        if let Some((pid, root)) = self.pid.zip(self.procfs_root.as_ref()) {
+            self.past_file_end = procfs::past_file_end(root, pid, qemu_map.0, qemu_map.1);
+        }
+        if self.ram_mmap.is_some() {
+            let dax = qemu_mem_dax(&self.cmdline);
+            self.ram_mmap =
+                self.pid.zip(self.procfs_root.as_ref()).and_then(
+                    |(pid, root)| match MappedRam::map(root, pid, qemu_map.0, qemu_map.1, dax) {
+                        Ok(ram) => Some(Arc::new(ram)),
+                        Err(err) => {
+                            warn!("unable to map guest memory, using procfs reads: {}", err);
+                            None
+                        }
+                    },
+                );
+        }
+        if let Some(reader) = &self.timeout_reader {
+            self.set_read_timeout(Some(reader.timeout()));
+        }
+
+        Ok(())
+    }
+
+    /// Reinitializes the connector when reads of mapped guest memory fail and retries them once.
+    ///
+    /// This lets long running tools survive the guest memory being moved, see
+    /// [`reinitialize`](Self::reinitialize). Reads which still fail after reinitializing
+    /// are reported as failed, reads of unmapped guest memory never trigger a reinitialization.
+    pub fn set_auto_recover(&mut self, enabled: bool) {
+        self.recover = enabled.then_some(Self::reinitialize as fn(&mut Self) -> Result<()>);
+    }
+
+    /// Watches the qmp monitor for guest resets and shutdowns in the background.
+    ///
+    /// The guest memory layout, e.g. hotplugged memory regions, can change when the guest reboots.
+    /// After a `RESET` or `SHUTDOWN` event the memory map is rebuilt on the next access.
+    /// This keeps one qmp connection open for the lifetime of the connector and its clones.
+    pub fn watch_qmp_events(&mut self) -> Result<()> {
+        let qmp = self.qmp.as_ref().ok_or_else(|| {
+            Error::from(QemuError::QmpNotConfigured)
+                .log_error("watching qmp events requires a qmp monitor")
+        })?;
+
+        let resets = Arc::new(AtomicUsize::new(0));
+        qmp.watch_resets(&resets)?;
+        self.reset_watch = Some(ResetWatch {
+            resets,
+            seen: 0,
+            refresh: Self::refresh_mem_map,
+        });
+        Ok(())
+    }
+
+    /// Reads all mapped guest memory within the scan range sequentially in chunks of `chunk_size` bytes.
+    ///
+    /// `callback` receives the guest physical address and contents of each chunk,
+    /// returning `false` stops the scan. Chunks which cannot be read are skipped.
+    ///
+    /// With a `prefetch` depth greater than 0 the chunks are read by a background thread
+    /// on a clone of the connector, up to `prefetch` chunks ahead of the callback.
+    /// This overlaps reading guest memory with processing it in the callback.
+    pub fn scan_ram(
+        &mut self,
+        chunk_size: usize,
+        prefetch: usize,
+        mut callback: impl FnMut(Address, &[u8]) -> bool,
+    ) {
+        let chunks = self
+            .scan_mappings()
+            .into_iter()
+            .flat_map(|(base, size, _)| {
+                (0..size)
+                    .step_by(chunk_size.max(1))
+                    .map(move |offset| (base + offset, (size - offset).min(chunk_size as umem)))
+            })
+            .collect::<Vec<_>>();
+
+        let zeroize = self.zeroize_buffers;
+        if prefetch == 0 {
+            let mut buf = ScratchBuf::new(zeroize);
+            for (addr, len) in chunks {
+                buf.resize(len as usize, 0);
+                match self.phys_view().read_raw_into(addr, &mut buf).data() {
+                    Ok(_) => {
+                        if !callback(addr, &buf) {
+                            break;
+                        }
+                    }
+                    Err(err) => warn!("unable to scan guest memory at {:x}: {}", addr, err),
+                }
+            }
+            return;
+        }
+
+        // the bounded channel stops the reader once it is `prefetch` chunks ahead,
+        // processed buffers are handed back so at most `prefetch + 2` of them are allocated
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel::<(Address, ScratchBuf)>(prefetch);
+        let (free_tx, free_rx) = mpsc::channel::<ScratchBuf>();
+        let mut reader = self.clone();
+        let handle = std::thread::spawn(move || {
+            for (addr, len) in chunks {
+                let mut buf = free_rx
+                    .try_recv()
+                    .unwrap_or_else(|_| ScratchBuf::new(zeroize));
+                buf.resize(len as usize, 0);
+                match reader.phys_view().read_raw_into(addr, &mut buf).data() {
+                    Ok(_) => {
+                        if chunk_tx.send((addr, buf)).is_err() {
+                            // the scan was stopped
+                            break;
+                        }
+                    }
+                    Err(err) => warn!("unable to scan guest memory at {:x}: {}", addr, err),
+                }
+            }
+        });
+
+        for (addr, buf) in chunk_rx.iter() {
+            if !callback(addr, &buf) {
+                break;
+            }
+            free_tx.send(buf).ok();
+        }
+
+        drop(chunk_rx);
+        handle.join().ok();
+    }
+}
+
+/// Returns the first qemu process matching `filter`.
+///
+/// With `kvm_only` processes which have `/dev/kvm` open are preferred.
+/// If none of them matches, all qemu processes are considered so tcg guests are still found.
+fn find_qemu_process<O: Os>(
+    os: &mut O,
+    procfs_root: Option<&Path>,
+    kvm_only: bool,
+    filter: impl Fn(&ProcessInfo) -> bool,
+) -> Result<Option<ProcessInfo>> {
+    // the open file descriptors can only be checked through a local procfs
+    let kvm_only = kvm_only && procfs_root.is_some();
+
+    let mut find = |kvm_only: bool| -> Result<Option<ProcessInfo>> {
+        let mut proc = None;
+
+        let callback = &mut |info: ProcessInfo| {
+            if proc.is_none()
+                && (!kvm_only || procfs_root.is_some_and(|root| procfs::has_kvm_fd(root, info.pid)))
+                && is_qemu(&info)
+                && filter(&info)
+            {
+                proc = Some(info);
             }
+
+            proc.is_none()
+        };
+
+        os.process_info_list_callback(callback.into())?;
+        Ok(proc)
+    };
+
+    if kvm_only {
+        if let Some(proc) = find(true)? {
+            return Ok(Some(proc));
+        }
+        info!("no matching qemu process with /dev/kvm open found, falling back to tcg guests");
+    }
+
+    find(false)
+}
+
+/// Finds the qemu process listed under `name` in the target list.
+///
+/// If no guest is listed under `name` the process with `fallback_pid` is used instead,
+/// so a bare number only selects a process by its pid if it is not the name of a guest.
+fn find_guest_process<O: Os>(
+    os: &mut O,
+    procfs_root: Option<&Path>,
+    kvm_only: bool,
+    name: &str,
+    fallback_pid: Option<Pid>,
+) -> Result<Option<ProcessInfo>> {
+    // duplicate names are listed as `<name> (pid <n>)` in the target list
+    let (name, pid) = split_pid_suffix(name);
+    let proc = find_qemu_process(os, procfs_root, kvm_only, |info| {
+        qemu_guest_label(&process_cmdline(procfs_root, info)).as_deref() == Some(name)
+            && pid.is_none_or(|pid| info.pid == pid)
+    })?;
+
+    match (proc, fallback_pid) {
+        (None, Some(pid)) => {
+            debug!("no guest is named {}, looking it up as pid", name);
+            os.process_info_by_pid(pid).map(Some)
         }
+        (proc, _) => Ok(proc),
+    }
+}
+
+/// Number of the largest host mappings which are checked for guest ram.
+const GUEST_RAM_CANDIDATES: usize = 4;
 
+/// Locates the guest memory mapping in the qemu process.
+fn locate_guest_ram<P: MemoryView + Process>(prc: &mut P) -> Option<CTup2<Address, umem>> {
+    let mut candidates = vec![];
+    let callback = &mut |range: MemoryRange| {
+        candidates.push(CTup2(range.0, range.1));
         true
     };
 
-    os.process_info_list_callback(callback.into())?;
+    prc.mapped_mem_range(
+        smem::mb(-1),
+        Address::NULL,
+        Address::INVALID,
+        callback.into(),
+    );
+
+    select_guest_ram(prc, candidates)
+}
+
+/// Returns the largest host mapping whose first page looks like guest ram.
+///
+/// Large non-ram mappings (e.g. vfio regions or the kvm coalesced mmio ring) are either
+/// unreadable or read back as all `0xff`. If none of the largest candidates looks like ram
+/// the largest mapping is used regardless.
+fn select_guest_ram<P: MemoryView>(
+    prc: &mut P,
+    mut candidates: Vec<CTup2<Address, umem>>,
+) -> Option<CTup2<Address, umem>> {
+    candidates.sort_by_key(|CTup2(_, size)| std::cmp::Reverse(*size));
+
+    let mut page = vec![0u8; mem::kb(4) as usize];
+    for &candidate in candidates.iter().take(GUEST_RAM_CANDIDATES) {
+        let CTup2(base, size) = candidate;
+        let len = page.len().min(size as usize);
+        let looks_like_ram = prc.read_raw_into(base, &mut page[..len]).is_ok()
+            && page[..len].iter().any(|&b| b != 0xff);
+        if looks_like_ram {
+            return Some(candidate);
+        }
+        info!(
+            "host mapping at {:x} with size {:x} does not look like guest ram, trying the next one",
+            base, size
+        );
+    }
+
+    let biggest = candidates.first().copied();
+    if biggest.is_some() {
+        warn!("no host mapping looks like guest ram, using the largest one");
+    }
+    biggest
+}
+
+/// Computes the guest physical memory map of a qemu process without attaching to it.
+///
+/// `base` and `size` describe the guest memory mapping in the qemu process.
+/// The qmp monitor from the command line is queried if available,
+/// otherwise the hard-coded mappings for the machine type are used.
+pub fn compute_mem_map(
+    cmdline: &str,
+    base: Address,
+    size: umem,
+) -> Result<MemoryMap<(Address, umem)>> {
+    let qmp = QmpMonitor::from_cmdline(cmdline, None);
+    let (mem_map, _) = qemu_mem_mappings(
+        cmdline,
+        &CTup2(base, size),
+        qmp.as_ref(),
+        &Default::default(),
+    )?;
+    Ok(mem_map)
+}
+
+/// Fills the parts of `ops` which are translated into the host range `past_file_end` with zeroes.
+///
+/// The zero-filled parts are passed to `out` right away, the remaining parts are returned
+/// as separate ops. See [`procfs::past_file_end`] for the range past the end of a sparse file.
+fn zero_fill_past_file_end<'a>(
+    mem_map: &MemoryMap<(Address, umem)>,
+    (eof_start, eof_end): (Address, Address),
+    ops: impl Iterator<Item = PhysicalReadData<'a>>,
+    out: &mut Option<&mut ReadCallback<'_, 'a>>,
+) -> Vec<PhysicalReadData<'a>> {
+    let mut remaining = vec![];
+    for CTup3(addr, meta_addr, data) in ops {
+        let start = addr.address();
+        let end = start + data.len();
+
+        // offsets of the op which lie past the end of the file, sorted as the mappings are
+        let mut holes = mem_map
+            .iter()
+            .filter_map(|mapping| {
+                let (host, size) = *mapping.output();
+                let base = mapping.base();
+                let lo = start.max(base).max(base + (eof_start - host));
+                let hi = end.min(base + size).min(base + (eof_end - host));
+                (host < eof_end && host + size > eof_start && lo < hi)
+                    .then(|| ((lo - start) as usize, (hi - start) as usize))
+            })
+            .peekable();
+        if holes.peek().is_none() {
+            remaining.push(CTup3(addr, meta_addr, data));
+            continue;
+        }
+
+        let mut rest: &'a mut [u8] = data.into();
+        let mut offset = 0;
+        for (lo, hi) in holes {
+            let (backed, tail) = rest.split_at_mut(lo - offset);
+            let (hole, tail) = tail.split_at_mut(hi - lo);
+            if !backed.is_empty() {
+                remaining.push(CTup3(
+                    (start + offset).into(),
+                    meta_addr + offset,
+                    backed.into(),
+                ));
+            }
+            hole.fill(0);
+            opt_call(out.as_deref_mut(), CTup2(meta_addr + lo, hole.into()));
+            rest = tail;
+            offset = hi;
+        }
+        if !rest.is_empty() {
+            remaining.push(CTup3(
+                (start + offset).into(),
+                meta_addr + offset,
+                rest.into(),
+            ));
+        }
+    }
+    remaining
+}
+
+fn host_endianess() -> Endianess {
+    if cfg!(target_endian = "big") {
+        Endianess::BigEndian
+    } else {
+        Endianess::LittleEndian
+    }
+}
+
+/// Reads a `#[repr(C)]` structure from guest physical memory and checks its size at compile time.
+///
+/// This is a thin wrapper around [`QemuProcfs::read_struct`] which fails to compile
+/// when the size of the structure does not match the expected size of the guest structure.
+///
+/// # Examples
+///
+/// ```no_run
+/// use memflow::prelude::v1::*;
+/// use memflow_qemu::{read_guest_struct, QemuProcfs};
+///
+/// #[repr(C)]
+/// #[derive(Clone, Copy, Pod, ByteSwap)]
+/// struct ListEntry {
+///     flink: u64,
+///     blink: u64,
+/// }
+///
+/// # fn read(connector: &mut QemuProcfs<impl MemoryView>) -> Result<()> {
+/// let entry = read_guest_struct!(connector, Address::from(0x1000), ListEntry, 16)?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! read_guest_struct {
+    ($connector:expr, $addr:expr, $ty:ty, $size:expr) => {{
+        const _: () = assert!(
+            ::core::mem::size_of::<$ty>() == $size,
+            concat!("unexpected size of ", stringify!($ty))
+        );
+        $connector.read_struct::<$ty>($addr)
+    }};
+}
+
+impl<P: MemoryView> QemuProcfs<P> {
+    fn read_phys_iter(
+        &mut self,
+        MemOps {
+            inp,
+            mut out,
+            out_fail,
+        }: PhysicalReadMemOps,
+    ) -> Result<()> {
+        let Some(past_file_end) = self.past_file_end else {
+            return self.read_phys_backed(MemOps { inp, out, out_fail });
+        };
+
+        let ops = zero_fill_past_file_end(&self.mem_map, past_file_end, inp, &mut out);
+        MemOps::with_raw(ops.into_iter(), out, out_fail, |ops| {
+            self.read_phys_backed(ops)
+        })
+    }
+
+    /// Reads ops which are backed by the guest memory of the qemu process.
+    fn read_phys_backed(
+        &mut self,
+        MemOps {
+            inp,
+            mut out,
+            mut out_fail,
+        }: PhysicalReadMemOps,
+    ) -> Result<()> {
+        if let Some(reader) = self.timeout_reader.clone() {
+            let ops = inp.collect::<Vec<_>>();
+            let results = reader.read(
+                ops.iter()
+                    .map(|CTup3(addr, _, data)| (addr.address(), data.len()))
+                    .collect(),
+            )?;
+            for (CTup3(_, meta_addr, mut data), result) in ops.into_iter().zip(results) {
+                match result {
+                    Some(buf) => {
+                        data.copy_from_slice(&buf);
+                        opt_call(out.as_deref_mut(), CTup2(meta_addr, data));
+                    }
+                    None => {
+                        opt_call(out_fail.as_deref_mut(), CTup2(meta_addr, data));
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(cache) = self.cache.clone() {
+            for CTup3(addr, meta_addr, mut data) in inp {
+                let addr = addr.address();
+                if self.read_cached(&cache, addr, &mut data).is_ok() {
+                    opt_call(out.as_deref_mut(), CTup2(meta_addr, data));
+                } else {
+                    // the page touched unmapped memory, retry with the exact range
+                    MemOps::with_raw(
+                        std::iter::once(CTup3(addr, meta_addr, data)),
+                        out.as_deref_mut(),
+                        out_fail.as_deref_mut(),
+                        |data| self.view.read_raw_iter(data),
+                    )?;
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(alignment) = self.read_alignment {
+            for CTup3(addr, meta_addr, mut data) in inp {
+                let addr = addr.address();
+                let start = addr.to_umem() & !(alignment as umem - 1);
+                let offset = (addr.to_umem() - start) as usize;
+                let size = data.len();
+                let len = (offset + size).next_multiple_of(alignment);
+
+                let mut buf = std::mem::take(&mut self.read_buf);
+                buf.clear();
+                buf.resize(len, 0);
+
+                let res = self.view.read_raw_into(start.into(), &mut buf);
+                if res.is_ok() {
+                    data.copy_from_slice(&buf[offset..offset + size]);
+                }
+
+                self.read_buf = buf;
+                self.scrub_read_buf();
+
+                if res.is_ok() {
+                    opt_call(out.as_deref_mut(), CTup2(meta_addr, data));
+                } else {
+                    // the aligned read touched unmapped memory, retry with the exact range
+                    MemOps::with_raw(
+                        std::iter::once(CTup3(addr, meta_addr, data)),
+                        out.as_deref_mut(),
+                        out_fail.as_deref_mut(),
+                        |data| self.view.read_raw_iter(data),
+                    )?;
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(proc_mem) = self.proc_mem.clone() {
+            let ram_mmap = self.ram_mmap.clone();
+            let mut ranges = self
+                .mem_map
+                .map_base_iter(
+                    inp.map(|CTup3(addr, meta_addr, data)| CTup3(addr.address(), meta_addr, data)),
+                    out_fail.as_deref_mut(),
+                )
+                .map(|CTup3((host, _), meta_addr, data)| (host.to_umem(), meta_addr, data))
+                .collect::<Vec<_>>();
+
+            let mut read = ranges
+                .iter_mut()
+                .map(|(host, _, data)| ram_mmap.as_ref().is_some_and(|ram| ram.read(*host, data)))
+                .collect::<Vec<_>>();
+
+            let (pending, mut bufs): (Vec<_>, Vec<_>) = ranges
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, _)| !read[*i])
+                .map(|(i, (host, _, data))| (i, (*host, &mut **data)))
+                .unzip();
+            for (i, pread) in pending.into_iter().zip(proc_mem.read_ranges(&mut bufs)) {
+                read[i] = pread;
+            }
+
+            for ((_, meta_addr, data), read) in ranges.into_iter().zip(read) {
+                if read {
+                    opt_call(out.as_deref_mut(), CTup2(meta_addr, data));
+                } else {
+                    opt_call(out_fail.as_deref_mut(), CTup2(meta_addr, data));
+                }
+            }
+            return Ok(());
+        }
+
+        let inp = inp.map(|CTup3(addr, meta_addr, data)| CTup3(addr.into(), meta_addr, data));
+        MemOps::with_raw(inp, out, out_fail, |data| self.view.read_raw_iter(data))
+    }
+
+    /// Reads the ops and returns the ones which failed with their tag.
+    ///
+    /// Each op carries a tag as metadata. Ops crossing a gap in the memory map are split,
+    /// the pieces carry the tag of their op plus their offset into it. `meta` resolves
+    /// a tag to the metadata passed to `out`.
+    fn read_phys_tagged<'a>(
+        &mut self,
+        ops: impl Iterator<Item = (umem, PhysicalAddress, CSliceMut<'a, u8>)>,
+        out: &mut Option<&mut ReadCallback<'_, 'a>>,
+        meta: impl Fn(umem) -> Address,
+    ) -> Result<Vec<(umem, CSliceMut<'a, u8>)>> {
+        let mut failed = vec![];
+        let mut on_ok = |CTup2(tag, data): CTup2<Address, CSliceMut<'a, u8>>| {
+            opt_call(out.as_deref_mut(), CTup2(meta(tag.to_umem()), data))
+        };
+        let mut on_fail = |CTup2(tag, data): CTup2<Address, CSliceMut<'a, u8>>| {
+            failed.push((tag.to_umem(), data));
+            true
+        };
+        let mut on_ok = (&mut on_ok).into();
+        let mut on_fail = (&mut on_fail).into();
+
+        MemOps::with_raw(
+            ops.map(|(tag, addr, data)| CTup3(addr, Address::from(tag), data)),
+            Some(&mut on_ok),
+            Some(&mut on_fail),
+            |ops| self.read_phys_iter(ops),
+        )?;
+        Ok(failed)
+    }
+}
+
+impl<P: MemoryView> QemuProcfs<P> {
+    /// Reads guest memory, reinitializing the connector once on failed reads if enabled.
+    fn read_phys_recovering(
+        &mut self,
+        MemOps {
+            inp,
+            mut out,
+            mut out_fail,
+        }: PhysicalReadMemOps,
+    ) -> Result<()> {
+        let Some(recover) = self.recover else {
+            return self.read_phys_iter(MemOps { inp, out, out_fail });
+        };
+
+        // the tags of two ops are at least one byte further apart than the size of the first,
+        // so every piece of a split op can be traced back to its op and offset
+        let (mut addrs, mut metas, mut tags) = (vec![], vec![], vec![]);
+        let mut next_tag = 0;
+        let ops = inp
+            .map(|CTup3(addr, meta_addr, data)| {
+                let tag = next_tag;
+                next_tag += data.len() as umem + 1;
+                addrs.push(addr.address());
+                metas.push(meta_addr);
+                tags.push(tag);
+                (tag, addr, data)
+            })
+            .collect::<Vec<_>>();
+
+        let locate = |tag: umem| {
+            let i = tags.partition_point(|t| *t <= tag) - 1;
+            (i, tag - tags[i])
+        };
+        let meta = |tag| {
+            let (i, offset) = locate(tag);
+            metas[i] + offset
+        };
+        let addr = |tag| {
+            let (i, offset) = locate(tag);
+            addrs[i] + offset
+        };
+
+        let mut failed = self.read_phys_tagged(ops.into_iter(), &mut out, meta)?;
 
-    Ok(out)
+        // unmapped addresses are expected to fail, only a moved guest memory is recovered from
+        if failed
+            .iter()
+            .any(|(tag, _)| self.describe_address(addr(*tag)).is_some())
+        {
+            match recover(self) {
+                Ok(()) => {
+                    let retry = failed
+                        .into_iter()
+                        .map(|(tag, data)| (tag, PhysicalAddress::from(addr(tag)), data));
+                    failed = self.read_phys_tagged(retry, &mut out, meta)?;
+                }
+                Err(err) => warn!("unable to recover from failed guest memory reads: {}", err),
+            }
+        }
+
+        for (tag, data) in failed {
+            opt_call(out_fail.as_deref_mut(), CTup2(meta(tag), data));
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the memory map if the guest was reset since the last access.
+    fn refresh_after_reset(&mut self) {
+        let Some(watch) = &mut self.reset_watch else {
+            return;
+        };
+        let resets = watch.resets.load(Ordering::SeqCst);
+        if resets == watch.seen {
+            return;
+        }
+        watch.seen = resets;
+
+        let refresh = watch.refresh;
+        if let Err(err) = refresh(self) {
+            warn!(
+                "unable to refresh the memory map after a guest reset: {}",
+                err
+            );
+        }
+    }
+}
+
+impl<P: MemoryView> PhysicalMemory for QemuProcfs<P> {
+    fn phys_read_raw_iter(
+        &mut self,
+        MemOps {
+            inp,
+            mut out,
+            out_fail,
+        }: PhysicalReadMemOps,
+    ) -> Result<()> {
+        self.refresh_after_reset();
+
+        let Some(width) = self.swap_width else {
+            return self.read_phys_recovering(MemOps { inp, out, out_fail });
+        };
+
+        let mut out = Some(move |data| opt_call(out.as_deref_mut(), swapped(data, width)));
+        let mut out = out.as_mut().map(<_>::into);
+        let mut out_fail = out_fail.map(|o| move |data| o.call(data));
+        let mut out_fail = out_fail.as_mut().map(<_>::into);
+        MemOps::with_raw(inp, out.as_mut(), out_fail.as_mut(), |ops| {
+            self.read_phys_recovering(ops)
+        })
+    }
+
+    fn phys_write_raw_iter(
+        &mut self,
+        MemOps { inp, out, out_fail }: PhysicalWriteMemOps,
+    ) -> Result<()> {
+        if self.readonly {
+            return Err(Error(ErrorOrigin::Connector, ErrorKind::ReadOnly));
+        }
+        self.refresh_after_reset();
+
+        let cache = self.cache.clone();
+        let dax_ram = self.ram_mmap.clone().filter(|ram| ram.is_dax());
+        let mut written = vec![];
+        let inp = inp.map(|CTup3(addr, meta_addr, data)| {
+            if let Some(cache) = &cache {
+                cache
+                    .lock()
+                    .unwrap()
+                    .invalidate(addr.address().to_umem(), data.len() as umem);
+            }
+            if dax_ram.is_some() {
+                written.push((addr.address(), data.len() as umem));
+            }
+            CTup3(addr.into(), meta_addr, data)
+        });
+        MemOps::with_raw(inp, out, out_fail, |data| self.view.write_raw_iter(data))?;
+
+        if let Some(ram) = dax_ram {
+            self.flush_dax_writes(&ram, &written);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> PhysicalMemoryMetadata {
+        let md = self.view.metadata();
+
+        PhysicalMemoryMetadata {
+            max_address: md.max_address,
+            real_size: md.real_size,
+            readonly: self.readonly || md.readonly,
+            ideal_batch_size: self.ideal_batch_size,
+        }
+    }
+}
+
+impl<P: MemoryView + 'static> ConnectorCpuState for QemuProcfs<P> {
+    type CpuStateType<'a> = Fwd<&'a mut QemuProcfs<P>>;
+    type IntoCpuStateType = QemuProcfs<P>;
+
+    fn cpu_state(&mut self) -> Result<Self::CpuStateType<'_>> {
+        if !self.cpu_state_supported() {
+            return Err(cpu_state_unsupported());
+        }
+        Ok(self.forward_mut())
+    }
+
+    fn into_cpu_state(self) -> Result<Self::IntoCpuStateType> {
+        if !self.cpu_state_supported() {
+            return Err(cpu_state_unsupported());
+        }
+        Ok(self)
+    }
+}
+
+fn cpu_state_unsupported() -> Error {
+    Error(ErrorOrigin::Connector, ErrorKind::NotImplemented)
+        .log_warn("pausing and resuming the guest requires a reachable qmp monitor")
+}
+
+impl<P: MemoryView> CpuState for QemuProcfs<P> {
+    fn pause(&mut self) {
+        if let Some(qmp) = &self.qmp {
+            if let Err(err) = qmp.pause() {
+                error!("unable to pause the guest: {}", err);
+            }
+        }
+    }
+
+    fn resume(&mut self) {
+        if let Some(qmp) = &self.qmp {
+            if let Err(err) = qmp.resume() {
+                error!("unable to resume the guest: {}", err);
+            }
+        }
+    }
+}
+
+fn validator() -> ArgsValidator {
+    ArgsValidator::new()
+        .arg(ArgDescriptor::new("map_base").description("override of VM memory base"))
+        .arg(ArgDescriptor::new("map_size").description("override of VM memory size"))
+        .arg(
+            ArgDescriptor::new("prefault")
+                .description("advise the host to swap in guest memory on startup (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("ram_region_names")
+                .description("additional comma separated memory region names treated as ram"),
+        )
+        .arg(
+            ArgDescriptor::new("dump_mtree")
+                .description("log the raw qmp memory tree at debug level (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("log_samples")
+                .description("log the first bytes of each memory mapping on startup (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("page_cache")
+                .description("number of 4kb pages cached and shared between connector clones"),
+        )
+        .arg(
+            ArgDescriptor::new("byte_swap")
+                .description("byte-swap elements of this width in physical reads (2, 4 or 8)"),
+        )
+        .arg(
+            ArgDescriptor::new("ideal_batch_size")
+                .description("batch size reported in the metadata (power of two multiple of 4kb)"),
+        )
+        .arg(
+            ArgDescriptor::new("readonly")
+                .description("reject all writes to guest memory (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("auto_recover")
+                .description("reinitialize once when reads of guest memory fail (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("qmp_events")
+                .description("rebuild the memory map after qmp reports a guest reset (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("no_mtree_cache").description(
+                "always query qmp instead of reusing recently resolved mappings (0 or 1)",
+            ),
+        )
+        .arg(
+            ArgDescriptor::new("zeroize_buffers")
+                .description("zero temporary read buffers after use (0 or 1)"),
+        )
+        .arg(ArgDescriptor::new("mapping_source").description(
+            "force the memory map source without falling back (qmp, fallback or file)",
+        ))
+        .arg(
+            ArgDescriptor::new("hybrid_map")
+                .description("fill gaps in the qmp memory map with the fallback mappings (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("backend")
+                .description("restrict the memory map to the memory backend with this id"),
+        )
+        .arg(
+            ArgDescriptor::new("map_file").description(
+                "json file with the memory map, replaces the qmp and fallback mappings",
+            ),
+        )
+        .arg(
+            ArgDescriptor::new("dump_map").description(
+                "writes the resolved memory map to this json file in the map_file format",
+            ),
+        )
+        .arg(ArgDescriptor::new("procfs_root").description(
+            "procfs to access qemu through when it runs in another pid namespace, e.g. /host/proc",
+        ))
+        .arg(ArgDescriptor::new("host_pid").description(
+            "access qemu through the pid namespace of this process, same as procfs_root=/proc/<host_pid>/root/proc",
+        ))
+        .arg(
+            ArgDescriptor::new("use_mmap")
+                .description("map shared file backed guest memory into the connector (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("soft_dirty")
+                .description("track dirty pages with the soft-dirty bits instead of qmp (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("read_timeout")
+                .description("timeout of a single physical read in milliseconds"),
+        )
+        .arg(
+            ArgDescriptor::new("kvm_only")
+                .description("prefer qemu processes which have /dev/kvm open (0 or 1)"),
+        )
+        .arg(
+            ArgDescriptor::new("scan_start")
+                .description("start of the guest physical range used for bulk operations"),
+        )
+        .arg(
+            ArgDescriptor::new("scan_end")
+                .description("end of the guest physical range used for bulk operations"),
+        )
+        .arg(ArgDescriptor::new("qmp").description(
+            "qmp socket address overriding the -qmp argument (unix:path, tcp:host:port or path)",
+        ))
+        .arg(
+            ArgDescriptor::new("qmp_retries")
+                .description("number of attempts to connect to the qmp socket (default: 5)"),
+        )
+        .arg(ArgDescriptor::new("qmp_backoff").description(
+            "initial delay between qmp connection attempts in milliseconds (default: 50)",
+        ))
+        .arg(ArgDescriptor::new("qmp_prefer").description(
+            "preferred qmp transport when multiple are available (unix, tcp or vsock)",
+        ))
+}
+
+fn arg_enabled(args: &Args, name: &str) -> bool {
+    matches!(args.get(name), Some("1") | Some("true"))
+}
+
+/// Returns the procfs selected by the `procfs_root` or `host_pid` argument.
+fn procfs_root_arg(args: &Args) -> Result<Option<PathBuf>> {
+    match (args.get("procfs_root"), args.get("host_pid")) {
+        (Some(_), Some(_)) => Err(Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+            .log_error("procfs_root and host_pid cannot be combined")),
+        (Some(root), None) => Ok(Some(PathBuf::from(root))),
+        (None, Some(pid)) => pid
+            .parse()
+            .map(|pid| Some(builder::host_pid_procfs_root(pid)))
+            .map_err(|_| {
+                Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                    .log_error("host_pid has to be a process id")
+            }),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Returns the backend for the procfs selected by the arguments.
+fn backend_from_args(args: &Args) -> Result<Box<dyn HostBackend>> {
+    Ok(match procfs_root_arg(args)? {
+        Some(root) => Box::new(ProcfsBackend::with_root(root)),
+        None => host_backend(),
+    })
+}
+
+/// Creates a new Qemu Procfs instance.
+#[connector(
+    name = "qemu",
+    help_fn = "help",
+    target_list_fn = "target_list",
+    accept_input = true,
+    return_wrapped = true
+)]
+fn create_plugin(
+    args: &ConnectorArgs,
+    os: Option<OsInstanceArcBox<'static>>,
+    lib: LibArc,
+) -> Result<ConnectorInstanceArcBox<'static>> {
+    let os = match os {
+        Some(os) => os,
+        None => backend_from_args(&args.extra_args)?.create_os()?,
+    };
+
+    let qemu = create_connector_with_os(args, os)?;
+    Ok(memflow::plugins::connector::create_instance(
+        qemu, lib, args, false,
+    ))
+}
+
+/// Creates the connector on top of the [`host_backend`] of the current host os.
+///
+/// With the `procfs_root` or `host_pid` argument the processes are read from that procfs instead.
+/// On hosts other than linux this fails with `UnsupportedOptionalFeature`,
+/// use [`create_connector_with_backend`] with a custom backend there.
+pub fn create_connector(
+    args: &ConnectorArgs,
+) -> Result<QemuProcfs<IntoProcessInstanceArcBox<'static>>> {
+    create_connector_with_backend(args, backend_from_args(&args.extra_args)?.as_ref())
+}
+
+/// Creates the connector on top of the os provided by `backend`.
+///
+/// The guest memory is only read through the local procfs if the backend provides one,
+/// see [`HostBackend::procfs_root`].
+pub fn create_connector_with_backend(
+    args: &ConnectorArgs,
+    backend: &dyn HostBackend,
+) -> Result<QemuProcfs<IntoProcessInstanceArcBox<'static>>> {
+    debug!(
+        "creating qemu connector with the {} backend",
+        backend.name()
+    );
+    let procfs_root = backend.procfs_root().map(Path::to_path_buf);
+    configure_connector(args, backend.create_os()?, |builder| match procfs_root {
+        Some(root) => builder.procfs_root(root),
+        None => builder.process_view_only(true),
+    })
+}
+
+/// Time spent in each step of attaching to a qemu process.
+///
+/// The steps do not overlap, steps which were skipped (e.g. the process scan when
+/// the connector was created for a pid) are zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DetectionTimings {
+    /// Enumerating the processes to find the qemu process.
+    pub process_scan: Duration,
+    /// Reading the command line and opening the qemu process.
+    pub cmdline_read: Duration,
+    /// Connecting to the qmp monitor sockets.
+    pub qmp_connect: Duration,
+    /// Locating the guest memory and building the memory map, excluding the qmp connections.
+    pub map_build: Duration,
+}
+
+impl DetectionTimings {
+    /// Returns the combined duration of all steps.
+    pub fn total(&self) -> Duration {
+        self.process_scan + self.cmdline_read + self.qmp_connect + self.map_build
+    }
+}
+
+/// Creates the connector like [`create_connector`] and returns how long attaching to qemu took.
+///
+/// This helps to find the cause of a slow connector creation on busy hosts.
+pub fn create_connector_timed(
+    args: &ConnectorArgs,
+) -> Result<(
+    QemuProcfs<IntoProcessInstanceArcBox<'static>>,
+    DetectionTimings,
+)> {
+    let qemu = create_connector(args)?;
+    let timings = qemu.detection_timings();
+    info!(
+        "qemu connector created in {:?}: {:?}",
+        timings.total(),
+        timings
+    );
+    Ok((qemu, timings))
+}
+
+/// A Win32 kernel with the default caches on top of the qemu connector.
+#[cfg(feature = "win32")]
+pub type QemuWin32Kernel = memflow_win32::prelude::v1::Win32Kernel<
+    CachedPhysicalMemory<
+        'static,
+        QemuProcfs<IntoProcessInstanceArcBox<'static>>,
+        DefaultCacheValidator,
+    >,
+    CachedVirtualTranslate<DirectTranslate, DefaultCacheValidator>,
+>;
+
+/// Creates the connector and builds a Win32 kernel with the default caches on top of it.
+///
+/// Requires the `win32` feature.
+///
+/// # Examples
+///
+/// ```no_run
+/// use memflow::prelude::v1::*;
+///
+/// let mut os = memflow_qemu::create_win32(&Default::default()).unwrap();
+/// for process in os.process_info_list().unwrap() {
+///     println!("{} {}", process.pid, process.name);
+/// }
+/// ```
+#[cfg(feature = "win32")]
+pub fn create_win32(args: &ConnectorArgs) -> Result<QemuWin32Kernel> {
+    let connector = create_connector(args)?;
+    memflow_win32::prelude::v1::Win32Kernel::builder(connector)
+        .build_default_caches()
+        .build()
+}
+
+/// Creates the connector on top of `os`.
+///
+/// The os has to read the processes of the local procfs, the guest memory is read directly
+/// through it. Use [`create_connector_with_backend`] for any other os.
+pub fn create_connector_with_os<O: Os>(
+    args: &ConnectorArgs,
+    os: O,
+) -> Result<QemuProcfs<O::IntoProcessType>> {
+    configure_connector(args, os, |builder| builder)
+}
+
+/// Creates the connector from the arguments, `configure` is applied to the builder last.
+fn configure_connector<O: Os>(
+    args: &ConnectorArgs,
+    os: O,
+    configure: impl FnOnce(QemuProcfsBuilder<O>) -> QemuProcfsBuilder<O>,
+) -> Result<QemuProcfs<O::IntoProcessType>> {
+    info!("{}", build_info());
+
+    let validator = validator();
+
+    let name = args.target.as_deref();
+
+    let args = &args.extra_args;
+
+    let qemu = match validator.validate(args) {
+        Ok(_) => {
+            let map_override = args
+                .get("map_base")
+                .and_then(|base| umem::from_str_radix(base, 16).ok())
+                .zip(
+                    args.get("map_size")
+                        .and_then(|size| umem::from_str_radix(size, 16).ok()),
+                )
+                .map(|(start, size)| CTup2(Address::from(start), size));
+
+            let mut builder = QemuProcfs::builder(os)
+                .map_override(map_override)
+                .prefault(arg_enabled(args, "prefault"))
+                .use_mmap(arg_enabled(args, "use_mmap"))
+                .soft_dirty(arg_enabled(args, "soft_dirty"))
+                .kvm_only(arg_enabled(args, "kvm_only"))
+                .readonly(arg_enabled(args, "readonly"))
+                .auto_recover(arg_enabled(args, "auto_recover"))
+                .qmp_events(arg_enabled(args, "qmp_events"))
+                .zeroize_buffers(arg_enabled(args, "zeroize_buffers"))
+                .log_samples(arg_enabled(args, "log_samples"))
+                .dump_mtree(arg_enabled(args, "dump_mtree"))
+                .mtree_cache(!arg_enabled(args, "no_mtree_cache"))
+                .hybrid_mappings(arg_enabled(args, "hybrid_map"));
+
+            if let Some(names) = args.get("ram_region_names") {
+                builder = builder.ram_region_names(
+                    names
+                        .split(',')
+                        .filter(|n| !n.is_empty())
+                        .map(str::to_owned)
+                        .collect(),
+                );
+            }
+
+            if let Some(pages) = args.get("page_cache") {
+                builder = builder.page_cache(pages.parse().map_err(|_| {
+                    Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                        .log_error("page_cache has to be a number of pages")
+                })?);
+            }
+
+            if let Some(width) = args.get("byte_swap") {
+                builder = builder.byte_swap(width.parse().map_err(|_| {
+                    Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                        .log_error("byte_swap has to be a width in bytes")
+                })?);
+            }
+
+            if let Some(size) = args.get("ideal_batch_size") {
+                builder = builder.ideal_batch_size(size.parse().map_err(|_| {
+                    Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                        .log_error("ideal_batch_size has to be a number of bytes")
+                })?);
+            }
+
+            let scan_start = args
+                .get("scan_start")
+                .map(|start| umem::from_str_radix(start, 16));
+            let scan_end = args
+                .get("scan_end")
+                .map(|end| umem::from_str_radix(end, 16));
+            if scan_start.is_some() || scan_end.is_some() {
+                match (
+                    scan_start.unwrap_or(Ok(0)),
+                    scan_end.unwrap_or(Ok(umem::MAX)),
+                ) {
+                    (Ok(start), Ok(end)) => builder = builder.scan_range(start.into(), end.into()),
+                    _ => {
+                        return Err(Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                            .log_error("scan_start and scan_end have to be hex addresses"))
+                    }
+                }
+            }
+
+            if let Some(timeout) = args.get("read_timeout") {
+                builder = builder.read_timeout(std::time::Duration::from_millis(
+                    timeout.parse().map_err(|_| {
+                        Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                            .log_error("read_timeout has to be a number of milliseconds")
+                    })?,
+                ));
+            }
+
+            if let Some(source) = args.get("mapping_source") {
+                builder = builder.mapping_source(source.parse()?);
+            }
+
+            if let Some(id) = args.get("backend") {
+                builder = builder.backend(id);
+            }
+
+            if let Some(path) = args.get("map_file") {
+                builder = builder.map_file(path);
+            }
+
+            if let Some(path) = args.get("dump_map") {
+                builder = builder.dump_map(path);
+            }
+
+            if let Some(root) = procfs_root_arg(args)? {
+                builder = builder.procfs_root(root);
+            }
+
+            if let Some(addr) = args.get("qmp") {
+                builder = builder.qmp(addr);
+            }
+
+            if args.get("qmp_retries").is_some() || args.get("qmp_backoff").is_some() {
+                let retry = QmpRetry::default();
+                let attempts = match args.get("qmp_retries") {
+                    Some(attempts) => attempts.parse().map_err(|_| {
+                        Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                            .log_error("qmp_retries has to be a number of attempts")
+                    })?,
+                    None => retry.attempts,
+                };
+                let backoff = match args.get("qmp_backoff") {
+                    Some(backoff) => {
+                        std::time::Duration::from_millis(backoff.parse().map_err(|_| {
+                            Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                                .log_error("qmp_backoff has to be a number of milliseconds")
+                        })?)
+                    }
+                    None => retry.backoff,
+                };
+                builder = builder.qmp_retry(attempts, backoff);
+            }
+
+            if let Some(prefer) = args.get("qmp_prefer") {
+                builder = builder.qmp_prefer(prefer.parse()?);
+            }
+
+            if let Some(name) = name.or_else(|| args.get("name")) {
+                builder = match parse_target_pid(name)? {
+                    Some(pid) => builder.pid(pid),
+                    // a bare number is only a pid if no guest is listed under that name
+                    None => match Pid::from_str_radix(name, 10) {
+                        Ok(pid) => builder.guest_name_or_pid(name, pid),
+                        Err(_) => builder.guest_name(name),
+                    },
+                };
+            }
+
+            configure(builder).build()
+        }
+        Err(err) => {
+            error!(
+                "unable to validate provided arguments, valid arguments are:\n{}",
+                validator
+            );
+            Err(err)
+        }
+    }?;
+
+    Ok(qemu)
+}
+
+/// Parses targets of the form `pid:<n>`.
+///
+/// Returns `None` if the target is a guest name, which includes plain decimal numbers
+/// as guests may be listed under a number, e.g. after their disk image `1234.qcow2`.
+fn parse_target_pid(target: &str) -> Result<Option<Pid>> {
+    target
+        .strip_prefix("pid:")
+        .map(|pid| {
+            Pid::from_str_radix(pid, 10).map_err(|_| {
+                Error(ErrorOrigin::Connector, ErrorKind::ArgValidation)
+                    .log_error("pid targets have to be of the form `pid:<n>`")
+            })
+        })
+        .transpose()
+}
+
+/// Returns the name of the qemu process in the target list.
+///
+/// Unnamed guests are listed under the name of their disk image, see [`qemu_guest_label`].
+/// Processes without any usable name are listed as `pid:<n>` so they can still be selected.
+fn target_name(pid: Pid, cmdline: &str) -> String {
+    qemu_guest_label(cmdline).unwrap_or_else(|| format!("pid:{}", pid))
+}
+
+/// Returns the names of the qemu processes in the target list.
+///
+/// Guests sharing the same name are disambiguated by appending their pid, e.g. `win10 (pid 14837)`.
+fn target_names(processes: &[(Pid, String)]) -> Vec<String> {
+    let names = processes
+        .iter()
+        .map(|(pid, cmdline)| target_name(*pid, cmdline))
+        .collect::<Vec<_>>();
+
+    names
+        .iter()
+        .zip(processes)
+        .map(|(name, (pid, _))| {
+            if names.iter().filter(|n| *n == name).count() > 1 {
+                format!("{} (pid {})", name, pid)
+            } else {
+                name.clone()
+            }
+        })
+        .collect()
+}
+
+/// Splits a `<name> (pid <n>)` target into the guest name and pid.
+fn split_pid_suffix(target: &str) -> (&str, Option<Pid>) {
+    target
+        .strip_suffix(')')
+        .and_then(|target| target.rsplit_once(" (pid "))
+        .and_then(|(name, pid)| Some((name, Some(Pid::from_str_radix(pid, 10).ok()?))))
+        .unwrap_or((target, None))
+}
+
+/// Version and compile-time features of this connector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "memflow-qemu {} (features: {})",
+            self.version,
+            self.features.join(", ")
+        )
+    }
+}
+
+/// Returns the crate version and the cargo features this connector was compiled with.
+pub fn build_info() -> BuildInfo {
+    let mut features = vec![];
+    if cfg!(feature = "qmp") {
+        features.push("qmp");
+    }
+    if cfg!(feature = "zeroize") {
+        features.push("zeroize");
+    }
+    if cfg!(feature = "serde") {
+        features.push("serde");
+    }
+    if cfg!(feature = "win32") {
+        features.push("win32");
+    }
+    if cfg!(feature = "vsock") {
+        features.push("vsock");
+    }
+
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        features,
+    }
+}
+
+/// Retrieve the help text for the Qemu Procfs Connector.
+pub fn help() -> String {
+    let validator = validator();
+    format!(
+        "\
+The `qemu` connector implements a memflow plugin interface
+for QEMU on top of the Process Filesystem on Linux.
+
+This connector requires access to the qemu process via the linux procfs.
+This means any process which loads this connector requires
+to have at least ptrace permissions set.
+Other host operating systems are not supported and fail with
+an `UnsupportedOptionalFeature` error.
+
+The `target` argument specifies the target qemu virtual machine.
+The qemu virtual machine name can be specified when starting qemu with the -name flag.
+Unnamed virtual machines are listed under the file name of their disk image.
+
+Alternatively, the qemu process can be selected by its PID with `pid:<n>`.
+A plain number is used as PID if no virtual machine is listed under that name.
+Virtual machines sharing the same name are listed and selected as `<name> (pid <n>)`.
+
+Available arguments are:
+{validator}"
+    )
+}
+
+/// Retrieve a list of all currently available Qemu targets.
+pub fn target_list() -> Result<Vec<TargetInfo>> {
+    list_targets(&ProcfsBackend::default(), false)
+}
+
+/// Retrieve a list of all Qemu targets running kvm accelerated guests.
+///
+/// Only processes which have `/dev/kvm` open are considered, tcg guests are not listed.
+pub fn kvm_target_list() -> Result<Vec<TargetInfo>> {
+    list_targets(&ProcfsBackend::default(), true)
+}
+
+/// Retrieve a list of the Qemu targets found in the procfs mounted at `procfs_root`.
+///
+/// This lists the qemu processes of another pid namespace, see the `procfs_root` argument.
+pub fn procfs_target_list(procfs_root: impl Into<PathBuf>) -> Result<Vec<TargetInfo>> {
+    list_targets(&ProcfsBackend::with_root(procfs_root), false)
+}
+
+/// Returns the command line of the qemu process including the options of its `-readconfig` files.
+fn process_cmdline(procfs_root: Option<&Path>, info: &ProcessInfo) -> String {
+    let proc_dir = procfs_root.map(|root| root.join(info.pid.to_string()));
+    qemu_expand_readconfig(&info.command_line, proc_dir.as_deref())
+}
+
+fn list_targets(backend: &ProcfsBackend, kvm_only: bool) -> Result<Vec<TargetInfo>> {
+    let mut os = backend.create_os()?;
+    let procfs_root = backend.root();
+
+    let mut processes = vec![];
+
+    let callback = &mut |info: ProcessInfo| {
+        if (!kvm_only || procfs::has_kvm_fd(procfs_root, info.pid)) && is_qemu(&info) {
+            processes.push((info.pid, process_cmdline(Some(procfs_root), &info)));
+        }
+
+        true
+    };
+
+    os.process_info_list_callback(callback.into())?;
+
+    Ok(target_names(&processes)
+        .into_iter()
+        .map(|name| TargetInfo {
+            name: ReprCString::from(name),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    /// A memory view over a plain buffer which records all reads issued to it.
+    #[derive(Clone)]
+    pub(crate) struct StubMemory {
+        pub mem: Arc<Mutex<Vec<u8>>>,
+        pub reads: Arc<Mutex<Vec<(Address, usize)>>>,
+    }
+
+    impl StubMemory {
+        pub fn new(mem: Vec<u8>) -> Self {
+            Self {
+                mem: Arc::new(Mutex::new(mem)),
+                reads: Default::default(),
+            }
+        }
+
+        /// Creates a buffer where every byte contains the lower 8 bits of its address.
+        pub fn with_pattern(size: usize) -> Self {
+            Self::new((0..size).map(|i| i as u8).collect())
+        }
+    }
+
+    impl MemoryView for StubMemory {
+        fn read_raw_iter(
+            &mut self,
+            MemOps {
+                inp,
+                mut out,
+                mut out_fail,
+            }: ReadRawMemOps,
+        ) -> Result<()> {
+            let mem = self.mem.lock().unwrap();
+            for CTup3(addr, meta_addr, mut data) in inp {
+                self.reads.lock().unwrap().push((addr, data.len()));
+
+                let start = addr.to_umem() as usize;
+                if let Some(src) = mem.get(start..start + data.len()) {
+                    data.copy_from_slice(src);
+                    opt_call(out.as_deref_mut(), CTup2(meta_addr, data));
+                } else {
+                    opt_call(out_fail.as_deref_mut(), CTup2(meta_addr, data));
+                }
+            }
+            Ok(())
+        }
+
+        fn write_raw_iter(
+            &mut self,
+            MemOps {
+                inp,
+                mut out,
+                mut out_fail,
+            }: WriteRawMemOps,
+        ) -> Result<()> {
+            let mut mem = self.mem.lock().unwrap();
+            for CTup3(addr, meta_addr, data) in inp {
+                let start = addr.to_umem() as usize;
+                if let Some(dst) = mem.get_mut(start..start + data.len()) {
+                    dst.copy_from_slice(&data);
+                    opt_call(out.as_deref_mut(), CTup2(meta_addr, data));
+                } else {
+                    opt_call(out_fail.as_deref_mut(), CTup2(meta_addr, data));
+                }
+            }
+            Ok(())
+        }
+
+        fn metadata(&self) -> MemoryViewMetadata {
+            let size = self.mem.lock().unwrap().len() as umem;
+            MemoryViewMetadata {
+                max_address: Address::from(size - 1),
+                real_size: size,
+                readonly: false,
+                little_endian: true,
+                arch_bits: 64,
+            }
+        }
+    }
+
+    /// Creates a connector on top of a stub memory using the fallback mappings for q35.
+    pub(crate) fn stub_connector(
+        stub: &StubMemory,
+        cmdline: &str,
+        options: BuildOptions,
+    ) -> QemuProcfs<StubMemory> {
+        let size = stub.mem.lock().unwrap().len() as umem;
+        QemuProcfs::with_cmdline_and_mem(stub.clone(), cmdline, CTup2(Address::NULL, size), options)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_read_alignment() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                read_alignment: Some(0x1000),
+                ..Default::default()
+            },
+        );
+
+        let mut buf = [0u8; 3];
+        connector
+            .phys_view()
+            .read_raw_into(Address::from(0x1ffe), &mut buf)
+            .unwrap();
+        assert_eq!(buf, [0xfe, 0xff, 0x00]);
+
+        assert_eq!(
+            stub.reads.lock().unwrap().as_slice(),
+            &[(Address::from(0x1000), 0x2000)]
+        );
+    }
+
+    #[test]
+    fn test_read_past_file_end() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+        // the backing file was truncated to 0x1800 bytes, reads past it must not touch the mapping
+        connector.past_file_end = Some((Address::from(0x1800), Address::from(0x4000)));
+
+        let mut buf = [0xffu8; 0x1000];
+        connector
+            .phys_view()
+            .read_raw_into(Address::from(0x1000), &mut buf)
+            .unwrap();
+        assert_eq!(buf[..0x800], stub.mem.lock().unwrap()[0x1000..0x1800]);
+        assert!(buf[0x800..].iter().all(|&b| b == 0));
+
+        assert_eq!(
+            stub.reads.lock().unwrap().as_slice(),
+            &[(Address::from(0x1000), 0x800)]
+        );
+    }
+
+    #[test]
+    fn test_read_across_gap() {
+        let stub = StubMemory::with_pattern(0x2000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        // a hole between two mappings, like the pci hole on q35
+        let mut mem_map = MemoryMap::new();
+        mem_map.push_range(0x0.into(), 0x1000.into(), 0x0.into());
+        mem_map.push_range(0x2000.into(), 0x3000.into(), 0x1000.into());
+        connector.view.set_mem_map(mem_map.clone());
+        connector.mem_map = mem_map;
+
+        for auto_recover in [false, true] {
+            connector.set_auto_recover(auto_recover);
+
+            let mut buf = vec![0xccu8; 0x2000];
+            let (mut ok, mut failed) = (vec![], vec![]);
+            let mut on_ok = |CTup2(meta, data): ReadData| {
+                ok.push((meta, data.len(), data[0]));
+                true
+            };
+            let mut on_fail = |CTup2(meta, data): ReadData| {
+                failed.push((meta, data.len(), data[0]));
+                true
+            };
+            let mut on_ok = (&mut on_ok).into();
+            let mut on_fail = (&mut on_fail).into();
+            MemOps::with_raw(
+                std::iter::once(CTup3(
+                    PhysicalAddress::from(0x800u64),
+                    Address::from(0x5000),
+                    CSliceMut::from(&mut buf[..]),
+                )),
+                Some(&mut on_ok),
+                Some(&mut on_fail),
+                |ops| connector.phys_read_raw_iter(ops),
+            )
+            .unwrap();
+
+            // the metadata of each piece is offset like its data, the hole is neither read nor zeroed
+            assert_eq!(
+                ok,
+                vec![
+                    (Address::from(0x5000), 0x800, 0x00),
+                    (Address::from(0x6800), 0x800, 0x00)
+                ]
+            );
+            assert_eq!(failed, vec![(Address::from(0x5800), 0x1000, 0xcc)]);
+        }
+    }
+
+    #[test]
+    fn test_ideal_batch_size() {
+        let stub = StubMemory::with_pattern(0x1000);
+        let connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+        assert_eq!(connector.metadata().ideal_batch_size, DEFAULT_BATCH_SIZE);
+
+        let connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                ideal_batch_size: Some(0x10000),
+                ..Default::default()
+            },
+        );
+        assert_eq!(connector.metadata().ideal_batch_size, 0x10000);
+    }
+
+    #[test]
+    fn test_byte_swap() {
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                swap_width: Some(4),
+                ..Default::default()
+            },
+        );
+
+        let mut view = connector.phys_view();
+        assert_eq!(
+            view.read::<u32>(Address::from(0x20)).unwrap(),
+            u32::from_be_bytes([0x20, 0x21, 0x22, 0x23])
+        );
+        assert_eq!(
+            view.read::<[u32; 2]>(Address::from(0x20)).unwrap(),
+            [
+                u32::from_be_bytes([0x20, 0x21, 0x22, 0x23]),
+                u32::from_be_bytes([0x24, 0x25, 0x26, 0x27])
+            ]
+        );
+        // reads which are not made of whole elements are not swapped
+        assert_eq!(
+            view.read::<[u8; 3]>(Address::from(0x20)).unwrap(),
+            [0x20, 0x21, 0x22]
+        );
+    }
+
+    #[test]
+    fn test_self_check() {
+        let stub = StubMemory::with_pattern(0x2000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+        let report = connector.self_check();
+        assert!(report.is_ok());
+        assert_eq!(report.findings, vec![]);
+
+        // the i440fx fallback does not fit a guest with 8kb of ram
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine pc", Default::default());
+        let report = connector.self_check();
+        assert_eq!(report.mapping_source, MappingSource::Fallback);
+        assert!(!report.is_ok());
+        let warning = report.warnings().next().unwrap();
+        assert!(warning.message.contains("past the end of the guest memory"));
+        assert!(warning
+            .suggestion
+            .as_deref()
+            .unwrap()
+            .contains("mapping_source=file"));
+        assert!(report.to_string().contains("Warning: "));
+    }
+
+    #[test]
+    fn test_cpu_state_unsupported() {
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        assert!(!connector.cpu_state_supported());
+        assert_eq!(
+            connector.cpu_state().err().map(|err| err.1),
+            Some(ErrorKind::NotImplemented)
+        );
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_cpu_state_supported() {
+        use crate::qmp::tests::{status, MockQmp};
+
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "query-status" => Ok(status(true)),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+
+        assert!(connector.cpu_state_supported());
+        assert!(connector.cpu_state().is_ok());
+    }
+
+    #[test]
+    fn test_cpu_count_smp() {
+        let stub = StubMemory::with_pattern(0x1000);
+        let connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35 -smp cpus=4,sockets=2",
+            Default::default(),
+        );
+        assert_eq!(connector.cpu_count().unwrap(), 4);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_cpu_count_qmp() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let cpu = |index: usize| {
+            json!({
+                "cpu-index": index,
+                "qom-path": format!("/machine/unattached/device[{}]", index),
+                "thread-id": 1000 + index,
+                "target": "x86_64",
+            })
+        };
+        let mock = MockQmp::spawn(move |cmd, _| match cmd {
+            "query-cpus-fast" => Ok(json!([cpu(0), cpu(1)])),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -smp 4 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let connector = stub_connector(&stub, &cmdline, Default::default());
+
+        // qmp takes precedence over the command line and is only queried once
+        assert_eq!(connector.cpu_count().unwrap(), 2);
+        assert_eq!(connector.cpu_count().unwrap(), 2);
+        let queries = mock
+            .commands()
+            .iter()
+            .filter(|cmd| *cmd == "query-cpus-fast")
+            .count();
+        assert_eq!(queries, 1);
+    }
+
+    #[test]
+    fn test_qemu_version_machine() {
+        let stub = StubMemory::with_pattern(0x1000);
+        let connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine pc-q35-10.0",
+            Default::default(),
+        );
+        assert_eq!(connector.qemu_version(), Some((10, 0)));
+
+        let connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+        assert_eq!(connector.qemu_version(), None);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_qemu_version_qmp() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "query-version" => Ok(json!({
+                "qemu": { "major": 9, "minor": 2, "micro": 3 },
+                "package": "Debian 1:9.2.3+ds-1"
+            })),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        // the binary is newer than the emulated machine version
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine pc-q35-8.2 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let connector = stub_connector(&stub, &cmdline, Default::default());
+        assert_eq!(connector.qemu_version(), Some((9, 2)));
+        assert_eq!(connector.qemu_version(), Some((9, 2)));
+        let queries = mock
+            .commands()
+            .iter()
+            .filter(|cmd| *cmd == "query-version")
+            .count();
+        assert_eq!(queries, 1);
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(&stub, "qemu-system-x86_64", Default::default());
+        // the dirty rate is measured by qmp, which does not report the pages
+        assert_eq!(
+            QemuError::try_from(connector.enable_dirty_tracking().unwrap_err()),
+            Ok(QemuError::QmpNotConfigured)
+        );
+        assert_eq!(
+            connector.poll_dirty_pages().unwrap_err().1,
+            ErrorKind::NotImplemented
+        );
+
+        // the stub is not backed by a qemu process to track soft-dirty pages in
+        let options = BuildOptions {
+            soft_dirty: true,
+            ..Default::default()
+        };
+        let mut connector = stub_connector(&stub, "qemu-system-x86_64", options);
+        assert_eq!(
+            connector.enable_dirty_tracking().unwrap_err().1,
+            ErrorKind::NotSupported
+        );
+        assert_eq!(
+            connector.poll_dirty_pages().unwrap_err().1,
+            ErrorKind::NotSupported
+        );
+
+        let mut mem_map = MemoryMap::new();
+        mem_map.push_range(0x0.into(), 0x3000.into(), 0x7f0000000000u64.into());
+        mem_map.push_range(
+            0x100000000u64.into(),
+            0x100002000u64.into(),
+            0x7f0000003000u64.into(),
+        );
+        let dirty = QemuProcfs::<StubMemory>::collect_dirty_pages(&mem_map, |host, size| {
+            // every other host page is dirty
+            Ok((0..size)
+                .step_by(0x1000)
+                .filter(|offset| (host + offset) / 0x1000 % 2 == 0)
+                .collect())
+        })
+        .unwrap();
+        assert_eq!(
+            dirty,
+            vec![
+                Address::from(0x0),
+                Address::from(0x2000),
+                Address::from(0x100001000u64)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_endianess() {
+        let stub = StubMemory::with_pattern(0x1000);
+        let connector = stub_connector(
+            &stub,
+            "/usr/bin/qemu-system-s390x -machine s390-ccw-virtio",
+            Default::default(),
+        );
+        assert_eq!(connector.endianess(), Endianess::BigEndian);
+
+        let connector = stub_connector(&stub, "qemu-kvm -machine q35", Default::default());
+        assert_eq!(connector.endianess(), Endianess::LittleEndian);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_endianess_qmp() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, args| {
+            match cmd {
+            "query-target" => Ok(json!({ "arch": "ppc64" })),
+            "human-monitor-command" if args["command-line"] == "info mtree -f" => Ok(json!(
+                "Root memory region: system\n 0000000000000000-0000000000000fff (prio 0, ram): ram0 KVM\n"
+            )),
+            _ => Err(format!("unknown command {}", cmd)),
+        }
+        });
+        // a wrapper script does not reveal the target
+        let cmdline = format!(
+            "/opt/vm/bin/qemu -machine pseries,memory-backend=ram0 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let connector = stub_connector(&stub, &cmdline, Default::default());
+        assert_eq!(connector.endianess(), Endianess::BigEndian);
+        assert_eq!(connector.endianess(), Endianess::BigEndian);
+        let queries = mock
+            .commands()
+            .iter()
+            .filter(|cmd| *cmd == "query-target")
+            .count();
+        assert_eq!(queries, 1);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_cpu_state_unreachable_qmp() {
+        // the socket on the command line does not exist (anymore)
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35 -qmp unix:/nonexistent/qmp.sock,server,nowait",
+            Default::default(),
+        );
+
+        assert!(connector.qmp.is_some());
+        assert!(!connector.cpu_state_supported());
+        assert_eq!(
+            connector.cpu_state().err().map(|err| err.1),
+            Some(ErrorKind::NotImplemented)
+        );
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_hmp_command() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, args| match cmd {
+            "human-monitor-command" if args["command-line"] == "info lapic" => {
+                Ok(json!("dumping local APIC state for CPU 0\n"))
+            }
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+        assert_eq!(
+            connector.hmp_command("info lapic").unwrap(),
+            "dumping local APIC state for CPU 0\n"
+        );
+
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+        assert_eq!(
+            connector.hmp_command("info lapic").unwrap_err().1,
+            ErrorKind::NotImplemented
+        );
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_verify_translation() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, args| {
+            match (cmd, args["command-line"].as_str().unwrap_or_default()) {
+                ("human-monitor-command", "gpa2hva 0x123") => Ok(json!(
+                    "Host virtual address for 0x123 (pc.ram) is 0x123\r\n"
+                )),
+                ("human-monitor-command", "gpa2hva 0x456") => Ok(json!("unknown command\r\n")),
+                _ => Err(format!("unknown command {}", cmd)),
+            }
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+        connector.verify_translation(Address::from(0x123)).unwrap();
+        assert!(connector.verify_translation(Address::from(0x456)).is_err());
+
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+        assert!(connector.verify_translation(Address::from(0x123)).is_err());
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_describe_address() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "human-monitor-command" => Ok(json!(
+                r#"
+            Root memory region: system
+             0000000000000000-0000000000000fff (prio 0, ram): pc.ram KVM
+             0000000000100000-0000000000100fff (prio 0, ram): pc.ram @0000000000001000 KVM
+             0000000100000000-0000000100000fff (prio 0, ram): pc.ram @0000000000002000 KVM
+            "#
+            )),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x3000);
+        let connector = stub_connector(&stub, &cmdline, Default::default());
+
+        let mapping = connector
+            .describe_address(Address::from(0x100000123u64))
+            .unwrap();
+        assert_eq!(
+            mapping,
+            AddressMapping {
+                gpa_start: Address::from(0x100000000u64),
+                size: 0x1000,
+                host_va: Address::from(0x2000),
+                host_address: Address::from(0x2123),
+            }
+        );
+        assert_eq!(mapping.remap_delta(), 0x2000 - 0x100000000);
+
+        assert_eq!(
+            connector
+                .describe_address(Address::from(0x100010))
+                .map(|m| m.host_address),
+            Some(Address::from(0x1010))
+        );
+        assert_eq!(connector.describe_address(Address::from(0x1000)), None);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_sample_mappings() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "human-monitor-command" => Ok(json!(
+                r#"
+            Root memory region: system
+             0000000000000000-0000000000000fff (prio 0, ram): pc.ram KVM
+             0000000000100000-0000000000100fff (prio 0, ram): pc.ram @0000000000001000 KVM
+             0000000100000000-0000000100000fff (prio 0, ram): pc.ram @0000000000002000 KVM
+            "#
+            )),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        // every backing page is filled with a distinct byte
+        let stub = StubMemory::new((0..0x3000).map(|i| 0xa0 + (i / 0x1000) as u8).collect());
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+
+        assert_eq!(
+            connector.sample_mappings(4),
+            vec![
+                (Address::NULL, vec![0xa0; 4]),
+                (Address::from(0x100000), vec![0xa1; 4]),
+                (Address::from(0x100000000u64), vec![0xa2; 4]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_guest_ram() {
+        // the largest mapping reads back as all 0xff like an unbacked mmio region
+        let mut mem = vec![0xffu8; 0x3000];
+        mem[0x2000..].fill(0);
+        mem[0x2010] = 0x42;
+        let mut stub = StubMemory::new(mem);
+
+        let mmio = CTup2(Address::NULL, 0x2000);
+        let ram = CTup2(Address::from(0x2000), 0x1000);
+        let unreadable = CTup2(Address::from(0x10000), 0x1800);
+        assert_eq!(
+            select_guest_ram(&mut stub, vec![ram, unreadable, mmio]),
+            Some(ram)
+        );
+
+        // the largest mapping is used if nothing looks like ram
+        assert_eq!(
+            select_guest_ram(&mut stub, vec![unreadable, mmio]),
+            Some(mmio)
+        );
+        assert_eq!(select_guest_ram(&mut stub, vec![]), None);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_qmp_addr_override() {
+        use crate::qmp::tests::{status, MockQmp};
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "query-status" => Ok(status(true)),
+            "stop" | "cont" => Ok(json!({})),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+
+        // the socket path on the command line is not reachable from the connector
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -qmp unix:/nonexistent/qmp.sock,server,nowait",
+            BuildOptions {
+                qmp: Some(mock.path().display().to_string()),
+                ..Default::default()
+            },
+        );
+
+        connector.with_paused(|_| ()).unwrap();
+        assert!(mock.commands().contains(&"stop".to_owned()));
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_snapshots() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, args| match (cmd, args["command-line"].as_str()) {
+            ("human-monitor-command", Some("savevm clean" | "loadvm clean")) => Ok(json!("")),
+            ("human-monitor-command", Some("loadvm missing")) => Ok(json!(
+                "Error: Snapshot 'missing' does not exist in one or more devices\r\n"
+            )),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let cmdline = format!(
+            "qemu-system-x86_64 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+        let commands = mock.commands().len();
+
+        connector.snapshot_save("clean").unwrap();
+        connector.snapshot_load("clean").unwrap();
+        assert!(connector.snapshot_load("missing").is_err());
+        assert_eq!(
+            connector.snapshot_save("two words").unwrap_err().1,
+            ErrorKind::InvalidArgument
+        );
+        // the invalid tag is rejected without running a command
+        assert_eq!(mock.commands().len(), commands + 3);
+
+        let mut connector = stub_connector(&stub, "qemu-system-x86_64", Default::default());
+        assert_eq!(
+            QemuError::try_from(connector.snapshot_save("clean").unwrap_err()),
+            Ok(QemuError::QmpNotConfigured)
+        );
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_detection_timings() {
+        use crate::qmp::tests::MockQmp;
+
+        let mock = MockQmp::spawn(|cmd, _| Err(format!("unknown command {}", cmd)));
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let start = Instant::now();
+        let connector = stub_connector(&stub, &cmdline, Default::default());
+        let elapsed = start.elapsed();
+
+        let timings = connector.detection_timings();
+        assert!(timings.qmp_connect > Duration::ZERO);
+        assert!(timings.map_build > Duration::ZERO);
+        // the connector was not created from a process
+        assert_eq!(timings.process_scan, Duration::ZERO);
+        assert_eq!(timings.cmdline_read, Duration::ZERO);
+        assert_eq!(timings.total(), timings.qmp_connect + timings.map_build);
+        assert!(timings.total() <= elapsed);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_qmp_events() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+        use std::sync::atomic::AtomicBool;
+
+        static REBOOTED: AtomicBool = AtomicBool::new(false);
+
+        let mock = MockQmp::spawn(|cmd, args| {
+            match (cmd, args["command-line"].as_str().unwrap_or_default()) {
+                ("human-monitor-command", "info mtree -f") => {
+                    // the guest got more memory hotplugged after the reboot
+                    let end = if REBOOTED.load(Ordering::SeqCst) {
+                        "1fff"
+                    } else {
+                        "0fff"
+                    };
+                    Ok(json!(format!(
+                        "Root memory region: system\n  0000000000000000-000000000000{} (prio 0, ram): pc.ram KVM\n",
+                        end
+                    )))
+                }
+                _ => Err(format!("unknown command {}", cmd)),
+            }
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x2000);
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+        connector.watch_qmp_events().unwrap();
+        assert_eq!(connector.metadata().real_size, 0x1000);
+
+        let mut buf = [0u8; 2];
+        assert!(connector
+            .phys_view()
+            .read_raw_into(Address::from(0x1800), &mut buf)
+            .is_err());
+
+        REBOOTED.store(true, Ordering::SeqCst);
+        mock.send_event("RESET");
+
+        // the event is received in the background
+        let start = Instant::now();
+        while connector
+            .phys_view()
+            .read_raw_into(Address::from(0x1800), &mut buf)
+            .is_err()
+        {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(buf, [0x00, 0x01]);
+        assert_eq!(connector.metadata().real_size, 0x2000);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_dump_firmware() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, args| {
+            match (cmd, args["command-line"].as_str().unwrap_or_default()) {
+                ("human-monitor-command", "info mtree -f") => Ok(json!(
+                    r#"
+            Root memory region: system
+             0000000000000000-0000000000000fff (prio 0, ram): pc.ram KVM
+             00000000ffffe000-00000000ffffefff (prio 0, romd): system.flash1 KVM
+             00000000fffff000-00000000ffffffff (prio 0, romd): system.flash0 KVM
+            "#
+                )),
+                ("human-monitor-command", "gpa2hva 0xffffe000") => Ok(json!(
+                    "Host virtual address for 0xffffe000 (system.flash1) is 0x1000"
+                )),
+                ("human-monitor-command", "gpa2hva 0xfffff000") => Ok(json!(
+                    "Host virtual address for 0xfffff000 (system.flash0) is 0x2000"
+                )),
+                _ => Err(format!("unknown command {}", cmd)),
+            }
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        // the flash devices are separate allocations in the qemu process
+        let host = std::env::temp_dir().join(format!("memflow-qemu-flash-{}", std::process::id()));
+        std::fs::write(
+            &host,
+            (0..0x3000)
+                .map(|i| 0xa0 + (i / 0x1000) as u8)
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+        connector.proc_mem = Some(ProcMem::open_path(&host).unwrap());
+
+        let firmware = connector.dump_firmware().unwrap();
+        assert_eq!(
+            firmware,
+            vec![
+                ("system.flash1".to_owned(), vec![0xa1; 0x1000]),
+                ("system.flash0".to_owned(), vec![0xa2; 0x1000]),
+            ]
+        );
+
+        std::fs::remove_file(&host).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_cpu_state_pause_resume() {
+        use crate::qmp::tests::{status, MockQmp};
+        use serde_json::json;
+
+        let running = Arc::new(Mutex::new(true));
+        let state = running.clone();
+        let mock = MockQmp::spawn(move |cmd, _| {
+            let mut running = state.lock().unwrap();
+            match cmd {
+                "query-status" => Ok(status(*running)),
+                "stop" => {
+                    *running = false;
+                    Ok(json!({}))
+                }
+                "cont" => {
+                    *running = true;
+                    Ok(json!({}))
+                }
+                _ => Err(format!("unknown command {}", cmd)),
+            }
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+
+        let mut cpu_state = connector.cpu_state().unwrap();
+        cpu_state.pause();
+        assert!(!*running.lock().unwrap());
+        cpu_state.resume();
+        assert!(*running.lock().unwrap());
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_with_paused() {
+        use crate::qmp::tests::{status, MockQmp};
+        use serde_json::json;
+
+        let running = Arc::new(Mutex::new(true));
+        let state = running.clone();
+        let mock = MockQmp::spawn(move |cmd, _| {
+            let mut running = state.lock().unwrap();
+            match cmd {
+                "query-status" => Ok(status(*running)),
+                "stop" => {
+                    *running = false;
+                    Ok(json!({}))
+                }
+                "cont" => {
+                    *running = true;
+                    Ok(json!({}))
+                }
+                _ => Err(format!("unknown command {}", cmd)),
+            }
+        });
+        let cmdline = format!(
+            "qemu-system-x86_64 -machine q35 -qmp unix:{},server,nowait",
+            mock.path().display()
+        );
+
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(&stub, &cmdline, Default::default());
+
+        let res = connector
+            .with_paused(|_| {
+                assert!(!*running.lock().unwrap());
+                Err::<(), _>(Error(ErrorOrigin::Connector, ErrorKind::NotSupported))
+            })
+            .unwrap();
+        assert!(res.is_err());
+        assert!(*running.lock().unwrap());
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            connector.with_paused(|_| panic!("closure panicked"))
+        }));
+        assert!(res.is_err());
+        assert!(*running.lock().unwrap());
+
+        // the mtree and target queries during construction come first
+        assert_eq!(
+            mock.commands()[2..],
+            [
+                "query-status",
+                "stop",
+                "cont",
+                "query-status",
+                "stop",
+                "cont"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_with_paused_unreachable_qmp() {
+        let stub = StubMemory::with_pattern(0x1000);
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35 -qmp unix:/nonexistent/qmp.sock,server,nowait",
+            Default::default(),
+        );
+
+        // the closure still runs, just without pausing the guest
+        assert_eq!(connector.with_paused(|_| 42).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_prefault() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        let mut advised = vec![];
+        connector
+            .prefault_with(|ranges| {
+                advised.extend_from_slice(ranges);
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(advised, vec![(Address::NULL, 0x4000)]);
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let stub = StubMemory::new(vec![0u8; 0x4000]);
+        {
+            let mut mem = stub.mem.lock().unwrap();
+            mem[0x800..0x804].copy_from_slice(b"MZ\x90\x00");
+            mem[0x2ffe..0x3002].copy_from_slice(b"MZ\x90\x00");
+        }
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                scan_range: Some((Address::from(0x2000), Address::from(0x8000))),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            connector.find_pattern(b"MZ\x90\x00"),
+            vec![Address::from(0x2ffe)]
+        );
+
+        let mut advised = vec![];
+        connector
+            .prefault_with(|ranges| {
+                advised.extend_from_slice(ranges);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(advised, vec![(Address::from(0x2000), 0x2000)]);
+
+        connector.set_scan_range(None);
+        assert_eq!(
+            connector.find_pattern(b"MZ\x90\x00"),
+            vec![Address::from(0x800), Address::from(0x2ffe)]
+        );
+    }
+
+    #[test]
+    fn test_scan_ram_prefetch() {
+        let stub = StubMemory::with_pattern(0x9000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        let mut scan = |prefetch: usize, limit: usize| {
+            let mut chunks = vec![];
+            connector.scan_ram(0x1800, prefetch, |addr, data| {
+                chunks.push((addr, data.to_vec()));
+                chunks.len() < limit
+            });
+            chunks
+        };
+
+        let chunks = scan(0, usize::MAX);
+        assert_eq!(chunks.len(), 6);
+        assert_eq!(chunks[1].0, Address::from(0x1800));
+        assert_eq!(chunks[1].1, stub.mem.lock().unwrap()[0x1800..0x3000]);
+
+        assert_eq!(scan(1, usize::MAX), chunks);
+        assert_eq!(scan(4, usize::MAX), chunks);
+        // stopping the scan early shuts down the prefetching reader
+        assert_eq!(scan(2, 2), chunks[..2]);
+    }
+
+    #[test]
+    fn test_translate_gva() {
+        let stub = StubMemory::new(vec![0u8; 0x10000]);
+        {
+            let mut mem = stub.mem.lock().unwrap();
+            let mut entry = |table: usize, index: usize, value: u64| {
+                let pos = table + index * 8;
+                mem[pos..pos + 8].copy_from_slice(&value.to_le_bytes());
+            };
+
+            entry(0x1000, 0, 0x2000 | 0x3);
+            entry(0x2000, 0, 0x3000 | 0x3);
+            entry(0x2000, 1, 0xc000_0000 | 0x83);
+            entry(0x3000, 0, 0x4000 | 0x3);
+            entry(0x3000, 1, 0x60_0000 | 0x83);
+            entry(0x4000, 5, 0x8000 | 0x3);
+            entry(0x4000, 6, 0x9000);
+        }
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        let cr3 = Address::from(0x1000);
+        let translate = |connector: &mut QemuProcfs<_>, gva: umem| {
+            connector
+                .translate_gva(cr3, Address::from(gva))
+                .unwrap()
+                .map(|addr| addr.to_umem())
+        };
+
+        assert_eq!(translate(&mut connector, 0x5123), Some(0x8123));
+        assert_eq!(translate(&mut connector, 0x2a_bcde), Some(0x6a_bcde));
+        assert_eq!(translate(&mut connector, 0x4000_1234), Some(0xc000_1234));
+        assert_eq!(translate(&mut connector, 0x6000), None);
+        assert_eq!(translate(&mut connector, 0xffff_8000_0000_0000), None);
+    }
+
+    #[test]
+    fn test_read_records() {
+        #[repr(C)]
+        #[derive(Clone, Copy, Pod, ByteSwap)]
+        struct Record {
+            id: u32,
+            value: u32,
+        }
+
+        let stub = StubMemory::new(vec![0u8; 0x10000]);
+        {
+            let mut mem = stub.mem.lock().unwrap();
+            for i in 0..100usize {
+                let pos = 0x1000 + i * 12;
+                mem[pos..pos + 4].copy_from_slice(&(i as u32).to_le_bytes());
+                mem[pos + 4..pos + 8].copy_from_slice(&(i as u32 * 3).to_le_bytes());
+            }
+        }
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        let mut records = vec![];
+        connector
+            .read_records::<Record, _>(Address::from(0x1000), 100, 12, |index, record| {
+                records.push((index, record.id, record.value))
+            })
+            .unwrap();
+
+        assert_eq!(records.len(), 100);
+        for (i, &(index, id, value)) in records.iter().enumerate() {
+            assert_eq!((index, id as usize, value as usize), (i, i, i * 3));
+        }
+
+        assert!(connector
+            .read_records::<Record, _>(Address::from(0x1000), 1, 4, |_, _| ())
+            .is_err());
+
+        #[repr(C)]
+        #[derive(Clone, Copy, Pod, ByteSwap)]
+        struct Empty {}
+
+        // zero-sized records are delivered without dividing by their size
+        let mut indices = vec![];
+        connector
+            .read_records::<Empty, _>(Address::from(0x1000), 3, 0, |index, _| indices.push(index))
+            .unwrap();
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+
+    /// A memory view which takes `delay` to complete every read.
+    #[derive(Clone)]
+    struct SlowMemory(StubMemory, std::time::Duration);
+
+    impl MemoryView for SlowMemory {
+        fn read_raw_iter(&mut self, data: ReadRawMemOps) -> Result<()> {
+            std::thread::sleep(self.1);
+            self.0.read_raw_iter(data)
+        }
+
+        fn write_raw_iter(&mut self, data: WriteRawMemOps) -> Result<()> {
+            self.0.write_raw_iter(data)
+        }
+
+        fn metadata(&self) -> MemoryViewMetadata {
+            self.0.metadata()
+        }
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        use std::time::Duration;
+
+        let connector = |delay| {
+            let stub = StubMemory::with_pattern(0x2000);
+            let mut connector = QemuProcfs::with_cmdline_and_mem(
+                SlowMemory(stub, delay),
+                "qemu-system-x86_64 -machine q35",
+                CTup2(Address::NULL, 0x2000),
+                Default::default(),
+            )
+            .unwrap();
+            connector.set_read_timeout(Some(Duration::from_millis(50)));
+            connector
+        };
+
+        let mut buf = [0u8; 4];
+        connector(Duration::ZERO)
+            .phys_view()
+            .read_raw_into(Address::from(0x1002), &mut buf)
+            .unwrap();
+        assert_eq!(buf, [0x02, 0x03, 0x04, 0x05]);
+
+        let err = connector(Duration::from_millis(500))
+            .phys_read_into(Address::from(0x1002).into(), &mut buf)
+            .unwrap_err();
+        assert_eq!(err.1, ErrorKind::UnableToReadMemory);
+    }
+
+    #[test]
+    fn test_target_pid() {
+        assert_eq!(parse_target_pid("pid:1234").unwrap(), Some(1234));
+        assert_eq!(parse_target_pid("1234").unwrap(), None);
+        assert_eq!(parse_target_pid("win10").unwrap(), None);
+        assert_eq!(
+            parse_target_pid("pid:win10").unwrap_err().1,
+            ErrorKind::ArgValidation
+        );
+
+        assert_eq!(
+            target_name(
+                1234,
+                "qemu-system-x86_64 -name guest=win10,debug-threads=on"
+            ),
+            "win10"
+        );
+        assert_eq!(
+            target_name(
+                1234,
+                "qemu-system-x86_64 -drive file=/vms/win10.qcow2,if=virtio"
+            ),
+            "win10"
+        );
+        assert_eq!(target_name(1234, "qemu-system-x86_64 -m 4G"), "pid:1234");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_numeric_guest_name() {
+        use std::fs;
+
+        let root =
+            std::env::temp_dir().join(format!("memflow-qemu-targets-{}", std::process::id()));
+        for (pid, cmdline) in [
+            (100, "qemu-system-x86_64\0-drive\0file=/vms/1234.qcow2\0"),
+            (1234, "qemu-system-x86_64\0-name\0other\0"),
+        ] {
+            let proc_dir = root.join(pid.to_string());
+            fs::create_dir_all(&proc_dir).unwrap();
+            fs::write(proc_dir.join("cmdline"), cmdline).unwrap();
+        }
+        let mut os = procfs_os::ProcfsOs::new(&root).unwrap();
+        let find = |os: &mut procfs_os::ProcfsOs, name| {
+            find_guest_process(os, None, false, name, Pid::from_str_radix(name, 10).ok())
+                .unwrap()
+                .map(|info| info.pid)
+        };
+
+        // the guest listed as `1234` wins over the unrelated process with pid 1234
+        assert_eq!(find(&mut os, "1234"), Some(100));
+        // other numbers are still looked up as pid
+        assert_eq!(find(&mut os, "100"), Some(100));
+        assert_eq!(find(&mut os, "other"), Some(1234));
+        assert_eq!(find(&mut os, "win10"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_target_names() {
+        let processes = [
+            (100, "qemu-system-x86_64 -name win10".to_owned()),
+            (200, "qemu-system-x86_64 -name linux".to_owned()),
+            (300, "qemu-system-x86_64 -name guest=win10".to_owned()),
+        ];
+        let names = target_names(&processes);
+        assert_eq!(names, vec!["win10 (pid 100)", "linux", "win10 (pid 300)"]);
+
+        assert_eq!(split_pid_suffix(&names[0]), ("win10", Some(100)));
+        assert_eq!(split_pid_suffix("linux"), ("linux", None));
+        assert_eq!(split_pid_suffix("odd (name)"), ("odd (name)", None));
+    }
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.features.contains(&"qmp"), cfg!(feature = "qmp"));
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Pod, ByteSwap)]
+    struct TestHeader {
+        magic: u32,
+        version: u16,
+        flags: u16,
+        base: u64,
+    }
+
+    #[test]
+    fn test_read_struct() {
+        let mut mem = vec![0u8; 0x2000];
+        mem[0x1000..0x1004].copy_from_slice(&0xfeedfaceu32.to_le_bytes());
+        mem[0x1004..0x1006].copy_from_slice(&2u16.to_le_bytes());
+        mem[0x1006..0x1008].copy_from_slice(&0x8001u16.to_le_bytes());
+        mem[0x1008..0x1010].copy_from_slice(&0xfffff80000000000u64.to_le_bytes());
+        let stub = StubMemory::new(mem);
+
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+        let header = read_guest_struct!(connector, Address::from(0x1000), TestHeader, 16).unwrap();
+        assert_eq!(header.magic, 0xfeedface);
+        assert_eq!(header.version, 2);
+        assert_eq!(header.flags, 0x8001);
+        assert_eq!(header.base, 0xfffff80000000000);
+
+        // big endian guests are converted to the host byte order
+        let mut connector =
+            stub_connector(&stub, "qemu-system-s390x -machine q35", Default::default());
+        let header = connector
+            .read_struct::<TestHeader>(Address::from(0x1000))
+            .unwrap();
+        assert_eq!(header.magic, 0xcefaedfe);
+        assert_eq!(header.version, 0x0200);
+    }
+
+    #[test]
+    #[cfg(feature = "zeroize")]
+    fn test_zeroize_buffers() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let options = || BuildOptions {
+            read_alignment: Some(0x1000),
+            ..Default::default()
+        };
+
+        let mut buf = [0u8; 0x10];
+
+        let mut connector = stub_connector(&stub, "qemu-system-x86_64 -machine q35", options());
+        connector
+            .phys_view()
+            .read_raw_into(0x1010.into(), &mut buf)
+            .unwrap();
+        assert!(connector.read_buf.iter().any(|&b| b != 0));
+
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                zeroize_buffers: true,
+                ..options()
+            },
+        );
+        connector
+            .phys_view()
+            .read_raw_into(0x1010.into(), &mut buf)
+            .unwrap();
+        assert_eq!(buf[0], 0x10);
+        assert_eq!(connector.read_buf.len(), 0x1000);
+        assert!(connector.read_buf.iter().all(|&b| b == 0));
+
+        // all other temporary buffers are zeroed before they are freed,
+        // no other test zeroes buffers so only this test counts
+        let scrubbed = || scrub::SCRUBBED_BYTES.load(std::sync::atomic::Ordering::SeqCst);
+
+        let before = scrubbed();
+        assert_eq!(connector.find_pattern(&[0x20, 0x21]).len(), 0x40);
+        assert!(scrubbed() >= before + 0x4000);
+
+        let before = scrubbed();
+        connector
+            .read_records::<u32, _>(0x0.into(), 4, 0x10, |_, _| ())
+            .unwrap();
+        assert!(scrubbed() >= before + 0x34);
+
+        for prefetch in [0, 2] {
+            let before = scrubbed();
+            connector.scan_ram(0x1000, prefetch, |_, _| true);
+            assert!(scrubbed() >= before + 0x1000);
+        }
+
+        let before = scrubbed();
+        connector.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+        connector
+            .phys_view()
+            .read_raw_into(0x1010.into(), &mut buf)
+            .unwrap();
+        assert!(scrubbed() >= before + 0x10);
+        connector.set_read_timeout(None);
+
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                zeroize_buffers: true,
+                cache_pages: Some(1),
+                ..Default::default()
+            },
+        );
+        let before = scrubbed();
+        let mut page = [0u8; 0x1000];
+        for addr in [0x0, 0x1000] {
+            connector
+                .phys_view()
+                .read_raw_into(addr.into(), &mut page)
+                .unwrap();
+        }
+        // the first page was evicted by the second one
+        assert_eq!(scrubbed(), before + 0x1000);
+        drop(connector);
+        assert_eq!(scrubbed(), before + 0x2000);
+    }
+
+    fn mappings(mem_map: &MemoryMap<(Address, umem)>) -> Vec<(Address, Address, umem)> {
+        mem_map
+            .iter()
+            .map(|mapping| (mapping.base(), mapping.output().0, mapping.output().1))
+            .collect()
+    }
+
+    #[test]
+    fn test_compute_mem_map_q35() {
+        let base = Address::from(0x7f0000000000u64);
+        let mem_map = compute_mem_map(
+            "qemu-system-x86_64 -machine q35,accel=kvm",
+            base,
+            mem::gb(8),
+        )
+        .unwrap();
+        assert_eq!(
+            mappings(&mem_map),
+            vec![
+                (Address::NULL, base, mem::gb(2)),
+                (Address::from(mem::gb(4)), base + mem::gb(2), mem::gb(6)),
+            ]
+        );
+
+        let mem_map = compute_mem_map("qemu-system-x86_64 -machine q35", base, mem::gb(2)).unwrap();
+        assert_eq!(mappings(&mem_map), vec![(Address::NULL, base, mem::gb(2))]);
+    }
+
+    #[test]
+    fn test_compute_mem_map_aarch64() {
+        let base = Address::from(0x7f0000000000u64);
+        let mem_map =
+            compute_mem_map("qemu-system-aarch64 -machine virt", base, mem::gb(4)).unwrap();
+        assert_eq!(
+            mappings(&mem_map),
+            vec![(Address::from(mem::gb(1)), base, mem::gb(4))]
+        );
+    }
+
+    #[test]
+    fn test_shared_page_cache() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                cache_pages: Some(16),
+                ..Default::default()
+            },
+        );
+
+        let threads = (0..4)
+            .map(|_| {
+                let mut connector = connector.clone();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 0x20];
+                    connector
+                        .phys_view()
+                        .read_raw_into(0x1ff0.into(), &mut buf)
+                        .unwrap();
+                    buf
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            let buf = thread.join().unwrap();
+            assert_eq!(buf[0], 0xf0);
+            assert_eq!(buf[0x1f], 0x0f);
+        }
+
+        // the two touched pages are fetched at most once per racing reader and hit afterwards
+        let (hits, misses) = connector.cache_stats().unwrap();
+        assert_eq!(hits + misses, 8);
+        let mut reader = connector.clone();
+        let mut buf = [0u8; 0x20];
+        reader
+            .phys_view()
+            .read_raw_into(0x1ff0.into(), &mut buf)
+            .unwrap();
+        assert_eq!(reader.cache_stats().unwrap().0, hits + 2);
+
+        // a write through any clone invalidates the page for all others
+        stub.reads.lock().unwrap().clear();
+        let mut writer = connector.clone();
+        writer
+            .phys_view()
+            .write_raw(0x1ff8.into(), &[0xaa; 4])
+            .unwrap();
+        reader
+            .phys_view()
+            .read_raw_into(0x1ff0.into(), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[8..12], &[0xaa; 4]);
+        assert_eq!(
+            *stub.reads.lock().unwrap(),
+            vec![(Address::from(0x1000), 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_auto_recover() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static RELOCATIONS: AtomicUsize = AtomicUsize::new(0);
+        static BASE: AtomicUsize = AtomicUsize::new(0);
+
+        let stub = StubMemory::with_pattern(0x4000);
+        // the guest memory moved from 0x10000 to 0 after the connector was created
+        let mut connector = QemuProcfs::with_cmdline_and_mem(
+            stub.clone(),
+            "qemu-system-x86_64 -machine q35",
+            CTup2(Address::from(0x10000), 0x4000),
+            BuildOptions::default(),
+        )
+        .unwrap();
+        connector.relocate = Some(|_| {
+            RELOCATIONS.fetch_add(1, Ordering::SeqCst);
+            Some(CTup2(Address::from(BASE.load(Ordering::SeqCst)), 0x4000))
+        });
+
+        let mut buf = [0u8; 4];
+        assert!(connector
+            .phys_view()
+            .read_raw_into(Address::from(0x100), &mut buf)
+            .is_err());
+        assert_eq!(RELOCATIONS.load(Ordering::SeqCst), 0);
+
+        connector.set_auto_recover(true);
+        connector
+            .phys_view()
+            .read_raw_into(Address::from(0x100), &mut buf)
+            .unwrap();
+        assert_eq!(buf, [0x00, 0x01, 0x02, 0x03]);
+        assert_eq!(RELOCATIONS.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            connector
+                .describe_address(Address::from(0x100))
+                .unwrap()
+                .host_address,
+            Address::from(0x100)
+        );
+
+        // the memory moved again to a location which cannot be read, the read is only retried once
+        BASE.store(0x20000, Ordering::SeqCst);
+        connector.reinitialize().unwrap();
+        assert_eq!(RELOCATIONS.load(Ordering::SeqCst), 2);
+        assert!(connector
+            .phys_view()
+            .read_raw_into(Address::from(0x100), &mut buf)
+            .is_err());
+        assert_eq!(RELOCATIONS.load(Ordering::SeqCst), 3);
+
+        // unmapped guest memory does not trigger a reinitialization
+        assert!(connector
+            .phys_view()
+            .read_raw_into(Address::from(0x100000), &mut buf)
+            .is_err());
+        assert_eq!(RELOCATIONS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_as_slices() {
+        let path = std::env::temp_dir().join(format!("memflow-qemu-slices-{}", std::process::id()));
+        std::fs::write(&path, (0..0x4000).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+
+        let stub = StubMemory::with_pattern(0x4000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine pc", Default::default());
+        assert!(unsafe { connector.as_slices() }.is_empty());
+
+        connector.ram_mmap = Some(Arc::new(
+            MappedRam::from_file(&file, 0, 0, 0x4000, false).unwrap(),
+        ));
+        // nothing writes to the backing file during the test
+        let slices = unsafe { connector.as_slices() };
+        // the remaining pc mappings lie beyond the end of the backing file
+        assert_eq!(slices.len(), 1);
+        assert_eq!(slices[0].0, Address::NULL);
+        assert_eq!(slices[0].1.len(), 0x4000);
+        assert_eq!(slices[0].1[0x1234], 0x34);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_dax_write() {
+        let path = std::env::temp_dir().join(format!("memflow-qemu-dax-{}", std::process::id()));
+        std::fs::write(&path, vec![0u8; 0x4000]).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+
+        let stub = StubMemory::with_pattern(0x4000);
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine pc -object memory-backend-file,id=pc.ram,size=16K,mem-path=/mnt/pmem/guest.img,pmem=on",
+            Default::default(),
+        );
+        connector.ram_mmap = Some(Arc::new(
+            MappedRam::from_file(&file, 0, 0, 0x4000, true).unwrap(),
+        ));
+        assert!(connector.ram_mmap.as_ref().unwrap().is_dax());
+
+        // the written range is flushed to the backing file after the write
+        connector
+            .phys_view()
+            .write_raw(Address::from(0x1ffe), &[0xab; 4])
+            .unwrap();
+        assert_eq!(stub.mem.lock().unwrap()[0x1ffe..0x2002], [0xab; 4]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_readonly() {
+        let stub = StubMemory::with_pattern(0x2000);
+        let mut connector = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                readonly: true,
+                ..Default::default()
+            },
+        );
+
+        assert!(connector.metadata().readonly);
+
+        let err = connector
+            .phys_write(Address::from(0x100).into(), &[0xffu8; 4])
+            .unwrap_err();
+        assert_eq!(err.1, ErrorKind::ReadOnly);
+        assert_eq!(
+            stub.mem.lock().unwrap()[0x100..0x104],
+            [0x00, 0x01, 0x02, 0x03]
+        );
+
+        let mut buf = [0u8; 4];
+        connector
+            .phys_view()
+            .read_raw_into(Address::from(0x100), &mut buf)
+            .unwrap();
+        assert_eq!(buf, [0x00, 0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        assert_eq!(
+            mappings(connector.memory_map()),
+            vec![(Address::NULL, Address::NULL, 0x4000)]
+        );
+    }
+
+    #[test]
+    fn test_ram_regions() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        // two adjacent mappings followed by a hole
+        let mut mem_map = MemoryMap::new();
+        mem_map.push_range(0x0.into(), 0x1000.into(), 0x0.into());
+        mem_map.push_range(0x1000.into(), 0x2000.into(), 0x2000.into());
+        mem_map.push_range(0x8000.into(), 0x9000.into(), 0x1000.into());
+        connector.mem_map = mem_map;
+
+        assert_eq!(
+            connector.ram_regions(),
+            vec![(Address::NULL, 0x2000), (Address::from(0x8000), 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_to_host_offset() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        let mut mem_map = MemoryMap::new();
+        mem_map.push_range(0x0.into(), 0x1000.into(), 0x7f00_0000_0000u64.into());
+        mem_map.push_range(0x8000.into(), 0xa000.into(), 0x7f00_0000_1000u64.into());
+        connector.mem_map = mem_map;
+
+        assert_eq!(
+            connector.to_host_offset(Address::NULL),
+            Some((Address::from(0x7f00_0000_0000u64), 0x1000))
+        );
+        assert_eq!(
+            connector.to_host_offset(Address::from(0x8800)),
+            Some((Address::from(0x7f00_0000_1800u64), 0x1800))
+        );
+        assert_eq!(
+            connector.to_host_offset(Address::from(0x9fff)),
+            Some((Address::from(0x7f00_0000_2fffu64), 1))
+        );
+        assert_eq!(connector.to_host_offset(Address::from(0x1000)), None);
+        assert_eq!(connector.to_host_offset(Address::from(0xa000)), None);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_mapping_json() {
+        let stub = StubMemory::with_pattern(0x4000);
+        let connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+        assert_eq!(connector.mapping_source(), MappingSource::Fallback);
+
+        let json = connector.mapping_to_json();
+        assert!(json.contains("\"source\": \"fallback\""));
+
+        let mem_map = mapping_from_json(&json).unwrap();
+        assert_eq!(mappings(&mem_map), mappings(&connector.mem_map));
+
+        assert!(mapping_from_json("[{\"gpa_start\": 0}]").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_dump_memory_map() {
+        let path =
+            std::env::temp_dir().join(format!("memflow-qemu-dump-{}.json", std::process::id()));
+        let stub = StubMemory::with_pattern(0x4000);
+        let mut connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        let mut mem_map = MemoryMap::new();
+        mem_map.push_range(0x0.into(), 0x1000.into(), 0x1000.into());
+        mem_map.push_range(0x8000.into(), 0xa000.into(), 0x2000.into());
+        connector.mem_map = mem_map;
+        connector.dump_memory_map(&path).unwrap();
+
+        // replaying the dumped file results in the same memory map
+        let replayed = stub_connector(
+            &stub,
+            "qemu-system-x86_64 -machine q35",
+            BuildOptions {
+                mapping: MappingOptions {
+                    map_file: Some(path.clone()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        assert_eq!(replayed.mapping_source(), MappingSource::File);
+        assert_eq!(mappings(&replayed.mem_map), mappings(&connector.mem_map));
+
+        // mappings before the guest memory cannot be expressed relative to it
+        connector.qemu_map = CTup2(Address::from(0x2000), 0x2000);
+        assert!(connector.dump_memory_map(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "serde"))]
+    fn test_dump_memory_map_without_serde() {
+        let path = std::env::temp_dir().join(format!(
+            "memflow-qemu-dump-noserde-{}.json",
+            std::process::id()
+        ));
+        let stub = StubMemory::with_pattern(0x4000);
+        let connector =
+            stub_connector(&stub, "qemu-system-x86_64 -machine q35", Default::default());
+
+        assert_eq!(
+            connector.dump_memory_map(&path).unwrap_err().1,
+            ErrorKind::UnsupportedOptionalFeature
+        );
+        assert!(!path.exists());
+    }
 }
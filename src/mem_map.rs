@@ -1,17 +1,19 @@
-use log::info;
+use log::{debug, info, warn};
 
-use crate::qemu_args::qemu_arg_opt;
-
-use memflow::prelude::v1::{
-    mem, umem, Address, CTup2, Error, ErrorKind, ErrorOrigin, MemoryMap, Result,
+use crate::error::QemuError;
+use crate::qemu_args::{
+    qemu_arg_objects, qemu_arg_opt, qemu_arg_prop, qemu_objects, qemu_parse_size,
 };
+use crate::qmp::QmpMonitor;
 
-#[cfg(all(target_os = "linux", feature = "qmp"))]
-use {
-    qapi::{qmp, Qmp},
-    std::io::{Read, Write},
-    std::net::TcpStream,
-    std::os::unix::net::UnixStream,
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use memflow::prelude::v1::{
+    mem, umem, Address, CTup2, Error, ErrorKind, ErrorOrigin, MemoryMap, Pid, Result,
 };
 
 #[derive(Debug, Clone)]
@@ -19,6 +21,10 @@ struct Mapping {
     pub range_start: umem,
     pub range_end: umem,
     pub remap_start: umem,
+    /// Host address of the ram block `remap_start` is relative to.
+    ///
+    /// `None` refers to the guest memory mapping found in the qemu process.
+    pub host_base: Option<umem>,
 }
 
 impl Mapping {
@@ -27,6 +33,186 @@ impl Mapping {
             range_start,
             range_end,
             remap_start,
+            host_base: None,
+        }
+    }
+}
+
+/// Names of the memory regions which are treated as guest ram by default.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+const DEFAULT_RAM_REGION_NAMES: &[&str] = &["pc.ram"];
+
+/// Options controlling how the guest memory mappings are resolved.
+#[derive(Debug, Clone, Default)]
+pub struct MappingOptions {
+    /// Additional memory region names that are treated as guest ram.
+    pub ram_region_names: Vec<String>,
+    /// Logs the raw memory tree received from qmp at debug level.
+    pub dump_mtree: bool,
+    /// Forces the mappings to be obtained from the given source.
+    pub source: Option<MappingSource>,
+    /// Json file containing the mappings for [`MappingSource::File`].
+    pub map_file: Option<PathBuf>,
+    /// Fills gaps in the qmp mappings with the fallback mappings of the machine.
+    pub hybrid: bool,
+    /// Reuses the qmp mappings of the same qemu process for a few seconds.
+    pub mtree_cache: bool,
+    /// Restricts the qmp mappings to the memory backend with this id.
+    pub backend: Option<String>,
+    /// The qemu process the mappings are resolved for, required by the mtree cache.
+    pub pid: Option<Pid>,
+}
+
+impl MappingOptions {
+    /// Returns the forced mapping source, a map file alone forces [`MappingSource::File`].
+    pub(crate) fn forced_source(&self) -> Option<MappingSource> {
+        self.source
+            .or_else(|| self.map_file.is_some().then_some(MappingSource::File))
+    }
+
+    /// Returns true for the default ram regions, the per-node ram of legacy numa
+    /// setups (`ram-node0`, ...) and all additionally configured names.
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn is_ram_region(&self, name: &str) -> bool {
+        DEFAULT_RAM_REGION_NAMES.contains(&name)
+            || name.starts_with("ram-node")
+            || self.ram_region_names.iter().any(|n| n == name)
+    }
+}
+
+/// Returns the ids of the memory backends used as guest ram.
+///
+/// These are referenced by `-numa node,memdev=<id>` or `-machine memory-backend=<id>`
+/// and show up under their id in the memory tree.
+fn qemu_ram_backends(cmdline: &str) -> Vec<String> {
+    let mut backends = qemu_arg_objects(cmdline, "-numa")
+        .iter()
+        .filter_map(|node| node.prop("memdev"))
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    backends.extend(qemu_arg_prop(
+        cmdline.split_whitespace(),
+        "-machine",
+        "memory-backend",
+    ));
+    backends
+}
+
+/// Returns the total size of the memory backends used as guest ram.
+///
+/// The sizes of all `-object memory-backend-*` objects referenced as guest ram are summed,
+/// other backends like ivshmem regions are not part of the guest ram. Returns `None` if the
+/// guest ram is not made of memory backends or if the size of a backend cannot be parsed.
+fn qemu_ram_backend_size(cmdline: &str) -> Option<umem> {
+    let ids = qemu_ram_backends(cmdline);
+    if ids.is_empty() {
+        return None;
+    }
+
+    qemu_objects(cmdline)
+        .iter()
+        .filter(|object| object.typename.starts_with("memory-backend-"))
+        .filter(|object| {
+            object
+                .prop("id")
+                .is_some_and(|id| ids.iter().any(|i| i == id))
+        })
+        .map(|backend| backend.prop("size").and_then(qemu_parse_size))
+        .sum()
+}
+
+/// Offsets of the legacy vga and option rom area in the guest memory.
+///
+/// The x86 fallback mappings leave parts of this area unmapped on purpose.
+pub(crate) const LEGACY_HOLE: (umem, umem) = (0xa0000, 0x100000);
+
+/// Returns the number of bytes of the guest memory mapping covered by `mappings`,
+/// not counting the legacy rom area.
+fn mapped_size(mappings: &[Mapping]) -> umem {
+    let mut ranges = mappings
+        .iter()
+        .filter(|mapping| mapping.host_base.is_none())
+        .map(|mapping| {
+            let start = mapping.remap_start;
+            (
+                start,
+                start + mapping.range_end.saturating_sub(mapping.range_start),
+            )
+        })
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+
+    let (mut covered, mut end) = (0, 0);
+    for (start, range_end) in ranges {
+        let start = start.max(end);
+        if range_end > start {
+            covered += range_end - start - legacy_overlap(start, range_end);
+            end = range_end;
+        }
+    }
+    covered
+}
+
+/// Returns how many bytes of `start..end` lie within the legacy rom area.
+fn legacy_overlap(start: umem, end: umem) -> umem {
+    end.min(LEGACY_HOLE.1)
+        .saturating_sub(start.max(LEGACY_HOLE.0))
+}
+
+/// Warns if the fallback mappings do not cover the guest memory mapping of the qemu process.
+///
+/// A mismatch usually means the machine type or its ram layout was detected wrongly.
+/// Hugepage backed mappings can be larger than the guest ram, so the declared
+/// ram size is used if it is smaller.
+fn check_fallback_size(cmdline: &str, mappings: &[Mapping], map_size: umem) {
+    let ram_size = qemu_ram_backend_size(cmdline).map_or(map_size, |size| size.min(map_size));
+    let expected = ram_size - legacy_overlap(0, ram_size);
+    let mapped = mapped_size(mappings);
+    if mapped.abs_diff(expected) > mem::kb(4) {
+        warn!(
+            "the fallback mappings cover {:x} bytes but the guest ram is {:x} bytes large, \
+             the machine type was probably detected wrongly and reads may return wrong data",
+            mapped, expected
+        );
+    }
+}
+
+/// Drops the parts of the mappings which lie beyond the first `map_size` bytes of guest ram.
+fn clamp_mappings(mappings: Vec<Mapping>, map_size: umem) -> Vec<Mapping> {
+    mappings
+        .into_iter()
+        .filter(|mapping| mapping.remap_start < map_size)
+        .map(|mut mapping| {
+            let size = mapping.range_end - mapping.range_start;
+            mapping.range_end = mapping.range_start + size.min(map_size - mapping.remap_start);
+            mapping
+        })
+        .collect()
+}
+
+/// Describes where the guest memory mappings were obtained from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum MappingSource {
+    /// The memory tree was queried from qemu via qmp.
+    Qmp,
+    /// Hard-coded mappings for the machine type were used.
+    Fallback,
+    /// The mappings were loaded from a user supplied file.
+    File,
+}
+
+impl std::str::FromStr for MappingSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "qmp" => Ok(Self::Qmp),
+            "fallback" => Ok(Self::Fallback),
+            "file" => Ok(Self::File),
+            _ => Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                .log_error("mapping source has to be either `qmp`, `fallback` or `file`")),
         }
     }
 }
@@ -34,156 +220,999 @@ impl Mapping {
 pub fn qemu_mem_mappings(
     cmdline: &str,
     qemu_map: &CTup2<Address, umem>,
-) -> Result<MemoryMap<(Address, umem)>> {
+    qmp: Option<&QmpMonitor>,
+    options: &MappingOptions,
+) -> Result<(MemoryMap<(Address, umem)>, MappingSource)> {
     let mut mem_map = MemoryMap::new();
 
-    let mappings = if let Ok(mappings) = qmp_get_mtree(cmdline.split_whitespace()) {
-        mappings
+    let mut options = options.clone();
+    options.ram_region_names.extend(qemu_ram_backends(cmdline));
+    // the selected backend may not be guest ram, e.g. an ivshmem region
+    options.ram_region_names.extend(options.backend.clone());
+
+    let (mappings, source) = match options.forced_source() {
+        // a forced source never falls back to another one so the mappings are reproducible
+        Some(MappingSource::Qmp) => {
+            let mappings = qmp_get_mtree_cached(qmp, cmdline, &options).map_err(|err| {
+                err.log_error("the qmp mapping source was requested but qmp is not available")
+            })?;
+            (mappings, MappingSource::Qmp)
+        }
+        Some(MappingSource::Fallback) => (
+            qemu_fallback_mappings(cmdline, qemu_map, qmp)?,
+            MappingSource::Fallback,
+        ),
+        Some(MappingSource::File) => {
+            let path = options.map_file.as_deref().ok_or_else(|| {
+                Error(ErrorOrigin::Connector, ErrorKind::Configuration)
+                    .log_error("the file mapping source requires a map file")
+            })?;
+            (load_map_file(path)?, MappingSource::File)
+        }
+        // the fallback mappings cannot be restricted to a backend
+        None if options.backend.is_some() => (
+            qmp_get_mtree_cached(qmp, cmdline, &options)?,
+            MappingSource::Qmp,
+        ),
+        None => match qmp_get_mtree_cached(qmp, cmdline, &options) {
+            Ok(mappings) => (mappings, MappingSource::Qmp),
+            Err(err) => {
+                match QemuError::try_from(err) {
+                    Ok(QemuError::QmpNotConfigured) => {
+                        info!("qmp is not enabled, using the hard-coded fallback mappings")
+                    }
+                    Ok(reason) => warn!(
+                        "{}, using the hard-coded fallback mappings which might be wrong",
+                        reason
+                    ),
+                    Err(err) => warn!(
+                        "unable to get the qmp mappings ({}), using the hard-coded fallback mappings which might be wrong",
+                        err
+                    ),
+                }
+                (
+                    qemu_fallback_mappings(cmdline, qemu_map, qmp)?,
+                    MappingSource::Fallback,
+                )
+            }
+        },
+    };
+
+    if mappings.is_empty() {
+        return Err(Error::from(QemuError::NoMemoryMap).log_error(format!(
+            "the {:?} mapping source does not contain any guest ram",
+            source
+        )));
+    }
+
+    if source == MappingSource::Fallback {
+        check_fallback_size(cmdline, &mappings, qemu_map.1);
+    }
+
+    let mappings = if options.hybrid && source == MappingSource::Qmp {
+        let fallback = qemu_fallback_mappings(cmdline, qemu_map, qmp).unwrap_or_default();
+        fill_mapping_gaps(mappings, &fallback)
     } else {
-        // find machine architecture and type
-        let machine = if !cmdline.is_empty()
-            && cmdline
-                .split_whitespace()
-                .next()
-                .unwrap()
-                .contains("aarch64")
-        {
-            "aarch64".into()
-        } else {
-            qemu_arg_opt(cmdline.split_whitespace(), "-machine", "type")
-                .unwrap_or_else(|| "pc".into())
-        };
-        info!("qemu process started with machine: {}", machine);
-        qemu_get_mtree_fallback(&machine, qemu_map)
+        mappings
     };
 
     // add all mappings
-    for mapping in mappings.iter() {
+    for mapping in coalesce_mappings(mappings).iter() {
         mem_map.push_range(
             mapping.range_start.into(),
             mapping.range_end.into(),
-            qemu_map.0 + mapping.remap_start,
+            mapping.host_base.map(Address::from).unwrap_or(qemu_map.0) + mapping.remap_start,
         );
     }
 
+    Ok((mem_map, source))
+}
+
+/// Merges consecutive mappings which are adjacent both in guest physical memory and in the
+/// host memory they are remapped to, so the memory map needs fewer lookups per read.
+fn coalesce_mappings(mappings: Vec<Mapping>) -> Vec<Mapping> {
+    let mut coalesced: Vec<Mapping> = Vec::with_capacity(mappings.len());
+    for mapping in mappings {
+        if let Some(prev) = coalesced.last_mut() {
+            let prev_remap_end = prev.remap_start + (prev.range_end - prev.range_start);
+            if prev.range_end == mapping.range_start
+                && prev_remap_end == mapping.remap_start
+                && prev.host_base == mapping.host_base
+            {
+                prev.range_end = mapping.range_end;
+                continue;
+            }
+        }
+        coalesced.push(mapping);
+    }
+    coalesced
+}
+
+/// Duration the qmp mappings of a qemu process are cached for.
+const MTREE_CACHE_TTL: Duration = Duration::from_secs(5);
+
+type MtreeCache = HashMap<(Pid, u64), (Instant, Vec<Mapping>)>;
+
+/// Mappings parsed from the qmp memory tree, keyed by pid and a hash of the command line.
+static MTREE_CACHE: OnceLock<Mutex<MtreeCache>> = OnceLock::new();
+
+/// Returns the qmp mappings, reusing the ones of a recent connector for the same qemu process.
+///
+/// Building multiple connectors in quick succession otherwise reconnects to the monitor
+/// and runs `info mtree -f` every time, which is slow and can disturb a busy monitor.
+/// Only the parsed mappings are cached, so the cache is bypassed if the raw tree is dumped.
+fn qmp_get_mtree_cached(
+    qmp: Option<&QmpMonitor>,
+    cmdline: &str,
+    options: &MappingOptions,
+) -> Result<Vec<Mapping>> {
+    let Some(pid) = options
+        .pid
+        .filter(|_| options.mtree_cache && !options.dump_mtree)
+    else {
+        return qmp_get_mtree(qmp, options);
+    };
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    cmdline.hash(&mut hasher);
+    options.ram_region_names.hash(&mut hasher);
+    options.backend.hash(&mut hasher);
+    let key = (pid, hasher.finish());
+
+    let cache = MTREE_CACHE.get_or_init(Default::default);
+    if let Some((_, mappings)) = cache
+        .lock()
+        .unwrap()
+        .get(&key)
+        .filter(|(created, _)| created.elapsed() < MTREE_CACHE_TTL)
+    {
+        debug!("using cached qmp mappings of process {}", pid);
+        return Ok(mappings.clone());
+    }
+
+    let mappings = qmp_get_mtree(qmp, options)?;
+    let mut cache = cache.lock().unwrap();
+    cache.retain(|_, (created, _)| created.elapsed() < MTREE_CACHE_TTL);
+    cache.insert(key, (Instant::now(), mappings.clone()));
+    Ok(mappings)
+}
+
+/// Gaps smaller than this are expected holes (e.g. the vga window) and are not filled.
+const MIN_HYBRID_GAP: umem = mem::mb(1);
+
+/// Adds the parts of the `fallback` mappings which are missing from the qmp `mappings`.
+///
+/// A part is missing if neither its guest physical range nor its offset in the guest memory
+/// mapping is covered by any qmp mapping. This recovers ram blocks some qemu versions
+/// omit from the memory tree while keeping everything qmp reported.
+fn fill_mapping_gaps(mut mappings: Vec<Mapping>, fallback: &[Mapping]) -> Vec<Mapping> {
+    let mut filled = vec![];
+    for fb in fallback {
+        // ranges of this fallback mapping which are covered by qmp, in guest physical addresses
+        let mut covered = vec![];
+        for mapping in mappings.iter() {
+            covered.push((mapping.range_start, mapping.range_end));
+            if mapping.host_base.is_none() {
+                let size = mapping.range_end - mapping.range_start;
+                let start = (fb.range_start + mapping.remap_start).wrapping_sub(fb.remap_start);
+                covered.push((start, start.wrapping_add(size)));
+            }
+        }
+        covered.sort_unstable();
+
+        let mut start = fb.range_start;
+        for (lo, hi) in covered
+            .into_iter()
+            .chain(std::iter::once((fb.range_end, fb.range_end)))
+        {
+            let (lo, hi) = (lo.clamp(start, fb.range_end), hi.min(fb.range_end));
+            if lo - start >= MIN_HYBRID_GAP {
+                info!(
+                    "qmp is missing guest ram {:x}-{:x}, adding it from the fallback mappings",
+                    start, lo
+                );
+                filled.push(Mapping::new(
+                    start,
+                    lo,
+                    fb.remap_start + (start - fb.range_start),
+                ));
+            }
+            start = start.max(hi);
+        }
+    }
+
+    mappings.extend(filled);
+    mappings.sort_by_key(|mapping| mapping.range_start);
+    mappings
+}
+
+fn qemu_fallback_mappings(
+    cmdline: &str,
+    qemu_map: &CTup2<Address, umem>,
+    qmp: Option<&QmpMonitor>,
+) -> Result<Vec<Mapping>> {
+    let arch = qemu_target_arch(cmdline, qmp);
+    if !qemu_target_supported(&arch) {
+        return Err(
+            Error::from(QemuError::UnsupportedMachine).log_error(format!(
+                "no fallback memory mappings are available for {}",
+                arch
+            )),
+        );
+    }
+
+    let machine = qemu_machine(cmdline, &arch);
+    info!("qemu process started with machine: {}", machine);
+    if machine == "aarch64" && qemu_secure_world(cmdline) {
+        info!("aarch64 secure world is enabled, the secure ram is not accessible through this connector");
+    }
+
+    let max_below_4g = qemu_max_ram_below_4g(cmdline);
+    if let Some(max_below_4g) = max_below_4g {
+        info!("guest ram below 4GB is limited to {:x} bytes", max_below_4g);
+    }
+
+    // the layout depends on the total guest ram which can be split into multiple backends
+    let map_size = qemu_map.1;
+    match qemu_ram_backend_size(cmdline) {
+        Some(ram_size) if ram_size != map_size => {
+            info!(
+                "using the declared guest ram size {:x} instead of the mapping size {:x}",
+                ram_size, map_size
+            );
+            Ok(clamp_mappings(
+                qemu_get_mtree_fallback(&machine, &CTup2(qemu_map.0, ram_size), max_below_4g),
+                map_size,
+            ))
+        }
+        _ => Ok(qemu_get_mtree_fallback(&machine, qemu_map, max_below_4g)),
+    }
+}
+
+/// A single entry of a memory map file.
+///
+/// `remap_start` is relative to the guest memory mapping in the qemu process.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct MapFileEntry {
+    range_start: umem,
+    range_end: umem,
+    remap_start: umem,
+}
+
+/// Loads the mappings from a json array of `{range_start, range_end, remap_start}` entries.
+#[cfg(feature = "serde")]
+fn load_map_file(path: &Path) -> Result<Vec<Mapping>> {
+    let json = std::fs::read_to_string(path).map_err(|err| {
+        Error(ErrorOrigin::Connector, ErrorKind::UnableToReadFile).log_error(format!(
+            "unable to read map file {}: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    let entries: Vec<MapFileEntry> = serde_json::from_str(&json).map_err(|err| {
+        Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument).log_error(format!(
+            "invalid map file {}: {}",
+            path.display(),
+            err
+        ))
+    })?;
+
+    let mut mappings = entries
+        .into_iter()
+        .map(|entry| Mapping::new(entry.range_start, entry.range_end, entry.remap_start))
+        .collect::<Vec<_>>();
+    validate_map_file(&mut mappings).map_err(|err| {
+        Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument).log_error(format!(
+            "invalid map file {}: {}",
+            path.display(),
+            err
+        ))
+    })?;
+
+    info!("loaded {} mappings from {}", mappings.len(), path.display());
+    Ok(mappings)
+}
+
+/// Sorts the mappings of a map file and makes sure they are neither empty nor overlapping.
+#[cfg(feature = "serde")]
+fn validate_map_file(mappings: &mut [Mapping]) -> std::result::Result<(), String> {
+    if mappings.is_empty() {
+        return Err("the file does not contain any mappings".to_owned());
+    }
+    if let Some(mapping) = mappings.iter().find(|m| m.range_end <= m.range_start) {
+        return Err(format!(
+            "the mapping {:x}-{:x} is empty",
+            mapping.range_start, mapping.range_end
+        ));
+    }
+
+    mappings.sort_by_key(|mapping| mapping.range_start);
+    if let Some(pair) = mappings
+        .windows(2)
+        .find(|pair| pair[1].range_start < pair[0].range_end)
+    {
+        return Err(format!(
+            "the mappings {:x}-{:x} and {:x}-{:x} overlap",
+            pair[0].range_start, pair[0].range_end, pair[1].range_start, pair[1].range_end
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn load_map_file(_path: &Path) -> Result<Vec<Mapping>> {
+    Err(Error(
+        ErrorOrigin::Connector,
+        ErrorKind::UnsupportedOptionalFeature,
+    )
+    .log_error("loading a map file requires the `serde` feature"))
+}
+
+/// Serializes the memory map into the map file format read by [`MappingSource::File`].
+///
+/// `remap_start` is stored relative to the guest memory mapping `qemu_map` so the file stays
+/// valid after qemu was restarted. Mappings below it, e.g. of a separate memory backend,
+/// cannot be expressed in this format.
+#[cfg(feature = "serde")]
+pub(crate) fn mapping_to_map_file(
+    mem_map: &MemoryMap<(Address, umem)>,
+    qemu_map: &CTup2<Address, umem>,
+) -> Result<String> {
+    let entries = mem_map
+        .iter()
+        .map(|mapping| {
+            let (host, size) = *mapping.output();
+            if host < qemu_map.0 {
+                return Err(Error(ErrorOrigin::Connector, ErrorKind::MemoryMapOutOfRange)
+                    .log_error(format!(
+                        "the mapping at {:x} lies before the guest memory and cannot be stored in a map file",
+                        mapping.base()
+                    )));
+            }
+            Ok(MapFileEntry {
+                range_start: mapping.base().to_umem(),
+                range_end: mapping.base().to_umem() + size,
+                remap_start: (host - qemu_map.0) as umem,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(serde_json::to_string_pretty(&entries).unwrap())
+}
+
+#[cfg(not(feature = "serde"))]
+pub(crate) fn mapping_to_map_file(
+    _mem_map: &MemoryMap<(Address, umem)>,
+    _qemu_map: &CTup2<Address, umem>,
+) -> Result<String> {
+    Err(Error(
+        ErrorOrigin::Connector,
+        ErrorKind::UnsupportedOptionalFeature,
+    )
+    .log_error("dumping a map file requires the `serde` feature"))
+}
+
+/// A single guest memory mapping in its serialized form.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct MappingEntry {
+    gpa_start: umem,
+    gpa_end: umem,
+    host_va: umem,
+    source: MappingSource,
+}
+
+/// Serializes the memory map into a json array of mappings.
+#[cfg(feature = "serde")]
+pub fn mapping_to_json(mem_map: &MemoryMap<(Address, umem)>, source: MappingSource) -> String {
+    let entries = mem_map
+        .iter()
+        .map(|mapping| MappingEntry {
+            gpa_start: mapping.base().to_umem(),
+            gpa_end: mapping.base().to_umem() + mapping.output().1,
+            host_va: mapping.output().0.to_umem(),
+            source,
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_string_pretty(&entries).unwrap()
+}
+
+/// Restores a memory map previously serialized with [`mapping_to_json`].
+#[cfg(feature = "serde")]
+pub fn mapping_from_json(json: &str) -> Result<MemoryMap<(Address, umem)>> {
+    let entries: Vec<MappingEntry> = serde_json::from_str(json)
+        .map_err(|err| Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument).log_error(err))?;
+
+    let mut mem_map = MemoryMap::new();
+    for entry in entries {
+        mem_map.push_range(
+            entry.gpa_start.into(),
+            entry.gpa_end.into(),
+            entry.host_va.into(),
+        );
+    }
     Ok(mem_map)
 }
 
 #[cfg(all(target_os = "linux", feature = "qmp"))]
-fn qmp_get_mtree<'a>(cmdline: impl IntoIterator<Item = &'a str>) -> Result<Vec<Mapping>> {
-    // -qmp unix:/tmp/qmp-win10-reversing.sock,server,nowait
-    let socket_addr = qemu_arg_opt(cmdline, "-qmp", "")
-        .ok_or(Error(ErrorOrigin::Connector, ErrorKind::Configuration))?;
-    if socket_addr.starts_with("unix:") {
-        let socket_path = socket_addr
-            .strip_prefix("unix:")
-            .ok_or(Error(ErrorOrigin::Connector, ErrorKind::Configuration))?;
-
-        info!("connecting to qmp unix socket at: {}", socket_path);
-        let stream = UnixStream::connect(socket_path).map_err(|err| {
-            Error(ErrorOrigin::Connector, ErrorKind::Configuration).log_error(err)
-        })?;
-
-        qmp_get_mtree_stream(&stream)
-    } else if socket_addr.starts_with("tcp:") {
-        let socket_url = socket_addr
-            .strip_prefix("tcp:")
-            .ok_or(Error(ErrorOrigin::Connector, ErrorKind::Configuration))?;
-
-        info!("connecting to qmp tcp socket at: {}", socket_url);
-
-        let stream = TcpStream::connect(socket_url).map_err(|err| {
-            Error(ErrorOrigin::Connector, ErrorKind::Configuration).log_error(err)
-        })?;
-
-        qmp_get_mtree_stream(&stream)
+fn qmp_get_mtree(qmp: Option<&QmpMonitor>, options: &MappingOptions) -> Result<Vec<Mapping>> {
+    let qmp = qmp.ok_or(Error::from(QemuError::QmpNotConfigured))?;
+    let mtreestr = qmp.human_monitor_command("info mtree -f")?;
+    if options.dump_mtree {
+        debug!("qmp info mtree -f:\n{}", mtreestr);
+    }
+
+    let mut regions = qmp_parse_mtree_regions(&mtreestr, options);
+    qmp_resolve_host_bases(qmp, &mut regions);
+    if let Some(backend) = &options.backend {
+        regions = qmp_select_backend(regions, backend)?;
+    }
+    if regions.is_empty() {
+        return Err(Error::from(QemuError::QmpEmptyMtree)
+            .log_warn("no guest ram was found in the qmp memory tree"));
+    }
+    Ok(regions.into_iter().map(|r| r.mapping).collect())
+}
+
+/// Keeps only the regions of the memory backend with the given id.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn qmp_select_backend(regions: Vec<MtreeRegion>, id: &str) -> Result<Vec<MtreeRegion>> {
+    let (selected, others): (Vec<_>, Vec<_>) = regions.into_iter().partition(|r| r.name == id);
+    if selected.is_empty() {
+        let mut names = others.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+        names.dedup();
+        return Err(
+            Error(ErrorOrigin::Connector, ErrorKind::NotFound).log_error(format!(
+                "memory backend {} was not found in the memory tree, the guest ram consists of: {}",
+                id,
+                names.join(", ")
+            )),
+        );
+    }
+    info!("restricting the memory map to memory backend {}", id);
+    Ok(selected)
+}
+
+/// Resolves the host address of each ram block when the guest ram consists of multiple blocks.
+///
+/// This is the case for numa guests where every node is backed by a separate allocation
+/// (e.g. `ram-node0` and `ram-node1`). The offsets in the memory tree are relative
+/// to the start of each block, so every block has to be located individually.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn qmp_resolve_host_bases(qmp: &QmpMonitor, regions: &mut [MtreeRegion]) {
+    let mut names: Vec<String> = vec![];
+    for region in regions.iter() {
+        if !names.contains(&region.name) {
+            names.push(region.name.clone());
+        }
+    }
+    if names.len() < 2 {
+        return;
+    }
+
+    for name in names {
+        let Some(first) = regions.iter().find(|r| r.name == name) else {
+            continue;
+        };
+        let mapping = &first.mapping;
+
+        match qmp_gpa2hva(qmp, mapping.range_start) {
+            Ok(hva) => {
+                let base = hva - mapping.remap_start;
+                info!("ram block {} found at {:x}", name, base);
+                regions
+                    .iter_mut()
+                    .filter(|r| r.name == name)
+                    .for_each(|r| r.mapping.host_base = Some(base));
+            }
+            Err(err) => warn!("unable to locate ram block {}: {}", name, err),
+        }
+    }
+}
+
+/// Translates a guest physical address into a host virtual address of the qemu process.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+pub fn qmp_gpa2hva(qmp: &QmpMonitor, gpa: umem) -> Result<umem> {
+    let output = qmp.human_monitor_command(&format!("gpa2hva {:#x}", gpa))?;
+    parse_gpa2hva(&output)
+        .ok_or_else(|| Error::from(QemuError::QmpParse).log_warn(output.trim().to_owned()))
+}
+
+/// Parses the output of `gpa2hva`, e.g. `Host virtual address for 0x0 (pc.ram) is 0x7f5a80000000`.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn parse_gpa2hva(output: &str) -> Option<umem> {
+    let (_, hva) = output.trim().rsplit_once(" is ")?;
+    umem::from_str_radix(hva.trim_start_matches("0x"), 16).ok()
+}
+
+#[cfg(not(all(target_os = "linux", feature = "qmp")))]
+fn qmp_get_mtree(_qmp: Option<&QmpMonitor>, _options: &MappingOptions) -> Result<Vec<Mapping>> {
+    Err(Error(
+        ErrorOrigin::Connector,
+        ErrorKind::UnsupportedOptionalFeature,
+    ))
+}
+
+/// Runs the `savevm` or `loadvm` monitor command for the snapshot `tag`.
+///
+/// Both commands print nothing on success and an error message otherwise.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+pub fn qmp_snapshot(qmp: Option<&QmpMonitor>, command: &str, tag: &str) -> Result<()> {
+    let qmp = qmp.ok_or_else(|| {
+        Error::from(QemuError::QmpNotConfigured).log_error("snapshots require a qmp monitor")
+    })?;
+    if tag.is_empty() || tag.contains(char::is_whitespace) {
+        return Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+            .log_error(format!("invalid snapshot tag {:?}", tag)));
+    }
+
+    let output = qmp.human_monitor_command(&format!("{} {}", command, tag))?;
+    if output.trim().is_empty() {
+        Ok(())
     } else {
-        Err(Error(ErrorOrigin::Connector, ErrorKind::Configuration))
+        Err(Error::from(QemuError::QmpCommand).log_error(format!(
+            "{} {} failed: {}",
+            command,
+            tag,
+            output.trim()
+        )))
     }
 }
 
 #[cfg(all(target_os = "linux", feature = "qmp"))]
-fn qmp_get_mtree_stream<S: Read + Write + Clone>(stream: S) -> Result<Vec<Mapping>> {
-    let mut qmp = Qmp::from_stream(stream);
-    qmp.handshake()
-        .map_err(|err| Error(ErrorOrigin::Connector, ErrorKind::Configuration).log_error(err))?;
-
-    let mtreestr = qmp
-        .execute(&qmp::human_monitor_command {
-            command_line: "info mtree -f".to_owned(),
-            cpu_index: None,
+pub fn qmp_get_device_bar(
+    qmp: Option<&QmpMonitor>,
+    bdf: &str,
+    bar: u32,
+) -> Result<(Address, umem)> {
+    let qmp = qmp.ok_or(Error::from(QemuError::QmpNotConfigured))?;
+    let mtreestr = qmp.human_monitor_command("info mtree -f")?;
+    qmp_parse_device_bar(&mtreestr, bdf, bar)
+        .map(|(start, size)| (start.into(), size))
+        .ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::NotFound)
+                .log_error(format!("BAR {} of device {} could not be found", bar, bdf))
         })
-        .map_err(|err| Error(ErrorOrigin::Connector, ErrorKind::Configuration).log_error(err))?;
+}
 
-    Ok(qmp_parse_mtree(&mtreestr))
+#[cfg(not(all(target_os = "linux", feature = "qmp")))]
+pub fn qmp_get_device_bar(
+    _qmp: Option<&QmpMonitor>,
+    _bdf: &str,
+    _bar: u32,
+) -> Result<(Address, umem)> {
+    Err(Error(
+        ErrorOrigin::Connector,
+        ErrorKind::UnsupportedOptionalFeature,
+    ))
+}
+
+/// Describes how a guest physical address is translated into the qemu process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressMapping {
+    /// Guest physical start of the mapping containing the address.
+    pub gpa_start: Address,
+    /// Size of the mapping.
+    pub size: umem,
+    /// Host virtual address `gpa_start` is mapped to.
+    pub host_va: Address,
+    /// Host virtual address of the requested guest physical address.
+    pub host_address: Address,
+}
+
+impl AddressMapping {
+    /// Returns the difference between host and guest physical addresses in this mapping.
+    pub fn remap_delta(&self) -> i128 {
+        self.host_va.to_umem() as i128 - self.gpa_start.to_umem() as i128
+    }
+}
+
+/// A flash or rom region holding the guest firmware.
+pub(crate) struct FirmwareRegion {
+    pub name: String,
+    pub range_start: umem,
+    pub range_end: umem,
+    /// Host address of the region in the qemu process if it could be resolved.
+    pub host: Option<umem>,
+}
+
+/// Returns the firmware regions (`system.flash0/1`, `pc.bios`, `isa-bios` and `pc.rom`) of the guest.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+pub(crate) fn qmp_get_firmware_regions(qmp: Option<&QmpMonitor>) -> Result<Vec<FirmwareRegion>> {
+    let qmp = qmp.ok_or(Error::from(QemuError::QmpNotConfigured))?;
+    let mtreestr = qmp.human_monitor_command("info mtree -f")?;
+    Ok(qmp_parse_firmware_regions(&mtreestr)
+        .into_iter()
+        .map(|(name, range_start, range_end)| FirmwareRegion {
+            name,
+            range_start,
+            range_end,
+            host: qmp_gpa2hva(qmp, range_start).ok(),
+        })
+        .collect())
 }
 
 #[cfg(not(all(target_os = "linux", feature = "qmp")))]
-fn qmp_get_mtree<'a>(_cmdline: impl IntoIterator<Item = &'a str>) -> Result<Vec<Mapping>> {
+pub(crate) fn qmp_get_firmware_regions(_qmp: Option<&QmpMonitor>) -> Result<Vec<FirmwareRegion>> {
     Err(Error(
         ErrorOrigin::Connector,
         ErrorKind::UnsupportedOptionalFeature,
     ))
 }
 
+/// Returns the names and guest physical ranges `[start, end)` of the firmware regions.
+///
+/// Regions which are split into adjacent lines are merged.
 #[cfg(all(target_os = "linux", feature = "qmp"))]
-fn qmp_parse_mtree(mtreestr: &str) -> Vec<Mapping> {
-    let mut mappings = Vec::new();
-    let mut system_region = false;
-    for line in mtreestr.lines().map(|l| l.trim()) {
-        let memory_region = scan_fmt!(line, "Root memory region: {}", String);
-        match memory_region.as_deref() {
-            Ok("system") => {
-                system_region = true;
+fn qmp_parse_firmware_regions(mtreestr: &str) -> Vec<(String, umem, umem)> {
+    let mut regions: Vec<(String, umem, umem)> = vec![];
+    for line in mtree_system_lines(mtreestr) {
+        // 00000000ffe20000-00000000ffffffff (prio 0, romd): system.flash0 KVM
+        if let Ok((start, end, kind, name)) = scan_fmt!(
+            &line,
+            "{x}-{x} (prio {*d}, {[^)]}): {}",
+            [hex umem],
+            [hex umem],
+            String,
+            String
+        ) {
+            if !matches!(kind.as_str(), "rom" | "romd") || !is_firmware_region(&name) {
+                continue;
             }
-            Ok(_) => {
-                system_region = false;
+            match regions.last_mut() {
+                Some((last, _, last_end)) if *last == name && *last_end == start => {
+                    *last_end = end + 1;
+                }
+                _ => regions.push((name, start, end + 1)),
             }
-            _ => (),
         }
+    }
+    regions
+}
 
-        if system_region {
-            let range = scan_fmt_some!(line, "{x}-{x} {*[^:]}: pc.ram {*[@]}{x} KVM", [hex umem], [hex umem], [hex umem]);
-            if range.0.is_some() && range.1.is_some() {
-                // add the mapping here, in case the third entry is None
-                // we just add the first start mapping here.
-                // this should only ever happen for the first entry which starts/remaps at/to 0.
-                mappings.push(Mapping::new(
-                    range.0.unwrap(),
-                    range.1.unwrap() + 1,
-                    range.2.unwrap_or_else(|| range.0.unwrap()),
-                ))
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn is_firmware_region(name: &str) -> bool {
+    name.starts_with("system.flash") || matches!(name, "pc.bios" | "isa-bios" | "pc.rom")
+}
+
+/// Returns all lines of the flattened `system` memory region.
+///
+/// Runs of whitespace (including tabs) are collapsed into single spaces.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn mtree_system_lines(mtreestr: &str) -> impl Iterator<Item = String> + '_ {
+    let mut system_region = false;
+    mtreestr
+        .lines()
+        .map(normalize_whitespace)
+        .filter(move |line| {
+            let memory_region = scan_fmt!(line, "Root memory region: {}", String);
+            match memory_region.as_deref() {
+                Ok("system") => {
+                    system_region = true;
+                }
+                Ok(_) => {
+                    system_region = false;
+                }
+                _ => (),
             }
+            system_region
+        })
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn normalize_whitespace(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A single line of the flattened `system` memory region.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+#[derive(Clone)]
+struct MtreeRegion {
+    name: String,
+    is_ram: bool,
+    /// Regions with a higher priority shadow overlapping regions with a lower one.
+    prio: i32,
+    mapping: Mapping,
+}
+
+/// Parses the guest ram mappings from the flattened `system` memory region.
+///
+/// Regions matching [`MappingOptions::ram_region_names`] (or `pc.ram`) are used if present.
+/// Otherwise the `(prio 0, ram)` region with the largest total size is treated as guest ram,
+/// which covers memory backends like `-object memory-backend-file,id=mem0`.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn qmp_parse_mtree_regions(mtreestr: &str, options: &MappingOptions) -> Vec<MtreeRegion> {
+    let mut regions = Vec::new();
+    for line in mtree_system_lines(mtreestr) {
+        // the accelerator suffix (` KVM`) is not matched, tcg guests do not print it
+        let range = scan_fmt_some!(&line, "{x}-{x} (prio {d}, {[^)]}): {} {*[@]}{x}", [hex umem], [hex umem], i32, String, String, [hex umem]);
+        if let (Some(range_start), Some(range_end), Some(prio), Some(kind), Some(name)) =
+            (range.0, range.1, range.2, range.3, range.4)
+        {
+            // qemu omits the offset into the region if it is 0,
+            // this is the case for the first mapping of each ram block.
+            regions.push(MtreeRegion {
+                name,
+                is_ram: prio == 0 && kind == "ram",
+                prio,
+                mapping: Mapping::new(range_start, range_end + 1, range.5.unwrap_or(0)),
+            });
         }
     }
-    mappings
+    let regions = resolve_region_priorities(regions);
+
+    let ram_region = if regions.iter().any(|r| options.is_ram_region(&r.name)) {
+        None
+    } else {
+        largest_ram_region(&regions)
+    };
+    if let Some(name) = ram_region {
+        info!("using memory region {} as guest ram", name);
+    }
+
+    let ram_region = ram_region.map(str::to_owned);
+    regions
+        .into_iter()
+        .filter(|r| match &ram_region {
+            Some(name) => r.name == *name,
+            None => options.is_ram_region(&r.name),
+        })
+        .collect()
+}
+
+/// Removes the parts of regions which are shadowed by an overlapping region with a higher priority.
+///
+/// The flattened tree of `info mtree -f` is already resolved by qemu, but dumps of older
+/// versions or overlays like smram and shadowed roms can contain overlapping lines. Shadowed
+/// regions are split around the higher priority region, of two regions with the same priority
+/// the one listed first is kept.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn resolve_region_priorities(mut regions: Vec<MtreeRegion>) -> Vec<MtreeRegion> {
+    // the sort is stable so regions of the same priority keep their order
+    regions.sort_by_key(|region| std::cmp::Reverse(region.prio));
+
+    let mut resolved: Vec<MtreeRegion> = Vec::with_capacity(regions.len());
+    for region in regions {
+        let mut pieces = vec![region];
+        for claimed in resolved.iter().map(|r| &r.mapping) {
+            pieces = pieces
+                .into_iter()
+                .flat_map(|piece| {
+                    let mapping = &piece.mapping;
+                    if claimed.range_end <= mapping.range_start
+                        || claimed.range_start >= mapping.range_end
+                    {
+                        return vec![piece];
+                    }
+                    debug!(
+                        "memory region {} is shadowed at {:x}-{:x}",
+                        piece.name,
+                        claimed.range_start.max(mapping.range_start),
+                        claimed.range_end.min(mapping.range_end)
+                    );
+                    let mut split = vec![];
+                    if mapping.range_start < claimed.range_start {
+                        let mut before = piece.clone();
+                        before.mapping.range_end = claimed.range_start;
+                        split.push(before);
+                    }
+                    if claimed.range_end < mapping.range_end {
+                        let mut after = piece.clone();
+                        after.mapping.remap_start += claimed.range_end - mapping.range_start;
+                        after.mapping.range_start = claimed.range_end;
+                        split.push(after);
+                    }
+                    split
+                })
+                .collect();
+        }
+        resolved.extend(pieces);
+    }
+
+    resolved.sort_by_key(|region| region.mapping.range_start);
+    resolved
+}
+
+/// Returns the name of the `(prio 0, ram)` region spanning the most guest memory.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn largest_ram_region(regions: &[MtreeRegion]) -> Option<&str> {
+    let mut sizes: Vec<(&str, umem)> = Vec::new();
+    for region in regions.iter().filter(|r| r.is_ram) {
+        let size = region.mapping.range_end - region.mapping.range_start;
+        match sizes.iter_mut().find(|(name, _)| *name == region.name) {
+            Some((_, total)) => *total += size,
+            None => sizes.push((&region.name, size)),
+        }
+    }
+    sizes
+        .into_iter()
+        .max_by_key(|(_, size)| *size)
+        .map(|(name, _)| name)
+}
+
+/// Returns the guest physical start and size of the given device BAR.
+///
+/// A BAR can be split into several regions (e.g. by vfio quirks),
+/// in this case the returned range spans all of them.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn qmp_parse_device_bar(mtreestr: &str, bdf: &str, bar: u32) -> Option<(umem, umem)> {
+    let mut range: Option<(umem, umem)> = None;
+    for line in mtree_system_lines(mtreestr) {
+        // 0000000800000000-000000080fffffff (prio 0, ramd): 0000:0c:00.0 BAR 1 mmaps[0] KVM
+        if let Ok((start, end, kind, name, index)) = scan_fmt!(
+            &line,
+            "{x}-{x} (prio {*d}, {[^)]}): {} BAR {d}",
+            [hex umem],
+            [hex umem],
+            String,
+            String,
+            u32
+        ) {
+            if kind.starts_with("ram") && name == bdf && index == bar {
+                range = Some(match range {
+                    Some((s, e)) => (s.min(start), e.max(end + 1)),
+                    None => (start, end + 1),
+                });
+            }
+        }
+    }
+    range.map(|(start, end)| (start, end - start))
+}
+
+/// Returns the machine architecture or type used to select the fallback mappings.
+///
+/// Architectures with a single memory layout are selected by the target `arch`,
+/// otherwise the `-machine` type is used.
+fn qemu_machine(cmdline: &str, arch: &str) -> String {
+    let machine = qemu_arg_opt(cmdline.split_whitespace(), "-machine", "type");
+    if arch.starts_with("aarch64") {
+        // sbsa-ref is the only aarch64 machine with ram outside of the `virt` layout
+        if machine.as_deref().unwrap_or_default().contains("sbsa") {
+            "aarch64-sbsa-ref".into()
+        } else {
+            "aarch64".into()
+        }
+    } else if arch.starts_with("s390x") {
+        "s390x".into()
+    } else if arch.starts_with("riscv64") && machine.as_deref().unwrap_or_default().contains("virt")
+    {
+        // riscv64 and aarch64 both use the `virt` machine type
+        "riscv64-virt".into()
+    } else {
+        machine.unwrap_or_else(|| "pc".into())
+    }
 }
 
+/// Returns the target architecture of the qemu process, e.g. `aarch64`.
+///
+/// The architecture reported by qmp `query-target` is preferred as renamed binaries and
+/// multi-arch wrappers do not reveal it. Without qmp it is taken from the binary name.
+pub(crate) fn qemu_target_arch(cmdline: &str, qmp: Option<&QmpMonitor>) -> String {
+    match qmp.map(QmpMonitor::target_arch) {
+        Some(Ok(arch)) => {
+            info!("qmp reported target architecture: {}", arch);
+            arch.to_owned()
+        }
+        Some(Err(err)) => {
+            debug!("unable to query the target architecture: {}", err);
+            qemu_binary_arch(cmdline)
+        }
+        None => qemu_binary_arch(cmdline),
+    }
+}
+
+/// Returns the target architecture from the name of the qemu binary.
+///
+/// Binaries which do not follow the `qemu-system-<arch>` naming (e.g. `qemu-kvm`) are x86 hosts.
+fn qemu_binary_arch(cmdline: &str) -> String {
+    let binary = cmdline.split_whitespace().next().unwrap_or_default();
+    let name = binary.rsplit('/').next().unwrap_or_default();
+    name.strip_prefix("qemu-system-")
+        .unwrap_or("x86_64")
+        .to_owned()
+}
+
+/// Returns false if there are no fallback mappings for the target architecture.
+fn qemu_target_supported(arch: &str) -> bool {
+    const SUPPORTED: [&str; 6] = ["x86_64", "i386", "aarch64", "arm", "s390x", "riscv64"];
+
+    SUPPORTED.iter().any(|s| arch.starts_with(s))
+}
+
+/// Returns the hard-coded mappings of the machine for `map_size` bytes of guest ram.
+///
+/// `max_below_4g` is the `max-ram-below-4g` machine property which lowers the split
+/// between the low and high ram of x86 machines.
 fn qemu_get_mtree_fallback(
     machine: &str,
     &CTup2(_, map_size): &CTup2<Address, umem>,
+    max_below_4g: Option<umem>,
 ) -> Vec<Mapping> {
     info!("qemu memory map size: {:x}", map_size);
 
     if machine.contains("q35") {
-        if map_size >= mem::mb(2816) {
-            info!("using fallback memory mappings for q35 with more than 2816mb of ram");
-            qemu_get_mtree_fallback_q35(map_size)
+        let low_size = q35_low_mem_size(map_size, max_below_4g);
+        if low_size < map_size {
+            info!(
+                "using fallback memory mappings for q35 with {:x} bytes of low ram",
+                low_size
+            );
+            qemu_get_mtree_fallback_q35(map_size, low_size)
         } else {
-            info!("using fallback memory mappings for q35 with less than 2816mb of ram");
+            info!("using fallback memory mappings for q35 without ram above 4GB");
             qemu_get_mtree_fallback_q35_smallmem(map_size)
         }
+    } else if machine.contains("s390") {
+        // checked before `virt` as the s390x machine type is `s390-ccw-virtio`
+        info!("using fallback memory mappings for s390x");
+        qemu_get_mtree_fallback_s390x(map_size)
+    } else if machine.contains("riscv64") {
+        info!("using fallback memory mappings for riscv64");
+        qemu_get_mtree_fallback_riscv(map_size)
+    } else if machine.contains("microvm") {
+        info!("using fallback memory mappings for microvm");
+        qemu_get_mtree_fallback_microvm(map_size)
+    } else if machine.contains("sbsa") {
+        // checked before `aarch64` as the machine is `aarch64-sbsa-ref`
+        info!("using fallback memory mappings for sbsa-ref");
+        qemu_get_mtree_fallback_sbsa_ref(map_size)
     } else if machine.contains("aarch64") || machine.contains("virt") {
         info!("using fallback memory mappings for aarch64");
         qemu_get_mtree_fallback_aarch64(map_size)
     } else {
-        info!("using fallback memory mappings for pc-i1440fx");
-        qemu_get_mtree_fallback_pc(map_size)
+        let low_size = pc_low_mem_size(map_size, max_below_4g);
+        if low_size < map_size {
+            info!(
+                "using fallback memory mappings for pc-i1440fx with {:x} bytes of low ram",
+                low_size
+            );
+            qemu_get_mtree_fallback_pc(map_size, low_size)
+        } else {
+            info!("using fallback memory mappings for pc-i1440fx without ram above 4GB");
+            qemu_get_mtree_fallback_pc_smallmem(map_size)
+        }
+    }
+}
+
+/// Returns the amount of ram qemu places below 4GB for q35 machines.
+///
+/// Guests with at least 2816mb of ram are split at 2GB, the remaining ram is remapped above 4GB.
+/// A `max_below_4g` limit moves the split further down.
+fn q35_low_mem_size(map_size: umem, max_below_4g: Option<umem>) -> umem {
+    let lowmem = if map_size >= mem::mb(2816) {
+        mem::gb(2)
+    } else {
+        mem::mb(2816)
+    };
+    map_size.min(lowmem.min(max_below_4g.unwrap_or(mem::gb(4))))
+}
+
+/// Returns the amount of ram qemu places below 4GB for pc-i1440fx machines.
+///
+/// Guests with at least 3584mb of ram are split at 3GB, the remaining ram is remapped above 4GB.
+/// Smaller guests have all of their ram below 4GB, even if it extends past 3GB.
+/// A `max_below_4g` limit replaces the 3584mb threshold, ram is never split above 3GB.
+fn pc_low_mem_size(map_size: umem, max_below_4g: Option<umem>) -> umem {
+    let mut lowmem = max_below_4g.unwrap_or(mem::mb(3584));
+    if map_size >= lowmem {
+        lowmem = lowmem.min(mem::gb(3));
     }
+    map_size.min(lowmem)
+}
+
+/// Returns the `max-ram-below-4g` machine property, qemu ignores a value of 0 and caps it at 4GB.
+fn qemu_max_ram_below_4g(cmdline: &str) -> Option<umem> {
+    qemu_arg_prop(cmdline.split_whitespace(), "-machine", "max-ram-below-4g")
+        .as_deref()
+        .and_then(qemu_parse_size)
+        .filter(|size| *size > 0)
+        .map(|size| size.min(mem::gb(4)))
 }
 
-/// Returns hard-coded mem-mappings for q35 qemu machine types with more than 2816 mb of ram.
-fn qemu_get_mtree_fallback_q35(map_size: umem) -> Vec<Mapping> {
+/// Returns hard-coded mem-mappings for q35 qemu machine types with ram above 4GB.
+///
+/// The first `low_size` bytes of ram are mapped at 0, the rest is remapped to 4GB.
+fn qemu_get_mtree_fallback_q35(map_size: umem, low_size: umem) -> Vec<Mapping> {
     /*
     0000000000000000-000000000009ffff (prio 0, ram): pc.ram KVM
     00000000000c0000-00000000000c3fff (prio 0, rom): pc.ram @00000000000c0000 KVM
@@ -191,26 +1220,83 @@ fn qemu_get_mtree_fallback_q35(map_size: umem) -> Vec<Mapping> {
     0000000100000000-000000047fffffff (prio 0, ram): pc.ram @0000000080000000 KVM
     */
     vec![
-        Mapping::new(mem::mb(0), mem::gb(2), mem::mb(0)),
-        Mapping::new(mem::gb(4), map_size + mem::gb(2), mem::gb(2)),
+        Mapping::new(mem::mb(0), low_size, mem::mb(0)),
+        Mapping::new(mem::gb(4), mem::gb(4) + map_size - low_size, low_size),
     ]
 }
 
-/// Returns hard-coded mem-mappings for q35 qemu machine types with less than 2816 mb of ram.
+/// Returns hard-coded mem-mappings for q35 qemu machine types without ram above 4GB.
 fn qemu_get_mtree_fallback_q35_smallmem(map_size: umem) -> Vec<Mapping> {
     // Same as above but without the second mapping
     vec![Mapping::new(mem::mb(0), map_size, mem::mb(0))]
 }
 
+/// Returns hard-coded mem-mappings for microvm qemu machine types.
+///
+/// Up to 3GB of ram are mapped linearly at 0, the rest is remapped to 4GB.
+fn qemu_get_mtree_fallback_microvm(map_size: umem) -> Vec<Mapping> {
+    let low_size = map_size.min(mem::gb(3));
+    let mut mappings = vec![Mapping::new(mem::mb(0), low_size, mem::mb(0))];
+    if low_size < map_size {
+        mappings.push(Mapping::new(
+            mem::gb(4),
+            mem::gb(4) + map_size - low_size,
+            low_size,
+        ));
+    }
+    mappings
+}
+
+/// Returns hard-coded mem-mappings for s390x qemu machine types.
+///
+/// Ram is mapped linearly at 0 without any holes.
+fn qemu_get_mtree_fallback_s390x(map_size: umem) -> Vec<Mapping> {
+    vec![Mapping::new(0u64, map_size, 0u64)]
+}
+
+/// Returns hard-coded mem-mappings for riscv64 `virt` qemu machine types.
+///
+/// Dram starts at 2GB and is linear from there.
+fn qemu_get_mtree_fallback_riscv(map_size: umem) -> Vec<Mapping> {
+    vec![Mapping::new(mem::gb(2), map_size + mem::gb(2), 0u64)]
+}
+
+/// Returns true if the machine emulates the arm secure world (`-machine virt,secure=on`).
+fn qemu_secure_world(cmdline: &str) -> bool {
+    matches!(
+        qemu_arg_prop(cmdline.split_whitespace(), "-machine", "secure").as_deref(),
+        Some("on" | "yes" | "true")
+    )
+}
+
 /// Returns hard-coded mem-mappings for aarch64 qemu machine types.
+///
+/// The `virt` machine maps all of its ram as one contiguous region at 1GB (`VIRT_MEM` in
+/// `hw/arm/virt.c`), regardless of its size. The pcie and redistributor regions of the
+/// high memory map are placed above the end of the ram, so large guests are not split.
+///
+/// With `secure=on` the `virt` machine places 16mb of secure ram at `0x0e000000`.
+/// This region is a separate allocation in the qemu process and is not carved out
+/// of the non-secure ram, which still starts at 1GB and spans the entire `-m` size.
+/// The secure ram is therefore not part of these mappings.
 fn qemu_get_mtree_fallback_aarch64(map_size: umem) -> Vec<Mapping> {
-    // It is not known for sure whether this is correct for all ARM machines, but
-    // it seems like all memory on qemu ARM is shifted by 1GB and is linear from there.
     vec![Mapping::new(mem::gb(1), map_size + mem::gb(1), 0u64)]
 }
 
-/// Returns hard-coded mem-mappings for pc-i1440fx qemu machine types.
-fn qemu_get_mtree_fallback_pc(map_size: umem) -> Vec<Mapping> {
+/// Returns hard-coded mem-mappings for the aarch64 `sbsa-ref` qemu machine type.
+///
+/// The machine starts its ram at 1TB and is linear from there. The secure and
+/// non-secure flash below are separate allocations in the qemu process and are not
+/// part of these mappings.
+fn qemu_get_mtree_fallback_sbsa_ref(map_size: umem) -> Vec<Mapping> {
+    vec![Mapping::new(mem::gb(1024), mem::gb(1024) + map_size, 0u64)]
+}
+
+/// Returns hard-coded mem-mappings for pc-i1440fx qemu machine types with ram above 4GB.
+///
+/// The first `low_size` bytes of ram are mapped at 0 around the legacy rom areas,
+/// the rest is remapped to 4GB.
+fn qemu_get_mtree_fallback_pc(map_size: umem, low_size: umem) -> Vec<Mapping> {
     /*
     0000000000000000-00000000000bffff (prio 0, ram): pc.ram KVM
     00000000000c0000-00000000000cafff (prio 0, rom): pc.ram @00000000000c0000 KVM
@@ -225,19 +1311,37 @@ fn qemu_get_mtree_fallback_pc(map_size: umem) -> Vec<Mapping> {
         Mapping::new(0u64, mem::kb(768), 0u64),
         Mapping::new(mem::kb(812), mem::kb(824), mem::kb(812)),
         Mapping::new(mem::kb(928), mem::kb(960), mem::kb(928)),
-        Mapping::new(mem::mb(1), mem::gb(3), mem::mb(1)),
-        Mapping::new(mem::gb(4), map_size + mem::gb(1), mem::gb(3)),
+        Mapping::new(mem::mb(1), low_size, mem::mb(1)),
+        Mapping::new(mem::gb(4), mem::gb(4) + map_size - low_size, low_size),
+    ]
+}
+
+/// Returns hard-coded mem-mappings for pc-i1440fx qemu machine types with less than 3584mb of ram.
+fn qemu_get_mtree_fallback_pc_smallmem(map_size: umem) -> Vec<Mapping> {
+    // Same as above but the low ram ends with the guest ram and there is no high mapping
+    vec![
+        Mapping::new(0u64, mem::kb(768), 0u64),
+        Mapping::new(mem::kb(812), mem::kb(824), mem::kb(812)),
+        Mapping::new(mem::kb(928), mem::kb(960), mem::kb(928)),
+        Mapping::new(mem::mb(1), map_size, mem::mb(1)),
     ]
 }
 
 #[cfg(test)]
 #[cfg(all(target_os = "linux", feature = "qmp"))]
 mod tests {
-    use super::qmp_parse_mtree;
+    use super::*;
 
-    #[test]
-    fn test_parse_mtree() {
-        let mtreestr = r#"
+    use std::sync::{Mutex, OnceLock};
+
+    fn qmp_parse_mtree(mtreestr: &str, options: &MappingOptions) -> Vec<Mapping> {
+        qmp_parse_mtree_regions(mtreestr, options)
+            .into_iter()
+            .map(|r| r.mapping)
+            .collect()
+    }
+
+    const MTREE_Q35: &str = r#"
         FlatView #0
         AS \"I/O\", root: io
         Root memory region: io
@@ -589,7 +1693,9 @@ mod tests {
          0000000812502000-0000000812502fff (prio 0, i/o): virtio-pci-device-virtio-blk
          0000000812503000-0000000812503fff (prio 0, i/o): virtio-pci-notify-virtio-blk"#;
 
-        let mappings = qmp_parse_mtree(mtreestr);
+    #[test]
+    fn test_parse_mtree() {
+        let mappings = qmp_parse_mtree(MTREE_Q35, &Default::default());
 
         assert_eq!(mappings.len(), 4);
 
@@ -609,4 +1715,1058 @@ mod tests {
         assert_eq!(mappings[3].range_end, 0x480000000);
         assert_eq!(mappings[3].remap_start, 0x80000000);
     }
+
+    #[test]
+    fn test_parse_mtree_priorities() {
+        // pc.rom and isa-bios of MTREE_Q35 overlaid on a single pc.ram region like smram or
+        // shadowed roms do in an unresolved tree
+        let mtreestr = r#"
+        Root memory region: system
+         0000000000000000-000000007fffffff (prio 0, ram): pc.ram KVM
+         00000000000c0000-00000000000dffff (prio 1, rom): pc.rom KVM
+         00000000000e0000-00000000000fffff (prio 1, rom): isa-bios KVM
+        "#;
+
+        let mappings = qmp_parse_mtree(mtreestr, &Default::default());
+        let ranges = mappings
+            .iter()
+            .map(|m| (m.range_start, m.range_end, m.remap_start))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![(0, 0xc0000, 0), (0x100000, 0x80000000, 0x100000)]
+        );
+
+        // the higher priority region is kept if it is guest ram itself
+        let options = MappingOptions {
+            ram_region_names: vec!["pc.rom".into()],
+            ..Default::default()
+        };
+        let mappings = qmp_parse_mtree(mtreestr, &options);
+        let ranges = mappings
+            .iter()
+            .map(|m| (m.range_start, m.range_end, m.remap_start))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                (0, 0xc0000, 0),
+                (0xc0000, 0xe0000, 0),
+                (0x100000, 0x80000000, 0x100000)
+            ]
+        );
+
+        // the resolved dump is not changed
+        assert_eq!(qmp_parse_mtree(MTREE_Q35, &Default::default()).len(), 4);
+    }
+
+    #[test]
+    fn test_parse_device_bar() {
+        assert_eq!(
+            qmp_parse_device_bar(MTREE_Q35, "0000:0c:00.0", 1),
+            Some((0x800000000, 0x10000000))
+        );
+        // BAR 0 is split by the nvidia mirror quirk
+        assert_eq!(
+            qmp_parse_device_bar(MTREE_Q35, "0000:0c:00.0", 0),
+            Some((0xc0000000, 0x1000000))
+        );
+        assert_eq!(
+            qmp_parse_device_bar(MTREE_Q35, "0000:0c:00.1", 0),
+            Some((0xc2200000, 0x4000))
+        );
+        // i/o bars are not backed by memory
+        assert_eq!(qmp_parse_device_bar(MTREE_Q35, "0000:0c:00.0", 5), None);
+        assert_eq!(qmp_parse_device_bar(MTREE_Q35, "0000:0d:00.0", 0), None);
+    }
+
+    #[test]
+    fn test_parse_firmware_regions() {
+        assert_eq!(
+            qmp_parse_firmware_regions(MTREE_Q35),
+            vec![
+                ("pc.rom".to_owned(), 0xc0000, 0xe0000),
+                ("isa-bios".to_owned(), 0xe0000, 0x100000),
+                ("system.flash1".to_owned(), 0xffe00000, 0xffe20000),
+                ("system.flash0".to_owned(), 0xffe20000, 0x100000000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mtree_custom_region_names() {
+        let mtreestr = r#"
+        Root memory region: system
+         0000000000000000-000000007fffffff (prio 0, ram): custom.ram KVM
+         0000000100000000-000000017fffffff (prio 0, ram): custom.ram @0000000080000000 KVM
+        "#;
+
+        let options = MappingOptions {
+            ram_region_names: vec!["custom.ram".into()],
+            ..Default::default()
+        };
+        let mappings = qmp_parse_mtree(mtreestr, &options);
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[1].range_start, 0x100000000);
+        assert_eq!(mappings[1].range_end, 0x180000000);
+        assert_eq!(mappings[1].remap_start, 0x80000000);
+
+        // the default names are still accepted
+        assert_eq!(qmp_parse_mtree(MTREE_Q35, &options).len(), 4);
+    }
+
+    #[test]
+    fn test_parse_mtree_memory_backend() {
+        // same layout as MTREE_Q35 with `-object memory-backend-file,id=mem0 -numa node,memdev=mem0`
+        let mtreestr = MTREE_Q35.replace(": pc.ram ", ": mem0 ");
+        assert!(!mtreestr.contains("pc.ram"));
+
+        let expected = qmp_parse_mtree(MTREE_Q35, &Default::default());
+        let mappings = qmp_parse_mtree(&mtreestr, &Default::default());
+        assert_eq!(mappings.len(), expected.len());
+        for (mapping, expected) in mappings.iter().zip(expected.iter()) {
+            assert_eq!(mapping.range_start, expected.range_start);
+            assert_eq!(mapping.range_end, expected.range_end);
+            assert_eq!(mapping.remap_start, expected.remap_start);
+        }
+
+        let mtreestr = r#"
+        Root memory region: system
+         0000000000000000-000000007fffffff (prio 0, ram): custom.ram KVM
+         0000000100000000-000000017fffffff (prio 0, ram): custom.ram @0000000080000000 KVM
+        "#;
+        assert_eq!(qmp_parse_mtree(mtreestr, &Default::default()).len(), 2);
+    }
+
+    /// Collects all log messages so tests can check what was logged.
+    struct CaptureLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn captured_logs() -> &'static CaptureLogger {
+        static LOGGER: OnceLock<&'static CaptureLogger> = OnceLock::new();
+        LOGGER.get_or_init(|| {
+            let logger = Box::leak(Box::new(CaptureLogger(Mutex::new(vec![]))));
+            log::set_logger(logger).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+            logger
+        })
+    }
+
+    #[test]
+    fn test_dump_mtree() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let logger = captured_logs();
+        let dumped = |marker: &str| {
+            logger
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|msg| msg.contains(marker))
+        };
+
+        for dump_mtree in [true, false] {
+            let marker = format!("FlatView #dump-{}", dump_mtree);
+            let mtreestr = format!(
+                "{}\nRoot memory region: system\n 0000000000000000-0000000000000fff (prio 0, ram): pc.ram KVM",
+                marker
+            );
+            let mock = MockQmp::spawn(move |cmd, _| match cmd {
+                "human-monitor-command" => Ok(json!(mtreestr)),
+                _ => Err(format!("unknown command {}", cmd)),
+            });
+
+            let options = MappingOptions {
+                dump_mtree,
+                ..Default::default()
+            };
+            let mappings = qmp_get_mtree(Some(&mock.monitor()), &options).unwrap();
+            assert_eq!(mappings.len(), 1);
+            assert_eq!(dumped(&marker), dump_mtree);
+        }
+    }
+
+    #[test]
+    fn test_parse_mtree_tabs() {
+        let mtreestr = "Root memory region: system\n\t0000000000000000-000000000009ffff\t(prio 0, ram):\tpc.ram  KVM\n\t0000000100000000-000000017fffffff (prio 0,\tram): pc.ram\t@0000000080000000\tKVM\n";
+
+        let mappings = qmp_parse_mtree(mtreestr, &Default::default());
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].range_end, 0xa0000);
+        assert_eq!(mappings[1].range_start, 0x100000000);
+        assert_eq!(mappings[1].remap_start, 0x80000000);
+    }
+
+    #[test]
+    fn test_fallback_q35_low_split() {
+        let mappings = qemu_get_mtree_fallback("q35", &CTup2(Address::NULL, mem::gb(8)), None);
+        assert_eq!(mappings[1].range_start, mem::gb(4));
+        assert_eq!(mappings[1].range_end, mem::gb(10));
+        assert_eq!(mappings[1].remap_start, mem::gb(2));
+
+        // a 1.5gb low split remaps the high region right after the low ram
+        let mappings = qemu_get_mtree_fallback_q35(mem::gb(8), mem::mb(1536));
+        assert_eq!(mappings[0].range_end, mem::mb(1536));
+        assert_eq!(mappings[1].range_start, mem::gb(4));
+        assert_eq!(mappings[1].range_end, mem::gb(4) + mem::mb(6656));
+        assert_eq!(mappings[1].remap_start, mem::mb(1536));
+    }
+
+    #[test]
+    fn test_fallback_microvm() {
+        let mappings = qemu_get_mtree_fallback("microvm", &CTup2(Address::NULL, mem::gb(2)), None);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].range_end, mem::gb(2));
+
+        let mappings = qemu_get_mtree_fallback("microvm", &CTup2(Address::NULL, mem::gb(8)), None);
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].range_end, mem::gb(3));
+        assert_eq!(mappings[1].range_start, mem::gb(4));
+        assert_eq!(mappings[1].range_end, mem::gb(9));
+        assert_eq!(mappings[1].remap_start, mem::gb(3));
+    }
+
+    #[test]
+    fn test_coalesce_mappings() {
+        let mut split = Mapping::new(0x3000, 0x4000, 0x3000);
+        split.host_base = Some(0x7f00_0000_0000);
+        let mappings = coalesce_mappings(vec![
+            Mapping::new(0, 0x1000, 0),
+            Mapping::new(0x1000, 0x2000, 0x1000),
+            Mapping::new(0x2000, 0x3000, 0x2000),
+            // adjacent in guest memory, but backed by another ram block
+            split,
+            // adjacent remap, but a hole in guest memory
+            Mapping::new(0x5000, 0x6000, 0x3000),
+            Mapping::new(0x6000, 0x8000, 0x4000),
+            // adjacent in guest memory, but not in the remap
+            Mapping::new(0x8000, 0x9000, 0x10000),
+        ]);
+
+        let ranges = mappings
+            .iter()
+            .map(|m| (m.range_start, m.range_end, m.remap_start))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                (0, 0x3000, 0),
+                (0x3000, 0x4000, 0x3000),
+                (0x5000, 0x8000, 0x3000),
+                (0x8000, 0x9000, 0x10000),
+            ]
+        );
+        assert_eq!(mappings[1].host_base, Some(0x7f00_0000_0000));
+    }
+
+    #[test]
+    fn test_fallback_s390x() {
+        assert_eq!(
+            qemu_machine("/usr/bin/qemu-system-s390x -m 4G", "s390x"),
+            "s390x"
+        );
+        assert_eq!(
+            qemu_machine("qemu-system-s390x -machine s390-ccw-virtio -m 4G", "s390x"),
+            "s390x"
+        );
+        assert_eq!(
+            qemu_machine("qemu-system-x86_64 -machine q35", "x86_64"),
+            "q35"
+        );
+
+        let mappings =
+            qemu_get_mtree_fallback("s390-ccw-virtio", &CTup2(Address::NULL, mem::gb(4)), None);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].range_start, 0);
+        assert_eq!(mappings[0].range_end, mem::gb(4));
+        assert_eq!(mappings[0].remap_start, 0);
+    }
+
+    #[test]
+    fn test_fallback_riscv() {
+        let machine = qemu_machine("qemu-system-riscv64 -machine virt -m 4G", "riscv64");
+        assert_eq!(machine, "riscv64-virt");
+
+        let mappings = qemu_get_mtree_fallback(&machine, &CTup2(Address::NULL, mem::gb(4)), None);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].range_start, mem::gb(2));
+        assert_eq!(mappings[0].range_end, mem::gb(6));
+        assert_eq!(mappings[0].remap_start, 0);
+
+        assert_eq!(
+            qemu_machine("qemu-system-aarch64 -machine virt -m 4G", "aarch64"),
+            "aarch64"
+        );
+    }
+
+    #[test]
+    fn test_binary_arch() {
+        assert_eq!(
+            qemu_binary_arch("/usr/bin/qemu-system-aarch64 -m 4G"),
+            "aarch64"
+        );
+        assert_eq!(qemu_binary_arch("qemu-system-x86_64 -m 4G"), "x86_64");
+        assert_eq!(qemu_binary_arch("/usr/libexec/qemu-kvm -m 4G"), "x86_64");
+        assert_eq!(qemu_target_arch("qemu-system-riscv64", None), "riscv64");
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "qmp"))]
+    fn test_fallback_qmp_arch() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        // the memory tree is unavailable so the fallback mappings are used
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "query-target" => Ok(json!({ "arch": "aarch64" })),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        let qmp = mock.monitor();
+
+        // a renamed binary does not reveal the architecture
+        let cmdline = "/opt/vm/bin/guest-launcher -machine virt -m 4G";
+        assert_eq!(qemu_target_arch(cmdline, Some(&qmp)), "aarch64");
+
+        let (mem_map, source) = qemu_mem_mappings(
+            cmdline,
+            &CTup2(Address::NULL, mem::gb(4)),
+            Some(&qmp),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(source, MappingSource::Fallback);
+        let first = mem_map.iter().next().unwrap();
+        assert_eq!(first.base(), Address::from(mem::gb(1)));
+    }
+
+    #[test]
+    fn test_fallback_backend_size() {
+        assert_eq!(
+            qemu_ram_backend_size("qemu-system-x86_64 -object memory-backend-ram,id=mem0,size=2G -object memory-backend-ram,id=mem1,size=2048M -numa node,memdev=mem0 -numa node,memdev=mem1 -object memory-backend-file,id=shmem,size=1M,mem-path=/dev/shm/ivshmem"),
+            Some(mem::gb(4))
+        );
+        assert_eq!(
+            qemu_ram_backend_size("qemu-system-x86_64 -object memory-backend-ram,id=mem0,size=2G"),
+            None
+        );
+
+        // the hugepage backed mapping is larger than the declared guest ram
+        let cmdline = "qemu-system-x86_64 -machine pc,memory-backend=mem0 -object memory-backend-file,id=mem0,size=3584M,mem-path=/dev/hugepages";
+        let mappings =
+            qemu_fallback_mappings(cmdline, &CTup2(Address::NULL, mem::gb(4)), None).unwrap();
+        let high = mappings.last().unwrap();
+        assert_eq!(high.range_start, mem::gb(4));
+        assert_eq!(high.range_end, mem::gb(4) + mem::mb(512));
+        assert_eq!(high.remap_start, mem::gb(3));
+
+        // only the ram of the located backend is mapped
+        let cmdline = "qemu-system-x86_64 -machine pc -object memory-backend-ram,id=mem0,size=2G -object memory-backend-ram,id=mem1,size=2G -numa node,memdev=mem0 -numa node,memdev=mem1";
+        let mappings =
+            qemu_fallback_mappings(cmdline, &CTup2(Address::NULL, mem::gb(2)), None).unwrap();
+        assert_eq!(mappings.last().unwrap().range_end, mem::gb(2));
+        assert!(mappings.iter().all(|m| m.range_end <= mem::gb(2)));
+    }
+
+    #[test]
+    fn test_fallback_size_check() {
+        let logger = captured_logs();
+        let warned = |map_size: umem| {
+            logger
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|msg| msg.contains(&format!("{:x} bytes large", map_size)))
+        };
+
+        // the legacy rom area is not mapped by the pc fallback on purpose
+        for (cmdline, map_size) in [
+            ("qemu-system-x86_64 -machine pc", mem::mb(2000)),
+            ("qemu-system-x86_64 -machine pc", mem::gb(6)),
+            ("qemu-system-x86_64 -machine q35", mem::gb(6)),
+            ("qemu-system-aarch64 -machine virt", mem::gb(3)),
+        ] {
+            let qemu_map = CTup2(Address::from(0x7f0000000000u64), map_size);
+            let mappings = qemu_fallback_mappings(cmdline, &qemu_map, None).unwrap();
+            let expected = map_size - legacy_overlap(0, map_size);
+            assert_eq!(mapped_size(&mappings), expected);
+            check_fallback_size(cmdline, &mappings, map_size);
+            assert!(!warned(expected));
+        }
+
+        // a layout which loses the ram above 4gb
+        let map_size = mem::gb(5) + mem::mb(8);
+        let mappings = vec![
+            Mapping::new(0u64, mem::kb(640), 0u64),
+            Mapping::new(mem::mb(1), mem::gb(2), mem::mb(1)),
+        ];
+        check_fallback_size("qemu-system-x86_64", &mappings, map_size);
+        assert!(warned(map_size - legacy_overlap(0, map_size)));
+    }
+
+    #[test]
+    fn test_parse_mtree_tcg() {
+        let mtreestr = r#"
+        FlatView #2
+         AS "memory", root: system
+         AS "cpu-memory-0", root: system
+         Root memory region: system
+          0000000000000000-000000000009ffff (prio 0, ram): pc.ram
+          00000000000a0000-00000000000bffff (prio 1, i/o): vga-lowmem
+          00000000000c0000-00000000000dffff (prio 1, rom): pc.rom
+          00000000000e0000-00000000000fffff (prio 1, rom): isa-bios
+          0000000000100000-000000007fffffff (prio 0, ram): pc.ram @0000000000100000
+          00000000fd000000-00000000fdffffff (prio 1, ram): vga.vram
+          00000000fffc0000-00000000ffffffff (prio 0, rom): pc.bios
+          0000000100000000-000000017fffffff (prio 0, ram): pc.ram @0000000080000000 TCG
+        "#;
+
+        let mappings = qmp_parse_mtree(mtreestr, &Default::default());
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(mappings[0].range_end, 0xa0000);
+        assert_eq!(mappings[1].range_start, 0x100000);
+        assert_eq!(mappings[1].range_end, 0x80000000);
+        assert_eq!(mappings[1].remap_start, 0x100000);
+        assert_eq!(mappings[2].range_start, 0x100000000);
+        assert_eq!(mappings[2].remap_start, 0x80000000);
+    }
+
+    #[test]
+    fn test_hybrid_fills_gap() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        // the ram remapped above 4gb is only partially reported
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "human-monitor-command" => Ok(json!(
+                r#"
+            Root memory region: system
+             0000000000000000-000000000009ffff (prio 0, ram): pc.ram KVM
+             0000000000100000-000000007fffffff (prio 0, ram): pc.ram @0000000000100000 KVM
+             0000000100000000-000000013fffffff (prio 0, ram): pc.ram @0000000080000000 KVM
+            "#
+            )),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(4));
+        let total = |mem_map: &MemoryMap<(Address, umem)>| {
+            mem_map.iter().map(|m| m.output().1).sum::<umem>()
+        };
+
+        let (mem_map, _) = qemu_mem_mappings(
+            "qemu-system-x86_64 -machine q35",
+            &qemu_map,
+            Some(&mock.monitor()),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(total(&mem_map), mem::gb(3) - 0x60000);
+
+        let (mem_map, source) = qemu_mem_mappings(
+            "qemu-system-x86_64 -machine q35",
+            &qemu_map,
+            Some(&mock.monitor()),
+            &MappingOptions {
+                hybrid: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(source, MappingSource::Qmp);
+        // everything but the legacy vga and bios window below 1mb is mapped
+        assert_eq!(total(&mem_map), mem::gb(4) - 0x60000);
+        assert_eq!(
+            mem_map
+                .iter()
+                .map(|m| (
+                    m.base().to_umem(),
+                    (m.output().0 - qemu_map.0) as umem,
+                    m.output().1
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 0, 0xa0000),
+                (0x100000, 0x100000, mem::gb(2) - 0x100000),
+                // the filled gap is coalesced with the adjacent qmp mapping
+                (mem::gb(4), mem::gb(2), mem::gb(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mtree_cache() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let cmdline = "qemu-system-x86_64 -machine q35";
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(2));
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "human-monitor-command" => Ok(json!(MTREE_Q35)),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        let mtree_queries = || {
+            mock.commands()
+                .iter()
+                .filter(|cmd| *cmd == "human-monitor-command")
+                .count()
+        };
+        let mut options = MappingOptions {
+            mtree_cache: true,
+            pid: Some(0xfff0),
+            ..Default::default()
+        };
+
+        for _ in 0..2 {
+            let (mem_map, source) =
+                qemu_mem_mappings(cmdline, &qemu_map, Some(&mock.monitor()), &options).unwrap();
+            assert_eq!(source, MappingSource::Qmp);
+            assert_eq!(mem_map.iter().count(), 4);
+        }
+        assert_eq!(mtree_queries(), 1);
+
+        // a different command line is a different qemu instance
+        qemu_mem_mappings(
+            "qemu-system-x86_64 -machine q35 -name other",
+            &qemu_map,
+            Some(&mock.monitor()),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(mtree_queries(), 2);
+
+        // the raw tree is not cached and has to be queried to be dumped
+        options.dump_mtree = true;
+        qemu_mem_mappings(cmdline, &qemu_map, Some(&mock.monitor()), &options).unwrap();
+        assert_eq!(mtree_queries(), 3);
+        options.dump_mtree = false;
+
+        options.mtree_cache = false;
+        qemu_mem_mappings(cmdline, &qemu_map, Some(&mock.monitor()), &options).unwrap();
+        assert_eq!(mtree_queries(), 4);
+    }
+
+    #[test]
+    fn test_forced_source() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let cmdline = "qemu-system-x86_64 -machine q35";
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(2));
+        let forced = |source| MappingOptions {
+            source: Some(source),
+            ..Default::default()
+        };
+
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "human-monitor-command" => Ok(json!(MTREE_Q35)),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        let empty = MockQmp::spawn(|cmd, _| match cmd {
+            "human-monitor-command" => Ok(json!("Root memory region: system\n")),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+
+        // qmp
+        let (mem_map, source) = qemu_mem_mappings(
+            cmdline,
+            &qemu_map,
+            Some(&mock.monitor()),
+            &forced(MappingSource::Qmp),
+        )
+        .unwrap();
+        assert_eq!(source, MappingSource::Qmp);
+        assert_eq!(mem_map.iter().count(), 4);
+        assert!(qemu_mem_mappings(cmdline, &qemu_map, None, &forced(MappingSource::Qmp)).is_err());
+        assert!(qemu_mem_mappings(
+            cmdline,
+            &qemu_map,
+            Some(&empty.monitor()),
+            &forced(MappingSource::Qmp)
+        )
+        .is_err());
+
+        // fallback, the memory tree is not queried even though qmp is available
+        let commands = mock.commands().len();
+        let (mem_map, source) = qemu_mem_mappings(
+            cmdline,
+            &qemu_map,
+            Some(&mock.monitor()),
+            &forced(MappingSource::Fallback),
+        )
+        .unwrap();
+        assert_eq!(source, MappingSource::Fallback);
+        assert_eq!(mem_map.iter().count(), 1);
+        assert_eq!(mock.commands()[commands..], ["query-target"]);
+
+        // file
+        assert_eq!(
+            qemu_mem_mappings(cmdline, &qemu_map, None, &forced(MappingSource::File))
+                .err()
+                .map(|err| err.1),
+            Some(ErrorKind::Configuration)
+        );
+    }
+
+    #[test]
+    fn test_fallback_pc_smallmem() {
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(2));
+        let mappings = qemu_get_mtree_fallback("pc", &qemu_map, None);
+        assert_eq!(mappings.len(), 4);
+        assert!(mappings.iter().all(|m| m.range_end <= mem::gb(2)));
+        assert_eq!(mappings.last().unwrap().range_end, mem::gb(2));
+
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(4));
+        let mappings = qemu_get_mtree_fallback("pc", &qemu_map, None);
+        let high = mappings.last().unwrap();
+        assert_eq!(
+            (high.range_start, high.range_end, high.remap_start),
+            (mem::gb(4), mem::gb(5), mem::gb(3))
+        );
+    }
+
+    #[test]
+    fn test_fallback_max_ram_below_4g() {
+        assert_eq!(
+            qemu_max_ram_below_4g("qemu-system-x86_64 -machine q35,max-ram-below-4g=1G"),
+            Some(mem::gb(1))
+        );
+        assert_eq!(
+            qemu_max_ram_below_4g("qemu-system-x86_64 -machine q35,max-ram-below-4g=8G"),
+            Some(mem::gb(4))
+        );
+        assert_eq!(
+            qemu_max_ram_below_4g("qemu-system-x86_64 -machine q35,max-ram-below-4g=0"),
+            None
+        );
+        assert_eq!(
+            qemu_max_ram_below_4g("qemu-system-x86_64 -machine q35"),
+            None
+        );
+
+        // the default split of q35 is unaffected by a limit above it
+        assert_eq!(q35_low_mem_size(mem::gb(2), None), mem::gb(2));
+        assert_eq!(q35_low_mem_size(mem::gb(4), None), mem::gb(2));
+        assert_eq!(q35_low_mem_size(mem::gb(4), Some(mem::gb(3))), mem::gb(2));
+        assert_eq!(q35_low_mem_size(mem::gb(2), Some(mem::gb(1))), mem::gb(1));
+        assert_eq!(pc_low_mem_size(mem::gb(4), None), mem::gb(3));
+        assert_eq!(
+            pc_low_mem_size(mem::gb(2), Some(mem::mb(1536))),
+            mem::mb(1536)
+        );
+        assert_eq!(pc_low_mem_size(mem::gb(1), Some(mem::mb(1536))), mem::gb(1));
+
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(2));
+        let mappings = qemu_fallback_mappings(
+            "qemu-system-x86_64 -machine q35,max-ram-below-4g=1G",
+            &qemu_map,
+            None,
+        )
+        .unwrap();
+        let ranges = mappings
+            .iter()
+            .map(|m| (m.range_start, m.range_end, m.remap_start))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![(0, mem::gb(1), 0), (mem::gb(4), mem::gb(5), mem::gb(1))]
+        );
+    }
+
+    #[test]
+    fn test_fallback_pc_low_mem() {
+        // qemu only splits the ram at 3GB from 3584mb on, everything below stays below 4GB
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::mb(3328));
+        let mappings = qemu_get_mtree_fallback("pc", &qemu_map, None);
+        assert_eq!(mappings.len(), 4);
+        assert_eq!(mappings.last().unwrap().range_end, mem::mb(3328));
+
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::mb(3584));
+        let mappings = qemu_get_mtree_fallback("pc", &qemu_map, None);
+        let low = &mappings[3];
+        assert_eq!((low.range_start, low.range_end), (mem::mb(1), mem::gb(3)));
+        let high = mappings.last().unwrap();
+        assert_eq!(
+            (high.range_start, high.range_end, high.remap_start),
+            (mem::gb(4), mem::gb(4) + mem::mb(512), mem::gb(3))
+        );
+    }
+
+    #[test]
+    fn test_error_variants() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(2));
+        let forced = |source| MappingOptions {
+            source: Some(source),
+            ..Default::default()
+        };
+        let variant = |res: Result<(MemoryMap<(Address, umem)>, MappingSource)>| {
+            res.err().map(|err| QemuError::try_from(err).unwrap())
+        };
+
+        let empty = MockQmp::spawn(|cmd, _| match cmd {
+            "human-monitor-command" => Ok(json!("Root memory region: system\n")),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        assert_eq!(
+            variant(qemu_mem_mappings(
+                "qemu-system-x86_64",
+                &qemu_map,
+                Some(&empty.monitor()),
+                &forced(MappingSource::Qmp)
+            )),
+            Some(QemuError::QmpEmptyMtree)
+        );
+        assert_eq!(
+            variant(qemu_mem_mappings(
+                "qemu-system-x86_64",
+                &qemu_map,
+                None,
+                &forced(MappingSource::Qmp)
+            )),
+            Some(QemuError::QmpNotConfigured)
+        );
+
+        // a socket which does not speak qmp
+        let path =
+            std::env::temp_dir().join(format!("memflow-qemu-not-qmp-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = std::io::Write::write_all(&mut stream, b"SSH-2.0-OpenSSH_9.6\r\n");
+            }
+        });
+        let not_qmp = QmpMonitor::from_addr(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            variant(qemu_mem_mappings(
+                "qemu-system-x86_64",
+                &qemu_map,
+                Some(&not_qmp),
+                &forced(MappingSource::Qmp)
+            )),
+            Some(QemuError::QmpHandshake)
+        );
+        let _ = std::fs::remove_file(&path);
+
+        let unreachable = QmpMonitor::from_addr("unix:/nonexistent/memflow-qemu.sock").unwrap();
+        assert_eq!(
+            variant(qemu_mem_mappings(
+                "qemu-system-x86_64",
+                &qemu_map,
+                Some(&unreachable),
+                &forced(MappingSource::Qmp)
+            )),
+            Some(QemuError::QmpConnect)
+        );
+
+        let garbage = MockQmp::spawn(|cmd, _| match cmd {
+            "human-monitor-command" => Ok(json!("Invalid parameter 'addr'")),
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+        assert_eq!(
+            qmp_gpa2hva(&garbage.monitor(), 0)
+                .err()
+                .map(|err| QemuError::try_from(err).unwrap()),
+            Some(QemuError::QmpParse)
+        );
+
+        // there is no fallback layout for ppc64, with or without qmp
+        for options in [forced(MappingSource::Fallback), MappingOptions::default()] {
+            assert_eq!(
+                variant(qemu_mem_mappings(
+                    "/usr/bin/qemu-system-ppc64 -machine pseries",
+                    &qemu_map,
+                    None,
+                    &options
+                )),
+                Some(QemuError::UnsupportedMachine)
+            );
+        }
+        assert!(qemu_target_supported(&qemu_binary_arch(
+            "qemu-kvm -machine q35"
+        )));
+        assert!(qemu_target_supported(&qemu_binary_arch(
+            "/usr/bin/qemu-system-aarch64"
+        )));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_forced_source_file() {
+        let dir = std::env::temp_dir();
+        let valid = dir.join(format!("memflow-qemu-map-{}.json", std::process::id()));
+        let invalid = dir.join(format!(
+            "memflow-qemu-map-{}-invalid.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &valid,
+            r#"[
+                {"range_start": 0, "range_end": 655360, "remap_start": 0},
+                {"range_start": 1048576, "range_end": 2147483648, "remap_start": 1048576}
+            ]"#,
+        )
+        .unwrap();
+        std::fs::write(&invalid, r#"[{"range_start": 0}]"#).unwrap();
+        let overlapping = dir.join(format!(
+            "memflow-qemu-map-{}-overlapping.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &overlapping,
+            r#"[
+                {"range_start": 4096, "range_end": 12288, "remap_start": 0},
+                {"range_start": 0, "range_end": 8192, "remap_start": 0}
+            ]"#,
+        )
+        .unwrap();
+
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(2));
+        let options = |path: &Path| MappingOptions {
+            source: Some(MappingSource::File),
+            map_file: Some(path.to_owned()),
+            ..Default::default()
+        };
+
+        let (mem_map, source) =
+            qemu_mem_mappings("qemu-system-x86_64", &qemu_map, None, &options(&valid)).unwrap();
+        assert_eq!(source, MappingSource::File);
+        let mappings = mem_map
+            .iter()
+            .map(|m| (m.base().to_umem(), m.output().0.to_umem()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mappings,
+            vec![(0, 0x7f0000000000), (0x100000, 0x7f0000100000)]
+        );
+
+        assert!(
+            qemu_mem_mappings("qemu-system-x86_64", &qemu_map, None, &options(&invalid)).is_err()
+        );
+        assert!(qemu_mem_mappings(
+            "qemu-system-x86_64",
+            &qemu_map,
+            None,
+            &options(&overlapping)
+        )
+        .is_err());
+        let empty = dir.join(format!(
+            "memflow-qemu-map-{}-empty.json",
+            std::process::id()
+        ));
+        std::fs::write(&empty, "[]").unwrap();
+        assert!(
+            qemu_mem_mappings("qemu-system-x86_64", &qemu_map, None, &options(&empty)).is_err()
+        );
+
+        // the map file alone selects the file source
+        let implicit = MappingOptions {
+            map_file: Some(valid.clone()),
+            ..Default::default()
+        };
+        let (_, source) =
+            qemu_mem_mappings("qemu-system-x86_64", &qemu_map, None, &implicit).unwrap();
+        assert_eq!(source, MappingSource::File);
+        assert!(qemu_mem_mappings(
+            "qemu-system-x86_64",
+            &qemu_map,
+            None,
+            &options(&dir.join("memflow-qemu-missing.json"))
+        )
+        .is_err());
+
+        std::fs::remove_file(&valid).unwrap();
+        std::fs::remove_file(&invalid).unwrap();
+        std::fs::remove_file(&overlapping).unwrap();
+        std::fs::remove_file(&empty).unwrap();
+    }
+
+    #[test]
+    fn test_numa_nodes() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, args| match cmd {
+            "human-monitor-command" => match args["command-line"].as_str().unwrap() {
+                "info mtree -f" => Ok(json!(
+                    r#"
+            Root memory region: system
+             0000000000000000-000000000009ffff (prio 0, ram): ram-node0 KVM
+             00000000000c0000-000000007fffffff (prio 0, ram): ram-node0 @00000000000c0000 KVM
+             0000000080000000-00000000bfffffff (prio 0, ram): ram-node1 KVM
+             00000000fee00000-00000000feefffff (prio 4096, i/o): kvm-apic-msi
+             0000000100000000-000000013fffffff (prio 0, ram): ram-node1 @0000000040000000 KVM
+            "#
+                )),
+                "gpa2hva 0x0" => Ok(json!(
+                    "Host virtual address for 0x0 (ram-node0) is 0x7f0000000000\r\n"
+                )),
+                // the second node is allocated below the first one
+                "gpa2hva 0x80000000" => Ok(json!(
+                    "Host virtual address for 0x80000000 (ram-node1) is 0x7e0000000000\r\n"
+                )),
+                cmdline => Err(format!("unknown command {}", cmdline)),
+            },
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+
+        let (mem_map, source) = qemu_mem_mappings(
+            "qemu-system-x86_64 -machine q35 -m 4G -numa node,mem=2G -numa node,mem=2G",
+            &CTup2(Address::from(0x7f0000000000u64), mem::gb(2)),
+            Some(&mock.monitor()),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(source, MappingSource::Qmp);
+
+        let mappings = mem_map
+            .iter()
+            .map(|m| (m.base().to_umem(), m.output().0.to_umem(), m.output().1))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mappings,
+            vec![
+                (0x0, 0x7f0000000000, 0xa0000),
+                (0xc0000, 0x7f00000c0000, 0x7ff40000),
+                (0x80000000, 0x7e0000000000, 0x40000000),
+                (0x100000000, 0x7e0040000000, 0x40000000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_backend() {
+        use crate::qmp::tests::MockQmp;
+        use serde_json::json;
+
+        let mock = MockQmp::spawn(|cmd, args| match cmd {
+            "human-monitor-command" => match args["command-line"].as_str().unwrap() {
+                "info mtree -f" => Ok(json!(
+                    r#"
+            Root memory region: system
+             0000000000000000-000000000009ffff (prio 0, ram): mem0 KVM
+             00000000000c0000-000000007fffffff (prio 0, ram): mem0 @00000000000c0000 KVM
+             0000000080000000-00000000bfffffff (prio 0, ram): mem1 KVM
+             0000000100000000-000000013fffffff (prio 0, ram): mem1 @0000000040000000 KVM
+            "#
+                )),
+                "gpa2hva 0x0" => Ok(json!(
+                    "Host virtual address for 0x0 (mem0) is 0x7f0000000000\r\n"
+                )),
+                "gpa2hva 0x80000000" => Ok(json!(
+                    "Host virtual address for 0x80000000 (mem1) is 0x7e0000000000\r\n"
+                )),
+                cmdline => Err(format!("unknown command {}", cmdline)),
+            },
+            _ => Err(format!("unknown command {}", cmd)),
+        });
+
+        let cmdline = "qemu-system-x86_64 -machine q35 -object memory-backend-ram,id=mem0,size=2G -object memory-backend-ram,id=mem1,size=2G -numa node,memdev=mem0 -numa node,memdev=mem1";
+        let qemu_map = CTup2(Address::from(0x7f0000000000u64), mem::gb(2));
+        let backend = |id: &str| MappingOptions {
+            backend: Some(id.to_owned()),
+            ..Default::default()
+        };
+
+        let (mem_map, source) =
+            qemu_mem_mappings(cmdline, &qemu_map, Some(&mock.monitor()), &backend("mem1")).unwrap();
+        assert_eq!(source, MappingSource::Qmp);
+        let mappings = mem_map
+            .iter()
+            .map(|m| (m.base().to_umem(), m.output().0.to_umem(), m.output().1))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            mappings,
+            vec![
+                (0x80000000, 0x7e0000000000, 0x40000000),
+                (0x100000000, 0x7e0040000000, 0x40000000),
+            ]
+        );
+
+        // an unknown backend does not fall back to the hard-coded mappings
+        let err = qemu_mem_mappings(cmdline, &qemu_map, Some(&mock.monitor()), &backend("mem2"))
+            .unwrap_err();
+        assert_eq!(err.1, ErrorKind::NotFound);
+        assert!(qemu_mem_mappings(cmdline, &qemu_map, None, &backend("mem1")).is_err());
+    }
+
+    #[test]
+    fn test_ram_backends() {
+        assert_eq!(
+            qemu_ram_backends("qemu-system-x86_64 -object memory-backend-file,id=mem0,size=2G,mem-path=/dev/hugepages -object memory-backend-ram,id=mem1,size=2G -numa node,nodeid=0,memdev=mem0 -numa node,nodeid=1,memdev=mem1 -object memory-backend-file,id=shmem,size=1M,mem-path=/dev/shm/ivshmem"),
+            vec!["mem0", "mem1"]
+        );
+        assert_eq!(
+            qemu_ram_backends("qemu-system-x86_64 -machine q35,memory-backend=pc.ram0"),
+            vec!["pc.ram0"]
+        );
+        assert_eq!(
+            parse_gpa2hva("Host virtual address for 0x1000 (pc.ram) is 0x7f5a80001000\r\n"),
+            Some(0x7f5a80001000)
+        );
+    }
+
+    #[test]
+    fn test_secure_world() {
+        assert!(qemu_secure_world(
+            "qemu-system-aarch64 -machine virt,secure=on,virtualization=on -m 4G"
+        ));
+        assert!(!qemu_secure_world(
+            "qemu-system-aarch64 -machine virt,secure=off -m 4G"
+        ));
+        assert!(!qemu_secure_world(
+            "qemu-system-aarch64 -machine virt -m 4G"
+        ));
+
+        // the secure ram does not shift the non-secure ram
+        let mappings = qemu_get_mtree_fallback("aarch64", &CTup2(Address::NULL, mem::gb(4)), None);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].range_start, mem::gb(1));
+        assert_eq!(mappings[0].range_end, mem::gb(5));
+    }
+
+    #[test]
+    fn test_fallback_aarch64_high_mem() {
+        // the ram of large guests is contiguous above 4GB
+        let mappings = qemu_get_mtree_fallback("aarch64", &CTup2(Address::NULL, mem::gb(16)), None);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].range_start, mem::gb(1));
+        assert_eq!(mappings[0].range_end, mem::gb(17));
+        assert_eq!(mappings[0].remap_start, 0);
+    }
+
+    #[test]
+    fn test_fallback_sbsa_ref() {
+        let machine = qemu_machine("qemu-system-aarch64 -machine sbsa-ref -m 4G", "aarch64");
+        assert_eq!(machine, "aarch64-sbsa-ref");
+        assert_eq!(
+            qemu_machine(
+                "qemu-system-aarch64 -machine type=sbsa-ref,accel=tcg -m 4G",
+                "aarch64"
+            ),
+            "aarch64-sbsa-ref"
+        );
+
+        let mappings = qemu_get_mtree_fallback(&machine, &CTup2(Address::NULL, mem::gb(4)), None);
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].range_start, 0x100_0000_0000);
+        assert_eq!(mappings[0].range_end, 0x101_0000_0000);
+        assert_eq!(mappings[0].remap_start, 0);
+    }
 }
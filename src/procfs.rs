@@ -0,0 +1,871 @@
+use memflow::prelude::v1::{umem, Address, Error, ErrorKind, ErrorOrigin, Pid, Result};
+
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+use std::{
+    fs::{self, File},
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::{FileExt, FileTypeExt, MetadataExt},
+    },
+    path::PathBuf,
+    sync::Arc,
+};
+
+/// Mount point of the procfs of the connector's own pid namespace.
+pub(crate) const PROCFS_ROOT: &str = "/proc";
+
+/// Returns true if the process has `/dev/kvm` open.
+///
+/// This is a strong indicator for a qemu process running a kvm accelerated guest.
+/// Processes whose file descriptors cannot be inspected are treated as not using kvm.
+#[cfg(target_os = "linux")]
+pub fn has_kvm_fd(proc_root: &Path, pid: Pid) -> bool {
+    fs::read_dir(proc_root.join(pid.to_string()).join("fd"))
+        .map(|fds| {
+            fds.filter_map(|fd| fd.ok())
+                .filter_map(|fd| fs::read_link(fd.path()).ok())
+                .any(|target| target == Path::new("/dev/kvm"))
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn has_kvm_fd(_proc_root: &Path, _pid: Pid) -> bool {
+    false
+}
+
+/// Advises the kernel that the given host ranges of the qemu process will be accessed soon.
+///
+/// This uses `process_madvise(MADV_WILLNEED)` which causes swapped out pages to be read back
+/// asynchronously. It requires Linux 5.10 or newer, `CAP_SYS_NICE` and ptrace access to the
+/// qemu process. Pages backed by hugetlbfs or locked memory are not affected.
+#[cfg(target_os = "linux")]
+pub fn madvise_willneed(pid: Pid, ranges: &[(Address, umem)]) -> Result<()> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if pidfd < 0 {
+        return Err(Error(ErrorOrigin::Connector, ErrorKind::NotSupported)
+            .log_error(io::Error::last_os_error()));
+    }
+    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as _) };
+
+    for &(base, size) in ranges {
+        let iov = libc::iovec {
+            iov_base: base.to_umem() as *mut libc::c_void,
+            iov_len: size as usize,
+        };
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_process_madvise,
+                pidfd.as_raw_fd(),
+                &iov as *const libc::iovec,
+                1usize,
+                libc::MADV_WILLNEED,
+                0u32,
+            )
+        };
+        if ret < 0 {
+            return Err(Error(ErrorOrigin::Connector, ErrorKind::NotSupported)
+                .log_error(io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn madvise_willneed(_pid: Pid, _ranges: &[(Address, umem)]) -> Result<()> {
+    Err(Error(
+        ErrorOrigin::Connector,
+        ErrorKind::UnsupportedOptionalFeature,
+    ))
+}
+
+/// Persistent read-only handle to `/proc/<pid>/mem` of the qemu process.
+///
+/// All reads are positioned (`preadv`) so the handle can be shared between clones
+/// of the connector without seeking.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+pub(crate) struct ProcMem {
+    file: Arc<File>,
+}
+
+#[cfg(target_os = "linux")]
+impl ProcMem {
+    pub fn open(proc_root: &Path, pid: Pid) -> Result<Self> {
+        Self::open_path(&proc_root.join(pid.to_string()).join("mem"))
+    }
+
+    pub(crate) fn open_path(path: &Path) -> Result<Self> {
+        File::open(path)
+            .map(|file| Self {
+                file: Arc::new(file),
+            })
+            .map_err(|err| {
+                Error(ErrorOrigin::Connector, ErrorKind::UnableToReadFile).log_debug(err)
+            })
+    }
+
+    /// Reads each `(host address, buffer)` pair and returns which buffers were read completely.
+    ///
+    /// The buffers are ordered by their host address, so runs of host-adjacent buffers are read
+    /// with a single `preadv` call even if they are scattered across `ranges`. This keeps the
+    /// number of syscalls low for many small reads like page table walks.
+    pub fn read_ranges(&self, ranges: &mut [(umem, &mut [u8])]) -> Vec<bool> {
+        let mut read = vec![false; ranges.len()];
+        let mut order = (0..ranges.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| ranges[i].0);
+
+        let host_end = |(host, buf): &(umem, &mut [u8])| *host + buf.len() as umem;
+
+        let mut start = 0;
+        while start < order.len() {
+            let mut end = start + 1;
+            while end < order.len()
+                && end - start < libc::UIO_MAXIOV as usize
+                && host_end(&ranges[order[end - 1]]) == ranges[order[end]].0
+            {
+                end += 1;
+            }
+
+            let iovs = order[start..end]
+                .iter()
+                .map(|&i| libc::iovec {
+                    iov_base: ranges[i].1.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: ranges[i].1.len(),
+                })
+                .collect::<Vec<_>>();
+
+            let ret = unsafe {
+                libc::preadv(
+                    self.file.as_raw_fd(),
+                    iovs.as_ptr(),
+                    iovs.len() as libc::c_int,
+                    ranges[order[start]].0 as libc::off_t,
+                )
+            };
+            let mut remaining = ret.max(0) as usize;
+
+            // a short read stops at the first unreadable page, the buffers behind it are retried
+            let mut next = start;
+            while next < end && remaining >= ranges[order[next]].1.len() {
+                remaining -= ranges[order[next]].1.len();
+                read[order[next]] = true;
+                next += 1;
+            }
+            start = if next == start { start + 1 } else { next };
+        }
+
+        read
+    }
+}
+
+/// Read-only mapping of the file backing the guest ram of the qemu process into the connector.
+///
+/// Only shared file mappings (e.g. `memory-backend-file,share=on` or `memory-backend-memfd`)
+/// can be mapped, anonymous or private guest ram always has to be read through [`ProcMem`].
+/// The mapping is clamped to the size of the backing file, reads past its end return zeroes
+/// just like the qemu mapping does for sparse files.
+///
+/// Persistent memory (`pmem=on` or a `/dev/dax` device) is mapped with `MAP_SYNC` where the
+/// kernel supports it. Device dax has no file size, so it is mapped with the full size instead.
+/// Writes to persistent memory have to be flushed with [`flush`](Self::flush).
+#[cfg(target_os = "linux")]
+pub(crate) struct MappedRam {
+    host_base: umem,
+    size: umem,
+    ptr: *const u8,
+    len: usize,
+    dax: Option<DaxKind>,
+}
+
+/// Kind of persistent memory backing a [`MappedRam`].
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DaxKind {
+    /// A file on a dax filesystem, flushed with `msync`.
+    File,
+    /// A `/dev/dax` character device, which cannot be synced and is flushed from the cpu caches.
+    Device,
+}
+
+/// `MAP_SYNC` is not exported by libc for all targets, the value is the same on all of them.
+#[cfg(target_os = "linux")]
+const MAP_SYNC: libc::c_int = 0x80000;
+
+// the mapping is read-only and never remapped
+#[cfg(target_os = "linux")]
+unsafe impl Send for MappedRam {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for MappedRam {}
+
+#[cfg(target_os = "linux")]
+impl MappedRam {
+    /// Maps the host range `[host_base, host_base + size)` of the qemu process.
+    ///
+    /// With `dax` the guest memory is backed by persistent memory, see [`MappedRam`].
+    pub fn map(
+        proc_root: &Path,
+        pid: Pid,
+        host_base: Address,
+        size: umem,
+        dax: bool,
+    ) -> Result<Self> {
+        let proc_dir = proc_root.join(pid.to_string());
+        let maps = fs::read_to_string(proc_dir.join("maps")).map_err(|err| {
+            Error(ErrorOrigin::Connector, ErrorKind::UnableToReadFile).log_error(err)
+        })?;
+        let (end, offset) = find_shared_mapping(&maps, host_base.to_umem()).ok_or_else(|| {
+            Error(ErrorOrigin::Connector, ErrorKind::NotSupported)
+                .log_error("guest memory is not a shared file mapping")
+        })?;
+
+        let file = File::open(map_file(&proc_dir, host_base.to_umem(), end)).map_err(|err| {
+            Error(ErrorOrigin::Connector, ErrorKind::UnableToReadFile).log_error(err)
+        })?;
+
+        Self::from_file(
+            &file,
+            offset,
+            host_base.to_umem(),
+            size.min(end - host_base.to_umem()),
+            dax,
+        )
+    }
+
+    pub(crate) fn from_file(
+        file: &File,
+        offset: umem,
+        host_base: umem,
+        size: umem,
+        dax: bool,
+    ) -> Result<Self> {
+        let metadata = file.metadata().map_err(|err| {
+            Error(ErrorOrigin::Connector, ErrorKind::UnableToReadFile).log_error(err)
+        })?;
+        let dax = if metadata.file_type().is_char_device() {
+            Some(DaxKind::Device)
+        } else {
+            dax.then_some(DaxKind::File)
+        };
+
+        let len = if dax == Some(DaxKind::Device) {
+            // character devices report a size of 0, the qemu mapping already fits the device
+            size as usize
+        } else {
+            // touching pages past the end of the file would raise SIGBUS
+            metadata.size().saturating_sub(offset).min(size) as usize
+        };
+
+        let ptr = if len > 0 {
+            let mmap = |flags| unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    libc::PROT_READ,
+                    flags,
+                    file.as_raw_fd(),
+                    offset as libc::off_t,
+                )
+            };
+            let mut ptr = libc::MAP_FAILED;
+            if dax.is_some() {
+                // filesystems without dax support reject `MAP_SYNC`
+                ptr = mmap(libc::MAP_SHARED_VALIDATE | MAP_SYNC);
+            }
+            if ptr == libc::MAP_FAILED {
+                ptr = mmap(libc::MAP_SHARED);
+            }
+            if ptr == libc::MAP_FAILED {
+                return Err(Error(ErrorOrigin::Connector, ErrorKind::UnableToMapFile)
+                    .log_error(io::Error::last_os_error()));
+            }
+            ptr as *const u8
+        } else {
+            std::ptr::null()
+        };
+
+        Ok(Self {
+            host_base,
+            size,
+            ptr,
+            len,
+            dax,
+        })
+    }
+
+    /// Returns true if the guest memory is backed by persistent memory.
+    pub fn is_dax(&self) -> bool {
+        self.dax.is_some()
+    }
+
+    /// Makes writes of the qemu process to the host range starting at `host` persistent.
+    ///
+    /// Dax files are synced with `msync`, dax devices are flushed from the cpu caches on x86_64.
+    /// Does nothing for memory which is not persistent.
+    pub fn flush(&self, host: umem, len: umem) -> Result<()> {
+        let Some(offset) = host.checked_sub(self.host_base) else {
+            return Ok(());
+        };
+        let offset = offset as usize;
+        let end = offset.saturating_add(len as usize).min(self.len);
+        if offset >= end {
+            return Ok(());
+        }
+
+        match self.dax {
+            Some(DaxKind::File) => {
+                // msync requires a page aligned address
+                let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+                let page = offset & !(page_size - 1);
+                let ret = unsafe {
+                    libc::msync(
+                        self.ptr.add(page) as *mut libc::c_void,
+                        end - page,
+                        libc::MS_SYNC,
+                    )
+                };
+                if ret < 0 {
+                    return Err(Error(ErrorOrigin::Connector, ErrorKind::UnableToWriteFile)
+                        .log_error(io::Error::last_os_error()));
+                }
+            }
+            #[cfg(target_arch = "x86_64")]
+            Some(DaxKind::Device) => {
+                use std::arch::x86_64::{_mm_clflush, _mm_sfence};
+
+                // clflush evicts the cache line of the physical memory from all mappings
+                for line in (offset & !63..end).step_by(64) {
+                    unsafe { _mm_clflush(self.ptr.add(line)) };
+                }
+                unsafe { _mm_sfence() };
+            }
+            #[cfg(not(target_arch = "x86_64"))]
+            Some(DaxKind::Device) => {
+                log::debug!("flushing dax devices is not supported on this architecture");
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Copies the host range starting at `host` into `buf`.
+    ///
+    /// Returns false if the range is not fully contained in the mapped region.
+    pub fn read(&self, host: umem, buf: &mut [u8]) -> bool {
+        let Some(offset) = host.checked_sub(self.host_base) else {
+            return false;
+        };
+        if offset + buf.len() as umem > self.size {
+            return false;
+        }
+
+        let offset = offset as usize;
+        let mapped = self.len.saturating_sub(offset).min(buf.len());
+        if mapped > 0 {
+            let src = unsafe { std::slice::from_raw_parts(self.ptr.add(offset), mapped) };
+            buf[..mapped].copy_from_slice(src);
+        }
+        buf[mapped..].fill(0);
+        true
+    }
+
+    /// Borrows up to `len` bytes of the mapping starting at the host address `host`.
+    ///
+    /// The slice ends early if the backing file is shorter than the guest memory.
+    ///
+    /// # Safety
+    ///
+    /// The backing file must not be written while the slice is borrowed.
+    pub unsafe fn slice(&self, host: umem, len: umem) -> Option<&[u8]> {
+        let offset = host.checked_sub(self.host_base)? as usize;
+        let len = self.len.checked_sub(offset)?.min(len as usize);
+        if len == 0 {
+            return None;
+        }
+        Some(unsafe { std::slice::from_raw_parts(self.ptr.add(offset), len) })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for MappedRam {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+/// Returns the host range of the guest ram mapping at `host_base` past the end of its backing file.
+///
+/// Sparse backing files can be shorter than the guest ram, e.g. when the file was truncated.
+/// Reads of the mapping past the end of the file fail, so the range has to read as zeroes instead.
+/// Returns `None` if the guest ram is not a shared file mapping or the file covers all of it.
+#[cfg(target_os = "linux")]
+pub fn past_file_end(
+    proc_root: &Path,
+    pid: Pid,
+    host_base: Address,
+    size: umem,
+) -> Option<(Address, Address)> {
+    let proc_dir = proc_root.join(pid.to_string());
+    let maps = fs::read_to_string(proc_dir.join("maps")).ok()?;
+    let (end, offset) = find_shared_mapping(&maps, host_base.to_umem())?;
+    let metadata = fs::metadata(map_file(&proc_dir, host_base.to_umem(), end)).ok()?;
+    if metadata.file_type().is_char_device() {
+        // dax devices report a size of 0 but are always backed entirely
+        return None;
+    }
+
+    let end = end.min(host_base.to_umem() + size);
+    let file_end = host_base.to_umem() + metadata.size().saturating_sub(offset);
+    (file_end < end).then(|| (Address::from(file_end), Address::from(end)))
+}
+
+/// Returns the end and file offset of the shared file mapping starting at `host_base`.
+#[cfg(target_os = "linux")]
+fn find_shared_mapping(maps: &str, host_base: umem) -> Option<(umem, umem)> {
+    maps.lines()
+        .filter_map(parse_shared_mapping)
+        .find(|&(start, _, _)| start == host_base)
+        .map(|(_, end, offset)| (end, offset))
+}
+
+/// Returns the path of the file backing the mapping `[start, end)` of the qemu process.
+#[cfg(target_os = "linux")]
+fn map_file(proc_dir: &Path, start: umem, end: umem) -> PathBuf {
+    proc_dir
+        .join("map_files")
+        .join(format!("{:x}-{:x}", start, end))
+}
+
+/// Parses a line of `/proc/<pid>/maps` into `(start, end, file offset)` if it is a shared file mapping.
+#[cfg(target_os = "linux")]
+fn parse_shared_mapping(line: &str) -> Option<(umem, umem, umem)> {
+    let mut fields = line.split_whitespace();
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?;
+    let offset = fields.next()?;
+    let _dev = fields.next()?;
+    let inode = fields.next()?;
+
+    if !perms.ends_with('s') || inode == "0" {
+        return None;
+    }
+
+    Some((
+        umem::from_str_radix(start, 16).ok()?,
+        umem::from_str_radix(end, 16).ok()?,
+        umem::from_str_radix(offset, 16).ok()?,
+    ))
+}
+
+/// Soft-dirty flag of a `/proc/<pid>/pagemap` entry.
+#[cfg(target_os = "linux")]
+const PAGEMAP_SOFT_DIRTY: u64 = 1 << 55;
+
+/// Size of the pages described by the pagemap entries.
+#[cfg(target_os = "linux")]
+const PAGEMAP_PAGE_SIZE: umem = 0x1000;
+
+/// Tracks the pages written by the qemu process with the soft-dirty bits of the kernel.
+///
+/// Writes of kvm guests are tracked as well, as the kernel write-protects the guest
+/// mappings when the bits are cleared. This requires a kernel built with `CONFIG_MEM_SOFT_DIRTY`
+/// and ptrace access to the qemu process. Guest ram backed by hugetlbfs is not tracked.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+pub(crate) struct SoftDirty {
+    pagemap: Arc<File>,
+    clear_refs: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl SoftDirty {
+    /// Starts tracking the pages written by the process, the soft-dirty bits are cleared initially.
+    pub fn open(proc_root: &Path, pid: Pid) -> Result<Self> {
+        if !soft_dirty_supported() {
+            return Err(Error(ErrorOrigin::Connector, ErrorKind::NotImplemented)
+                .log_error("the kernel does not track soft-dirty pages (CONFIG_MEM_SOFT_DIRTY)"));
+        }
+        Self::open_in(&proc_root.join(pid.to_string()))
+    }
+
+    pub(crate) fn open_in(proc_dir: &Path) -> Result<Self> {
+        let pagemap = File::open(proc_dir.join("pagemap")).map_err(|err| {
+            Error(ErrorOrigin::Connector, ErrorKind::UnableToReadFile).log_error(err)
+        })?;
+        let tracker = Self {
+            pagemap: Arc::new(pagemap),
+            clear_refs: proc_dir.join("clear_refs"),
+        };
+        tracker.clear()?;
+        Ok(tracker)
+    }
+
+    /// Clears the soft-dirty bits of all pages of the process.
+    pub fn clear(&self) -> Result<()> {
+        fs::write(&self.clear_refs, "4").map_err(|err| {
+            Error(ErrorOrigin::Connector, ErrorKind::UnableToWriteFile).log_error(err)
+        })
+    }
+
+    /// Returns the offsets of the pages in `[host_base, host_base + size)` which were written
+    /// since the bits were cleared.
+    pub fn dirty_pages(&self, host_base: umem, size: umem) -> Result<Vec<umem>> {
+        const BATCH_PAGES: umem = 0x10000;
+
+        let first = host_base / PAGEMAP_PAGE_SIZE;
+        let last = (host_base + size).div_ceil(PAGEMAP_PAGE_SIZE);
+
+        let mut dirty = vec![];
+        let mut buf = vec![];
+        let mut page = first;
+        while page < last {
+            let count = BATCH_PAGES.min(last - page);
+            buf.resize(count as usize * 8, 0);
+            self.pagemap
+                .read_exact_at(&mut buf, page * 8)
+                .map_err(|err| {
+                    Error(ErrorOrigin::Connector, ErrorKind::UnableToReadFile).log_error(err)
+                })?;
+
+            for (i, entry) in buf.chunks_exact(8).enumerate() {
+                if u64::from_ne_bytes(entry.try_into().unwrap()) & PAGEMAP_SOFT_DIRTY != 0 {
+                    let addr = (page + i as umem) * PAGEMAP_PAGE_SIZE;
+                    dirty.push(addr.saturating_sub(host_base));
+                }
+            }
+            page += count;
+        }
+
+        Ok(dirty)
+    }
+}
+
+/// Returns true if the kernel tracks soft-dirty pages.
+///
+/// A page which was just written is always soft-dirty, unless the kernel was built without
+/// `CONFIG_MEM_SOFT_DIRTY` in which case the bit is never set.
+#[cfg(target_os = "linux")]
+fn soft_dirty_supported() -> bool {
+    let len = PAGEMAP_PAGE_SIZE as usize;
+    let page = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if page == libc::MAP_FAILED {
+        return false;
+    }
+    unsafe { std::ptr::write_volatile(page as *mut u8, 1) };
+
+    let mut entry = [0u8; 8];
+    let supported = File::open("/proc/self/pagemap")
+        .and_then(|pagemap| pagemap.read_exact_at(&mut entry, page as u64 / PAGEMAP_PAGE_SIZE * 8))
+        .is_ok_and(|_| u64::from_ne_bytes(entry) & PAGEMAP_SOFT_DIRTY != 0);
+
+    unsafe { libc::munmap(page, len) };
+    supported
+}
+
+#[cfg(not(target_os = "linux"))]
+#[derive(Clone)]
+pub(crate) struct ProcMem;
+
+#[cfg(not(target_os = "linux"))]
+impl ProcMem {
+    pub fn open(_proc_root: &Path, _pid: Pid) -> Result<Self> {
+        Err(Error(
+            ErrorOrigin::Connector,
+            ErrorKind::UnsupportedOptionalFeature,
+        ))
+    }
+
+    pub fn read_ranges(&self, ranges: &mut [(umem, &mut [u8])]) -> Vec<bool> {
+        vec![false; ranges.len()]
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn past_file_end(
+    _proc_root: &Path,
+    _pid: Pid,
+    _host_base: Address,
+    _size: umem,
+) -> Option<(Address, Address)> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) struct MappedRam;
+
+#[cfg(not(target_os = "linux"))]
+impl MappedRam {
+    pub fn map(
+        _proc_root: &Path,
+        _pid: Pid,
+        _host_base: Address,
+        _size: umem,
+        _dax: bool,
+    ) -> Result<Self> {
+        Err(Error(
+            ErrorOrigin::Connector,
+            ErrorKind::UnsupportedOptionalFeature,
+        ))
+    }
+
+    pub fn read(&self, _host: umem, _buf: &mut [u8]) -> bool {
+        false
+    }
+
+    pub unsafe fn slice(&self, _host: umem, _len: umem) -> Option<&[u8]> {
+        None
+    }
+
+    pub fn is_dax(&self) -> bool {
+        false
+    }
+
+    pub fn flush(&self, _host: umem, _len: umem) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[derive(Clone)]
+pub(crate) struct SoftDirty;
+
+#[cfg(not(target_os = "linux"))]
+impl SoftDirty {
+    pub fn open(_proc_root: &Path, _pid: Pid) -> Result<Self> {
+        Err(Error(
+            ErrorOrigin::Connector,
+            ErrorKind::UnsupportedOptionalFeature,
+        ))
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn dirty_pages(&self, _host_base: umem, _size: umem) -> Result<Vec<umem>> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_has_kvm_fd() {
+        let root = std::env::temp_dir().join(format!("memflow-qemu-procfs-{}", std::process::id()));
+        for (pid, target) in [(100, "/dev/kvm"), (200, "/dev/null")] {
+            let fd = root.join(pid.to_string()).join("fd");
+            fs::create_dir_all(&fd).unwrap();
+            symlink("/dev/null", fd.join("0")).unwrap();
+            symlink(target, fd.join("3")).unwrap();
+        }
+
+        assert!(has_kvm_fd(&root, 100));
+        assert!(!has_kvm_fd(&root, 200));
+        assert!(!has_kvm_fd(&root, 300));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_proc_mem_read_ranges() {
+        let path = std::env::temp_dir().join(format!("memflow-qemu-mem-{}", std::process::id()));
+        fs::write(&path, (0..=255u8).cycle().take(0x3000).collect::<Vec<_>>()).unwrap();
+        let mem = ProcMem::open_path(&path).unwrap();
+
+        let (mut a, mut b, mut c, mut d) = ([0u8; 4], [0u8; 4], [0u8; 4], [0u8; 4]);
+        let read = mem.read_ranges(&mut [
+            (0x10, &mut a),
+            (0x14, &mut b),
+            (0x100, &mut c),
+            (0x2ffe, &mut d),
+        ]);
+
+        assert_eq!(read, vec![true, true, true, false]);
+        assert_eq!(a, [0x10, 0x11, 0x12, 0x13]);
+        assert_eq!(b, [0x14, 0x15, 0x16, 0x17]);
+        assert_eq!(c, [0, 1, 2, 3]);
+
+        // adjacent buffers are batched regardless of their order, a short read fails only the tail
+        let (mut a, mut b, mut c, mut d) = ([0u8; 4], [0u8; 4], [0u8; 4], [0u8; 2]);
+        let read = mem.read_ranges(&mut [
+            (0x2ffc, &mut c),
+            (0x104, &mut b),
+            (0x3000, &mut d),
+            (0x100, &mut a),
+        ]);
+
+        assert_eq!(read, vec![true, true, false, true]);
+        assert_eq!(a, [0, 1, 2, 3]);
+        assert_eq!(b, [4, 5, 6, 7]);
+        assert_eq!(c, [0xfc, 0xfd, 0xfe, 0xff]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_shared_mapping() {
+        assert_eq!(
+            parse_shared_mapping(
+                "7f0000000000-7f0100000000 rw-s 00200000 00:01 1043 /memfd:pc.ram (deleted)"
+            ),
+            Some((0x7f0000000000, 0x7f0100000000, 0x200000))
+        );
+        assert_eq!(
+            parse_shared_mapping("7f0000000000-7f0100000000 rw-p 00000000 00:00 0"),
+            None
+        );
+        assert_eq!(
+            parse_shared_mapping("7f0000000000-7f0100000000 rw-p 00000000 fd:01 42 /vm/ram"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mapped_ram_sparse() {
+        let path = std::env::temp_dir().join(format!("memflow-qemu-ram-{}", std::process::id()));
+        fs::write(&path, vec![0xaa; 0x2000]).unwrap();
+        let file = File::open(&path).unwrap();
+
+        // the guest ram is larger than the backing file
+        let ram = MappedRam::from_file(&file, 0x1000, 0x10000, 0x4000, false).unwrap();
+
+        let mut buf = [0xffu8; 8];
+        assert!(ram.read(0x10ffc, &mut buf));
+        assert_eq!(buf, [0xaa, 0xaa, 0xaa, 0xaa, 0, 0, 0, 0]);
+        assert!(ram.read(0x13ff8, &mut buf));
+        assert_eq!(buf, [0; 8]);
+        assert!(!ram.read(0x13ffc, &mut buf));
+        assert!(!ram.read(0xfff8, &mut buf));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_past_file_end() {
+        let path = std::env::temp_dir().join(format!("memflow-qemu-eof-{}", std::process::id()));
+        fs::write(&path, vec![0xaa; 0x1800]).unwrap();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        // the guest ram is mapped larger than its truncated backing file
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                0x4000,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(ptr, libc::MAP_FAILED);
+        let base = Address::from(ptr as umem);
+        let proc_root = Path::new(PROCFS_ROOT);
+        let pid = std::process::id() as Pid;
+
+        assert_eq!(
+            past_file_end(proc_root, pid, base, 0x4000),
+            Some((base + 0x1800, base + 0x4000))
+        );
+        assert_eq!(
+            past_file_end(proc_root, pid, base, 0x2000),
+            Some((base + 0x1800, base + 0x2000))
+        );
+        assert_eq!(past_file_end(proc_root, pid, base, 0x1000), None);
+
+        file.set_len(0x4000).unwrap();
+        assert_eq!(past_file_end(proc_root, pid, base, 0x4000), None);
+
+        unsafe { libc::munmap(ptr, 0x4000) };
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mapped_ram_dax() {
+        let path = std::env::temp_dir().join(format!("memflow-qemu-pmem-{}", std::process::id()));
+        fs::write(&path, vec![0xaa; 0x2000]).unwrap();
+        let file = File::open(&path).unwrap();
+
+        // filesystems without dax support fall back to a regular shared mapping
+        let ram = MappedRam::from_file(&file, 0, 0x10000, 0x2000, true).unwrap();
+        assert_eq!(ram.dax, Some(DaxKind::File));
+        let mut buf = [0u8; 4];
+        assert!(ram.read(0x11000, &mut buf));
+        assert_eq!(buf, [0xaa; 4]);
+        ram.flush(0x11000, 4).unwrap();
+        ram.flush(0x0, 0x100000).unwrap();
+
+        let ram = MappedRam::from_file(&file, 0, 0x10000, 0x2000, false).unwrap();
+        assert!(!ram.is_dax());
+        ram.flush(0x11000, 4).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // character devices have no size and are mapped entirely
+        let file = File::open("/dev/zero").unwrap();
+        let ram = MappedRam::from_file(&file, 0, 0x10000, 0x4000, false).unwrap();
+        assert_eq!(ram.dax, Some(DaxKind::Device));
+        assert_eq!(ram.len, 0x4000);
+        let mut buf = [0xffu8; 4];
+        assert!(ram.read(0x13ffc, &mut buf));
+        assert_eq!(buf, [0; 4]);
+        ram.flush(0x10000, 0x4000).unwrap();
+    }
+
+    #[test]
+    fn test_soft_dirty_pages() {
+        let dir = std::env::temp_dir().join(format!("memflow-qemu-pagemap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // pages 0x10, 0x12 and 0x13 are dirty, 0x11 is present but clean
+        let mut pagemap = vec![0u64; 0x20];
+        pagemap[0x10] = PAGEMAP_SOFT_DIRTY | (1 << 63);
+        pagemap[0x11] = 1 << 63;
+        pagemap[0x12] = PAGEMAP_SOFT_DIRTY;
+        pagemap[0x13] = PAGEMAP_SOFT_DIRTY | (1 << 63);
+        let pagemap = pagemap
+            .iter()
+            .flat_map(|entry| entry.to_ne_bytes())
+            .collect::<Vec<_>>();
+        fs::write(dir.join("pagemap"), pagemap).unwrap();
+
+        let tracker = SoftDirty::open_in(&dir).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("clear_refs")).unwrap(), "4");
+        assert_eq!(
+            tracker.dirty_pages(0x10000, 0x3000).unwrap(),
+            vec![0, 0x2000]
+        );
+        assert!(tracker.dirty_pages(0x11000, 0x1000).unwrap().is_empty());
+        assert_eq!(
+            tracker.dirty_pages(0x12000, 0x2000).unwrap(),
+            vec![0, 0x1000]
+        );
+        // the pagemap ends before the requested range
+        assert!(tracker.dirty_pages(0x1f000, 0x2000).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
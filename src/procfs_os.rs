@@ -0,0 +1,460 @@
+use memflow::cglue;
+use memflow::os::process::*;
+use memflow::prelude::v1::*;
+
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// An os reading the processes from a procfs mounted at an arbitrary path.
+///
+/// Unlike `memflow-native`, which always uses `/proc` and `process_vm_readv`, all accesses go
+/// through the files below `root`. This allows to reach a qemu process in another pid
+/// namespace, e.g. through the host procfs bind-mounted into a container or through
+/// `/proc/<pid>/root/proc` of a process inside a container. Pids are the ones of the
+/// namespace the procfs was mounted in.
+#[derive(Clone)]
+pub struct ProcfsOs {
+    root: PathBuf,
+    info: OsInfo,
+}
+
+cglue_impl_group!(ProcfsOs, OsInstance, {});
+
+impl ProcfsOs {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        if fs::read_dir(&root).is_err() {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadDir)
+                .log_error(format!("{} is not a readable procfs mount", root.display())));
+        }
+
+        Ok(Self {
+            root,
+            info: OsInfo {
+                base: Address::NULL,
+                size: 0,
+                arch: ArchitectureIdent::X86(64, false),
+            },
+        })
+    }
+}
+
+impl Os for ProcfsOs {
+    type ProcessType<'a> = ProcfsProcess;
+    type IntoProcessType = ProcfsProcess;
+
+    fn process_address_list_callback(&mut self, mut callback: AddressCallback) -> Result<()> {
+        let entries = fs::read_dir(&self.root).map_err(|err| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadDir).log_error(err)
+        })?;
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<umem>().ok())
+            .map(Address::from)
+            .take_while(|addr| callback.call(*addr))
+            .for_each(|_| {});
+        Ok(())
+    }
+
+    fn process_info_by_address(&mut self, address: Address) -> Result<ProcessInfo> {
+        self.process_info_by_pid(address.to_umem() as Pid)
+    }
+
+    fn process_info_by_pid(&mut self, pid: Pid) -> Result<ProcessInfo> {
+        let proc_dir = self.root.join(pid.to_string());
+        // the process might have exited since the directory was listed
+        let cmdline = fs::read(proc_dir.join("cmdline"))
+            .map_err(|_| Error(ErrorOrigin::OsLayer, ErrorKind::PartialData))?;
+
+        let args = cmdline
+            .split(|b| *b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>();
+        let path = match args.first() {
+            Some(path) => path.to_string(),
+            None => fs::read_to_string(proc_dir.join("comm"))
+                .map(|comm| comm.trim_end().to_owned())
+                .unwrap_or_else(|_| "unknown".to_owned()),
+        };
+        let name = path.rsplit('/').next().unwrap_or_default().to_owned();
+
+        Ok(ProcessInfo {
+            address: (pid as umem).into(),
+            pid,
+            state: ProcessState::Alive,
+            name: name.into(),
+            path: path.into(),
+            command_line: args.join(" ").into(),
+            sys_arch: self.info.arch,
+            proc_arch: self.info.arch,
+            dtb1: Address::invalid(),
+            dtb2: Address::invalid(),
+        })
+    }
+
+    fn process_by_info(&mut self, info: ProcessInfo) -> Result<Self::ProcessType<'_>> {
+        ProcfsProcess::open(&self.root, info)
+    }
+
+    fn into_process_by_info(mut self, info: ProcessInfo) -> Result<Self::IntoProcessType> {
+        self.process_by_info(info)
+    }
+
+    fn module_address_list_callback(&mut self, _callback: AddressCallback) -> Result<()> {
+        Ok(())
+    }
+
+    fn module_by_address(&mut self, _address: Address) -> Result<ModuleInfo> {
+        Err(Error(ErrorOrigin::OsLayer, ErrorKind::ModuleNotFound))
+    }
+
+    fn primary_module_address(&mut self) -> Result<Address> {
+        Err(Error(ErrorOrigin::OsLayer, ErrorKind::ModuleNotFound))
+    }
+
+    fn module_import_list_callback(
+        &mut self,
+        _info: &ModuleInfo,
+        _callback: ImportCallback,
+    ) -> Result<()> {
+        Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported))
+    }
+
+    fn module_export_list_callback(
+        &mut self,
+        _info: &ModuleInfo,
+        _callback: ExportCallback,
+    ) -> Result<()> {
+        Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported))
+    }
+
+    fn module_section_list_callback(
+        &mut self,
+        _info: &ModuleInfo,
+        _callback: SectionCallback,
+    ) -> Result<()> {
+        Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotSupported))
+    }
+
+    fn info(&self) -> &OsInfo {
+        &self.info
+    }
+}
+
+/// A process accessed through `<root>/<pid>/mem` and `<root>/<pid>/maps`.
+#[derive(Clone)]
+pub struct ProcfsProcess {
+    proc_dir: PathBuf,
+    mem: Arc<File>,
+    writable: bool,
+    info: ProcessInfo,
+    /// File backed mappings as `(start, end, path)`, refreshed by the module list.
+    modules: Vec<(umem, umem, String)>,
+}
+
+cglue_impl_group!(ProcfsProcess, ProcessInstance, {});
+cglue_impl_group!(ProcfsProcess, IntoProcessInstance, {});
+
+impl ProcfsProcess {
+    fn open(root: &Path, info: ProcessInfo) -> Result<Self> {
+        let proc_dir = root.join(info.pid.to_string());
+        let path = proc_dir.join("mem");
+        let (mem, writable) = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(mem) => (mem, true),
+            Err(_) => (
+                File::open(&path).map_err(|err| {
+                    Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile).log_error(format!(
+                        "unable to open {}: {}",
+                        path.display(),
+                        err
+                    ))
+                })?,
+                false,
+            ),
+        };
+
+        Ok(Self {
+            proc_dir,
+            mem: Arc::new(mem),
+            writable,
+            info,
+            modules: vec![],
+        })
+    }
+
+    /// Returns the mappings of the process as `(start, end, permissions, path)`.
+    fn maps(&self) -> Result<Vec<(umem, umem, String, String)>> {
+        let maps = fs::read_to_string(self.proc_dir.join("maps")).map_err(|err| {
+            Error(ErrorOrigin::OsLayer, ErrorKind::UnableToReadFile).log_error(err)
+        })?;
+        Ok(maps.lines().filter_map(parse_maps_line).collect())
+    }
+}
+
+/// Parses a line of `/proc/<pid>/maps` into `(start, end, permissions, path)`.
+fn parse_maps_line(line: &str) -> Option<(umem, umem, String, String)> {
+    let mut fields = line.splitn(6, ' ');
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?.to_owned();
+    let path = fields.nth(3).unwrap_or_default().trim_start().to_owned();
+    Some((
+        umem::from_str_radix(start, 16).ok()?,
+        umem::from_str_radix(end, 16).ok()?,
+        perms,
+        path,
+    ))
+}
+
+impl Process for ProcfsProcess {
+    fn state(&mut self) -> ProcessState {
+        match self.proc_dir.exists() {
+            true => ProcessState::Alive,
+            false => ProcessState::Dead(0),
+        }
+    }
+
+    fn set_dtb(&mut self, _dtb1: Address, _dtb2: Address) -> Result<()> {
+        Ok(())
+    }
+
+    fn module_address_list_callback(
+        &mut self,
+        target_arch: Option<&ArchitectureIdent>,
+        mut callback: ModuleAddressCallback,
+    ) -> Result<()> {
+        let mut modules: Vec<(umem, umem, String)> = vec![];
+        for (start, end, _, path) in self.maps()? {
+            if !path.starts_with('/') {
+                continue;
+            }
+            match modules.last_mut() {
+                Some((_, last_end, last_path)) if *last_end == start && *last_path == path => {
+                    *last_end = end
+                }
+                _ => modules.push((start, end, path)),
+            }
+        }
+        self.modules = modules;
+
+        if target_arch.is_some_and(|arch| *arch != self.info.sys_arch) {
+            return Ok(());
+        }
+        (0..self.modules.len())
+            .take_while(|i| {
+                callback.call(ModuleAddressInfo {
+                    address: Address::from(*i as umem),
+                    arch: self.info.proc_arch,
+                })
+            })
+            .for_each(|_| {});
+        Ok(())
+    }
+
+    fn module_by_address(
+        &mut self,
+        address: Address,
+        architecture: ArchitectureIdent,
+    ) -> Result<ModuleInfo> {
+        if architecture != self.info.sys_arch {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound));
+        }
+
+        self.modules
+            .get(address.to_umem() as usize)
+            .map(|(start, end, path)| ModuleInfo {
+                address,
+                parent_process: self.info.address,
+                base: Address::from(*start),
+                size: end - start,
+                name: path.rsplit('/').next().unwrap_or_default().into(),
+                path: path.as_str().into(),
+                arch: self.info.sys_arch,
+            })
+            .ok_or(Error(ErrorOrigin::OsLayer, ErrorKind::NotFound))
+    }
+
+    fn module_import_list_callback(
+        &mut self,
+        info: &ModuleInfo,
+        callback: ImportCallback,
+    ) -> Result<()> {
+        memflow::os::util::module_import_list_callback(self, info, callback)
+    }
+
+    fn module_export_list_callback(
+        &mut self,
+        info: &ModuleInfo,
+        callback: ExportCallback,
+    ) -> Result<()> {
+        memflow::os::util::module_export_list_callback(self, info, callback)
+    }
+
+    fn module_section_list_callback(
+        &mut self,
+        info: &ModuleInfo,
+        callback: SectionCallback,
+    ) -> Result<()> {
+        memflow::os::util::module_section_list_callback(self, info, callback)
+    }
+
+    fn primary_module_address(&mut self) -> Result<Address> {
+        Ok(Address::NULL)
+    }
+
+    fn info(&self) -> &ProcessInfo {
+        &self.info
+    }
+
+    fn mapped_mem_range(
+        &mut self,
+        gap_size: imem,
+        start: Address,
+        end: Address,
+        mut out: MemoryRangeCallback,
+    ) {
+        let Ok(maps) = self.maps() else {
+            return;
+        };
+
+        let mut ranges: Vec<(umem, umem, PageType)> = vec![];
+        for (map_start, map_end, perms, _) in maps {
+            let (map_start, map_end) = (map_start.max(start.to_umem()), map_end.min(end.to_umem()));
+            if map_start >= map_end || !perms.starts_with('r') {
+                continue;
+            }
+            let page_type = PageType::empty()
+                .noexec(perms.as_bytes().get(2) != Some(&b'x'))
+                .write(perms.as_bytes().get(1) == Some(&b'w'));
+            match ranges.last_mut() {
+                Some((last_start, last_size, last_type))
+                    if gap_size >= 0
+                        && *last_start + *last_size + gap_size as umem >= map_start
+                        && *last_type == page_type =>
+                {
+                    *last_size = map_end - *last_start
+                }
+                _ => ranges.push((map_start, map_end - map_start, page_type)),
+            }
+        }
+
+        ranges
+            .into_iter()
+            .map(|(start, size, page_type)| CTup3(Address::from(start), size, page_type))
+            .take_while(|range| out.call(*range))
+            .for_each(|_| {});
+    }
+}
+
+impl MemoryView for ProcfsProcess {
+    fn read_raw_iter(
+        &mut self,
+        MemOps {
+            inp,
+            mut out,
+            mut out_fail,
+        }: ReadRawMemOps,
+    ) -> Result<()> {
+        for CTup3(addr, meta_addr, mut data) in inp {
+            let ok = self.mem.read_exact_at(&mut data, addr.to_umem()).is_ok();
+            let cont = match ok {
+                true => opt_call(out.as_deref_mut(), CTup2(meta_addr, data)),
+                false => opt_call(out_fail.as_deref_mut(), CTup2(meta_addr, data)),
+            };
+            if !cont {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_raw_iter(
+        &mut self,
+        MemOps {
+            inp,
+            mut out,
+            mut out_fail,
+        }: WriteRawMemOps,
+    ) -> Result<()> {
+        if !self.writable {
+            return Err(Error(ErrorOrigin::OsLayer, ErrorKind::ReadOnly));
+        }
+
+        for CTup3(addr, meta_addr, data) in inp {
+            let ok = self.mem.write_all_at(&data, addr.to_umem()).is_ok();
+            let cont = match ok {
+                true => opt_call(out.as_deref_mut(), CTup2(meta_addr, data)),
+                false => opt_call(out_fail.as_deref_mut(), CTup2(meta_addr, data)),
+            };
+            if !cont {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> MemoryViewMetadata {
+        MemoryViewMetadata {
+            arch_bits: if cfg!(target_pointer_width = "64") {
+                64
+            } else {
+                32
+            },
+            little_endian: cfg!(target_endian = "little"),
+            max_address: Address::invalid(),
+            readonly: !self.writable,
+            real_size: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_procfs_os() {
+        // the own process is read through the procfs at its regular mount point
+        let mut os = ProcfsOs::new("/proc").unwrap();
+        let pid = std::process::id() as Pid;
+        let info = os.process_info_by_pid(pid).unwrap();
+        assert_eq!(info.pid, pid);
+        assert!(!info.command_line.is_empty());
+
+        let value = 0x1122_3344_5566_7788u64;
+        let mut process = os.into_process_by_info(info).unwrap();
+        let addr = Address::from(&value as *const u64 as umem);
+        assert_eq!(process.read::<u64>(addr).unwrap(), value);
+        assert!(process
+            .mapped_mem_vec(0)
+            .iter()
+            .any(|CTup3(base, size, _)| *base <= addr && addr < *base + *size));
+
+        assert!(ProcfsOs::new("/nonexistent/proc").is_err());
+    }
+
+    #[test]
+    fn test_parse_maps_line() {
+        assert_eq!(
+            parse_maps_line(
+                "7f0000000000-7f0080000000 rw-s 00000000 00:01 1234   /memfd:pc.ram (deleted)"
+            ),
+            Some((
+                0x7f0000000000,
+                0x7f0080000000,
+                "rw-s".to_owned(),
+                "/memfd:pc.ram (deleted)".to_owned()
+            ))
+        );
+        assert_eq!(
+            parse_maps_line("7ffc00000000-7ffc00021000 rw-p 00000000 00:00 0"),
+            Some((
+                0x7ffc00000000,
+                0x7ffc00021000,
+                "rw-p".to_owned(),
+                String::new()
+            ))
+        );
+    }
+}
@@ -1,38 +1,495 @@
+use std::iter::Peekable;
+use std::path::Path;
+
+use log::debug;
+
+use memflow::architecture::Endianess;
+use memflow::types::umem;
+
+/// Returns true if the process is a qemu system emulator.
+///
+/// Daemonized qemu processes (`-daemonize`) are reparented to init but keep their
+/// original command line in procfs, so they are detected like any other instance.
+///
+/// Besides `qemu-system-*` this matches the `qemu-kvm` and `kvm` binaries shipped by some distributions.
 pub fn is_qemu(process: &memflow::os::process::ProcessInfo) -> bool {
     let name = &*process.name;
-    name.contains("qemu-system-") || name == "QEMULauncher"
+    name.contains("qemu-system")
+        || name.contains("qemu-kvm")
+        || matches!(name, "kvm" | "QEMULauncher")
+}
+
+/// Returns the byte order of a qemu target architecture, e.g. `s390x` or the `arch` reported by `query-target`.
+///
+/// Bi-endian targets report their default byte order, e.g. `ppc64` is big endian
+/// even though ppc64le guests run on the same target.
+pub fn qemu_target_endianess(target: &str) -> Endianess {
+    const BIG_ENDIAN_TARGETS: &[&str] = &[
+        "s390x",
+        "ppc",
+        "ppc64",
+        "mips",
+        "mips64",
+        "sparc",
+        "sparc64",
+        "m68k",
+        "hppa",
+        "or1k",
+        "microblaze",
+        "sh4eb",
+        "xtensaeb",
+    ];
+
+    if BIG_ENDIAN_TARGETS.contains(&target) {
+        Endianess::BigEndian
+    } else {
+        Endianess::LittleEndian
+    }
+}
+
+/// Strips the second dash of a `--name` style option.
+fn normalize_arg(arg: &str) -> &str {
+    match arg.strip_prefix('-') {
+        Some(stripped) if stripped.starts_with('-') => stripped,
+        _ => arg,
+    }
+}
+
+/// Returns true if the boolean flag `argname` (e.g. `-snapshot`) is present, it may be the last argument.
+pub fn qemu_arg_flag<'a>(args: impl IntoIterator<Item = &'a str>, argname: &str) -> bool {
+    args.into_iter().any(|arg| normalize_arg(arg) == argname)
+}
+
+/// Advances `args` to the next occurrence of `argname` and returns its value.
+///
+/// Besides `-name value` the equivalent `--name value`, `-name=value` and `--name=value`
+/// spellings are recognized. An option without a value as the last argument is ignored.
+fn next_arg_value<'a, I: Iterator<Item = &'a str>>(
+    args: &mut Peekable<I>,
+    argname: &str,
+) -> Option<&'a str> {
+    while let Some(arg) = args.next() {
+        let arg = normalize_arg(arg);
+        if arg == argname {
+            return args.peek().copied();
+        }
+        if let Some(value) = arg
+            .strip_prefix(argname)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value);
+        }
+    }
+    None
 }
 
+/// Splits an option string at `,`, an escaped `,,` is kept as a literal comma.
+fn split_opts(opts: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = opts.chars().peekable();
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().unwrap();
+        if c != ',' {
+            part.push(c);
+        } else if chars.next_if_eq(&',').is_some() {
+            part.push(',');
+        } else {
+            parts.push(String::new());
+        }
+    }
+    parts
+}
+
+/// Returns the value of the option `argopt` of the first occurrence of `argname`.
+///
+/// See [`qemu_arg_opt_all`] for how the value of an occurrence is determined.
 pub fn qemu_arg_opt<'a>(
     args: impl IntoIterator<Item = &'a str>,
     argname: &str,
     argopt: &str,
+) -> Option<String> {
+    qemu_arg_opt_all(args, argname, argopt).into_iter().next()
+}
+
+/// Returns the value of an explicit `key=value` property of `argname`.
+///
+/// Unlike [`qemu_arg_opt`] this never falls back to the implicit first value (e.g. the machine type).
+pub fn qemu_arg_prop<'a>(
+    args: impl IntoIterator<Item = &'a str>,
+    argname: &str,
+    key: &str,
 ) -> Option<String> {
     let mut iter = args.into_iter().peekable();
 
-    while let (Some(arg), Some(next)) = (iter.next(), iter.peek()) {
-        if arg == argname {
-            let name = next.split(',');
-            for (i, kv) in name.clone().enumerate() {
-                let kvsplt = kv.split('=').collect::<Vec<_>>();
-                if kvsplt.len() == 2 {
-                    if kvsplt[0] == argopt {
-                        return Some(kvsplt[1].to_string());
-                    }
-                } else if i == 0 {
-                    return Some(kv.to_string());
+    while let Some(next) = next_arg_value(&mut iter, argname) {
+        if let Some((_, value)) = split_opts(next)
+            .iter()
+            .filter_map(|kv| kv.split_once('='))
+            .find(|(k, _)| *k == key)
+        {
+            return Some(value.to_string());
+        }
+    }
+
+    None
+}
+
+/// Returns the option values of all occurrences of `argname` in the order they appear.
+///
+/// Like in qemu an explicit `argopt=value` overrides the implicit first value, which qemu
+/// assigns to the implied option (e.g. the machine type in `-machine q35,type=pc`), and the
+/// last of multiple explicit values wins. Occurrences without `argopt` contribute their
+/// implicit first value. Repeated arguments like `-object` or `-numa` therefore yield one
+/// value each, e.g. the `size` of every memory backend.
+pub fn qemu_arg_opt_all<'a>(
+    args: impl IntoIterator<Item = &'a str>,
+    argname: &str,
+    argopt: &str,
+) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut iter = args.into_iter().peekable();
+
+    while let Some(next) = next_arg_value(&mut iter, argname) {
+        let opts = split_opts(next);
+        let explicit = opts
+            .iter()
+            .filter_map(|kv| kv.split_once('='))
+            .rfind(|(k, _)| *k == argopt)
+            .map(|(_, v)| v.to_string());
+        let implicit = || opts.first().filter(|kv| !kv.contains('=')).cloned();
+        values.extend(explicit.or_else(implicit));
+    }
+
+    values
+}
+
+/// An argument like `-object` or `-device` split into its type name and properties.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QemuObject {
+    pub typename: String,
+    pub props: Vec<(String, String)>,
+}
+
+impl QemuObject {
+    pub fn prop(&self, key: &str) -> Option<&str> {
+        self.props
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns true if this is a file memory backend on a DAX device or with persistent memory semantics.
+    ///
+    /// e.g. `-object memory-backend-file,id=mem0,size=4G,mem-path=/dev/dax0.0,pmem=on`
+    pub fn is_dax_backend(&self) -> bool {
+        self.typename == "memory-backend-file"
+            && (self
+                .prop("mem-path")
+                .map(|path| path.starts_with("/dev/dax"))
+                .unwrap_or(false)
+                || matches!(self.prop("pmem"), Some("on" | "yes" | "true")))
+    }
+}
+
+/// Parses a qemu size like `4G`, `2048M`, `512k` or `0x40000000` into bytes.
+///
+/// Sizes without a suffix are in bytes.
+pub fn qemu_parse_size(size: &str) -> Option<umem> {
+    if let Some(hex) = size.strip_prefix("0x").or_else(|| size.strip_prefix("0X")) {
+        return umem::from_str_radix(hex, 16).ok();
+    }
+
+    let (value, suffix) = size.split_at(
+        size.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len()),
+    );
+    let shift = match suffix.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        "P" => 50,
+        "E" => 60,
+        _ => return None,
+    };
+    value.parse::<umem>().ok()?.checked_mul(1 << shift)
+}
+
+/// Returns true if the guest ram is allocated and faulted in when qemu starts.
+///
+/// This is the case with `-mem-prealloc` or if every memory backend was created with `prealloc=on`.
+/// Otherwise pages the guest has not touched yet are allocated lazily and read as zero.
+pub fn qemu_mem_prealloc(cmdline: &str) -> bool {
+    if qemu_arg_flag(cmdline.split_whitespace(), "-mem-prealloc") {
+        return true;
+    }
+
+    let backends = qemu_objects(cmdline)
+        .into_iter()
+        .filter(|object| object.typename.starts_with("memory-backend-"))
+        .collect::<Vec<_>>();
+    !backends.is_empty()
+        && backends
+            .iter()
+            .all(|backend| matches!(backend.prop("prealloc"), Some("on" | "yes" | "true")))
+}
+
+/// Returns true if a memory backend is backed by persistent memory, see [`QemuObject::is_dax_backend`].
+pub fn qemu_mem_dax(cmdline: &str) -> bool {
+    qemu_objects(cmdline)
+        .iter()
+        .any(|object| object.is_dax_backend())
+}
+
+/// Returns the qemu version of a versioned machine type, e.g. `(10, 0)` for `-machine pc-q35-10.0`.
+///
+/// Versioned machine types keep the guest visible layout of the given qemu release,
+/// unversioned aliases like `q35` or `virt` do not carry a version.
+pub fn qemu_machine_version(cmdline: &str) -> Option<(u32, u32)> {
+    let machine = qemu_arg_opt(cmdline.split_whitespace(), "-machine", "type")?;
+    let (_, version) = machine.rsplit_once('-')?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Returns the number of vCPUs the guest was started with.
+///
+/// This is the `cpus` of `-smp`, either as `-smp 4` or `-smp cpus=4,...`. Without an explicit
+/// count qemu derives it from the topology (`sockets`, `dies`, `clusters`, `cores` and `threads`).
+/// A guest started without `-smp` has a single vCPU.
+pub fn qemu_smp_cpus(cmdline: &str) -> usize {
+    let args = || cmdline.split_whitespace();
+    if let Some(cpus) = qemu_arg_opt(args(), "-smp", "cpus").and_then(|cpus| cpus.parse().ok()) {
+        return cpus;
+    }
+
+    ["sockets", "dies", "clusters", "cores", "threads"]
+        .iter()
+        .map(|key| {
+            qemu_arg_prop(args(), "-smp", key)
+                .and_then(|value| value.parse::<usize>().ok())
+                .unwrap_or(1)
+        })
+        .product()
+}
+
+/// Returns a human readable name of the guest.
+///
+/// This is the `-name` of the guest, for unnamed guests the name of the first disk image
+/// (`-drive file=` or `-hda`) without its extension is used, e.g. `win10` for `/vms/win10.qcow2`.
+pub fn qemu_guest_label(cmdline: &str) -> Option<String> {
+    let args = || cmdline.split_whitespace();
+    qemu_arg_opt(args(), "-name", "guest").or_else(|| {
+        qemu_arg_prop(args(), "-drive", "file")
+            .or_else(|| qemu_arg_opt(args(), "-hda", "file"))
+            .and_then(|image| {
+                Path::new(&image)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+            })
+            .filter(|label| !label.is_empty())
+    })
+}
+
+/// Appends the options of all `-readconfig` files to the command line.
+///
+/// Launchers pass most options in a config file instead of the command line, so e.g.
+/// the machine type and `-name` of the guest would not be found otherwise.
+/// The paths are resolved in the namespace of the qemu process through its procfs directory
+/// `proc_dir`: relative paths against its `cwd` and absolute paths against its `root`, as qemu
+/// may run in a container or chroot. Without `proc_dir` the paths are used as is.
+/// Unreadable files are skipped and the command line is returned unchanged.
+pub fn qemu_expand_readconfig(cmdline: &str, proc_dir: Option<&Path>) -> String {
+    let mut expanded = cmdline.to_owned();
+    for path in qemu_arg_opt_all_raw(cmdline.split_whitespace(), "-readconfig") {
+        let path = match (proc_dir, path.strip_prefix('/')) {
+            (Some(proc_dir), Some(path)) => proc_dir.join("root").join(path),
+            (Some(proc_dir), None) => proc_dir.join("cwd").join(path),
+            (None, _) => Path::new(path).to_path_buf(),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(config) => {
+                for arg in qemu_readconfig_args(&config) {
+                    expanded.push(' ');
+                    expanded.push_str(&arg);
                 }
             }
+            Err(err) => debug!("unable to read qemu config {}: {}", path.display(), err),
         }
     }
+    expanded
+}
 
-    None
+/// Returns the raw values of all occurrences of `argname` without splitting their options.
+fn qemu_arg_opt_all_raw<'a>(
+    args: impl IntoIterator<Item = &'a str>,
+    argname: &str,
+) -> Vec<&'a str> {
+    let mut iter = args.into_iter().peekable();
+    std::iter::from_fn(|| next_arg_value(&mut iter, argname)).collect()
+}
+
+/// Converts the sections of a qemu config file into the equivalent command line arguments.
+///
+/// ```text
+/// [machine]
+///   type = "q35"
+/// [object "mem0"]
+///   qom-type = "memory-backend-ram"
+///   size = "4G"
+/// ```
+///
+/// becomes `-machine type=q35` and `-object memory-backend-ram,id=mem0,size=4G`.
+fn qemu_readconfig_args(config: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut section: Option<(String, Vec<String>)> = None;
+
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            args.extend(section.take().map(readconfig_section_args));
+            let (group, id) = match header.split_once(char::is_whitespace) {
+                Some((group, id)) => (group, Some(id.trim().trim_matches('"'))),
+                None => (header, None),
+            };
+            let opts = id.map(|id| vec![format!("id={}", id)]).unwrap_or_default();
+            section = Some((group.to_owned(), opts));
+        } else if let (Some((_, opts)), Some((key, value))) = (&mut section, line.split_once('=')) {
+            let value = value.trim().trim_matches('"').replace(',', ",,");
+            match key.trim() {
+                // the type of an object is its first option on the command line
+                "qom-type" => opts.insert(0, value),
+                key => opts.push(format!("{}={}", key, value)),
+            }
+        }
+    }
+    args.extend(section.map(readconfig_section_args));
+
+    args
+}
+
+/// Formats a config section as `-<option> <opts>`.
+fn readconfig_section_args((group, opts): (String, Vec<String>)) -> String {
+    let option = match group.as_str() {
+        "memory" => "m",
+        "smp-opts" => "smp",
+        group => group,
+    };
+    format!("-{} {}", option, opts.join(","))
+}
+
+/// Returns all `-object` arguments of the qemu command line.
+pub fn qemu_objects(cmdline: &str) -> Vec<QemuObject> {
+    qemu_arg_objects(cmdline, "-object")
+}
+
+/// Returns all occurrences of `argname` split into their type and properties.
+///
+/// The type is the first option (e.g. the backend of `-chardev` or the driver of `-device`),
+/// an explicit `qom-type=`/`driver=` as first option is supported as well.
+pub fn qemu_arg_objects(cmdline: &str, argname: &str) -> Vec<QemuObject> {
+    let mut objects = Vec::new();
+    let mut iter = cmdline.split_whitespace().peekable();
+
+    while let Some(next) = next_arg_value(&mut iter, argname) {
+        let opts = split_opts(next);
+        let mut opts = opts.iter();
+        let typename = opts
+            .next()
+            .map(|typename| typename.split_once('=').map(|(_, v)| v).unwrap_or(typename))
+            .unwrap_or_default()
+            .to_string();
+        let props = opts
+            .filter_map(|kv| kv.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        objects.push(QemuObject { typename, props });
+    }
+
+    objects
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_readconfig() {
+        let config = r#"
+# qemu config file
+
+[name]
+  guest = "win10"
+
+[machine]
+  type = "pc-q35-8.2"
+  accel = "kvm"
+
+[memory]
+  size = "4096"
+
+[object "mem0"]
+  qom-type = "memory-backend-file"
+  size = "4G"
+  mem-path = "/dev/hugepages"
+"#;
+        assert_eq!(
+            qemu_readconfig_args(config),
+            vec![
+                "-name guest=win10",
+                "-machine type=pc-q35-8.2,accel=kvm",
+                "-m size=4096",
+                "-object memory-backend-file,id=mem0,size=4G,mem-path=/dev/hugepages",
+            ]
+        );
+
+        // a fake procfs directory of the qemu process
+        let proc_dir =
+            std::env::temp_dir().join(format!("memflow-qemu-readconfig-{}", std::process::id()));
+        std::fs::create_dir_all(proc_dir.join("cwd")).unwrap();
+        std::fs::write(proc_dir.join("cwd").join("guest.cfg"), config).unwrap();
+
+        // options given on the command line take precedence
+        let cmdline = "qemu-system-x86_64 -machine accel=tcg -readconfig guest.cfg -nodefaults";
+        let expanded = qemu_expand_readconfig(cmdline, Some(&proc_dir));
+        assert_eq!(qemu_guest_label(&expanded).as_deref(), Some("win10"));
+        assert_eq!(
+            qemu_arg_opt(expanded.split_whitespace(), "-machine", "type").as_deref(),
+            Some("pc-q35-8.2")
+        );
+        assert_eq!(
+            qemu_arg_prop(expanded.split_whitespace(), "-machine", "accel").as_deref(),
+            Some("tcg")
+        );
+        assert_eq!(qemu_objects(&expanded)[0].prop("id"), Some("mem0"));
+
+        // absolute paths are resolved against the root of the qemu process
+        std::fs::create_dir_all(proc_dir.join("root/etc/qemu")).unwrap();
+        std::fs::write(proc_dir.join("root/etc/qemu/guest.cfg"), config).unwrap();
+        let abs_cmdline = "qemu-system-x86_64 -readconfig /etc/qemu/guest.cfg";
+        let expanded = qemu_expand_readconfig(abs_cmdline, Some(&proc_dir));
+        assert_eq!(qemu_guest_label(&expanded).as_deref(), Some("win10"));
+
+        std::fs::remove_dir_all(&proc_dir).unwrap();
+
+        // a missing file is skipped
+        assert_eq!(qemu_expand_readconfig(cmdline, Some(&proc_dir)), cmdline);
+        assert_eq!(
+            qemu_expand_readconfig(abs_cmdline, Some(&proc_dir)),
+            abs_cmdline
+        );
+        assert_eq!(
+            qemu_expand_readconfig("qemu-system-x86_64 -m 4G", None),
+            "qemu-system-x86_64 -m 4G"
+        );
+    }
+
     #[test]
     fn test_name() {
         assert_eq!(
@@ -77,6 +534,115 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_equals_style() {
+        for args in [
+            ["-name=win10-test", "-m"],
+            ["--name=win10-test", "-m"],
+            ["--name", "win10-test"],
+        ] {
+            assert_eq!(
+                qemu_arg_opt(args.iter().copied(), "-name", "guest"),
+                Some("win10-test".into())
+            );
+        }
+        assert_eq!(
+            qemu_arg_opt(
+                ["--machine=q35,accel=kvm"].iter().copied(),
+                "-machine",
+                "type"
+            ),
+            Some("q35".into())
+        );
+        assert_eq!(
+            qemu_arg_opt(
+                ["-name=guest=win10-test,debug-threads=on"].iter().copied(),
+                "-name",
+                "guest"
+            ),
+            Some("win10-test".into())
+        );
+        // other options sharing the prefix are not matched
+        assert_eq!(
+            qemu_arg_opt(["-named=x"].iter().copied(), "-name", "guest"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_last_arg() {
+        assert_eq!(
+            qemu_arg_opt(["-m", "4G", "-name"].iter().copied(), "-name", "guest"),
+            None
+        );
+        assert_eq!(
+            qemu_arg_opt(
+                ["-name", "win10", "-name"].iter().copied(),
+                "-name",
+                "guest"
+            ),
+            Some("win10".into())
+        );
+        assert_eq!(
+            qemu_arg_opt(["-m", "-name=win10"].iter().copied(), "-name", "guest"),
+            Some("win10".into())
+        );
+        assert!(qemu_arg_opt_all(["-qmp"].iter().copied(), "-qmp", "").is_empty());
+        assert_eq!(
+            qemu_arg_prop(["-machine"].iter().copied(), "-machine", "type"),
+            None
+        );
+
+        assert!(qemu_arg_flag(
+            ["-m", "4G", "-snapshot"].iter().copied(),
+            "-snapshot"
+        ));
+        assert!(qemu_arg_flag(["--snapshot"].iter().copied(), "-snapshot"));
+        assert!(!qemu_arg_flag(
+            ["-snapshot=on"].iter().copied(),
+            "-snapshot"
+        ));
+        assert!(qemu_mem_prealloc("qemu-system-x86_64 -m 4G -mem-prealloc"));
+    }
+
+    #[test]
+    fn test_escaped_comma() {
+        assert_eq!(
+            qemu_arg_opt(["-name", "my,,guest"].iter().copied(), "-name", "guest"),
+            Some("my,guest".into())
+        );
+        assert_eq!(
+            qemu_arg_opt(
+                ["-name", "guest=a,,b,debug-threads=on"].iter().copied(),
+                "-name",
+                "guest"
+            ),
+            Some("a,b".into())
+        );
+        assert_eq!(
+            qemu_arg_opt(
+                ["-name", "debug-threads=on,guest=a,,,,b"].iter().copied(),
+                "-name",
+                "guest"
+            ),
+            Some("a,,b".into())
+        );
+        assert_eq!(
+            qemu_arg_prop(
+                ["-machine", "q35,,x,dump-guest-core=off"].iter().copied(),
+                "-machine",
+                "dump-guest-core"
+            ),
+            Some("off".into())
+        );
+
+        let objects = qemu_objects(
+            "qemu-system-x86_64 -object memory-backend-file,id=mem0,mem-path=/vm/a,,b.img,size=4G",
+        );
+        assert_eq!(objects[0].prop("mem-path"), Some("/vm/a,b.img"));
+        assert_eq!(objects[0].prop("size"), Some("4G"));
+    }
+
     #[test]
     fn test_machine() {
         assert_eq!(
@@ -120,4 +686,242 @@ mod tests {
             None
         );
     }
+
+    #[test]
+    fn test_daemonized() {
+        use memflow::prelude::v1::*;
+
+        // the forked daemon keeps the pre-fork argv, only its parent changes
+        let cmdline = "/usr/bin/qemu-system-x86_64 -name guest=win10,debug-threads=on -daemonize -pidfile /run/qemu/win10.pid -machine q35 -qmp unix:/run/qemu/win10.qmp,server,nowait";
+        let info = ProcessInfo {
+            address: Address::from(4242),
+            pid: 4242,
+            state: ProcessState::Alive,
+            name: "qemu-system-x86_64".into(),
+            path: "/usr/bin/qemu-system-x86_64".into(),
+            command_line: cmdline.into(),
+            sys_arch: ArchitectureIdent::X86(64, false),
+            proc_arch: ArchitectureIdent::X86(64, false),
+            dtb1: Address::NULL,
+            dtb2: Address::INVALID,
+        };
+
+        assert!(is_qemu(&info));
+        assert_eq!(
+            qemu_arg_opt(info.command_line.split_whitespace(), "-name", "guest"),
+            Some("win10".into())
+        );
+        assert_eq!(
+            qemu_arg_opt(info.command_line.split_whitespace(), "-qmp", ""),
+            Some("unix:/run/qemu/win10.qmp".into())
+        );
+    }
+
+    #[test]
+    fn test_is_qemu_names() {
+        use memflow::prelude::v1::*;
+
+        let info = |name: &str| ProcessInfo {
+            address: Address::from(4242),
+            pid: 4242,
+            state: ProcessState::Alive,
+            name: name.into(),
+            path: format!("/usr/bin/{}", name).into(),
+            command_line: format!("{} -machine q35", name).into(),
+            sys_arch: ArchitectureIdent::X86(64, false),
+            proc_arch: ArchitectureIdent::X86(64, false),
+            dtb1: Address::NULL,
+            dtb2: Address::INVALID,
+        };
+
+        for name in [
+            "qemu-system-x86_64",
+            "QEMULauncher",
+            "qemu-kvm",
+            "kvm",
+            "qemu-system",
+        ] {
+            assert!(is_qemu(&info(name)), "{}", name);
+        }
+        for name in ["kvmd", "qemu-img", "qemu-ga", "bash"] {
+            assert!(!is_qemu(&info(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_target_endianess() {
+        assert_eq!(qemu_target_endianess("x86_64"), Endianess::LittleEndian);
+        assert_eq!(qemu_target_endianess("s390x"), Endianess::BigEndian);
+        assert_eq!(qemu_target_endianess("ppc64"), Endianess::BigEndian);
+        assert_eq!(qemu_target_endianess("mips64el"), Endianess::LittleEndian);
+        assert_eq!(qemu_target_endianess("aarch64"), Endianess::LittleEndian);
+    }
+
+    #[test]
+    fn test_qmp_all() {
+        let cmdline = "qemu-system-x86_64 -qmp unix:/tmp/qmp.sock,server,nowait -m 4G -qmp tcp:localhost:4444,server,nowait";
+        assert_eq!(
+            qemu_arg_opt_all(cmdline.split_whitespace(), "-qmp", ""),
+            vec!["unix:/tmp/qmp.sock", "tcp:localhost:4444"]
+        );
+        assert_eq!(
+            qemu_arg_opt(cmdline.split_whitespace(), "-qmp", ""),
+            Some("unix:/tmp/qmp.sock".into())
+        );
+        assert!(qemu_arg_opt_all(cmdline.split_whitespace(), "-name", "guest").is_empty());
+    }
+
+    #[test]
+    fn test_opt_all_objects() {
+        let cmdline = "qemu-system-x86_64 -object memory-backend-ram,id=mem0,size=2G -numa node,memdev=mem0 -object memory-backend-ram,id=mem1,size=1G -numa node,memdev=mem1 -object memory-backend-ram,size=512M,id=mem2";
+        let args = || cmdline.split_whitespace();
+        assert_eq!(
+            qemu_arg_opt_all(args(), "-object", "size"),
+            vec!["2G", "1G", "512M"]
+        );
+        assert_eq!(
+            qemu_arg_opt_all(args(), "-numa", "memdev"),
+            vec!["mem0", "mem1"]
+        );
+        // the single value function returns the first occurrence
+        assert_eq!(
+            qemu_arg_opt(args(), "-object", "size").as_deref(),
+            Some("2G")
+        );
+        assert_eq!(
+            qemu_arg_opt_all(args(), "-object", "type"),
+            vec!["memory-backend-ram"; 3]
+        );
+
+        // explicit values override the implicit first value and each other like in qemu
+        let args =
+            || "qemu-system-x86_64 -machine q35,type=pc -m 2G,size=4G,size=8G".split_whitespace();
+        assert_eq!(
+            qemu_arg_opt(args(), "-machine", "type").as_deref(),
+            Some("pc")
+        );
+        assert_eq!(qemu_arg_opt_all(args(), "-machine", "type"), vec!["pc"]);
+        assert_eq!(qemu_arg_opt(args(), "-m", "size").as_deref(), Some("8G"));
+    }
+
+    #[test]
+    fn test_guest_label() {
+        assert_eq!(
+            qemu_guest_label("qemu-system-x86_64 -name win10-named -drive file=/vms/other.qcow2")
+                .as_deref(),
+            Some("win10-named")
+        );
+        assert_eq!(
+            qemu_guest_label(
+                "qemu-system-x86_64 -m 4G -drive file=/vms/win10.qcow2,if=virtio,format=qcow2"
+            )
+            .as_deref(),
+            Some("win10")
+        );
+        assert_eq!(
+            qemu_guest_label("qemu-system-x86_64 -hda /var/lib/images/debian.img").as_deref(),
+            Some("debian")
+        );
+        assert_eq!(qemu_guest_label("qemu-system-x86_64 -m 4G"), None);
+    }
+
+    #[test]
+    fn test_mem_prealloc() {
+        assert!(qemu_mem_prealloc("qemu-system-x86_64 -m 4G -mem-prealloc"));
+        assert!(qemu_mem_prealloc(
+            "qemu-system-x86_64 -object memory-backend-memfd,id=mem0,size=4G,prealloc=on -machine q35,memory-backend=mem0"
+        ));
+        // every numa node has to be preallocated
+        assert!(!qemu_mem_prealloc(
+            "qemu-system-x86_64 -object memory-backend-ram,id=mem0,size=2G,prealloc=on -object memory-backend-ram,id=mem1,size=2G -numa node,memdev=mem0 -numa node,memdev=mem1"
+        ));
+        assert!(!qemu_mem_prealloc(
+            "qemu-system-x86_64 -object memory-backend-ram,id=mem0,size=4G,prealloc=off"
+        ));
+        assert!(!qemu_mem_prealloc("qemu-system-x86_64 -m 4G"));
+    }
+
+    #[test]
+    fn test_dax_backend() {
+        let objects = qemu_objects("qemu-system-x86_64 -object memory-backend-file,id=mem0,size=4G,mem-path=/dev/dax0.0,align=2M -object memory-backend-file,id=mem1,size=4G,mem-path=/mnt/pmem/guest.img,pmem=on -object memory-backend-file,id=mem2,size=4G,mem-path=/dev/hugepages,share=on -object memory-backend-ram,id=mem3,size=4G");
+
+        assert_eq!(objects.len(), 4);
+        assert_eq!(objects[0].prop("id"), Some("mem0"));
+        assert!(objects[0].is_dax_backend());
+        assert!(objects[1].is_dax_backend());
+        assert!(!objects[2].is_dax_backend());
+        assert!(!objects[3].is_dax_backend());
+
+        assert!(qemu_mem_dax("qemu-system-x86_64 -object memory-backend-file,id=mem0,size=4G,mem-path=/dev/dax0.0 -machine q35,memory-backend=mem0"));
+        assert!(!qemu_mem_dax("qemu-system-x86_64 -object memory-backend-file,id=mem0,size=4G,mem-path=/mnt/guest.img,share=on"));
+    }
+
+    #[test]
+    fn test_arg_prop() {
+        let args = ["-machine", "virt,secure=on"];
+        assert_eq!(
+            qemu_arg_prop(args.iter().copied(), "-machine", "secure"),
+            Some("on".into())
+        );
+        assert_eq!(
+            qemu_arg_prop(args.iter().copied(), "-machine", "type"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_machine_version() {
+        assert_eq!(
+            qemu_machine_version("qemu-system-x86_64 -machine pc-q35-10.0,accel=kvm"),
+            Some((10, 0))
+        );
+        assert_eq!(
+            qemu_machine_version("qemu-system-x86_64 -machine type=pc-i440fx-8.2"),
+            Some((8, 2))
+        );
+        assert_eq!(
+            qemu_machine_version("qemu-system-aarch64 -machine virt-9.1"),
+            Some((9, 1))
+        );
+        assert_eq!(
+            qemu_machine_version("qemu-system-x86_64 -machine q35"),
+            None
+        );
+        assert_eq!(
+            qemu_machine_version("qemu-system-x86_64 -machine s390-ccw-virtio"),
+            None
+        );
+        assert_eq!(qemu_machine_version("qemu-system-x86_64"), None);
+    }
+
+    #[test]
+    fn test_smp_cpus() {
+        assert_eq!(qemu_smp_cpus("qemu-system-x86_64 -m 4G"), 1);
+        assert_eq!(qemu_smp_cpus("qemu-system-x86_64 -smp 4"), 4);
+        assert_eq!(
+            qemu_smp_cpus("qemu-system-x86_64 -smp 4,sockets=2,cores=2"),
+            4
+        );
+        assert_eq!(
+            qemu_smp_cpus("qemu-system-x86_64 -smp cpus=6,maxcpus=8,sockets=2"),
+            6
+        );
+        assert_eq!(
+            qemu_smp_cpus("qemu-system-x86_64 -smp sockets=2,cores=4,threads=2"),
+            16
+        );
+        assert_eq!(qemu_smp_cpus("qemu-system-x86_64 --smp=2"), 2);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(qemu_parse_size("1G"), Some(0x4000_0000));
+        assert_eq!(qemu_parse_size("2048M"), Some(0x8000_0000));
+        assert_eq!(qemu_parse_size("0x40000000"), Some(0x4000_0000));
+        assert_eq!(qemu_parse_size("512k"), Some(0x8_0000));
+        assert_eq!(qemu_parse_size("4096"), Some(0x1000));
+        assert_eq!(qemu_parse_size("1.5G"), None);
+        assert_eq!(qemu_parse_size("G"), None);
+        assert_eq!(qemu_parse_size("4X"), None);
+    }
 }
@@ -0,0 +1,189 @@
+use memflow::prelude::v1::Result;
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+use {
+    crate::qemu_args::qemu_arg_objects,
+    log::info,
+    memflow::prelude::v1::{Error, ErrorKind, ErrorOrigin},
+    serde_json::{json, Value},
+    std::io::{BufRead, BufReader, Write},
+    std::os::unix::net::UnixStream,
+    std::time::Duration,
+};
+
+/// Name of the virtio-serial port used by the qemu guest agent.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+const GUEST_AGENT_PORT: &str = "org.qemu.guest_agent.0";
+
+/// Operating system information reported by the qemu guest agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuestOsInfo {
+    /// Operating system id, e.g. `mswindows` or `ubuntu`.
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub pretty_name: Option<String>,
+    pub version: Option<String>,
+    pub kernel_release: Option<String>,
+    pub kernel_version: Option<String>,
+    pub machine: Option<String>,
+}
+
+/// A connection handle to the qemu guest agent running inside the guest.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+#[derive(Clone)]
+pub struct GuestAgent {
+    path: String,
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl GuestAgent {
+    /// Finds the unix socket of the guest agent channel in the qemu command line.
+    ///
+    /// e.g. `-chardev socket,path=/tmp/qga.sock,server=on,wait=off,id=qga0
+    /// -device virtserialport,chardev=qga0,name=org.qemu.guest_agent.0`
+    pub fn from_cmdline(cmdline: &str) -> Option<Self> {
+        let chardev = qemu_arg_objects(cmdline, "-device")
+            .into_iter()
+            .find(|device| {
+                device.typename == "virtserialport" && device.prop("name") == Some(GUEST_AGENT_PORT)
+            })?
+            .prop("chardev")?
+            .to_owned();
+
+        let path = qemu_arg_objects(cmdline, "-chardev")
+            .into_iter()
+            .find(|dev| dev.typename == "socket" && dev.prop("id") == Some(&chardev))?
+            .prop("path")?
+            .to_owned();
+
+        Some(Self { path })
+    }
+
+    /// Queries the operating system information from the guest agent.
+    pub fn guest_os_info(&self) -> Result<GuestOsInfo> {
+        info!("connecting to guest agent at: {}", self.path);
+        let stream = UnixStream::connect(&self.path)
+            .and_then(|stream| {
+                // the agent might not be running inside the guest
+                stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+                Ok(stream)
+            })
+            .map_err(|err| {
+                Error(ErrorOrigin::Connector, ErrorKind::Configuration).log_error(err)
+            })?;
+
+        let mut reader = BufReader::new(&stream);
+
+        // flush stale responses of previous clients before issuing the actual command
+        let sync_id = std::process::id();
+        execute(&stream, "guest-sync", json!({ "id": sync_id }))?;
+        loop {
+            let response = read_response(&mut reader)?;
+            if response.get("return") == Some(&json!(sync_id)) {
+                break;
+            }
+        }
+
+        execute(&stream, "guest-get-osinfo", json!({}))?;
+        parse_guest_osinfo(&read_response(&mut reader)?)
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn execute(mut stream: &UnixStream, command: &str, arguments: Value) -> Result<()> {
+    writeln!(
+        stream,
+        "{}",
+        json!({ "execute": command, "arguments": arguments })
+    )
+    .map_err(|err| Error(ErrorOrigin::Connector, ErrorKind::UnableToWriteFile).log_error(err))
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn read_response(reader: &mut impl BufRead) -> Result<Value> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .map_err(|err| Error(ErrorOrigin::Connector, ErrorKind::UnableToReadFile).log_error(err))?;
+    serde_json::from_str(&line)
+        .map_err(|err| Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument).log_error(err))
+}
+
+/// Parses the response of a `guest-get-osinfo` command.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn parse_guest_osinfo(response: &Value) -> Result<GuestOsInfo> {
+    let info = response.get("return").ok_or_else(|| {
+        Error(ErrorOrigin::Connector, ErrorKind::NotSupported)
+            .log_error(format!("guest-get-osinfo failed: {}", response["error"]))
+    })?;
+
+    let field = |name: &str| info.get(name).and_then(Value::as_str).map(str::to_owned);
+    Ok(GuestOsInfo {
+        id: field("id"),
+        name: field("name"),
+        pretty_name: field("pretty-name"),
+        version: field("version"),
+        kernel_release: field("kernel-release"),
+        kernel_version: field("kernel-version"),
+        machine: field("machine"),
+    })
+}
+
+/// Placeholder for builds without qmp support, no guest agent can ever be constructed.
+#[cfg(not(all(target_os = "linux", feature = "qmp")))]
+#[derive(Clone)]
+pub enum GuestAgent {}
+
+#[cfg(not(all(target_os = "linux", feature = "qmp")))]
+impl GuestAgent {
+    pub fn from_cmdline(_cmdline: &str) -> Option<Self> {
+        None
+    }
+
+    pub fn guest_os_info(&self) -> Result<GuestOsInfo> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_cmdline() {
+        let cmdline = "qemu-system-x86_64 -chardev socket,path=/tmp/qmp.sock,id=mon0 -chardev socket,path=/tmp/qga.sock,server=on,wait=off,id=qga0 -device virtio-serial -device virtserialport,chardev=qga0,name=org.qemu.guest_agent.0";
+        assert_eq!(
+            GuestAgent::from_cmdline(cmdline).map(|agent| agent.path),
+            Some("/tmp/qga.sock".into())
+        );
+        assert!(GuestAgent::from_cmdline("qemu-system-x86_64 -machine q35").is_none());
+    }
+
+    #[test]
+    fn test_parse_guest_osinfo() {
+        let response = serde_json::from_str(
+            r#"{"return": {"name": "Microsoft Windows", "kernel-release": "19045", "version": "Microsoft Windows 10", "variant": "client", "pretty-name": "Windows 10 Pro", "version-id": "10", "variant-id": "client", "kernel-version": "10.0", "machine": "x86_64", "id": "mswindows"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            parse_guest_osinfo(&response).unwrap(),
+            GuestOsInfo {
+                id: Some("mswindows".into()),
+                name: Some("Microsoft Windows".into()),
+                pretty_name: Some("Windows 10 Pro".into()),
+                version: Some("Microsoft Windows 10".into()),
+                kernel_release: Some("19045".into()),
+                kernel_version: Some("10.0".into()),
+                machine: Some("x86_64".into()),
+            }
+        );
+
+        let response = serde_json::from_str(
+            r#"{"error": {"class": "CommandNotFound", "desc": "The command guest-get-osinfo has not been found"}}"#,
+        )
+        .unwrap();
+        assert!(parse_guest_osinfo(&response).is_err());
+    }
+}
@@ -0,0 +1,1298 @@
+use log::info;
+
+use memflow::prelude::v1::{Error, ErrorKind, ErrorOrigin, Result};
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+use {
+    crate::error::QemuError,
+    crate::qemu_args::qemu_arg_opt_all,
+    log::{debug, warn},
+    qapi::{qmp, Qmp},
+    std::io::{self, BufRead, BufReader, Read, Write},
+    std::net::TcpStream,
+    std::os::unix::net::{SocketAddr, UnixStream},
+    std::sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    std::sync::{Arc, Mutex, Weak},
+    std::time::Instant,
+};
+
+use std::time::Duration;
+
+/// Transport of a QMP monitor socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QmpTransport {
+    Unix,
+    Tcp,
+    Vsock,
+}
+
+impl std::str::FromStr for QmpTransport {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "unix" => Ok(Self::Unix),
+            "tcp" => Ok(Self::Tcp),
+            "vsock" => Ok(Self::Vsock),
+            _ => Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                .log_error("qmp transport has to be either `unix`, `tcp` or `vsock`")),
+        }
+    }
+}
+
+/// Upper bound of the delay between two connection attempts.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Time a tcp monitor has to send its greeting.
+///
+/// Monitors secured with `tls-creds` wait for the client to start a tls session
+/// instead, without a timeout connecting to them would block forever.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+const TCP_GREETING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often connecting to a QMP monitor is attempted before giving up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QmpRetry {
+    pub attempts: u32,
+    /// Delay before the second attempt, doubled for every further attempt.
+    pub backoff: Duration,
+}
+
+impl Default for QmpRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl QmpRetry {
+    /// Returns the delay before the given attempt, starting at 0 for the first attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match attempt {
+            0 => Duration::ZERO,
+            _ => self
+                .backoff
+                .saturating_mul(1 << (attempt - 1).min(16))
+                .min(MAX_RETRY_BACKOFF),
+        }
+    }
+}
+
+/// Address of a QMP monitor as specified with the `-qmp` argument.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QmpAddr {
+    Unix(String),
+    Tcp(String),
+    Vsock { cid: u32, port: u32 },
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl QmpAddr {
+    /// Parses a qmp address in the form of `unix:/path/to/socket`, `tcp:host:port` or `vsock:cid:port`.
+    ///
+    /// Unix sockets in the abstract namespace are specified with a leading `@`, e.g. `unix:@my-vm`.
+    pub fn parse(addr: &str) -> Option<Self> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            Some(Self::Unix(path.to_owned()))
+        } else if let Some(vsock) = addr.strip_prefix("vsock:") {
+            let (cid, port) = vsock.split_once(':')?;
+            Some(Self::Vsock {
+                cid: cid.parse().ok()?,
+                port: port.parse().ok()?,
+            })
+        } else {
+            addr.strip_prefix("tcp:")
+                .map(|url| Self::Tcp(url.to_owned()))
+        }
+    }
+
+    pub fn transport(&self) -> QmpTransport {
+        match self {
+            Self::Unix(_) => QmpTransport::Unix,
+            Self::Tcp(_) => QmpTransport::Tcp,
+            Self::Vsock { .. } => QmpTransport::Vsock,
+        }
+    }
+}
+
+/// A connection handle to the QMP monitor of a qemu process.
+///
+/// A new connection is established for every command so other clients
+/// are not locked out of the monitor while the connector is alive.
+///
+/// When qemu exposes multiple monitors each of them is tried in order until a connection succeeds.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+#[derive(Clone)]
+pub struct QmpMonitor {
+    addrs: Vec<QmpAddr>,
+    pause: Arc<Mutex<PauseState>>,
+    connect_time: Arc<Mutex<Duration>>,
+    retry: QmpRetry,
+    /// Set until the first connection was attempted, the sockets might not exist yet before.
+    retry_pending: Arc<AtomicBool>,
+}
+
+/// Pause bookkeeping shared between all clones of a connector.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+#[derive(Default)]
+struct PauseState {
+    count: usize,
+    stopped_by_us: bool,
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+enum QmpStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+    #[cfg(feature = "vsock")]
+    Vsock(vsock::VsockStream),
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl Read for &QmpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            QmpStream::Unix(stream) => (&*stream).read(buf),
+            QmpStream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(feature = "vsock")]
+            QmpStream::Vsock(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl Write for &QmpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            QmpStream::Unix(stream) => (&*stream).write(buf),
+            QmpStream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(feature = "vsock")]
+            QmpStream::Vsock(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            QmpStream::Unix(stream) => (&*stream).flush(),
+            QmpStream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(feature = "vsock")]
+            QmpStream::Vsock(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl Read for QmpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl Write for QmpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl QmpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            QmpStream::Unix(stream) => stream.try_clone().map(QmpStream::Unix),
+            QmpStream::Tcp(stream) => stream.try_clone().map(QmpStream::Tcp),
+            #[cfg(feature = "vsock")]
+            QmpStream::Vsock(stream) => stream.try_clone().map(QmpStream::Vsock),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            QmpStream::Unix(stream) => stream.set_read_timeout(timeout),
+            QmpStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "vsock")]
+            QmpStream::Vsock(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+/// Events after which the guest memory layout may have changed.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+const LAYOUT_EVENTS: [&str; 2] = ["RESET", "SHUTDOWN"];
+
+/// Interval in which the event watcher checks whether it is still needed.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+const EVENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl QmpMonitor {
+    pub fn new(addrs: Vec<QmpAddr>) -> Self {
+        Self {
+            addrs,
+            pause: Default::default(),
+            connect_time: Default::default(),
+            retry: Default::default(),
+            retry_pending: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Retries the first connection to the monitor, e.g. when qemu was just started
+    /// and has not created the socket yet.
+    pub fn with_retry(mut self, retry: QmpRetry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Returns the total time spent connecting to the monitor sockets by all clones.
+    pub fn connect_time(&self) -> Duration {
+        *self.connect_time.lock().unwrap()
+    }
+
+    /// Creates a monitor handle for the `-qmp` sockets found in the qemu command line.
+    ///
+    /// Sockets using the `prefer`red transport are tried first,
+    /// otherwise they are tried in the order they appear on the command line.
+    pub fn from_cmdline(cmdline: &str, prefer: Option<QmpTransport>) -> Option<Self> {
+        // -qmp unix:/tmp/qmp-win10-reversing.sock,server,nowait
+        let mut addrs = qemu_arg_opt_all(cmdline.split_whitespace(), "-qmp", "")
+            .iter()
+            .filter_map(|addr| QmpAddr::parse(addr))
+            .collect::<Vec<_>>();
+
+        if let Some(prefer) = prefer {
+            addrs.sort_by_key(|addr| addr.transport() != prefer);
+        }
+
+        if addrs.is_empty() {
+            None
+        } else {
+            Some(Self::new(addrs))
+        }
+    }
+
+    /// Creates a monitor handle for a user supplied socket address.
+    ///
+    /// Accepts `unix:/path/to/socket`, `tcp:host:port` or a bare path to a unix socket.
+    pub fn from_addr(addr: &str) -> Option<Self> {
+        let addr = QmpAddr::parse(addr).unwrap_or_else(|| QmpAddr::Unix(addr.to_owned()));
+        Some(Self::new(vec![addr]))
+    }
+
+    fn connect(&self) -> Result<QmpStream> {
+        let start = Instant::now();
+        let stream = self.connect_any();
+        *self.connect_time.lock().unwrap() += start.elapsed();
+        stream
+    }
+
+    fn connect_any(&self) -> Result<QmpStream> {
+        // once qemu is up a failing socket is not going to appear anymore
+        let attempts = match self.retry_pending.swap(false, Ordering::SeqCst) {
+            true => self.retry.attempts.max(1),
+            false => 1,
+        };
+
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                let delay = self.retry.delay(attempt);
+                debug!(
+                    "retrying to connect to qmp in {:?} (attempt {} of {})",
+                    delay,
+                    attempt + 1,
+                    attempts
+                );
+                std::thread::sleep(delay);
+            }
+            if let Some(stream) = self.try_connect() {
+                return Ok(stream);
+            }
+        }
+
+        Err(Error::from(QemuError::QmpConnect).log_error("unable to connect to any qmp socket"))
+    }
+
+    fn try_connect(&self) -> Option<QmpStream> {
+        for addr in self.addrs.iter() {
+            let stream = match addr {
+                QmpAddr::Unix(socket_path) => {
+                    info!("connecting to qmp unix socket at: {}", socket_path);
+                    connect_unix(socket_path).map(QmpStream::Unix)
+                }
+                QmpAddr::Tcp(socket_url) => {
+                    info!("connecting to qmp tcp socket at: {}", socket_url);
+                    TcpStream::connect(socket_url).and_then(|stream| {
+                        stream.set_read_timeout(Some(TCP_GREETING_TIMEOUT))?;
+                        Ok(QmpStream::Tcp(stream))
+                    })
+                }
+                QmpAddr::Vsock { cid, port } => {
+                    info!("connecting to qmp vsock socket at: {}:{}", cid, port);
+                    connect_vsock(*cid, *port)
+                }
+            };
+
+            match stream {
+                Ok(stream) => return Some(stream),
+                Err(err) => warn!("unable to connect to qmp socket {:?}: {}", addr, err),
+            }
+        }
+        None
+    }
+
+    /// Executes a single QMP command and returns its result.
+    pub fn execute<C: qapi::Command>(&self, command: &C) -> Result<C::Ok> {
+        let stream = self.connect()?;
+
+        let mut qmp = Qmp::from_stream(&stream);
+        qmp.handshake()
+            .map_err(|err| handshake_error(&stream, err))?;
+        stream
+            .set_read_timeout(None)
+            .map_err(|err| Error::from(QemuError::QmpConnect).log_error(err))?;
+
+        qmp.execute(command).map_err(execute_error)
+    }
+
+    /// Executes a single QMP command out-of-band if the monitor supports it.
+    ///
+    /// Out-of-band commands are handled by qemu even while its main loop is blocked, e.g. by
+    /// a hung guest. The command is executed in-band if the monitor was not started with
+    /// `-qmp ...,oob=on` or if qemu does not allow the command out-of-band.
+    pub fn execute_oob<C: qapi::Command>(&self, command: &C) -> Result<C::Ok> {
+        let stream = self.connect()?;
+
+        let mut qmp = Qmp::from_stream(&stream);
+        let greeting = qmp
+            .read_capabilities()
+            .map_err(|err| handshake_error(&stream, err))?;
+        stream
+            .set_read_timeout(None)
+            .map_err(|err| Error::from(QemuError::QmpConnect).log_error(err))?;
+        let oob = greeting.capabilities.iter().any(is_oob_capability);
+        qmp.execute(&qmp::qmp_capabilities {
+            enable: oob.then(|| vec![qmp::QMPCapability::oob]),
+        })
+        .map_err(|err| Error::from(QemuError::QmpHandshake).log_error(err))?;
+
+        if oob {
+            // out-of-band commands have to carry an id to match their response
+            let request = serde_json::json!({
+                "exec-oob": C::NAME,
+                "arguments": command,
+                "id": "memflow-oob",
+            });
+            serde_json::to_writer(&mut *qmp.inner_mut(), &request)
+                .map_err(io::Error::from)
+                .and_then(|_| qmp.inner_mut().write_all(b"\n"))
+                .and_then(|_| qmp.inner_mut().flush())
+                .map_err(|err| Error::from(QemuError::QmpConnect).log_error(err))?;
+
+            match qmp.read_response::<C>() {
+                Ok(ret) => return Ok(ret),
+                Err(qapi::ExecuteError::Qapi(err)) => info!(
+                    "unable to execute {} out-of-band, executing it in-band: {}",
+                    C::NAME,
+                    err.desc
+                ),
+                Err(err) => return Err(execute_error(err)),
+            }
+        }
+
+        qmp.execute(command).map_err(execute_error)
+    }
+
+    /// Runs a human monitor (HMP) command and returns its textual output.
+    pub fn human_monitor_command(&self, command_line: &str) -> Result<String> {
+        self.human_monitor_command_on(command_line, None)
+    }
+
+    /// Runs a human monitor (HMP) command in the context of the given vCPU.
+    pub fn human_monitor_command_on(
+        &self,
+        command_line: &str,
+        cpu_index: Option<i64>,
+    ) -> Result<String> {
+        self.execute(&qmp::human_monitor_command {
+            command_line: command_line.to_owned(),
+            cpu_index,
+        })
+    }
+
+    /// Returns the number of vCPUs reported by `query-cpus-fast`.
+    pub fn cpu_count(&self) -> Result<usize> {
+        self.execute(&qmp::query_cpus_fast {})
+            .map(|cpus| cpus.len())
+    }
+
+    /// Returns the major and minor version of qemu reported by `query-version`.
+    pub fn version(&self) -> Result<(u32, u32)> {
+        let version = self.execute(&qmp::query_version {})?;
+        match (
+            u32::try_from(version.qemu.major),
+            u32::try_from(version.qemu.minor),
+        ) {
+            (Ok(major), Ok(minor)) => Ok((major, minor)),
+            _ => Err(Error::from(QemuError::QmpParse)
+                .log_error(format!("invalid qemu version {:?}", version.qemu))),
+        }
+    }
+
+    /// Returns the target architecture of the qemu binary reported by `query-target`.
+    pub fn target_arch(&self) -> Result<&'static str> {
+        use qapi::Enum;
+        self.execute(&qmp::query_target {})
+            .map(|target| target.arch.name())
+    }
+
+    /// Starts measuring the rate at which the guest dirties its memory with `calc-dirty-rate`.
+    ///
+    /// qemu measures the rate in the background for `calc_time`, see [`dirty_rate`](Self::dirty_rate).
+    /// The commands were added in qemu 5.2, `NotImplemented` is returned if they are not available.
+    pub fn calc_dirty_rate(&self, calc_time: Duration) -> Result<()> {
+        let commands = self.execute(&qmp::query_commands {})?;
+        let supported = ["calc-dirty-rate", "query-dirty-rate"]
+            .iter()
+            .all(|name| commands.iter().any(|command| command.name == *name));
+        if !supported {
+            return Err(Error(ErrorOrigin::Connector, ErrorKind::NotImplemented)
+                .log_error("qemu does not support calc-dirty-rate (requires qemu 5.2)"));
+        }
+
+        self.execute(&qmp::calc_dirty_rate {
+            calc_time: calc_time.as_secs().max(1) as i64,
+            sample_pages: None,
+            mode: None,
+        })
+        .map(drop)
+    }
+
+    /// Returns the dirty page rate in MB/s of the last `calc-dirty-rate` reported by
+    /// `query-dirty-rate`, or `None` while it is still measured.
+    pub fn dirty_rate(&self) -> Result<Option<u64>> {
+        let info = self.execute(&qmp::query_dirty_rate {})?;
+        match info.status {
+            qmp::DirtyRateStatus::measured => {
+                Ok(Some(info.dirty_rate.map_or(0, |rate| rate.max(0) as u64)))
+            }
+            qmp::DirtyRateStatus::measuring => Ok(None),
+            qmp::DirtyRateStatus::unstarted => Err(Error::from(QemuError::QmpCommand)
+                .log_error("no dirty rate measurement has been started")),
+        }
+    }
+
+    /// Counts guest resets and shutdowns reported by qmp events in the background.
+    ///
+    /// Unlike commands the events are read from a connection which is kept open, so this
+    /// occupies one monitor socket. The watcher thread exits once `resets` was dropped or
+    /// the connection is closed by qemu.
+    pub fn watch_resets(&self, resets: &Arc<AtomicUsize>) -> Result<()> {
+        let stream = self.connect()?;
+        let writer = stream
+            .try_clone()
+            .map_err(|err| Error::from(QemuError::QmpConnect).log_error(err))?;
+
+        // the reader is kept after the handshake so no buffered event is lost
+        let mut qmp = Qmp::new(qapi::Stream::new(BufReader::new(stream), writer));
+        qmp.handshake()
+            .map_err(|err| handshake_error(qmp.inner().get_ref_write(), err))?;
+        let (reader, _) = qmp.into_inner().into_inner();
+        reader
+            .get_ref()
+            .set_read_timeout(Some(EVENT_POLL_INTERVAL))
+            .map_err(|err| Error::from(QemuError::QmpConnect).log_error(err))?;
+
+        let resets = Arc::downgrade(resets);
+        std::thread::spawn(move || watch_events(reader, resets));
+        Ok(())
+    }
+
+    /// Stops the guest unless it is already stopped.
+    ///
+    /// Pauses are reference counted between clones. If the guest was already
+    /// stopped by someone else it will not be resumed by us either.
+    pub fn pause(&self) -> Result<()> {
+        let mut state = self.pause.lock().unwrap();
+
+        if state.count == 0 {
+            // out-of-band so a guest with a blocked main loop can still be paused
+            let status = self.execute_oob(&qmp::query_status {})?;
+            if status.running {
+                self.execute_oob(&qmp::stop {})?;
+                state.stopped_by_us = true;
+            } else {
+                info!(
+                    "guest is already stopped ({:?}), not issuing stop",
+                    status.status
+                );
+                state.stopped_by_us = false;
+            }
+        }
+
+        state.count += 1;
+        Ok(())
+    }
+
+    /// Resumes the guest once the last pause has been released.
+    pub fn resume(&self) -> Result<()> {
+        let mut state = self.pause.lock().unwrap();
+
+        match state.count {
+            0 => {
+                warn!("resume called without a matching pause");
+                Ok(())
+            }
+            1 => {
+                state.count = 0;
+                if std::mem::take(&mut state.stopped_by_us) {
+                    self.execute(&qmp::cont {}).map(drop)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => {
+                state.count -= 1;
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that the monitor is reachable and answers commands with `query-status`.
+    pub fn probe(&self) -> Result<()> {
+        self.execute(&qmp::query_status {}).map(drop)
+    }
+}
+
+/// Connects to a unix socket, names with a leading `@` refer to the abstract namespace.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn connect_unix(path: &str) -> io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+
+    match path.strip_prefix('@') {
+        Some(name) => UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?),
+        None => UnixStream::connect(path),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp", feature = "vsock"))]
+fn connect_vsock(cid: u32, port: u32) -> io::Result<QmpStream> {
+    vsock::VsockStream::connect_with_cid_port(cid, port).map(QmpStream::Vsock)
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp", not(feature = "vsock")))]
+fn connect_vsock(_cid: u32, _port: u32) -> io::Result<QmpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "vsock qmp sockets require the `vsock` feature",
+    ))
+}
+
+/// Reads qmp events from `stream` until the connection is closed or `resets` is dropped.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn watch_events(mut reader: BufReader<QmpStream>, resets: Weak<AtomicUsize>) {
+    let mut line = vec![];
+
+    loop {
+        // a timeout may interrupt a partially received line, which is kept in `line`
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {
+                let Some(resets) = resets.upgrade() else {
+                    break;
+                };
+                if let Some(event) = parse_layout_event(&line) {
+                    info!(
+                        "qmp event {} received, guest memory mappings are stale",
+                        event
+                    );
+                    resets.fetch_add(1, Ordering::SeqCst);
+                }
+                line.clear();
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if resets.strong_count() == 0 {
+                    break;
+                }
+            }
+            Err(err) => {
+                warn!("qmp event connection failed: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Returns the name of the event if `line` is a qmp event changing the guest memory layout.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn parse_layout_event(line: &[u8]) -> Option<&'static str> {
+    let message: serde_json::Value = serde_json::from_slice(line).ok()?;
+    let event = message.get("event")?.as_str()?;
+    LAYOUT_EVENTS.into_iter().find(|e| *e == event)
+}
+
+/// Maps a failed greeting or capability negotiation to [`QemuError::QmpHandshake`].
+///
+/// Qmp itself has no authentication which could be negotiated, tcp monitors are secured
+/// with tls instead and never send a greeting to a plain connection.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn handshake_error(stream: &QmpStream, err: impl std::fmt::Display) -> Error {
+    let error = Error::from(QemuError::QmpHandshake);
+    match stream {
+        QmpStream::Tcp(_) => error.log_error(format!(
+            "{}, tls secured tcp monitors are not supported, connect through a unix socket or an ssh tunnel instead",
+            err
+        )),
+        _ => error.log_error(err),
+    }
+}
+
+/// Maps an error of a command rejected by qemu to [`QemuError::QmpCommand`].
+///
+/// Failures of the connection itself remain a [`QemuError::QmpConnect`].
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn execute_error(err: qapi::ExecuteError) -> Error {
+    match err {
+        qapi::ExecuteError::Qapi(err) => Error::from(QemuError::QmpCommand).log_error(err.desc),
+        err => Error::from(QemuError::QmpConnect).log_error(err),
+    }
+}
+
+/// Returns true for the `oob` capability of the qmp greeting.
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+fn is_oob_capability(capability: &qmp::QmpCapability) -> bool {
+    match capability {
+        qmp::QmpCapability::OutOfBand => true,
+        // the untagged enum only matches the unit variant for `null`
+        qmp::QmpCapability::Unknown(value) => value.as_str() == Some("oob"),
+    }
+}
+
+/// Resumes the guest when dropped, see [`QmpMonitor::pause_guard`].
+pub struct PauseGuard(QmpMonitor);
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.resume() {
+            log::error!("unable to resume guest: {}", err);
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+impl QmpMonitor {
+    /// Pauses the guest until the returned guard is dropped.
+    ///
+    /// The guest is also resumed when the guard is dropped during unwinding.
+    pub fn pause_guard(&self) -> Result<PauseGuard> {
+        self.pause()?;
+        Ok(PauseGuard(self.clone()))
+    }
+}
+
+/// Abstract unix sockets only exist on linux.
+#[cfg(not(all(target_os = "linux", feature = "qmp")))]
+fn log_abstract_unsupported() {
+    if cfg!(target_os = "linux") {
+        return;
+    }
+    log::error!("qmp sockets in the abstract namespace (`unix:@name`) are only supported on linux");
+}
+
+/// Placeholder for builds without qmp support, no monitor can ever be constructed.
+#[cfg(not(all(target_os = "linux", feature = "qmp")))]
+#[derive(Clone)]
+pub enum QmpMonitor {}
+
+#[cfg(not(all(target_os = "linux", feature = "qmp")))]
+impl QmpMonitor {
+    pub fn from_cmdline(cmdline: &str, _prefer: Option<QmpTransport>) -> Option<Self> {
+        if cmdline.contains("unix:@") {
+            log_abstract_unsupported();
+        }
+        info!("qmp support is not enabled in this build");
+        None
+    }
+
+    pub fn from_addr(addr: &str) -> Option<Self> {
+        if addr.starts_with("unix:@") || addr.starts_with('@') {
+            log_abstract_unsupported();
+        }
+        info!("qmp support is not enabled in this build");
+        None
+    }
+
+    pub fn with_retry(self, _retry: QmpRetry) -> Self {
+        match self {}
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn connect_time(&self) -> std::time::Duration {
+        match *self {}
+    }
+
+    pub fn cpu_count(&self) -> Result<usize> {
+        match *self {}
+    }
+
+    pub fn version(&self) -> Result<(u32, u32)> {
+        match *self {}
+    }
+
+    pub fn target_arch(&self) -> Result<&'static str> {
+        match *self {}
+    }
+
+    pub fn calc_dirty_rate(&self, _calc_time: Duration) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn dirty_rate(&self) -> Result<Option<u64>> {
+        match *self {}
+    }
+
+    pub fn watch_resets(
+        &self,
+        _resets: &std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    ) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn probe(&self) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn pause_guard(&self) -> Result<PauseGuard> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+#[cfg(all(target_os = "linux", feature = "qmp"))]
+pub(crate) mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A minimal QMP server answering commands through a handler function.
+    pub(crate) struct MockQmp {
+        path: PathBuf,
+        commands: Arc<Mutex<Vec<String>>>,
+        oob_commands: Arc<Mutex<Vec<String>>>,
+        enabled: Arc<Mutex<Vec<Value>>>,
+        clients: Arc<Mutex<Vec<UnixStream>>>,
+    }
+
+    impl MockQmp {
+        pub fn spawn<F>(handler: F) -> Self
+        where
+            F: Fn(&str, &Value) -> std::result::Result<Value, String> + Send + 'static,
+        {
+            Self::spawn_with(json!([]), false, handler)
+        }
+
+        /// Spawns a server advertising the `oob` capability.
+        ///
+        /// Unless `allow_oob` is set out-of-band commands are rejected like qemu does
+        /// for commands which do not support it.
+        pub fn spawn_oob<F>(allow_oob: bool, handler: F) -> Self
+        where
+            F: Fn(&str, &Value) -> std::result::Result<Value, String> + Send + 'static,
+        {
+            Self::spawn_with(json!(["oob"]), allow_oob, handler)
+        }
+
+        fn spawn_with<F>(capabilities: Value, allow_oob: bool, handler: F) -> Self
+        where
+            F: Fn(&str, &Value) -> std::result::Result<Value, String> + Send + 'static,
+        {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+            let path = std::env::temp_dir().join(format!(
+                "memflow-qemu-qmp-{}-{}.sock",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            ));
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).unwrap();
+
+            let commands = Arc::new(Mutex::new(vec![]));
+            let log = commands.clone();
+            let oob_commands = Arc::new(Mutex::new(vec![]));
+            let oob_log = oob_commands.clone();
+            let enabled = Arc::new(Mutex::new(vec![]));
+            let enabled_log = enabled.clone();
+
+            let clients = Arc::new(Mutex::new(vec![]));
+            let connected = clients.clone();
+            let handler = Arc::new(Mutex::new(handler));
+
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(mut stream) = stream else {
+                        break;
+                    };
+                    let capabilities = capabilities.clone();
+                    let (log, oob_log, enabled_log) =
+                        (log.clone(), oob_log.clone(), enabled_log.clone());
+                    let connected = connected.clone();
+                    let handler = handler.clone();
+
+                    // connections are served concurrently as event watchers never close theirs
+                    std::thread::spawn(move || {
+                        let greeting = json!({
+                            "QMP": {
+                                "version": {
+                                    "qemu": { "major": 8, "minor": 2, "micro": 0 },
+                                    "package": ""
+                                },
+                                "capabilities": capabilities
+                            }
+                        });
+                        if writeln!(stream, "{}", greeting).is_err() {
+                            return;
+                        }
+                        connected.lock().unwrap().push(stream.try_clone().unwrap());
+
+                        let reader = BufReader::new(stream.try_clone().unwrap());
+                        for line in reader.lines() {
+                            let Ok(line) = line else {
+                                break;
+                            };
+                            let request: Value = serde_json::from_str(&line).unwrap();
+
+                            if let Some(command) = request["exec-oob"].as_str() {
+                                oob_log.lock().unwrap().push(command.to_owned());
+                                let result = if allow_oob {
+                                    (handler.lock().unwrap())(command, &request["arguments"])
+                                } else {
+                                    Err(format!("The command {} does not support OOB", command))
+                                };
+                                let response = match result {
+                                    Ok(ret) => json!({ "return": ret, "id": request["id"] }),
+                                    Err(desc) => json!({
+                                        "error": { "class": "GenericError", "desc": desc },
+                                        "id": request["id"]
+                                    }),
+                                };
+                                if writeln!(stream, "{}", response).is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+
+                            let command = request["execute"].as_str().unwrap_or_default();
+                            let response = if command == "qmp_capabilities" {
+                                if let Some(enable) = request["arguments"]["enable"].as_array() {
+                                    enabled_log.lock().unwrap().extend(enable.iter().cloned());
+                                }
+                                json!({ "return": {} })
+                            } else {
+                                log.lock().unwrap().push(command.to_owned());
+                                match (handler.lock().unwrap())(command, &request["arguments"]) {
+                                    Ok(ret) => json!({ "return": ret }),
+                                    Err(desc) => json!({
+                                        "error": { "class": "GenericError", "desc": desc }
+                                    }),
+                                }
+                            };
+
+                            if writeln!(stream, "{}", response).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+
+            Self {
+                path,
+                commands,
+                oob_commands,
+                enabled,
+                clients,
+            }
+        }
+
+        pub fn path(&self) -> &std::path::Path {
+            &self.path
+        }
+
+        pub fn addr(&self) -> QmpAddr {
+            QmpAddr::Unix(self.path.to_string_lossy().into_owned())
+        }
+
+        pub fn monitor(&self) -> QmpMonitor {
+            QmpMonitor::new(vec![self.addr()])
+        }
+
+        /// Returns all commands received so far, excluding the capabilities negotiation.
+        pub fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+
+        /// Returns all commands received with `exec-oob`, whether they were accepted or not.
+        pub fn oob_commands(&self) -> Vec<String> {
+            self.oob_commands.lock().unwrap().clone()
+        }
+
+        /// Sends an event to all connected clients.
+        pub fn send_event(&self, event: &str) {
+            let event = json!({
+                "event": event,
+                "data": {},
+                "timestamp": { "seconds": 0, "microseconds": 0 }
+            });
+            for mut client in self.clients.lock().unwrap().iter() {
+                let _ = writeln!(client, "{}", event);
+            }
+        }
+
+        /// Returns the capabilities enabled by clients during the negotiation.
+        pub fn enabled_capabilities(&self) -> Vec<Value> {
+            self.enabled.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for MockQmp {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    pub(crate) fn status(running: bool) -> Value {
+        json!({
+            "running": running,
+            "singlestep": false,
+            "status": if running { "running" } else { "paused" }
+        })
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(
+            QmpAddr::parse("unix:/tmp/qmp.sock"),
+            Some(QmpAddr::Unix("/tmp/qmp.sock".into()))
+        );
+        assert_eq!(
+            QmpAddr::parse("tcp:localhost:4444"),
+            Some(QmpAddr::Tcp("localhost:4444".into()))
+        );
+        assert_eq!(
+            QmpAddr::parse("vsock:2:4444"),
+            Some(QmpAddr::Vsock { cid: 2, port: 4444 })
+        );
+        assert_eq!(QmpAddr::parse("vsock:2"), None);
+        assert_eq!(QmpAddr::parse("vsock:host:4444"), None);
+        assert_eq!(QmpAddr::parse("stdio"), None);
+    }
+
+    #[test]
+    fn test_from_cmdline_multiple() {
+        let cmdline = "qemu-system-x86_64 -qmp unix:/tmp/qmp.sock,server,nowait -qmp tcp:localhost:4444,server,nowait";
+
+        let monitor = QmpMonitor::from_cmdline(cmdline, None).unwrap();
+        assert_eq!(
+            monitor.addrs,
+            vec![
+                QmpAddr::Unix("/tmp/qmp.sock".into()),
+                QmpAddr::Tcp("localhost:4444".into())
+            ]
+        );
+
+        let monitor = QmpMonitor::from_cmdline(cmdline, Some(QmpTransport::Tcp)).unwrap();
+        assert_eq!(monitor.addrs[0], QmpAddr::Tcp("localhost:4444".into()));
+
+        assert!(QmpMonitor::from_cmdline("qemu-system-x86_64 -m 4G", None).is_none());
+    }
+
+    #[test]
+    fn test_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("memflow-qemu-qmp-abstract-{}", std::process::id());
+        let _listener =
+            UnixListener::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+
+        let addr = format!("unix:@{}", name);
+        let monitor = QmpMonitor::from_addr(&addr).unwrap();
+        assert_eq!(monitor.addrs, vec![QmpAddr::Unix(format!("@{}", name))]);
+        assert!(monitor.connect().is_ok());
+
+        // the same name as a filesystem path does not exist
+        assert!(connect_unix(&name).is_err());
+    }
+
+    #[test]
+    fn test_from_addr() {
+        for (addr, expected) in [
+            ("unix:/tmp/qmp.sock", QmpAddr::Unix("/tmp/qmp.sock".into())),
+            ("/tmp/qmp.sock", QmpAddr::Unix("/tmp/qmp.sock".into())),
+            ("tcp:localhost:4444", QmpAddr::Tcp("localhost:4444".into())),
+        ] {
+            assert_eq!(QmpMonitor::from_addr(addr).unwrap().addrs, vec![expected]);
+        }
+    }
+
+    #[test]
+    fn test_parse_layout_event() {
+        let event = |name: &str| {
+            json!({ "event": name, "data": {}, "timestamp": { "seconds": 1, "microseconds": 2 } })
+                .to_string()
+        };
+        assert_eq!(parse_layout_event(event("RESET").as_bytes()), Some("RESET"));
+        assert_eq!(
+            parse_layout_event(event("SHUTDOWN").as_bytes()),
+            Some("SHUTDOWN")
+        );
+        assert_eq!(parse_layout_event(event("STOP").as_bytes()), None);
+        assert_eq!(parse_layout_event(br#"{"return": {}}"#), None);
+        assert_eq!(parse_layout_event(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_pause_oob() {
+        let handler = |cmd: &str, _: &Value| match cmd {
+            "query-status" => Ok(status(true)),
+            "stop" | "cont" => Ok(json!({})),
+            _ => Err(format!("unexpected command {}", cmd)),
+        };
+
+        // the commands are tagged as out-of-band once the capability was negotiated
+        let mock = MockQmp::spawn_oob(true, handler);
+        let monitor = mock.monitor();
+        monitor.pause().unwrap();
+        // every command is sent over its own connection
+        assert_eq!(mock.enabled_capabilities(), vec![json!("oob"); 2]);
+        assert_eq!(mock.oob_commands(), vec!["query-status", "stop"]);
+        assert!(mock.commands().is_empty());
+        monitor.resume().unwrap();
+        assert_eq!(mock.commands(), vec!["cont"]);
+
+        // commands rejected out-of-band are retried in-band
+        let mock = MockQmp::spawn_oob(false, handler);
+        mock.monitor().pause().unwrap();
+        assert_eq!(mock.oob_commands(), vec!["query-status", "stop"]);
+        assert_eq!(mock.commands(), vec!["query-status", "stop"]);
+
+        // monitors without oob support are used in-band without enabling the capability
+        let mock = MockQmp::spawn(handler);
+        mock.monitor().pause().unwrap();
+        assert!(mock.enabled_capabilities().is_empty());
+        assert!(mock.oob_commands().is_empty());
+        assert_eq!(mock.commands(), vec!["query-status", "stop"]);
+    }
+
+    #[test]
+    fn test_command_error() {
+        let handler = |cmd: &str, _: &Value| match cmd {
+            "query-status" => Ok(status(true)),
+            _ => Err(format!("unexpected command {}", cmd)),
+        };
+
+        let mock = MockQmp::spawn(handler);
+        let err = mock.monitor().execute(&qmp::stop {}).unwrap_err();
+        assert_eq!(QemuError::try_from(err), Ok(QemuError::QmpCommand));
+
+        // a command rejected in-band after the out-of-band attempt failed as well
+        let mock = MockQmp::spawn_oob(false, handler);
+        let err = mock.monitor().execute_oob(&qmp::stop {}).unwrap_err();
+        assert_eq!(QemuError::try_from(err), Ok(QemuError::QmpCommand));
+
+        let monitor = QmpMonitor::new(vec![QmpAddr::Unix("/nonexistent/qmp.sock".into())]);
+        let err = monitor.execute(&qmp::stop {}).unwrap_err();
+        assert_eq!(QemuError::try_from(err), Ok(QemuError::QmpConnect));
+    }
+
+    #[test]
+    fn test_connect_fallback() {
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "query-status" => Ok(status(true)),
+            _ => Err(format!("unexpected command {}", cmd)),
+        });
+
+        let monitor = QmpMonitor::new(vec![
+            QmpAddr::Unix("/nonexistent/qmp.sock".into()),
+            mock.addr(),
+        ]);
+        assert!(monitor.execute(&qmp::query_status {}).unwrap().running);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = QmpRetry {
+            attempts: 5,
+            backoff: Duration::from_millis(300),
+        };
+        let delays = (0..5).map(|a| retry.delay(a)).collect::<Vec<_>>();
+        assert_eq!(delays, [0, 300, 600, 1000, 1000].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn test_tcp_without_greeting() {
+        // a tls secured monitor does not send a greeting but answers with a tls record
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"\x15\x03\x03\x00\x02\x02\x28\n").unwrap();
+            let _ = stream.read(&mut [0; 1]);
+        });
+
+        let monitor = QmpMonitor::from_addr(&format!("tcp:{}", addr)).unwrap();
+        let err = monitor.execute(&qmp::query_status {}).unwrap_err();
+        assert_eq!(QemuError::try_from(err), Ok(QemuError::QmpHandshake));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_retry() {
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "query-status" => Ok(status(true)),
+            _ => Err(format!("unexpected command {}", cmd)),
+        });
+
+        // the socket only appears after qemu has been started
+        let late = mock.path().with_extension("late");
+        std::fs::rename(mock.path(), &late).unwrap();
+        let retry = QmpRetry {
+            attempts: 5,
+            backoff: Duration::from_millis(50),
+        };
+
+        let monitor = QmpMonitor::new(vec![mock.addr()]).with_retry(QmpRetry {
+            attempts: 1,
+            ..retry
+        });
+        assert!(monitor.execute(&qmp::query_status {}).is_err());
+
+        let monitor = QmpMonitor::new(vec![mock.addr()]).with_retry(retry);
+        let (path, late_path) = (mock.path().to_owned(), late.clone());
+        let appear = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            std::fs::rename(late_path, path).unwrap();
+        });
+        assert!(monitor.execute(&qmp::query_status {}).unwrap().running);
+        appear.join().unwrap();
+
+        // later connections fail right away
+        std::fs::rename(mock.path(), &late).unwrap();
+        let start = Instant::now();
+        assert!(monitor.clone().execute(&qmp::query_status {}).is_err());
+        assert!(start.elapsed() < Duration::from_millis(50));
+        std::fs::rename(&late, mock.path()).unwrap();
+    }
+
+    #[test]
+    fn test_pause_running() {
+        let running = Arc::new(Mutex::new(true));
+        let state = running.clone();
+        let mock = MockQmp::spawn(move |cmd, _| {
+            let mut running = state.lock().unwrap();
+            match cmd {
+                "query-status" => Ok(status(*running)),
+                "stop" => {
+                    *running = false;
+                    Ok(json!({}))
+                }
+                "cont" => {
+                    *running = true;
+                    Ok(json!({}))
+                }
+                _ => Err(format!("unknown command {}", cmd)),
+            }
+        });
+
+        let monitor = mock.monitor();
+        let clone = monitor.clone();
+        monitor.pause().unwrap();
+        clone.pause().unwrap();
+        assert!(!*running.lock().unwrap());
+
+        clone.resume().unwrap();
+        assert!(!*running.lock().unwrap());
+        monitor.resume().unwrap();
+        assert!(*running.lock().unwrap());
+
+        assert_eq!(mock.commands(), vec!["query-status", "stop", "cont"]);
+    }
+
+    #[test]
+    fn test_pause_already_paused() {
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "query-status" => Ok(status(false)),
+            _ => Err(format!("unexpected command {}", cmd)),
+        });
+
+        let monitor = mock.monitor();
+        monitor.pause().unwrap();
+        monitor.resume().unwrap();
+
+        // the guest was paused by someone else, so it must neither be stopped nor resumed by us
+        assert_eq!(mock.commands(), vec!["query-status"]);
+    }
+
+    #[test]
+    fn test_dirty_rate() {
+        let measured = Arc::new(AtomicBool::new(false));
+        let state = measured.clone();
+        let mock = MockQmp::spawn(move |cmd, args| match cmd {
+            "query-commands" => Ok(json!([
+                { "name": "calc-dirty-rate" },
+                { "name": "query-dirty-rate" }
+            ])),
+            "calc-dirty-rate" if args["calc-time"] == 1 => Ok(json!({})),
+            "query-dirty-rate" => Ok(json!({
+                "status": if state.swap(true, Ordering::SeqCst) { "measured" } else { "measuring" },
+                "dirty-rate": 42,
+                "calc-time": 1,
+                "mode": "page-sampling",
+                "sample-pages": 512,
+                "start-time": 0
+            })),
+            _ => Err(format!("unexpected command {}", cmd)),
+        });
+
+        let monitor = mock.monitor();
+        monitor.calc_dirty_rate(Duration::from_secs(1)).unwrap();
+        assert_eq!(monitor.dirty_rate().unwrap(), None);
+        assert_eq!(monitor.dirty_rate().unwrap(), Some(42));
+
+        // qemu before 5.2 does not know the commands
+        let mock = MockQmp::spawn(|cmd, _| match cmd {
+            "query-commands" => Ok(json!([{ "name": "query-status" }])),
+            _ => Err(format!("unexpected command {}", cmd)),
+        });
+        assert_eq!(
+            mock.monitor()
+                .calc_dirty_rate(Duration::from_secs(1))
+                .unwrap_err()
+                .1,
+            ErrorKind::NotImplemented
+        );
+        assert_eq!(mock.commands(), vec!["query-commands"]);
+    }
+}
@@ -0,0 +1,141 @@
+/// General purpose and control registers of a x86 guest vCPU.
+///
+/// Registers of 32-bit guests are zero-extended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GuestRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl GuestRegisters {
+    /// Parses the output of the `info registers` monitor command.
+    ///
+    /// Both 64-bit (`RAX=`) and 32-bit (`EAX=`) dumps are supported.
+    /// Segment, descriptor table, fpu and vector registers are ignored.
+    /// Returns `None` if the output does not contain an instruction pointer.
+    pub fn parse(output: &str) -> Option<Self> {
+        let mut regs = Self::default();
+        let mut found_rip = false;
+
+        for line in output.lines() {
+            // registers are padded to the same width, e.g. `R8 =0000000000000000`
+            let line = line.replace(" =", "=");
+            for (name, value) in line
+                .split_whitespace()
+                .filter_map(|token| token.split_once('='))
+            {
+                let Some(reg) = regs.register_mut(name) else {
+                    continue;
+                };
+                let Ok(value) = u64::from_str_radix(value, 16) else {
+                    continue;
+                };
+
+                *reg = value;
+                found_rip |= matches!(name, "RIP" | "EIP");
+            }
+        }
+
+        found_rip.then_some(regs)
+    }
+
+    fn register_mut(&mut self, name: &str) -> Option<&mut u64> {
+        Some(match name {
+            "RAX" | "EAX" => &mut self.rax,
+            "RBX" | "EBX" => &mut self.rbx,
+            "RCX" | "ECX" => &mut self.rcx,
+            "RDX" | "EDX" => &mut self.rdx,
+            "RSI" | "ESI" => &mut self.rsi,
+            "RDI" | "EDI" => &mut self.rdi,
+            "RBP" | "EBP" => &mut self.rbp,
+            "RSP" | "ESP" => &mut self.rsp,
+            "R8" => &mut self.r8,
+            "R9" => &mut self.r9,
+            "R10" => &mut self.r10,
+            "R11" => &mut self.r11,
+            "R12" => &mut self.r12,
+            "R13" => &mut self.r13,
+            "R14" => &mut self.r14,
+            "R15" => &mut self.r15,
+            "RIP" | "EIP" => &mut self.rip,
+            "RFL" | "EFL" => &mut self.rflags,
+            "CR0" => &mut self.cr0,
+            "CR2" => &mut self.cr2,
+            "CR3" => &mut self.cr3,
+            "CR4" => &mut self.cr4,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_registers_64() {
+        let output = r#"RAX=0000000000000000 RBX=fffff8036d2e9180 RCX=0000000000000001 RDX=000000d0a6e7f000
+RSI=0000000000000000 RDI=ffffae8d1b2c0080 RBP=0000000000000000 RSP=fffff8036f0a5d38
+R8 =00000000000000b6 R9 =0000000000000000 R10=0000000000000000 R11=0000000000000000
+R12=0000000000000000 R13=0000000000000000 R14=0000000000000000 R15=fffff80369c00000
+RIP=fffff8036b7f6e4f RFL=00000246 [---Z-P-] CPL=0 II=0 A20=1 SMM=0 HLT=1
+ES =002b 0000000000000000 ffffffff 00c0f300 DPL=3 DS   [-WA]
+CS =0010 0000000000000000 00000000 00209b00 DPL=0 CS64 [-RA]
+GDT=     fffff8036f097fb0 00000057
+IDT=     fffff8036f095000 00000fff
+CR0=80050033 CR2=0000020fd7d14000 CR3=00000000001ad002 CR4=00350ef8
+FCW=027f FSW=0000 [ST=0] FTW=00 MXCSR=00001f80
+FPR0=0000000000000000 0000 FPR1=0000000000000000 0000
+XMM00=0000000000000000000000000000ffff XMM01=00000000000000000000000000000000
+"#;
+
+        let regs = GuestRegisters::parse(output).unwrap();
+        assert_eq!(regs.rbx, 0xfffff8036d2e9180);
+        assert_eq!(regs.rsp, 0xfffff8036f0a5d38);
+        assert_eq!(regs.r8, 0xb6);
+        assert_eq!(regs.r15, 0xfffff80369c00000);
+        assert_eq!(regs.rip, 0xfffff8036b7f6e4f);
+        assert_eq!(regs.rflags, 0x246);
+        assert_eq!(regs.cr0, 0x80050033);
+        assert_eq!(regs.cr3, 0x1ad002);
+        assert_eq!(regs.cr4, 0x350ef8);
+    }
+
+    #[test]
+    fn test_parse_registers_32() {
+        let output = r#"EAX=00000000 EBX=00000000 ECX=00000000 EDX=00000663
+ESI=00000000 EDI=00000000 EBP=00000000 ESP=00006f00
+EIP=0000fff0 EFL=00000002 [-------] CPL=0 II=0 A20=1 SMM=0 HLT=0
+CR0=60000010 CR2=00000000 CR3=00000000 CR4=00000000
+"#;
+
+        let regs = GuestRegisters::parse(output).unwrap();
+        assert_eq!(regs.rdx, 0x663);
+        assert_eq!(regs.rsp, 0x6f00);
+        assert_eq!(regs.rip, 0xfff0);
+        assert_eq!(regs.rflags, 0x2);
+        assert_eq!(regs.cr0, 0x60000010);
+
+        assert_eq!(GuestRegisters::parse("unknown command"), None);
+    }
+}
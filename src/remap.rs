@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+
+use memflow::prelude::v1::*;
+
+/// Remaps guest physical addresses into the qemu process.
+///
+/// This is the same as memflow's `RemapView` except that the memory map can be
+/// replaced after construction, e.g. when the guest memory moved in the qemu process.
+#[derive(Clone)]
+pub(crate) struct GuestView<T: MemoryView> {
+    mem: T,
+    mem_map: MemoryMap<(Address, umem)>,
+}
+
+impl<T: MemoryView> GuestView<T> {
+    pub fn new(mem: T, mem_map: MemoryMap<(Address, umem)>) -> Self {
+        Self { mem, mem_map }
+    }
+
+    /// Returns the underlying view of the qemu process.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.mem
+    }
+
+    pub fn set_mem_map(&mut self, mem_map: MemoryMap<(Address, umem)>) {
+        self.mem_map = mem_map;
+    }
+}
+
+impl<T: MemoryView> MemoryView for GuestView<T> {
+    fn read_raw_iter(&mut self, MemOps { inp, out_fail, out }: ReadRawMemOps) -> Result<()> {
+        let out_fail = out_fail.map(RefCell::new);
+
+        let mut out_fail1 = out_fail
+            .as_ref()
+            .map(|of| move |data| of.borrow_mut().call(data));
+        let mut out_fail2 = out_fail
+            .as_ref()
+            .map(|of| move |data| of.borrow_mut().call(data));
+        let mut out_fail2 = out_fail2.as_mut().map(<_>::into);
+
+        let mut out = out.map(|o| move |data| o.call(data));
+        let mut out = out.as_mut().map(<_>::into);
+
+        let mem = &mut self.mem;
+        let iter = self
+            .mem_map
+            .map_base_iter(inp, out_fail1.as_mut())
+            .map(|CTup3((addr, _), meta_addr, data)| CTup3(addr, meta_addr, data));
+
+        MemOps::with_raw(iter, out.as_mut(), out_fail2.as_mut(), |data| {
+            mem.read_raw_iter(data)
+        })
+    }
+
+    fn write_raw_iter(&mut self, MemOps { inp, out_fail, out }: WriteRawMemOps) -> Result<()> {
+        let out_fail = out_fail.map(RefCell::new);
+
+        let mut out_fail1 = out_fail
+            .as_ref()
+            .map(|of| move |data| of.borrow_mut().call(data));
+        let mut out_fail2 = out_fail
+            .as_ref()
+            .map(|of| move |data| of.borrow_mut().call(data));
+        let mut out_fail2 = out_fail2.as_mut().map(<_>::into);
+
+        let mut out = out.map(|o| move |data| o.call(data));
+        let mut out = out.as_mut().map(<_>::into);
+
+        let mem = &mut self.mem;
+        let iter = self
+            .mem_map
+            .map_base_iter(inp, out_fail1.as_mut())
+            .map(|CTup3((addr, _), meta_addr, data)| CTup3(addr, meta_addr, data));
+
+        MemOps::with_raw(iter, out.as_mut(), out_fail2.as_mut(), |data| {
+            mem.write_raw_iter(data)
+        })
+    }
+
+    fn metadata(&self) -> MemoryViewMetadata {
+        MemoryViewMetadata {
+            max_address: self.mem_map.max_address(),
+            real_size: self.mem_map.real_size(),
+            ..self.mem.metadata()
+        }
+    }
+}
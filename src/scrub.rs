@@ -0,0 +1,67 @@
+//! Zeroing of temporary buffers holding guest memory, see `zeroize_buffers` of the builder.
+
+use std::ops::{Deref, DerefMut};
+
+/// Number of bytes zeroed by [`scrub`], lets tests check that buffers are zeroed before they are freed.
+#[cfg(test)]
+pub(crate) static SCRUBBED_BYTES: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+
+/// Zeroes `buf` so no copy of guest memory lingers in the connector.
+///
+/// Without the `zeroize` feature nothing is zeroed, the builder rejects `zeroize_buffers` then.
+pub(crate) fn scrub(buf: &mut [u8]) {
+    #[cfg(test)]
+    SCRUBBED_BYTES.fetch_add(buf.len(), std::sync::atomic::Ordering::SeqCst);
+    #[cfg(feature = "zeroize")]
+    zeroize::Zeroize::zeroize(buf);
+    #[cfg(not(feature = "zeroize"))]
+    let _ = buf;
+}
+
+/// A temporary buffer of guest memory which is zeroed before it is freed if `zeroize` is set.
+///
+/// The whole capacity is zeroed, including bytes left behind by shrinking the buffer.
+pub(crate) struct ScratchBuf {
+    buf: Vec<u8>,
+    zeroize: bool,
+}
+
+impl ScratchBuf {
+    pub fn new(zeroize: bool) -> Self {
+        Self {
+            buf: vec![],
+            zeroize,
+        }
+    }
+
+    pub fn zeroed(len: usize, zeroize: bool) -> Self {
+        Self {
+            buf: vec![0u8; len],
+            zeroize,
+        }
+    }
+}
+
+impl Deref for ScratchBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for ScratchBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for ScratchBuf {
+    fn drop(&mut self) {
+        if self.zeroize {
+            self.buf.resize(self.buf.capacity(), 0);
+            scrub(&mut self.buf);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use memflow::prelude::v1::*;
+
+use crate::mem_map::{MappingSource, LEGACY_HOLE};
+
+/// Severity of a [`SelfCheckFinding`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SelfCheckSeverity {
+    /// Reads may return wrong data for parts of the guest memory.
+    Warning,
+    /// Parts of the guest memory cannot be read at all.
+    Error,
+}
+
+/// A single problem found by [`QemuProcfs::self_check`](crate::QemuProcfs::self_check).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfCheckFinding {
+    pub severity: SelfCheckSeverity,
+    pub message: String,
+    /// A configuration change which might fix the problem.
+    pub suggestion: Option<String>,
+}
+
+/// Result of [`QemuProcfs::self_check`](crate::QemuProcfs::self_check).
+///
+/// The report is meant to be attached to issues, its `Display` output lists the memory map
+/// and all findings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfCheckReport {
+    pub mapping_source: MappingSource,
+    /// Guest physical base, host address and size of each mapping.
+    pub mappings: Vec<(Address, Address, umem)>,
+    pub findings: Vec<SelfCheckFinding>,
+}
+
+impl SelfCheckReport {
+    pub(crate) fn new(mem_map: &MemoryMap<(Address, umem)>, mapping_source: MappingSource) -> Self {
+        Self {
+            mapping_source,
+            mappings: mem_map
+                .iter()
+                .map(|mapping| {
+                    let (host, size) = *mapping.output();
+                    (mapping.base(), host, size)
+                })
+                .collect(),
+            findings: vec![],
+        }
+    }
+
+    /// Returns true if no errors were found, warnings are allowed.
+    pub fn is_ok(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &SelfCheckFinding> {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == SelfCheckSeverity::Warning)
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &SelfCheckFinding> {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == SelfCheckSeverity::Error)
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        severity: SelfCheckSeverity,
+        message: String,
+        suggestion: Option<&str>,
+    ) {
+        self.findings.push(SelfCheckFinding {
+            severity,
+            message,
+            suggestion: suggestion.map(str::to_owned),
+        });
+    }
+
+    /// Checks the memory map against the guest memory block `qemu_map` in the qemu process.
+    ///
+    /// Mappings outside of the block belong to other memory backends and are only checked for overlaps.
+    pub(crate) fn check_mem_map(&mut self, &CTup2(ram_base, ram_size): &CTup2<Address, umem>) {
+        let fix = self.wrong_map_suggestion();
+
+        if self.mappings.is_empty() {
+            self.push(
+                SelfCheckSeverity::Error,
+                "the memory map is empty".to_owned(),
+                fix,
+            );
+            return;
+        }
+
+        let ram_end = ram_base + ram_size;
+        let mut host_ranges = vec![];
+        for (base, host, size) in self.mappings.clone() {
+            if host >= ram_base && host < ram_end {
+                if host + size > ram_end {
+                    self.push(
+                        SelfCheckSeverity::Warning,
+                        format!(
+                            "the mapping at {:x} ({:x} bytes) extends {:x} bytes past the end of the guest memory",
+                            base,
+                            size,
+                            (host + size) - ram_end
+                        ),
+                        fix,
+                    );
+                }
+                host_ranges.push((host, (host + size).min(ram_end)));
+            }
+        }
+
+        host_ranges.sort();
+        for pair in host_ranges.windows(2) {
+            if pair[1].0 < pair[0].1 {
+                self.push(
+                    SelfCheckSeverity::Warning,
+                    format!(
+                        "multiple guest physical ranges are backed by the guest memory at {:x}",
+                        pair[1].0
+                    ),
+                    None,
+                );
+            }
+        }
+
+        // the legacy vga and rom areas below 1mb are never mapped on x86
+        host_ranges.push((
+            (ram_base + LEGACY_HOLE.0).min(ram_end),
+            (ram_base + LEGACY_HOLE.1).min(ram_end),
+        ));
+        host_ranges.sort();
+
+        let mut covered = 0;
+        let mut end = ram_base;
+        for &(start, range_end) in host_ranges.iter() {
+            if range_end > end {
+                covered += (range_end - start.max(end)) as umem;
+                end = range_end;
+            }
+        }
+
+        if covered < ram_size {
+            self.push(
+                SelfCheckSeverity::Warning,
+                format!(
+                    "{:x} of {:x} bytes of guest memory are not mapped",
+                    ram_size - covered,
+                    ram_size
+                ),
+                fix,
+            );
+        }
+    }
+
+    /// Checks the first bytes read from each mapping by [`QemuProcfs::sample_mappings`](crate::QemuProcfs::sample_mappings).
+    pub(crate) fn check_samples(&mut self, samples: &[(Address, Vec<u8>)]) {
+        let unreadable = self
+            .mappings
+            .iter()
+            .map(|(base, _, _)| *base)
+            .filter(|base| !samples.iter().any(|(addr, _)| addr == base))
+            .collect::<Vec<_>>();
+        for base in unreadable {
+            self.push(
+                SelfCheckSeverity::Error,
+                format!("the mapping at {:x} cannot be read", base),
+                self.wrong_map_suggestion(),
+            );
+        }
+
+        if !samples.is_empty() && samples.iter().all(|(_, data)| data.iter().all(|b| *b == 0)) {
+            self.push(
+                SelfCheckSeverity::Warning,
+                "all mappings start with zeroes, the guest might not have booted yet or the memory map is wrong"
+                    .to_owned(),
+                self.wrong_map_suggestion(),
+            );
+        }
+    }
+
+    /// Compares the memory map with the one reported by qmp.
+    pub(crate) fn check_qmp_mem_map(&mut self, qmp_map: Result<MemoryMap<(Address, umem)>>) {
+        match qmp_map {
+            Ok(qmp_map) if self.mapping_source != MappingSource::Qmp => {
+                if SelfCheckReport::new(&qmp_map, MappingSource::Qmp).mappings != self.mappings {
+                    self.push(
+                        SelfCheckSeverity::Warning,
+                        format!(
+                            "the {:?} memory map differs from the memory map reported by qmp",
+                            self.mapping_source
+                        ),
+                        Some("remove the `mapping_source` argument to use the qmp memory map"),
+                    );
+                }
+            }
+            Ok(_) => (),
+            Err(err) if self.mapping_source == MappingSource::Qmp => self.push(
+                SelfCheckSeverity::Warning,
+                format!("the qmp memory map cannot be queried anymore: {}", err),
+                None,
+            ),
+            Err(_) => (),
+        }
+    }
+
+    fn wrong_map_suggestion(&self) -> Option<&'static str> {
+        match self.mapping_source {
+            MappingSource::Fallback => Some(
+                "the machine type was probably detected wrongly, enable qmp with `-qmp unix:/tmp/qmp.sock,server,nowait` \
+                 or provide the mappings with `mapping_source=file`",
+            ),
+            MappingSource::File => Some("verify the mappings in the `map_file`"),
+            MappingSource::Qmp => Some(
+                "add missing memory regions with `ram_region_names` or fill gaps with `hybrid_map`",
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for SelfCheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "memory map ({:?}):", self.mapping_source)?;
+        for (base, host, size) in self.mappings.iter() {
+            writeln!(f, "  {:x}-{:x} -> {:x}", base, *base + *size, host)?;
+        }
+
+        if self.findings.is_empty() {
+            return write!(f, "no problems found");
+        }
+        for finding in self.findings.iter() {
+            write!(f, "{:?}: {}", finding.severity, finding.message)?;
+            if let Some(suggestion) = &finding.suggestion {
+                write!(f, " ({})", suggestion)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use memflow::prelude::v1::*;
+
+/// Widths a [`ByteSwapView`] can swap.
+pub(crate) const SWAP_WIDTHS: [usize; 3] = [2, 4, 8];
+
+/// Reverses the byte order of every `width` sized element in `data`.
+///
+/// Buffers which do not consist of whole elements are left untouched.
+pub(crate) fn swap_elements(data: &mut [u8], width: usize) {
+    if width > 1 && data.len().is_multiple_of(width) {
+        data.chunks_exact_mut(width).for_each(<[u8]>::reverse);
+    }
+}
+
+/// Swaps the elements of a read buffer before it is passed on to the caller.
+pub(crate) fn swapped(CTup2(meta_addr, mut data): ReadData, width: usize) -> ReadData {
+    swap_elements(&mut data, width);
+    CTup2(meta_addr, data)
+}
+
+/// A view which converts multi-byte values read from memory of the other byte order.
+///
+/// Every read consisting of whole `width` sized elements, e.g. an `u32` or `[u32; 4]` with a width of 4,
+/// has each element byte-swapped so the values can be used as host-native integers.
+/// Reads of any other length as well as writes are passed through unchanged.
+///
+/// # Examples
+///
+/// ```no_run
+/// use memflow::prelude::v1::*;
+/// use memflow_qemu::{ByteSwapView, QemuProcfs};
+///
+/// let os = memflow_native::create_os(
+///     &Default::default(),
+///     Option::<std::sync::Arc<_>>::None.into(),
+/// )
+/// .unwrap();
+/// let connector = QemuProcfs::builder(os).build().unwrap();
+///
+/// // e.g. a big endian s390x guest read from a little endian host
+/// let mut view = ByteSwapView::new(connector.into_phys_view(), 4).unwrap();
+/// let value: u32 = view.read(Address::from(0x1000)).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct ByteSwapView<T> {
+    mem: T,
+    width: usize,
+}
+
+impl<T: MemoryView> ByteSwapView<T> {
+    /// Wraps `mem` swapping elements of `width` bytes, which has to be 2, 4 or 8.
+    pub fn new(mem: T, width: usize) -> Result<Self> {
+        if !SWAP_WIDTHS.contains(&width) {
+            return Err(Error(ErrorOrigin::Connector, ErrorKind::InvalidArgument)
+                .log_error("the byte swap width has to be 2, 4 or 8"));
+        }
+        Ok(Self { mem, width })
+    }
+
+    /// Returns the underlying view.
+    pub fn into_inner(self) -> T {
+        self.mem
+    }
+}
+
+impl<T: MemoryView> MemoryView for ByteSwapView<T> {
+    fn read_raw_iter(
+        &mut self,
+        MemOps {
+            inp,
+            mut out,
+            out_fail,
+        }: ReadRawMemOps,
+    ) -> Result<()> {
+        // the buffers are filled in place, so successful reads are swapped even without a callback
+        let width = self.width;
+        let mut out = Some(move |data| opt_call(out.as_deref_mut(), swapped(data, width)));
+        let mut out = out.as_mut().map(<_>::into);
+        let mut out_fail = out_fail.map(|o| move |data| o.call(data));
+        let mut out_fail = out_fail.as_mut().map(<_>::into);
+
+        let mem = &mut self.mem;
+        MemOps::with_raw(inp, out.as_mut(), out_fail.as_mut(), |data| {
+            mem.read_raw_iter(data)
+        })
+    }
+
+    fn write_raw_iter(&mut self, data: WriteRawMemOps) -> Result<()> {
+        self.mem.write_raw_iter(data)
+    }
+
+    fn metadata(&self) -> MemoryViewMetadata {
+        self.mem.metadata()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::StubMemory;
+
+    #[test]
+    fn test_swap_view() {
+        let mem = StubMemory::new(vec![0; 0x40]);
+        {
+            let mut data = mem.mem.lock().unwrap();
+            data[0x10..0x12].copy_from_slice(&0x1122u16.to_be_bytes());
+            data[0x20..0x24].copy_from_slice(&0x1122_3344u32.to_be_bytes());
+            data[0x30..0x38].copy_from_slice(&0x1122_3344_5566_7788u64.to_be_bytes());
+        }
+
+        let mut view = ByteSwapView::new(mem.clone(), 2).unwrap();
+        assert_eq!(view.read::<u16>(Address::from(0x10)).unwrap(), 0x1122);
+        // a single byte is not a whole element
+        assert_eq!(view.read::<u8>(Address::from(0x10)).unwrap(), 0x11);
+
+        let mut view = ByteSwapView::new(mem.clone(), 4).unwrap();
+        assert_eq!(view.read::<u32>(Address::from(0x20)).unwrap(), 0x1122_3344);
+        assert_eq!(
+            view.read::<[u32; 2]>(Address::from(0x20)).unwrap(),
+            [0x1122_3344, 0]
+        );
+
+        let mut view = ByteSwapView::new(mem.clone(), 8).unwrap();
+        assert_eq!(
+            view.read::<u64>(Address::from(0x30)).unwrap(),
+            0x1122_3344_5566_7788
+        );
+
+        assert!(ByteSwapView::new(mem, 3).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use memflow::prelude::v1::*;
+
+use crate::scrub::ScratchBuf;
+
+type ReadResults = Vec<Option<ScratchBuf>>;
+type SpawnRead = dyn Fn(Vec<(Address, usize)>) -> mpsc::Receiver<ReadResults> + Send + Sync;
+
+/// Reads guest memory on a separate thread so reads which hang on the host can be abandoned.
+///
+/// Neither `process_vm_readv` nor page faults on a file backed mapping (e.g. a stale nfs mount)
+/// can be interrupted. Instead each read is issued on a worker thread with its own clone of
+/// the memory view and the caller only waits for up to `timeout`. A timed out worker is left
+/// behind and exits once the kernel eventually completes the read.
+///
+/// With `zeroize` the buffers are zeroed once the caller is done with them, or once a timed
+/// out worker finishes.
+#[derive(Clone)]
+pub(crate) struct TimeoutReader {
+    timeout: Duration,
+    spawn: Arc<SpawnRead>,
+}
+
+impl TimeoutReader {
+    pub fn new<V: MemoryView + Clone + 'static>(view: V, timeout: Duration, zeroize: bool) -> Self {
+        let view = Mutex::new(view);
+        Self {
+            timeout,
+            spawn: Arc::new(move |ranges| {
+                let mut view = view.lock().unwrap().clone();
+                let (tx, rx) = mpsc::channel();
+                std::thread::spawn(move || {
+                    let results = ranges
+                        .into_iter()
+                        .map(|(addr, len)| {
+                            let mut buf = ScratchBuf::zeroed(len, zeroize);
+                            view.read_raw_into(addr, &mut buf).ok().map(|_| buf)
+                        })
+                        .collect::<Vec<_>>();
+                    // the receiver is gone if the read timed out
+                    tx.send(results).ok();
+                });
+                rx
+            }),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Reads all `(address, length)` ranges, unreadable ranges are returned as `None`.
+    pub fn read(&self, ranges: Vec<(Address, usize)>) -> Result<ReadResults> {
+        (self.spawn)(ranges)
+            .recv_timeout(self.timeout)
+            .map_err(|_| {
+                Error(ErrorOrigin::Connector, ErrorKind::UnableToReadMemory).log_warn(format!(
+                    "guest memory read timed out after {:?}",
+                    self.timeout
+                ))
+            })
+    }
+}